@@ -105,6 +105,8 @@ pub struct Profile {
     pub opt_level: u32,
     pub lto: bool,
     pub codegen_units: Option<u32>,    // None = use rustc default
+    pub target_cpu: Option<String>,    // None = use rustc default
+    pub target_features: Option<String>,
     pub rustc_args: Option<Vec<String>>,
     pub rustdoc_args: Option<Vec<String>>,
     pub debuginfo: bool,
@@ -458,6 +460,8 @@ impl Default for Profile {
             opt_level: 0,
             lto: false,
             codegen_units: None,
+            target_cpu: None,
+            target_features: None,
             rustc_args: None,
             rustdoc_args: None,
             debuginfo: false,
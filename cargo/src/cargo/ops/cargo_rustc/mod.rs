@@ -454,9 +454,9 @@ fn build_base_args(cx: &Context,
                    unit: &Unit,
                    crate_types: &[&str]) {
     let Profile {
-        opt_level, lto, codegen_units, ref rustc_args, debuginfo,
-        debug_assertions, rpath, test, doc: _doc, run_custom_build,
-        rustdoc_args: _,
+        opt_level, lto, codegen_units, ref target_cpu, ref target_features,
+        ref rustc_args, debuginfo, debug_assertions, rpath, test, doc: _doc,
+        run_custom_build, rustdoc_args: _,
     } = *unit.profile;
     assert!(!run_custom_build);
 
@@ -483,9 +483,12 @@ fn build_base_args(cx: &Context,
         cmd.arg("-C").arg(&format!("opt-level={}", opt_level));
     }
 
-    if opt_level == 3 {
-        cmd.arg("-C").arg("target-cpu=native");
-        cmd.arg("-C").arg("target-feature=+avx,+avx2,+sse4.1,+sse4.2,+movbe");
+    if let Some(ref cpu) = *target_cpu {
+        cmd.arg("-C").arg(&format!("target-cpu={}", cpu));
+    }
+
+    if let Some(ref features) = *target_features {
+        cmd.arg("-C").arg(&format!("target-feature={}", features));
     }
 
     // Disable LTO for host builds as prefer_dynamic and it are mutually
@@ -236,6 +236,8 @@ pub struct TomlProfile {
     opt_level: Option<u32>,
     lto: Option<bool>,
     codegen_units: Option<u32>,
+    target_cpu: Option<String>,
+    target_features: Option<String>,
     debug: Option<bool>,
     debug_assertions: Option<bool>,
     rpath: Option<bool>,
@@ -993,7 +995,8 @@ fn build_profiles(profiles: &Option<TomlProfiles>) -> Profiles {
 
     fn merge(profile: Profile, toml: Option<&TomlProfile>) -> Profile {
         let &TomlProfile {
-            opt_level, lto, codegen_units, debug, debug_assertions, rpath
+            opt_level, lto, codegen_units, ref target_cpu, ref target_features,
+            debug, debug_assertions, rpath
         } = match toml {
             Some(toml) => toml,
             None => return profile,
@@ -1002,6 +1005,8 @@ fn build_profiles(profiles: &Option<TomlProfiles>) -> Profiles {
             opt_level: opt_level.unwrap_or(profile.opt_level),
             lto: lto.unwrap_or(profile.lto),
             codegen_units: codegen_units,
+            target_cpu: target_cpu.clone().or(profile.target_cpu),
+            target_features: target_features.clone().or(profile.target_features),
             rustc_args: None,
             rustdoc_args: None,
             debuginfo: debug.unwrap_or(profile.debuginfo),
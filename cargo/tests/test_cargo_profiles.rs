@@ -109,3 +109,36 @@ test!(top_level_overrides_deps {
                     prefix = env::consts::DLL_PREFIX,
                     suffix = env::consts::DLL_SUFFIX)));
 });
+
+test!(profile_target_cpu_and_features {
+    let mut p = project("foo");
+    p = p
+        .file("Cargo.toml", r#"
+            [package]
+
+            name = "test"
+            version = "0.0.0"
+            authors = []
+
+            [profile.release]
+            target-cpu = "haswell"
+            target-features = "+avx2,+movbe"
+        "#)
+        .file("src/lib.rs", "");
+    assert_that(p.cargo_process("build").arg("-v").arg("--release"),
+                execs().with_status(0).with_stdout(&format!("\
+{compiling} test v0.0.0 ({url})
+{running} `rustc src{sep}lib.rs --crate-name test --crate-type lib \
+        -C opt-level=3 \
+        -C target-cpu=haswell \
+        -C target-feature=+avx2,+movbe \
+        --out-dir {dir}{sep}target{sep}release \
+        --emit=dep-info,link \
+        -L dependency={dir}{sep}target{sep}release \
+        -L dependency={dir}{sep}target{sep}release{sep}deps`
+",
+running = RUNNING, compiling = COMPILING, sep = SEP,
+dir = p.root().display(),
+url = p.url(),
+)));
+});
@@ -1,11 +1,23 @@
 use std::env;
+use std::path::Path;
 /// Cargo runs main in this file to get some additional settings (e.g., LD_LIBRARY_PATH). It reads the printed output
 /// looking for certain variables, see [here](http://doc.crates.io/build-script.html) for documentation.
 fn main() {
+    // The in-memory test backend does not call into DPDK at all.
+    if env::var("CARGO_FEATURE_TEST_BACKEND").is_ok() {
+        return;
+    }
     // Get the directory where we are building.
     let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let dpdk_lib = dir.clone() + "/../3rdparty/dpdk/build/lib";
+    // Fail here rather than with an obscure linker error later on. Build scripts cannot tell whether tests are being
+    // built, so machines without DPDK need to enable the `test-backend` feature to run tests.
+    if !Path::new(&dpdk_lib).exists() {
+        panic!("DPDK not found at {}, run build.sh first (or enable the `test-backend` feature to build without DPDK)",
+               dpdk_lib);
+    }
     // Send current directory as -L
-    println!("cargo:rustc-link-search=native={}", dir.clone() + "/../3rdparty/dpdk/build/lib");
+    println!("cargo:rustc-link-search=native={}", dpdk_lib);
     println!("cargo:rustc-link-search=native={}", dir + "/../native");
     // Add -ldpdk
     println!("cargo:rustc-link-lib=dylib=dpdk");
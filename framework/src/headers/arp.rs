@@ -0,0 +1,160 @@
+use super::EndOffset;
+use std::fmt;
+use std::default::Default;
+use std::net::Ipv4Addr;
+
+/// Ethertype of ARP packets.
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+const HDR_SIZE: usize = 28;
+
+/// An ARP packet (RFC 826) for IPv4 over Ethernet, the only kind in use.
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct ArpHeader {
+    htype: u16,
+    ptype: u16,
+    hlen: u8,
+    plen: u8,
+    oper: u16,
+    sha: [u8; 6],
+    spa: u32,
+    tha: [u8; 6],
+    tpa: u32,
+}
+
+impl fmt::Display for ArpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sha = self.sender_mac();
+        let tha = self.target_mac();
+        write!(f,
+               "oper: {} sender: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} {} \
+                target: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} {}",
+               self.operation(),
+               sha[0],
+               sha[1],
+               sha[2],
+               sha[3],
+               sha[4],
+               sha[5],
+               Ipv4Addr::from(self.sender_ip()),
+               tha[0],
+               tha[1],
+               tha[2],
+               tha[3],
+               tha[4],
+               tha[5],
+               Ipv4Addr::from(self.target_ip()))
+    }
+}
+
+impl EndOffset for ArpHeader {
+    #[inline]
+    fn offset(&self) -> usize {
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn size() -> usize {
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn payload_size(&self, hint: usize) -> usize {
+        hint.saturating_sub(HDR_SIZE)
+    }
+}
+
+impl ArpHeader {
+    #[inline]
+    pub fn new() -> ArpHeader {
+        ArpHeader {
+            htype: u16::to_be(HTYPE_ETHERNET),
+            ptype: u16::to_be(PTYPE_IPV4),
+            hlen: 6,
+            plen: 4,
+            ..Default::default()
+        }
+    }
+
+    /// Whether this is an ARP packet for IPv4 over Ethernet (and hence whether the address accessors are meaningful).
+    #[inline]
+    pub fn is_ipv4_over_ethernet(&self) -> bool {
+        u16::from_be(self.htype) == HTYPE_ETHERNET && u16::from_be(self.ptype) == PTYPE_IPV4 && self.hlen == 6 &&
+        self.plen == 4
+    }
+
+    #[inline]
+    pub fn operation(&self) -> u16 {
+        u16::from_be(self.oper)
+    }
+
+    #[inline]
+    pub fn set_operation(&mut self, oper: u16) {
+        self.oper = u16::to_be(oper);
+    }
+
+    #[inline]
+    pub fn is_request(&self) -> bool {
+        self.operation() == ARP_REQUEST
+    }
+
+    #[inline]
+    pub fn is_reply(&self) -> bool {
+        self.operation() == ARP_REPLY
+    }
+
+    #[inline]
+    pub fn sender_mac(&self) -> [u8; 6] {
+        self.sha
+    }
+
+    #[inline]
+    pub fn set_sender_mac(&mut self, mac: &[u8; 6]) {
+        self.sha = *mac;
+    }
+
+    #[inline]
+    pub fn sender_ip(&self) -> u32 {
+        u32::from_be(self.spa)
+    }
+
+    #[inline]
+    pub fn set_sender_ip(&mut self, ip: u32) {
+        self.spa = u32::to_be(ip);
+    }
+
+    #[inline]
+    pub fn target_mac(&self) -> [u8; 6] {
+        self.tha
+    }
+
+    #[inline]
+    pub fn set_target_mac(&mut self, mac: &[u8; 6]) {
+        self.tha = *mac;
+    }
+
+    #[inline]
+    pub fn target_ip(&self) -> u32 {
+        u32::from_be(self.tpa)
+    }
+
+    #[inline]
+    pub fn set_target_ip(&mut self, ip: u32) {
+        self.tpa = u32::to_be(ip);
+    }
+
+    /// Turn a request into the reply announcing that the target address is at `mac`.
+    #[inline]
+    pub fn make_reply(&mut self, mac: &[u8; 6]) {
+        let (requester_mac, requester_ip, target_ip) = (self.sender_mac(), self.sender_ip(), self.target_ip());
+        self.set_operation(ARP_REPLY);
+        self.set_target_mac(&requester_mac);
+        self.set_target_ip(requester_ip);
+        self.set_sender_mac(mac);
+        self.set_sender_ip(target_ip);
+    }
+}
@@ -0,0 +1,235 @@
+use byteorder::{BigEndian, ByteOrder};
+
+/// Add `data` (treated as a sequence of big endian 16-bit words, padded with a zero byte if of odd length) to the one's
+/// complement sum `initial`. The result is not folded, use `fold_checksum` to get the final checksum.
+#[inline]
+pub fn ones_complement_sum(data: &[u8], initial: u32) -> u32 {
+    let mut sum = initial;
+    let mut words = data.chunks(2);
+    while let Some(word) = words.next() {
+        sum = sum.wrapping_add(if word.len() == 2 {
+            BigEndian::read_u16(word) as u32
+        } else {
+            (word[0] as u32) << 8
+        });
+        // Fold early so we never overflow, even for jumbo frames.
+        if sum & 0x80000000 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+    }
+    sum
+}
+
+/// Fold a one's complement sum into 16 bits and complement it, producing a checksum.
+#[inline]
+pub fn fold_checksum(sum: u32) -> u16 {
+    let mut sum = sum;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum (RFC 1071) of `data`.
+#[inline]
+pub fn checksum(data: &[u8]) -> u16 {
+    fold_checksum(ones_complement_sum(data, 0))
+}
+
+/// One's complement sum of the IPv4 pseudo-header used by UDP and TCP. `src` and `dst` are in host order.
+#[inline]
+pub fn ipv4_pseudo_header_sum(src: u32, dst: u32, protocol: u8, len: u16) -> u32 {
+    (src >> 16) + (src & 0xffff) + (dst >> 16) + (dst & 0xffff) + protocol as u32 + len as u32
+}
+
+/// One's complement sum of the IPv6 pseudo-header used by UDP, TCP and ICMPv6. `protocol` is the upper layer protocol,
+/// not the next header field of the IPv6 header (which might be an extension header).
+#[inline]
+pub fn ipv6_pseudo_header_sum(src: &[u8; 16], dst: &[u8; 16], protocol: u8, len: u32) -> u32 {
+    let sum = ones_complement_sum(src, 0);
+    let sum = ones_complement_sum(dst, sum);
+    sum + (len >> 16) + (len & 0xffff) + protocol as u32
+}
+
+/// Incrementally update `csum` (in host order) to account for a 16-bit field changing from `old` to `new`, using
+/// equation 3 from RFC 1624.
+#[inline]
+pub fn update_checksum_16(csum: u16, old: u16, new: u16) -> u16 {
+    fold_checksum((!csum) as u32 + (!old) as u32 + new as u32)
+}
+
+/// Incrementally update `csum` (in host order) to account for a 32-bit field changing from `old` to `new`.
+#[inline]
+pub fn update_checksum_32(csum: u16, old: u32, new: u32) -> u16 {
+    let sum = (!csum) as u32 + (!(old >> 16) & 0xffff) + (!old & 0xffff) + (new >> 16) + (new & 0xffff);
+    fold_checksum(sum)
+}
+
+/// Incrementally update `csum` (in host order) to account for the bytes in `old` being replaced by the bytes in `new`.
+/// Both slices must have the same, even, length and be aligned to a 16-bit word within the checksummed data.
+#[inline]
+pub fn update_checksum_bytes(csum: u16, old: &[u8], new: &[u8]) -> u16 {
+    debug_assert!(old.len() == new.len() && old.len() % 2 == 0);
+    let mut sum = (!csum) as u32;
+    for (o, n) in old.chunks(2).zip(new.chunks(2)) {
+        sum += (!BigEndian::read_u16(o)) as u32 + BigEndian::read_u16(n) as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    fold_checksum(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headers::{IpHeader, TcpHeader, UdpHeader};
+
+    /// Deterministic pseudo-random words, including the all-ones and all-zeros corner cases.
+    fn words(count: usize) -> Vec<u16> {
+        let mut state = 0x12345678u32;
+        let mut words = vec![0, 0xffff, 0xfffe, 1];
+        while words.len() < count {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            words.push((state >> 8) as u16);
+        }
+        words
+    }
+
+    fn bytes(words: &[u16]) -> Vec<u8> {
+        let mut bytes = vec![0u8; words.len() * 2];
+        for (i, word) in words.iter().enumerate() {
+            BigEndian::write_u16(&mut bytes[i * 2..], *word);
+        }
+        bytes
+    }
+
+    #[test]
+    fn incremental_updates_match_recompute() {
+        let mut data = words(32);
+        for (i, new) in words(64).into_iter().enumerate() {
+            let at = i % (data.len() - 1);
+            let old_bytes = bytes(&data);
+            let csum = checksum(&old_bytes);
+            let (old16, old32) = (data[at], ((data[at] as u32) << 16) | data[at + 1] as u32);
+
+            data[at] = new;
+            assert_eq!(update_checksum_16(csum, old16, new), checksum(&bytes(&data)));
+            assert_eq!(update_checksum_bytes(csum, &old_bytes[at * 2..at * 2 + 2], &bytes(&[new])),
+                       checksum(&bytes(&data)));
+
+            data[at + 1] = new.rotate_left(3);
+            let new32 = ((data[at] as u32) << 16) | data[at + 1] as u32;
+            assert_eq!(update_checksum_32(csum, old32, new32), checksum(&bytes(&data)));
+        }
+    }
+
+    /// An IPv4 header followed by an 8 byte L4 header (or the start of a 20 byte one) and some payload.
+    fn packet(protocol: u8) -> Vec<u8> {
+        let mut pkt = bytes(&words(40));
+        let len = pkt.len();
+        pkt[0] = 0x45;
+        BigEndian::write_u16(&mut pkt[2..4], len as u16);
+        pkt[6] = 0;
+        pkt[8] = 64;
+        pkt[9] = protocol;
+        if protocol == 17 {
+            BigEndian::write_u16(&mut pkt[24..26], (len - 20) as u16);
+        } else {
+            pkt[32] = 0x50;
+        }
+        pkt
+    }
+
+    fn headers<T>(pkt: &mut [u8]) -> (&mut IpHeader, &mut T) {
+        unsafe { (&mut *(pkt.as_mut_ptr() as *mut IpHeader), &mut *(pkt.as_mut_ptr().offset(20) as *mut T)) }
+    }
+
+    #[test]
+    fn ip_setters() {
+        let mut pkt = packet(17);
+        let (ip, _) = headers::<UdpHeader>(&mut pkt);
+        ip.compute_checksum();
+        for (i, word) in words(16).into_iter().enumerate() {
+            ip.set_src(((word as u32) << 16) | i as u32);
+            ip.set_dst(!(word as u32));
+            ip.set_ttl(word as u8);
+            assert!(ip.checksum_valid());
+        }
+    }
+
+    /// Apply `change` to a packet with a correct checksum, and compare the incrementally updated checksum with a full
+    /// recompute. Returns the updated checksum.
+    fn udp_after<F: Fn(&mut IpHeader, &mut UdpHeader)>(initial: Option<u16>, change: F) -> u16 {
+        let mut pkt = packet(17);
+        {
+            let (ip, udp) = headers::<UdpHeader>(&mut pkt);
+            match initial {
+                Some(csum) => udp.set_checksum(csum),
+                None => udp.compute_checksum(ip),
+            }
+            change(ip, udp);
+        }
+        let mut recomputed = pkt.clone();
+        let (ip, udp) = headers::<UdpHeader>(&mut recomputed);
+        udp.compute_checksum(ip);
+        let updated = headers::<UdpHeader>(&mut pkt).1.checksum();
+        if initial.is_none() {
+            assert_eq!(updated, udp.checksum());
+        }
+        updated
+    }
+
+    #[test]
+    fn udp_setters() {
+        for word in words(32) {
+            udp_after(None, |_, udp| udp.set_src_port(word));
+            udp_after(None, |_, udp| udp.set_dst_port(word));
+            udp_after(None, |ip, udp| {
+                let (old, new) = (ip.dst(), ((word as u32) << 16) | 0x0a0b);
+                ip.set_dst(new);
+                udp.update_checksum_for_address(old, new);
+            });
+        }
+    }
+
+    #[test]
+    fn udp_zero_checksum() {
+        // A checksum of 0 means none was computed, and updates must leave it that way.
+        assert_eq!(udp_after(Some(0), |ip, udp| {
+                       udp.set_src_port(1234);
+                       let old = ip.src();
+                       ip.set_src(0x0a000001);
+                       udp.update_checksum_for_address(old, 0x0a000001);
+                   }),
+                   0);
+        // An update which computes to 0 must be sent as 0xffff instead, just like a full recompute.
+        let mut pkt = packet(17);
+        let (ip, udp) = headers::<UdpHeader>(&mut pkt);
+        udp.compute_checksum(ip);
+        let port = udp.src_port();
+        let zero = (0..0x10000u32)
+            .map(|p| p as u16)
+            .find(|p| update_checksum_16(udp.checksum(), port, *p) == 0)
+            .expect("Some port leads to a zero checksum");
+        assert_eq!(udp_after(None, |_, udp| udp.set_src_port(zero)), 0xffff);
+    }
+
+    #[test]
+    fn tcp_setters() {
+        for word in words(32) {
+            let mut pkt = packet(6);
+            {
+                let (ip, tcp) = headers::<TcpHeader>(&mut pkt);
+                tcp.compute_checksum(ip);
+                tcp.set_src_port(word);
+                tcp.set_dst_port(!word);
+                let (old, new) = (ip.src(), (word as u32) << 8);
+                ip.set_src(new);
+                tcp.update_checksum_for_address(old, new);
+            }
+            let mut recomputed = pkt.clone();
+            let (ip, tcp) = headers::<TcpHeader>(&mut recomputed);
+            tcp.compute_checksum(ip);
+            assert_eq!(headers::<TcpHeader>(&mut pkt).1.checksum(), tcp.checksum());
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use super::EndOffset;
+use std::fmt;
+use std::default::Default;
+
+/// UDP destination port assigned to Geneve.
+pub const GENEVE_PORT: u16 = 6081;
+const GENEVE_FLAG_OAM: u8 = 0x80;
+const GENEVE_FLAG_CRITICAL: u8 = 0x40;
+const HDR_SIZE: usize = 8;
+
+/// A Geneve header (draft-ietf-nvo3-geneve), without the variable length options which follow it (see `options_len`).
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct GeneveHeader {
+    ver_opt_len: u8,
+    flags: u8,
+    protocol: u16,
+    vni_reserved: u32,
+}
+
+impl fmt::Display for GeneveHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "version: {} options: {} protocol: 0x{:04x} vni: {}",
+               self.version(),
+               self.options_len(),
+               self.protocol(),
+               self.vni())
+    }
+}
+
+impl EndOffset for GeneveHeader {
+    #[inline]
+    fn offset(&self) -> usize {
+        HDR_SIZE + self.options_len()
+    }
+
+    #[inline]
+    fn size() -> usize {
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn payload_size(&self, hint: usize) -> usize {
+        hint.saturating_sub(self.offset())
+    }
+}
+
+impl GeneveHeader {
+    #[inline]
+    pub fn new() -> GeneveHeader {
+        Default::default()
+    }
+
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.ver_opt_len >> 6
+    }
+
+    /// Length of the options following the header, in bytes.
+    #[inline]
+    pub fn options_len(&self) -> usize {
+        (self.ver_opt_len & 0x3f) as usize * 4
+    }
+
+    /// Set the length of the options following the header, which must be a multiple of 4 bytes.
+    #[inline]
+    pub fn set_options_len(&mut self, len: usize) {
+        self.ver_opt_len = (self.ver_opt_len & 0xc0) | ((len / 4) as u8 & 0x3f);
+    }
+
+    /// Whether this is an OAM (control) packet.
+    #[inline]
+    pub fn oam(&self) -> bool {
+        self.flags & GENEVE_FLAG_OAM != 0
+    }
+
+    #[inline]
+    pub fn set_oam(&mut self, oam: bool) {
+        if oam {
+            self.flags |= GENEVE_FLAG_OAM;
+        } else {
+            self.flags &= !GENEVE_FLAG_OAM;
+        }
+    }
+
+    /// Whether any of the options are critical, i.e., must be understood to process the packet.
+    #[inline]
+    pub fn critical_options(&self) -> bool {
+        self.flags & GENEVE_FLAG_CRITICAL != 0
+    }
+
+    /// Ethertype of the encapsulated packet (`ETHERTYPE_TEB` for Ethernet frames).
+    #[inline]
+    pub fn protocol(&self) -> u16 {
+        u16::from_be(self.protocol)
+    }
+
+    #[inline]
+    pub fn set_protocol(&mut self, protocol: u16) {
+        self.protocol = u16::to_be(protocol);
+    }
+
+    /// Virtual network identifier (24 bits).
+    #[inline]
+    pub fn vni(&self) -> u32 {
+        u32::from_be(self.vni_reserved) >> 8
+    }
+
+    #[inline]
+    pub fn set_vni(&mut self, vni: u32) {
+        self.vni_reserved = u32::to_be((vni & 0xffffff) << 8);
+    }
+}
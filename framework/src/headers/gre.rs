@@ -0,0 +1,142 @@
+use super::EndOffset;
+use std::fmt;
+use std::default::Default;
+use std::slice;
+
+/// IP protocol number of GRE.
+pub const GRE_PROTO: u8 = 47;
+/// Ethertype of Ethernet frames carried by GRE or Geneve (transparent Ethernet bridging).
+pub const ETHERTYPE_TEB: u16 = 0x6558;
+const GRE_FLAG_CHECKSUM: u16 = 0x8000;
+const GRE_FLAG_KEY: u16 = 0x2000;
+const GRE_FLAG_SEQUENCE: u16 = 0x1000;
+const HDR_SIZE: usize = 4;
+
+/// A GRE header (RFC 2784 and RFC 2890), without the optional checksum, key and sequence number fields which follow
+/// it when the corresponding flags are set.
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct GreHeader {
+    flags_version: u16,
+    protocol: u16,
+}
+
+impl fmt::Display for GreHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "flags: 0x{:04x} protocol: 0x{:04x}",
+               u16::from_be(self.flags_version),
+               self.protocol())
+    }
+}
+
+impl EndOffset for GreHeader {
+    #[inline]
+    fn offset(&self) -> usize {
+        let mut offset = HDR_SIZE;
+        for flag in &[GRE_FLAG_CHECKSUM, GRE_FLAG_KEY, GRE_FLAG_SEQUENCE] {
+            if self.flags() & flag != 0 {
+                offset += 4;
+            }
+        }
+        offset
+    }
+
+    #[inline]
+    fn size() -> usize {
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn payload_size(&self, hint: usize) -> usize {
+        hint.saturating_sub(self.offset())
+    }
+}
+
+impl GreHeader {
+    #[inline]
+    pub fn new() -> GreHeader {
+        Default::default()
+    }
+
+    #[inline]
+    fn flags(&self) -> u16 {
+        u16::from_be(self.flags_version)
+    }
+
+    #[inline]
+    pub fn version(&self) -> u8 {
+        (self.flags() & 0x7) as u8
+    }
+
+    #[inline]
+    pub fn has_checksum(&self) -> bool {
+        self.flags() & GRE_FLAG_CHECKSUM != 0
+    }
+
+    #[inline]
+    pub fn has_key(&self) -> bool {
+        self.flags() & GRE_FLAG_KEY != 0
+    }
+
+    #[inline]
+    pub fn has_sequence(&self) -> bool {
+        self.flags() & GRE_FLAG_SEQUENCE != 0
+    }
+
+    /// Ethertype of the encapsulated packet.
+    #[inline]
+    pub fn protocol(&self) -> u16 {
+        u16::from_be(self.protocol)
+    }
+
+    #[inline]
+    pub fn set_protocol(&mut self, protocol: u16) {
+        self.protocol = u16::to_be(protocol);
+    }
+
+    /// The key, if the header has one.
+    ///
+    /// # Warning
+    /// This reads past the end of the header, and is hence only meaningful for headers that are part of a packet (as is
+    /// the case for headers passed into batch operations).
+    #[inline]
+    pub fn key(&self) -> Option<u32> {
+        if self.has_key() {
+            let key = unsafe {
+                slice::from_raw_parts((self as *const GreHeader as *const u8).offset(self.key_offset()), 4)
+            };
+            Some(((key[0] as u32) << 24) | ((key[1] as u32) << 16) | ((key[2] as u32) << 8) | key[3] as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Set the key flag, and the key if `key` is not None. Other optional fields must not be present, since this does
+    /// not move them. The same caveat as `key` applies.
+    #[inline]
+    pub fn set_key(&mut self, key: Option<u32>) {
+        match key {
+            Some(key) => {
+                self.flags_version = u16::to_be(self.flags() | GRE_FLAG_KEY);
+                let offset = self.key_offset();
+                let bytes = unsafe { slice::from_raw_parts_mut((self as *mut GreHeader as *mut u8).offset(offset), 4) };
+                bytes[0] = (key >> 24) as u8;
+                bytes[1] = (key >> 16) as u8;
+                bytes[2] = (key >> 8) as u8;
+                bytes[3] = key as u8;
+            }
+            None => self.flags_version = u16::to_be(self.flags() & !GRE_FLAG_KEY),
+        }
+    }
+
+    /// Offset of the key, which follows the checksum (and reserved field), if any.
+    #[inline]
+    fn key_offset(&self) -> isize {
+        if self.has_checksum() {
+            (HDR_SIZE + 4) as isize
+        } else {
+            HDR_SIZE as isize
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use super::{EndOffset, Ipv6Header};
+use super::checksum::{fold_checksum, ipv6_pseudo_header_sum, ones_complement_sum, update_checksum_16};
+use std::fmt;
+use std::default::Default;
+use std::slice;
+
+pub const ICMP_PROTO: u8 = 1;
+pub const ICMPV6_PROTO: u8 = 58;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_DEST_UNREACHABLE: u8 = 3;
+pub const ICMP_SOURCE_QUENCH: u8 = 4;
+pub const ICMP_REDIRECT: u8 = 5;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_PARAMETER_PROBLEM: u8 = 12;
+
+/// Destination unreachable code used when a packet needs to be fragmented but has the don't fragment flag set.
+pub const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+/// Time exceeded code used when the TTL reaches zero in transit.
+pub const ICMP_TTL_EXCEEDED: u8 = 0;
+
+pub const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+pub const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+pub const ICMPV6_TIME_EXCEEDED: u8 = 3;
+pub const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Time exceeded code used when the hop limit reaches zero in transit.
+pub const ICMPV6_HOP_LIMIT_EXCEEDED: u8 = 0;
+
+const HDR_SIZE: usize = 8;
+
+/// An ICMP header (RFC 792). The last four bytes depend on the message type: echo messages carry an identifier and a
+/// sequence number, fragmentation needed messages the next hop MTU, and most error messages nothing.
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct IcmpHeader {
+    msg_type: u8,
+    code: u8,
+    csum: u16,
+    rest: u32,
+}
+
+/// An ICMPv6 header (RFC 4443). The layout is the same as ICMP, but the checksum also covers an IPv6 pseudo-header.
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct Icmpv6Header {
+    msg_type: u8,
+    code: u8,
+    csum: u16,
+    rest: u32,
+}
+
+/// Accessors shared by both headers.
+macro_rules! icmp_accessors {
+    ($name: ident, $echo_request: expr, $echo_reply: expr) => {
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f,
+                       "type: {} code: {} checksum: {} rest: 0x{:08x}",
+                       self.msg_type(),
+                       self.code(),
+                       self.checksum(),
+                       self.rest())
+            }
+        }
+
+        impl EndOffset for $name {
+            #[inline]
+            fn offset(&self) -> usize {
+                HDR_SIZE
+            }
+
+            #[inline]
+            fn size() -> usize {
+                HDR_SIZE
+            }
+
+            #[inline]
+            fn payload_size(&self, hint: usize) -> usize {
+                hint.saturating_sub(HDR_SIZE)
+            }
+        }
+
+        impl $name {
+            #[inline]
+            pub fn new() -> $name {
+                Default::default()
+            }
+
+            #[inline]
+            pub fn msg_type(&self) -> u8 {
+                self.msg_type
+            }
+
+            #[inline]
+            pub fn set_msg_type(&mut self, msg_type: u8) {
+                self.msg_type = msg_type;
+            }
+
+            #[inline]
+            pub fn code(&self) -> u8 {
+                self.code
+            }
+
+            #[inline]
+            pub fn set_code(&mut self, code: u8) {
+                self.code = code;
+            }
+
+            #[inline]
+            pub fn checksum(&self) -> u16 {
+                u16::from_be(self.csum)
+            }
+
+            #[inline]
+            pub fn set_checksum(&mut self, csum: u16) {
+                self.csum = u16::to_be(csum);
+            }
+
+            /// The last four bytes of the header, whose meaning depends on the message type.
+            #[inline]
+            pub fn rest(&self) -> u32 {
+                u32::from_be(self.rest)
+            }
+
+            #[inline]
+            pub fn set_rest(&mut self, rest: u32) {
+                self.rest = u32::to_be(rest);
+            }
+
+            /// Identifier of an echo request or reply.
+            #[inline]
+            pub fn identifier(&self) -> u16 {
+                (self.rest() >> 16) as u16
+            }
+
+            #[inline]
+            pub fn set_identifier(&mut self, id: u16) {
+                let rest = (self.rest() & 0xffff) | ((id as u32) << 16);
+                self.set_rest(rest);
+            }
+
+            /// Sequence number of an echo request or reply.
+            #[inline]
+            pub fn sequence(&self) -> u16 {
+                self.rest() as u16
+            }
+
+            #[inline]
+            pub fn set_sequence(&mut self, seq: u16) {
+                let rest = (self.rest() & 0xffff0000) | seq as u32;
+                self.set_rest(rest);
+            }
+
+            #[inline]
+            pub fn is_echo_request(&self) -> bool {
+                self.msg_type == $echo_request
+            }
+
+            /// Turn an echo request into the matching reply, updating the checksum to match (identifier, sequence
+            /// number and data are echoed back as is).
+            #[inline]
+            pub fn make_echo_reply(&mut self) {
+                let old = ((self.msg_type as u16) << 8) | self.code as u16;
+                let csum = update_checksum_16(self.checksum(), old, ($echo_reply as u16) << 8);
+                self.msg_type = $echo_reply;
+                self.code = 0;
+                self.set_checksum(csum);
+            }
+
+            /// The header followed by `len - 8` bytes of data.
+            ///
+            /// # Warning
+            /// This reads `len` bytes from where the header resides, and is hence only meaningful for headers that
+            /// are part of a packet (as is the case for headers passed into batch operations).
+            #[inline]
+            pub fn message_bytes(&self, len: usize) -> &[u8] {
+                unsafe { slice::from_raw_parts(self as *const $name as *const u8, len) }
+            }
+        }
+    }
+}
+
+icmp_accessors!{IcmpHeader, ICMP_ECHO_REQUEST, ICMP_ECHO_REPLY}
+icmp_accessors!{Icmpv6Header, ICMPV6_ECHO_REQUEST, ICMPV6_ECHO_REPLY}
+
+impl IcmpHeader {
+    /// Whether this is an error message, to which no ICMP errors may be sent in response (RFC 1122).
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        match self.msg_type {
+            ICMP_DEST_UNREACHABLE | ICMP_SOURCE_QUENCH | ICMP_REDIRECT | ICMP_TIME_EXCEEDED |
+            ICMP_PARAMETER_PROBLEM => true,
+            _ => false,
+        }
+    }
+
+    /// Next hop MTU of a fragmentation needed message (RFC 1191).
+    #[inline]
+    pub fn next_hop_mtu(&self) -> u16 {
+        self.rest() as u16
+    }
+
+    /// Compute the checksum over the header and the `len - 8` bytes of data following it, and store it. The same
+    /// caveat as `message_bytes` applies.
+    #[inline]
+    pub fn compute_checksum(&mut self, len: usize) {
+        self.csum = 0;
+        let csum = fold_checksum(ones_complement_sum(self.message_bytes(len), 0));
+        self.set_checksum(csum);
+    }
+}
+
+impl Icmpv6Header {
+    /// Whether this is an error message (types below 128), to which no ICMPv6 errors may be sent in response.
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        self.msg_type < 128
+    }
+
+    /// MTU of a packet too big message.
+    #[inline]
+    pub fn mtu(&self) -> u32 {
+        self.rest()
+    }
+
+    /// Compute the checksum over the header, the `len - 8` bytes of data following it and the pseudo-header derived
+    /// from `ip`, and store it. The same caveat as `message_bytes` applies.
+    #[inline]
+    pub fn compute_checksum(&mut self, ip: &Ipv6Header, len: usize) {
+        self.csum = 0;
+        let pseudo = ipv6_pseudo_header_sum(&ip.src_octets(), &ip.dst_octets(), ICMPV6_PROTO, len as u32);
+        let csum = fold_checksum(ones_complement_sum(self.message_bytes(len), pseudo));
+        self.set_checksum(csum);
+    }
+}
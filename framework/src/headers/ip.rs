@@ -1,8 +1,10 @@
 use super::EndOffset;
+use super::checksum::{checksum, update_checksum_16, update_checksum_32};
 use std::fmt;
 use std::net::Ipv4Addr;
 use std::convert::From;
 use std::default::Default;
+use std::slice;
 
 /// IP header using SSE
 #[derive(Debug, Default)]
@@ -51,7 +53,7 @@ impl EndOffset for IpHeader {
 
     #[inline]
     fn payload_size(&self, _: usize) -> usize {
-        (self.length() as usize) - self.offset()
+        (self.length() as usize).saturating_sub(self.offset())
     }
 }
 
@@ -66,9 +68,12 @@ impl IpHeader {
         u32::from_be(self.src_ip)
     }
 
+    /// Set the source address, updating the header checksum to match.
     #[inline]
     pub fn set_src(&mut self, src: u32) {
-        self.src_ip = u32::to_be(src)
+        let csum = update_checksum_32(self.csum(), self.src(), src);
+        self.src_ip = u32::to_be(src);
+        self.set_csum(csum);
     }
 
     #[inline]
@@ -76,9 +81,12 @@ impl IpHeader {
         u32::from_be(self.dst_ip)
     }
 
+    /// Set the destination address, updating the header checksum to match.
     #[inline]
     pub fn set_dst(&mut self, dst: u32) {
+        let csum = update_checksum_32(self.csum(), self.dst(), dst);
         self.dst_ip = u32::to_be(dst);
+        self.set_csum(csum);
     }
 
     #[inline]
@@ -87,11 +95,17 @@ impl IpHeader {
         (ttlpcsum & 0x000000ff) as u8
     }
 
+    /// Set the TTL, updating the header checksum to match.
     #[inline]
     pub fn set_ttl(&mut self, ttl: u8) {
+        // TTL and protocol make up a single 16-bit word for checksum purposes.
+        let old_word = ((self.ttl() as u16) << 8) | self.protocol() as u16;
+        let new_word = ((ttl as u16) << 8) | self.protocol() as u16;
+        let csum = update_checksum_16(self.csum(), old_word, new_word);
         let ttlpcsum = self.ttl_to_csum;
         let blanked = ttlpcsum & !0x000000ff;
         self.ttl_to_csum = blanked | (ttl as u32);
+        self.set_csum(csum);
     }
 
     #[inline]
@@ -107,12 +121,15 @@ impl IpHeader {
         self.ttl_to_csum = blanked | ((protocol as u32) << 8);
     }
 
+    /// Header checksum, in host byte order (matching `set_csum`). This used to return the checksum in network byte
+    /// order; callers comparing it against bytes on the wire need to convert it with `u16::to_be`.
     #[inline]
     pub fn csum(&self) -> u16 {
         let ttlpcsum = self.ttl_to_csum;
-        ((ttlpcsum & 0xffff0000) >> 16) as u16
+        u16::from_be(((ttlpcsum & 0xffff0000) >> 16) as u16)
     }
 
+    /// Set the header checksum, given in host byte order.
     #[inline]
     pub fn set_csum(&mut self, csum: u16) {
         let ttlpcsum = self.ttl_to_csum;
@@ -147,9 +164,7 @@ impl IpHeader {
 
     #[inline]
     pub fn fragment_offset(&self) -> u16 {
-        let id_flag_fragment = self.id_to_foffset;
-        let flag_fragment = (id_flag_fragment & 0xffff) as u16;
-        u16::from_be(((flag_fragment & !0xe) >> 3))
+        (u32::from_be(self.id_to_foffset) & 0x1fff) as u16
     }
 
     #[inline]
@@ -208,4 +223,65 @@ impl IpHeader {
     pub fn set_length(&mut self, len: u16) {
         self.version_to_len = (self.version_to_len & !0xffff0000) | ((u16::to_be(len) as u32) << 16);
     }
+
+    /// The header (including options) as bytes.
+    ///
+    /// # Warning
+    /// This reads `ihl` words from where the header resides, and is hence only meaningful for headers that are part of
+    /// a packet (as is the case for headers passed into batch operations).
+    #[inline]
+    pub fn header_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const IpHeader as *const u8, self.ihl() as usize * 4) }
+    }
+
+    /// Compute the header checksum from scratch and store it. Use this after building a header, or after changing
+    /// fields whose setters do not update the checksum. The same caveat as `header_bytes` applies.
+    #[inline]
+    pub fn compute_checksum(&mut self) {
+        self.set_csum(0);
+        let csum = checksum(self.header_bytes());
+        self.set_csum(csum);
+    }
+
+    /// Returns true if the header checksum is correct. The same caveat as `header_bytes` applies.
+    #[inline]
+    pub fn checksum_valid(&self) -> bool {
+        checksum(self.header_bytes()) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(bytes: &mut [u8; 20]) -> &mut IpHeader {
+        unsafe { &mut *(bytes.as_mut_ptr() as *mut IpHeader) }
+    }
+
+    #[test]
+    fn fragment_fields() {
+        // Identification 0xbeef, more fragments set, offset 0x1234 (in 8 byte units).
+        let mut bytes = [0x45, 0, 0, 20, 0xbe, 0xef, 0x32, 0x34, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        {
+            let ip = header(&mut bytes);
+            assert_eq!(ip.id(), 0xbeef);
+            assert_eq!(ip.flags(), 0x1);
+            assert_eq!(ip.fragment_offset(), 0x1234);
+            ip.set_fragment_offset(0x0185);
+            assert_eq!(ip.fragment_offset(), 0x0185);
+            assert_eq!((ip.id(), ip.flags()), (0xbeef, 0x1));
+            ip.set_flags(0x2);
+            assert_eq!(ip.fragment_offset(), 0x0185);
+        }
+        assert_eq!(&bytes[4..8], &[0xbe, 0xef, 0x41, 0x85]);
+
+        // A first fragment (or an unfragmented packet with DF set) has offset 0, whatever the flags.
+        bytes[6] = 0xe0;
+        bytes[7] = 0;
+        assert_eq!(header(&mut bytes).fragment_offset(), 0);
+        bytes[6] = 0x1f;
+        bytes[7] = 0xff;
+        assert_eq!(header(&mut bytes).fragment_offset(), 0x1fff);
+        assert_eq!(header(&mut bytes).flags(), 0);
+    }
 }
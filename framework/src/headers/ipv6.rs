@@ -0,0 +1,367 @@
+use super::EndOffset;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::convert::From;
+use std::default::Default;
+
+pub const IPV6_HOP_BY_HOP: u8 = 0;
+pub const IPV6_ROUTING: u8 = 43;
+pub const IPV6_FRAGMENT: u8 = 44;
+pub const IPV6_AUTHENTICATION: u8 = 51;
+pub const IPV6_NO_NEXT_HEADER: u8 = 59;
+pub const IPV6_DESTINATION_OPTIONS: u8 = 60;
+pub const IPV6_MOBILITY: u8 = 135;
+
+const HDR_SIZE: usize = 40;
+
+/// IPv6 header. Extension headers, if any, are part of the payload and can be walked using `extension_headers`.
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct Ipv6Header {
+    version_to_flow: u32,
+    payload_len: u16,
+    next_header: u8,
+    hop_limit: u8,
+    src_ip: [u8; 16],
+    dst_ip: [u8; 16],
+}
+
+impl fmt::Display for Ipv6Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{} > {} version: {} traffic_class: {} flow_label: {} len: {} next_header: {} hop_limit: {}",
+               self.src(),
+               self.dst(),
+               self.version(),
+               self.traffic_class(),
+               self.flow_label(),
+               self.payload_len(),
+               self.next_header(),
+               self.hop_limit())
+    }
+}
+
+impl EndOffset for Ipv6Header {
+    #[inline]
+    fn offset(&self) -> usize {
+        // Extension headers are treated as payload.
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn size() -> usize {
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn payload_size(&self, _: usize) -> usize {
+        self.payload_len() as usize
+    }
+}
+
+impl Ipv6Header {
+    #[inline]
+    pub fn new() -> Ipv6Header {
+        Default::default()
+    }
+
+    #[inline]
+    pub fn version(&self) -> u8 {
+        (u32::from_be(self.version_to_flow) >> 28) as u8
+    }
+
+    #[inline]
+    pub fn set_version(&mut self, version: u8) {
+        let v = u32::from_be(self.version_to_flow);
+        self.version_to_flow = u32::to_be((v & !0xf0000000) | (((version & 0xf) as u32) << 28));
+    }
+
+    #[inline]
+    pub fn traffic_class(&self) -> u8 {
+        ((u32::from_be(self.version_to_flow) >> 20) & 0xff) as u8
+    }
+
+    #[inline]
+    pub fn set_traffic_class(&mut self, class: u8) {
+        let v = u32::from_be(self.version_to_flow);
+        self.version_to_flow = u32::to_be((v & !0x0ff00000) | ((class as u32) << 20));
+    }
+
+    #[inline]
+    pub fn flow_label(&self) -> u32 {
+        u32::from_be(self.version_to_flow) & 0x000fffff
+    }
+
+    #[inline]
+    pub fn set_flow_label(&mut self, label: u32) {
+        let v = u32::from_be(self.version_to_flow);
+        self.version_to_flow = u32::to_be((v & !0x000fffff) | (label & 0x000fffff));
+    }
+
+    /// Length of everything following this header, including extension headers.
+    #[inline]
+    pub fn payload_len(&self) -> u16 {
+        u16::from_be(self.payload_len)
+    }
+
+    #[inline]
+    pub fn set_payload_len(&mut self, len: u16) {
+        self.payload_len = u16::to_be(len);
+    }
+
+    /// Type of the header immediately following this one, this is either an extension header or the upper layer
+    /// protocol.
+    #[inline]
+    pub fn next_header(&self) -> u8 {
+        self.next_header
+    }
+
+    #[inline]
+    pub fn set_next_header(&mut self, next_header: u8) {
+        self.next_header = next_header;
+    }
+
+    #[inline]
+    pub fn hop_limit(&self) -> u8 {
+        self.hop_limit
+    }
+
+    #[inline]
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.hop_limit = hop_limit;
+    }
+
+    #[inline]
+    pub fn src(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.src_ip)
+    }
+
+    #[inline]
+    pub fn set_src(&mut self, src: Ipv6Addr) {
+        self.src_ip = src.octets();
+    }
+
+    #[inline]
+    pub fn dst(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.dst_ip)
+    }
+
+    #[inline]
+    pub fn set_dst(&mut self, dst: Ipv6Addr) {
+        self.dst_ip = dst.octets();
+    }
+
+    /// Source address as a byte array in network order.
+    #[inline]
+    pub fn src_octets(&self) -> [u8; 16] {
+        self.src_ip
+    }
+
+    /// Destination address as a byte array in network order.
+    #[inline]
+    pub fn dst_octets(&self) -> [u8; 16] {
+        self.dst_ip
+    }
+
+    /// Iterate over the extension headers at the start of `payload`, which should be the payload of this header.
+    #[inline]
+    pub fn extension_headers<'a>(&self, payload: &'a [u8]) -> Ipv6ExtensionHeaders<'a> {
+        Ipv6ExtensionHeaders::new(self.next_header, payload)
+    }
+
+    /// Skip past all extension headers in `payload` and return the upper layer protocol along with the offset at which
+    /// it starts in `payload`. Returns `None` if the extension headers are malformed or truncated.
+    #[inline]
+    pub fn upper_layer(&self, payload: &[u8]) -> Option<(u8, usize)> {
+        let mut headers = self.extension_headers(payload);
+        while let Some(_) = headers.next() {}
+        headers.upper_layer()
+    }
+}
+
+/// An IPv6 extension header. `data` covers the entire extension header, including the next header and length fields.
+#[derive(Debug)]
+pub struct Ipv6ExtensionHeader<'a> {
+    pub kind: u8,
+    pub next_header: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Ipv6ExtensionHeader<'a> {
+    /// For fragment headers, the offset (in 8 byte units) of this fragment.
+    #[inline]
+    pub fn fragment_offset(&self) -> Option<u16> {
+        if self.kind == IPV6_FRAGMENT {
+            Some(((self.data[2] as u16) << 5) | ((self.data[3] >> 3) as u16))
+        } else {
+            None
+        }
+    }
+
+    /// For fragment headers, whether more fragments follow.
+    #[inline]
+    pub fn more_fragments(&self) -> Option<bool> {
+        if self.kind == IPV6_FRAGMENT {
+            Some(self.data[3] & 0x1 == 1)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns true if `next_header` identifies an extension header (rather than an upper layer protocol).
+#[inline]
+pub fn is_ipv6_extension_header(next_header: u8) -> bool {
+    match next_header {
+        IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_FRAGMENT | IPV6_AUTHENTICATION | IPV6_DESTINATION_OPTIONS |
+        IPV6_MOBILITY => true,
+        _ => false,
+    }
+}
+
+/// Iterator over IPv6 extension headers. Once exhausted, `upper_layer` reports where the upper layer header begins.
+pub struct Ipv6ExtensionHeaders<'a> {
+    next_header: u8,
+    bytes: &'a [u8],
+    offset: usize,
+    malformed: bool,
+}
+
+impl<'a> Ipv6ExtensionHeaders<'a> {
+    pub fn new(next_header: u8, bytes: &'a [u8]) -> Ipv6ExtensionHeaders<'a> {
+        Ipv6ExtensionHeaders {
+            next_header: next_header,
+            bytes: bytes,
+            offset: 0,
+            malformed: false,
+        }
+    }
+
+    /// The protocol following the extension headers seen so far, and its offset. Only meaningful once the iterator is
+    /// exhausted, returns `None` if the chain was malformed.
+    #[inline]
+    pub fn upper_layer(&self) -> Option<(u8, usize)> {
+        if self.malformed {
+            None
+        } else {
+            Some((self.next_header, self.offset))
+        }
+    }
+}
+
+impl<'a> Iterator for Ipv6ExtensionHeaders<'a> {
+    type Item = Ipv6ExtensionHeader<'a>;
+
+    fn next(&mut self) -> Option<Ipv6ExtensionHeader<'a>> {
+        if self.malformed || !is_ipv6_extension_header(self.next_header) {
+            return None;
+        }
+        let rest = &self.bytes[self.offset..];
+        if rest.len() < 8 {
+            self.malformed = true;
+            return None;
+        }
+        let len = match self.next_header {
+            IPV6_FRAGMENT => 8,
+            // AH length is in 4 byte units, not counting the first 8 bytes.
+            IPV6_AUTHENTICATION => (rest[1] as usize + 2) * 4,
+            _ => (rest[1] as usize + 1) * 8,
+        };
+        if len > rest.len() {
+            self.malformed = true;
+            return None;
+        }
+        let header = Ipv6ExtensionHeader {
+            kind: self.next_header,
+            next_header: rest[0],
+            data: &rest[..len],
+        };
+        self.next_header = rest[0];
+        self.offset += len;
+        Some(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UDP: u8 = 17;
+
+    /// Hop-by-hop (8 bytes) -> authentication (16 bytes) -> fragment (8 bytes) -> UDP.
+    fn chain(fragment_offset: u16, more: bool) -> Vec<u8> {
+        let mut bytes = vec![IPV6_AUTHENTICATION, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend_from_slice(&[IPV6_FRAGMENT, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&[UDP,
+                                  0,
+                                  (fragment_offset >> 5) as u8,
+                                  ((fragment_offset << 3) as u8) | more as u8,
+                                  0,
+                                  0,
+                                  0,
+                                  1]);
+        bytes
+    }
+
+    #[test]
+    fn walk_extension_headers() {
+        let bytes = chain(0x1234 & 0x1fff, true);
+        let mut headers = Ipv6ExtensionHeaders::new(IPV6_HOP_BY_HOP, &bytes);
+        let kinds: Vec<_> = headers.by_ref().map(|hdr| (hdr.kind, hdr.next_header, hdr.data.len())).collect();
+        assert_eq!(kinds,
+                   vec![(IPV6_HOP_BY_HOP, IPV6_AUTHENTICATION, 8),
+                        (IPV6_AUTHENTICATION, IPV6_FRAGMENT, 16),
+                        (IPV6_FRAGMENT, UDP, 8)]);
+        assert_eq!(headers.upper_layer(), Some((UDP, 32)));
+
+        let mut ip = Ipv6Header::new();
+        ip.set_next_header(IPV6_HOP_BY_HOP);
+        assert_eq!(ip.upper_layer(&bytes), Some((UDP, 32)));
+    }
+
+    #[test]
+    fn fragment_fields() {
+        let bytes = chain(0x1234 & 0x1fff, true);
+        let fragment = Ipv6ExtensionHeaders::new(IPV6_HOP_BY_HOP, &bytes).last().unwrap();
+        assert_eq!(fragment.fragment_offset(), Some(0x1234 & 0x1fff));
+        assert_eq!(fragment.more_fragments(), Some(true));
+
+        let bytes = chain(0, false);
+        let mut headers = Ipv6ExtensionHeaders::new(IPV6_HOP_BY_HOP, &bytes);
+        let hop_by_hop = headers.next().unwrap();
+        assert_eq!(hop_by_hop.fragment_offset(), None);
+        assert_eq!(hop_by_hop.more_fragments(), None);
+        let fragment = headers.last().unwrap();
+        assert_eq!(fragment.fragment_offset(), Some(0));
+        assert_eq!(fragment.more_fragments(), Some(false));
+    }
+
+    #[test]
+    fn no_extension_headers() {
+        let mut headers = Ipv6ExtensionHeaders::new(UDP, &[]);
+        assert!(headers.next().is_none());
+        assert_eq!(headers.upper_layer(), Some((UDP, 0)));
+        let mut headers = Ipv6ExtensionHeaders::new(IPV6_NO_NEXT_HEADER, &[]);
+        assert!(headers.next().is_none());
+        assert_eq!(headers.upper_layer(), Some((IPV6_NO_NEXT_HEADER, 0)));
+    }
+
+    #[test]
+    fn truncated_extension_headers() {
+        let bytes = chain(0, false);
+        // Cut inside the fixed 8 bytes of the fragment header.
+        let mut headers = Ipv6ExtensionHeaders::new(IPV6_HOP_BY_HOP, &bytes[..28]);
+        assert_eq!(headers.by_ref().count(), 2);
+        assert_eq!(headers.upper_layer(), None);
+        // Cut inside the length announced by the authentication header.
+        let mut headers = Ipv6ExtensionHeaders::new(IPV6_HOP_BY_HOP, &bytes[..20]);
+        assert_eq!(headers.by_ref().count(), 1);
+        assert_eq!(headers.upper_layer(), None);
+        // Nothing at all after the IPv6 header.
+        let mut headers = Ipv6ExtensionHeaders::new(IPV6_HOP_BY_HOP, &[]);
+        assert!(headers.next().is_none());
+        assert_eq!(headers.upper_layer(), None);
+        // Once malformed, the iterator stays exhausted.
+        assert!(headers.next().is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use super::EndOffset;
+use super::{is_vlan_ethertype, EndOffset, VlanHeader, ETHERTYPE_QINQ, ETHERTYPE_QINQ_LEGACY, ETHERTYPE_VLAN};
 use std::fmt;
 use std::default::Default;
 
@@ -48,9 +48,9 @@ impl EndOffset for MacHeader {
         if cfg!(feature = "performance") {
             HDR_SIZE
         } else {
-            match self.etype {
-                0x8100 => HDR_SIZE_802_1Q,
-                0x9100 => HDR_SIZE_802_1AD,
+            match self.etype() {
+                ETHERTYPE_VLAN => HDR_SIZE_802_1Q,
+                ETHERTYPE_QINQ | ETHERTYPE_QINQ_LEGACY => HDR_SIZE_802_1AD,
                 _ => HDR_SIZE,
             }
         }
@@ -63,7 +63,7 @@ impl EndOffset for MacHeader {
 
     #[inline]
     fn payload_size(&self, hint: usize) -> usize {
-        hint - self.offset()
+        hint.saturating_sub(self.offset())
     }
 }
 
@@ -71,4 +71,61 @@ impl MacHeader {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// The ethertype (or, for tagged packets, the outer tag protocol identifier) in host order.
+    #[inline]
+    pub fn etype(&self) -> u16 {
+        u16::from_be(self.etype)
+    }
+
+    #[inline]
+    pub fn set_etype(&mut self, etype: u16) {
+        self.etype = u16::to_be(etype);
+    }
+
+    /// The outer VLAN tag, if the packet is tagged. For QinQ packets, the inner tag is the tag following it, see
+    /// `inner_vlan`. Tags the NIC stripped on receive are not part of the packet, see `MBuf::rx_vlan_tci`.
+    ///
+    /// # Warning
+    /// This reads past the end of the header, and is hence only meaningful for headers that are part of a packet (as is
+    /// the case for headers passed into batch operations).
+    #[inline]
+    pub fn vlan(&self) -> Option<&VlanHeader> {
+        if is_vlan_ethertype(self.etype()) {
+            Some(unsafe { &*((self as *const MacHeader as *const u8).offset(HDR_SIZE as isize) as *const VlanHeader) })
+        } else {
+            None
+        }
+    }
+
+    /// Same as `vlan`, allowing the tag to be changed.
+    #[inline]
+    pub fn vlan_mut(&mut self) -> Option<&mut VlanHeader> {
+        if is_vlan_ethertype(self.etype()) {
+            Some(unsafe { &mut *((self as *mut MacHeader as *mut u8).offset(HDR_SIZE as isize) as *mut VlanHeader) })
+        } else {
+            None
+        }
+    }
+
+    /// The inner VLAN tag of a QinQ packet. The same caveat as `vlan` applies.
+    #[inline]
+    pub fn inner_vlan(&self) -> Option<&VlanHeader> {
+        match self.vlan() {
+            Some(outer) if is_vlan_ethertype(outer.etype()) => {
+                Some(unsafe { &*((outer as *const VlanHeader).offset(1)) })
+            }
+            _ => None,
+        }
+    }
+
+    /// The ethertype of the packet's payload, i.e., after any VLAN tags. The same caveat as `vlan` applies.
+    #[inline]
+    pub fn inner_etype(&self) -> u16 {
+        match (self.vlan(), self.inner_vlan()) {
+            (_, Some(inner)) => inner.etype(),
+            (Some(outer), None) => outer.etype(),
+            (None, None) => self.etype(),
+        }
+    }
 }
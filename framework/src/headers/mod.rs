@@ -1,11 +1,29 @@
 pub use self::null_header::*;
+pub use self::checksum::*;
 pub use self::mac::*;
+pub use self::arp::*;
+pub use self::vlan::*;
 pub use self::ip::*;
+pub use self::ipv6::*;
+pub use self::icmp::*;
 pub use self::udp::*;
+pub use self::tcp::*;
+pub use self::vxlan::*;
+pub use self::gre::*;
+pub use self::geneve::*;
 mod mac;
+mod arp;
+mod vlan;
 mod ip;
+mod ipv6;
+mod icmp;
 mod udp;
+mod tcp;
+mod vxlan;
+mod gre;
+mod geneve;
 mod null_header;
+mod checksum;
 
 /// A trait implemented by all headers, used for reading them from a mbuf.
 pub trait EndOffset {
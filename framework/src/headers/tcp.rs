@@ -0,0 +1,526 @@
+use super::{EndOffset, IpHeader, Ipv6Header};
+use super::checksum::{fold_checksum, ipv4_pseudo_header_sum, ipv6_pseudo_header_sum, ones_complement_sum,
+                      update_checksum_16, update_checksum_32, update_checksum_bytes};
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
+use std::fmt;
+use std::default::Default;
+use std::net::Ipv6Addr;
+use std::slice;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+pub const TCP_ECE: u8 = 0x40;
+pub const TCP_CWR: u8 = 0x80;
+
+const HDR_SIZE: usize = 20;
+const TCP_PROTO: u8 = 6;
+
+/// A packet's TCP header. Options (if any) immediately follow this structure, and are accessed through `options`.
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct TcpHeader {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    offset_to_ns: u8,
+    flags: u8,
+    window: u16,
+    csum: u16,
+    urgent: u16,
+}
+
+impl fmt::Display for TcpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "src_port: {} dst_port: {} seq: {} ack: {} data_offset: {} flags: 0x{:02x} window: {} csum: {} \
+                urgent: {}",
+               self.src_port(),
+               self.dst_port(),
+               self.seq_num(),
+               self.ack_num(),
+               self.data_offset(),
+               self.flags(),
+               self.window(),
+               self.checksum(),
+               self.urgent_pointer())
+    }
+}
+
+impl EndOffset for TcpHeader {
+    #[inline]
+    fn offset(&self) -> usize {
+        // Unlike IP options, TCP options are common enough (e.g., timestamps) that we always look at data offset. A
+        // malformed data offset below the minimum is treated as the minimum.
+        cmp::max(self.data_offset() as usize * 4, HDR_SIZE)
+    }
+
+    #[inline]
+    fn size() -> usize {
+        // The struct itself is always 20 bytes.
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn payload_size(&self, hint: usize) -> usize {
+        hint.saturating_sub(self.offset())
+    }
+}
+
+impl TcpHeader {
+    #[inline]
+    pub fn new() -> TcpHeader {
+        Default::default()
+    }
+
+    #[inline]
+    pub fn src_port(&self) -> u16 {
+        u16::from_be(self.src_port)
+    }
+
+    /// Set the source port, updating the checksum to match.
+    #[inline]
+    pub fn set_src_port(&mut self, port: u16) {
+        let csum = update_checksum_16(self.checksum(), self.src_port(), port);
+        self.src_port = u16::to_be(port);
+        self.set_checksum(csum);
+    }
+
+    #[inline]
+    pub fn dst_port(&self) -> u16 {
+        u16::from_be(self.dst_port)
+    }
+
+    /// Set the destination port, updating the checksum to match.
+    #[inline]
+    pub fn set_dst_port(&mut self, port: u16) {
+        let csum = update_checksum_16(self.checksum(), self.dst_port(), port);
+        self.dst_port = u16::to_be(port);
+        self.set_checksum(csum);
+    }
+
+    #[inline]
+    pub fn seq_num(&self) -> u32 {
+        u32::from_be(self.seq)
+    }
+
+    #[inline]
+    pub fn set_seq_num(&mut self, seq: u32) {
+        self.seq = u32::to_be(seq);
+    }
+
+    #[inline]
+    pub fn ack_num(&self) -> u32 {
+        u32::from_be(self.ack)
+    }
+
+    #[inline]
+    pub fn set_ack_num(&mut self, ack: u32) {
+        self.ack = u32::to_be(ack);
+    }
+
+    /// Header length in 32-bit words, including options.
+    #[inline]
+    pub fn data_offset(&self) -> u8 {
+        (self.offset_to_ns & 0xf0) >> 4
+    }
+
+    #[inline]
+    pub fn set_data_offset(&mut self, offset: u8) {
+        self.offset_to_ns = (self.offset_to_ns & !0xf0) | ((offset & 0xf) << 4);
+    }
+
+    /// The control bits (`TCP_FIN`, `TCP_SYN`, etc.), not including NS.
+    #[inline]
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    #[inline]
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+    }
+
+    #[inline]
+    fn flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    #[inline]
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    #[inline]
+    pub fn fin_flag(&self) -> bool {
+        self.flag(TCP_FIN)
+    }
+
+    #[inline]
+    pub fn set_fin_flag(&mut self, fin: bool) {
+        self.set_flag(TCP_FIN, fin)
+    }
+
+    #[inline]
+    pub fn syn_flag(&self) -> bool {
+        self.flag(TCP_SYN)
+    }
+
+    #[inline]
+    pub fn set_syn_flag(&mut self, syn: bool) {
+        self.set_flag(TCP_SYN, syn)
+    }
+
+    #[inline]
+    pub fn rst_flag(&self) -> bool {
+        self.flag(TCP_RST)
+    }
+
+    #[inline]
+    pub fn set_rst_flag(&mut self, rst: bool) {
+        self.set_flag(TCP_RST, rst)
+    }
+
+    #[inline]
+    pub fn psh_flag(&self) -> bool {
+        self.flag(TCP_PSH)
+    }
+
+    #[inline]
+    pub fn set_psh_flag(&mut self, psh: bool) {
+        self.set_flag(TCP_PSH, psh)
+    }
+
+    #[inline]
+    pub fn ack_flag(&self) -> bool {
+        self.flag(TCP_ACK)
+    }
+
+    #[inline]
+    pub fn set_ack_flag(&mut self, ack: bool) {
+        self.set_flag(TCP_ACK, ack)
+    }
+
+    #[inline]
+    pub fn urg_flag(&self) -> bool {
+        self.flag(TCP_URG)
+    }
+
+    #[inline]
+    pub fn set_urg_flag(&mut self, urg: bool) {
+        self.set_flag(TCP_URG, urg)
+    }
+
+    #[inline]
+    pub fn ece_flag(&self) -> bool {
+        self.flag(TCP_ECE)
+    }
+
+    #[inline]
+    pub fn set_ece_flag(&mut self, ece: bool) {
+        self.set_flag(TCP_ECE, ece)
+    }
+
+    #[inline]
+    pub fn cwr_flag(&self) -> bool {
+        self.flag(TCP_CWR)
+    }
+
+    #[inline]
+    pub fn set_cwr_flag(&mut self, cwr: bool) {
+        self.set_flag(TCP_CWR, cwr)
+    }
+
+    #[inline]
+    pub fn ns_flag(&self) -> bool {
+        self.offset_to_ns & 0x1 != 0
+    }
+
+    #[inline]
+    pub fn set_ns_flag(&mut self, ns: bool) {
+        self.offset_to_ns = (self.offset_to_ns & !0x1) | (ns as u8);
+    }
+
+    #[inline]
+    pub fn window(&self) -> u16 {
+        u16::from_be(self.window)
+    }
+
+    #[inline]
+    pub fn set_window(&mut self, window: u16) {
+        self.window = u16::to_be(window);
+    }
+
+    #[inline]
+    pub fn checksum(&self) -> u16 {
+        u16::from_be(self.csum)
+    }
+
+    #[inline]
+    pub fn set_checksum(&mut self, csum: u16) {
+        self.csum = u16::to_be(csum);
+    }
+
+    #[inline]
+    pub fn urgent_pointer(&self) -> u16 {
+        u16::from_be(self.urgent)
+    }
+
+    #[inline]
+    pub fn set_urgent_pointer(&mut self, urgent: u16) {
+        self.urgent = u16::to_be(urgent);
+    }
+
+    /// Update the checksum to account for an address in the IPv4 pseudo-header changing from `old` to `new` (e.g., when
+    /// performing NAT). Call this along with `IpHeader::set_src` or `IpHeader::set_dst`.
+    #[inline]
+    pub fn update_checksum_for_address(&mut self, old: u32, new: u32) {
+        let csum = update_checksum_32(self.checksum(), old, new);
+        self.set_checksum(csum);
+    }
+
+    /// Update the checksum to account for an address in the IPv6 pseudo-header changing from `old` to `new`.
+    #[inline]
+    pub fn update_checksum_for_ipv6_address(&mut self, old: &Ipv6Addr, new: &Ipv6Addr) {
+        let csum = update_checksum_bytes(self.checksum(), &old.octets(), &new.octets());
+        self.set_checksum(csum);
+    }
+
+    /// Number of bytes between the start of this header and `end` (the end of the IP payload).
+    #[inline]
+    fn segment_len(&self, end: *const u8) -> usize {
+        (end as usize).saturating_sub(self as *const TcpHeader as usize)
+    }
+
+    #[inline]
+    fn compute_checksum_with(&mut self, len: usize, pseudo_header_sum: u32) {
+        self.csum = 0;
+        let segment = unsafe { slice::from_raw_parts(self as *const TcpHeader as *const u8, len) };
+        let csum = fold_checksum(ones_complement_sum(segment, pseudo_header_sum));
+        self.set_checksum(csum);
+    }
+
+    /// Compute the checksum over the header, payload and the pseudo-header derived from `ip`, and store it. The length
+    /// of the segment is derived from `ip`.
+    ///
+    /// # Warning
+    /// This reads memory between this header and the end of the IP payload, and is hence only meaningful when both
+    /// headers are part of the same packet (as is the case for headers passed into batch operations).
+    #[inline]
+    pub fn compute_checksum(&mut self, ip: &IpHeader) {
+        let end = unsafe { (ip as *const IpHeader as *const u8).offset(ip.length() as isize) };
+        let len = self.segment_len(end);
+        let pseudo = ipv4_pseudo_header_sum(ip.src(), ip.dst(), TCP_PROTO, len as u16);
+        self.compute_checksum_with(len, pseudo);
+    }
+
+    /// Same as `compute_checksum`, for segments carried over IPv6 (possibly after extension headers).
+    #[inline]
+    pub fn compute_checksum_ipv6(&mut self, ip: &Ipv6Header) {
+        let ip_len = ip.offset() + ip.payload_len() as usize;
+        let end = unsafe { (ip as *const Ipv6Header as *const u8).offset(ip_len as isize) };
+        let len = self.segment_len(end);
+        let pseudo = ipv6_pseudo_header_sum(&ip.src_octets(), &ip.dst_octets(), TCP_PROTO, len as u32);
+        self.compute_checksum_with(len, pseudo);
+    }
+
+    /// The raw option bytes following the fixed header.
+    ///
+    /// # Warning
+    /// This reads `data_offset` words from where the header resides, and is hence only meaningful for headers that are
+    /// part of a packet (as is the case for headers passed into batch operations).
+    #[inline]
+    pub fn option_bytes(&self) -> &[u8] {
+        let len = self.offset().saturating_sub(HDR_SIZE);
+        unsafe {
+            let start = (self as *const TcpHeader as *const u8).offset(HDR_SIZE as isize);
+            slice::from_raw_parts(start, len)
+        }
+    }
+
+    /// Iterate over the options carried by this header. Iteration stops at the end of option list, or at the first
+    /// malformed option. The same caveat as `option_bytes` applies.
+    #[inline]
+    pub fn options(&self) -> TcpOptions {
+        TcpOptions { bytes: self.option_bytes() }
+    }
+}
+
+/// A TCP option. Options not explicitly handled are returned as `Other` with their kind and (undecoded) value.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TcpOption<'a> {
+    MaxSegmentSize(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Sack(SackBlocks<'a>),
+    Timestamps { value: u32, echo_reply: u32 },
+    Other(u8, &'a [u8]),
+}
+
+const OPT_END: u8 = 0;
+const OPT_NOP: u8 = 1;
+const OPT_MSS: u8 = 2;
+const OPT_WSCALE: u8 = 3;
+const OPT_SACK_PERMITTED: u8 = 4;
+const OPT_SACK: u8 = 5;
+const OPT_TIMESTAMPS: u8 = 8;
+
+/// Iterator over the options in a `TcpHeader`.
+pub struct TcpOptions<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for TcpOptions<'a> {
+    type Item = TcpOption<'a>;
+
+    fn next(&mut self) -> Option<TcpOption<'a>> {
+        loop {
+            match self.bytes.first() {
+                None | Some(&OPT_END) => return None,
+                Some(&OPT_NOP) => self.bytes = &self.bytes[1..],
+                Some(&kind) => {
+                    let len = if self.bytes.len() < 2 {
+                        0
+                    } else {
+                        self.bytes[1] as usize
+                    };
+                    if len < 2 || len > self.bytes.len() {
+                        // Malformed option, there is no way to find the next one.
+                        self.bytes = &[];
+                        return None;
+                    }
+                    let value = &self.bytes[2..len];
+                    self.bytes = &self.bytes[len..];
+                    return Some(match (kind, value.len()) {
+                        (OPT_MSS, 2) => TcpOption::MaxSegmentSize(BigEndian::read_u16(value)),
+                        (OPT_WSCALE, 1) => TcpOption::WindowScale(value[0]),
+                        (OPT_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+                        (OPT_SACK, l) if l % 8 == 0 => TcpOption::Sack(SackBlocks { bytes: value }),
+                        (OPT_TIMESTAMPS, 8) => {
+                            TcpOption::Timestamps {
+                                value: BigEndian::read_u32(&value[0..4]),
+                                echo_reply: BigEndian::read_u32(&value[4..8]),
+                            }
+                        }
+                        _ => TcpOption::Other(kind, value),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Iterator over the `(left edge, right edge)` pairs in a SACK option.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SackBlocks<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for SackBlocks<'a> {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<(u32, u32)> {
+        if self.bytes.len() < 8 {
+            None
+        } else {
+            let block = (BigEndian::read_u32(&self.bytes[0..4]), BigEndian::read_u32(&self.bytes[4..8]));
+            self.bytes = &self.bytes[8..];
+            Some(block)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headers::EndOffset;
+
+    /// A header followed by `options`, with the data offset set to cover them.
+    fn segment(options: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0u8; HDR_SIZE];
+        bytes.extend_from_slice(options);
+        bytes[12] = (((HDR_SIZE + options.len()) / 4) as u8) << 4;
+        bytes
+    }
+
+    fn header(bytes: &mut [u8]) -> &mut TcpHeader {
+        unsafe { &mut *(bytes.as_mut_ptr() as *mut TcpHeader) }
+    }
+
+    #[test]
+    fn flags() {
+        let mut bytes = segment(&[]);
+        let tcp = header(&mut bytes);
+        tcp.set_syn_flag(true);
+        tcp.set_ack_flag(true);
+        assert_eq!(tcp.flags(), TCP_SYN | TCP_ACK);
+        assert!(tcp.syn_flag() && tcp.ack_flag() && !tcp.fin_flag() && !tcp.rst_flag());
+        tcp.set_syn_flag(false);
+        tcp.set_fin_flag(true);
+        assert_eq!(tcp.flags(), TCP_FIN | TCP_ACK);
+        // NS lives in the data offset byte, and must not disturb the data offset (or the other way around).
+        tcp.set_ns_flag(true);
+        assert!(tcp.ns_flag());
+        assert_eq!(tcp.data_offset(), 5);
+        tcp.set_data_offset(8);
+        assert!(tcp.ns_flag());
+        assert_eq!(tcp.flags(), TCP_FIN | TCP_ACK);
+    }
+
+    #[test]
+    fn options() {
+        let mut bytes = segment(&[2, 4, 0x05, 0xb4, 1, 3, 3, 7, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2, 5, 10, 0, 0, 0,
+                                  10, 0, 0, 0, 20, 30, 3, 9, 0, 0, 0]);
+        let tcp = header(&mut bytes);
+        assert_eq!(tcp.offset(), 56);
+        let options: Vec<_> = tcp.options().collect();
+        assert_eq!(options.len(), 6);
+        assert_eq!(options[0], TcpOption::MaxSegmentSize(1460));
+        assert_eq!(options[1], TcpOption::WindowScale(7));
+        assert_eq!(options[2], TcpOption::SackPermitted);
+        assert_eq!(options[3],
+                   TcpOption::Timestamps {
+                       value: 1,
+                       echo_reply: 2,
+                   });
+        match options[4] {
+            TcpOption::Sack(ref blocks) => assert_eq!(blocks.clone().collect::<Vec<_>>(), vec![(10, 20)]),
+            ref other => panic!("Expected SACK blocks, got {:?}", other),
+        }
+        assert_eq!(options[5], TcpOption::Other(30, &[9]));
+    }
+
+    #[test]
+    fn malformed_options() {
+        // An option claiming to be longer than what is left ends iteration.
+        let mut bytes = segment(&[2, 4, 0x05, 0xb4, 8, 10, 0, 0]);
+        assert_eq!(header(&mut bytes).options().collect::<Vec<_>>(),
+                   vec![TcpOption::MaxSegmentSize(1460)]);
+        // As does one with a length too short to cover its kind and length.
+        let mut bytes = segment(&[1, 1, 2, 0]);
+        assert_eq!(header(&mut bytes).options().count(), 0);
+    }
+
+    #[test]
+    fn malformed_data_offset() {
+        let mut bytes = segment(&[]);
+        let tcp = header(&mut bytes);
+        tcp.set_data_offset(0);
+        assert_eq!(tcp.offset(), HDR_SIZE);
+        assert!(tcp.option_bytes().is_empty());
+        assert_eq!(tcp.payload_size(30), 10);
+        // A data offset pointing past the end of the packet leaves no payload.
+        tcp.set_data_offset(15);
+        assert_eq!(tcp.offset(), 60);
+        assert_eq!(tcp.payload_size(40), 0);
+    }
+}
@@ -1,6 +1,12 @@
-use super::EndOffset;
+use super::{EndOffset, IpHeader, Ipv6Header};
+use super::checksum::{fold_checksum, ipv4_pseudo_header_sum, ipv6_pseudo_header_sum, ones_complement_sum,
+                      update_checksum_16, update_checksum_32, update_checksum_bytes};
 use std::fmt;
 use std::default::Default;
+use std::net::Ipv6Addr;
+use std::slice;
+
+const UDP_PROTO: u8 = 17;
 
 /// UDP header using SSE
 // #[repr(C, packed)]
@@ -37,7 +43,7 @@ impl EndOffset for UdpHeader {
 
     #[inline]
     fn payload_size(&self, _: usize) -> usize {
-        self.length() as usize - self.offset()
+        (self.length() as usize).saturating_sub(self.offset())
     }
 }
 
@@ -57,14 +63,20 @@ impl UdpHeader {
         u16::from_be(self.dst_port)
     }
 
+    /// Set the source port, updating the checksum to match (unless the checksum is disabled).
     #[inline]
     pub fn set_src_port(&mut self, port: u16) {
+        let csum = update_checksum_16(self.checksum(), self.src_port(), port);
         self.src_port = u16::to_be(port);
+        self.set_updated_checksum(csum);
     }
 
+    /// Set the destination port, updating the checksum to match (unless the checksum is disabled).
     #[inline]
     pub fn set_dst_port(&mut self, port: u16) {
+        let csum = update_checksum_16(self.checksum(), self.dst_port(), port);
         self.dst_port = u16::to_be(port);
+        self.set_updated_checksum(csum);
     }
 
     #[inline]
@@ -86,4 +98,60 @@ impl UdpHeader {
     pub fn set_checksum(&mut self, csum: u16) {
         self.csum = u16::to_be(csum);
     }
+
+    /// Store an incrementally updated checksum. A checksum of 0 means the sender did not compute a checksum (for IPv4),
+    /// so we leave it alone; a computed checksum of 0 is sent as 0xffff.
+    #[inline]
+    fn set_updated_checksum(&mut self, csum: u16) {
+        if self.csum != 0 {
+            self.set_checksum(if csum == 0 { 0xffff } else { csum });
+        }
+    }
+
+    /// Update the checksum to account for an address in the IPv4 pseudo-header changing from `old` to `new` (e.g., when
+    /// performing NAT). Call this along with `IpHeader::set_src` or `IpHeader::set_dst`.
+    #[inline]
+    pub fn update_checksum_for_address(&mut self, old: u32, new: u32) {
+        let csum = update_checksum_32(self.checksum(), old, new);
+        self.set_updated_checksum(csum);
+    }
+
+    /// Update the checksum to account for an address in the IPv6 pseudo-header changing from `old` to `new`.
+    #[inline]
+    pub fn update_checksum_for_ipv6_address(&mut self, old: &Ipv6Addr, new: &Ipv6Addr) {
+        let csum = update_checksum_bytes(self.checksum(), &old.octets(), &new.octets());
+        self.set_updated_checksum(csum);
+    }
+
+    /// The header and payload, as given by the length field.
+    ///
+    /// # Warning
+    /// This reads `length` bytes from where the header resides, and is hence only meaningful for headers that are part
+    /// of a packet (as is the case for headers passed into batch operations).
+    #[inline]
+    pub fn datagram_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const UdpHeader as *const u8, self.length() as usize) }
+    }
+
+    #[inline]
+    fn compute_checksum_with(&mut self, pseudo_header_sum: u32) {
+        self.csum = 0;
+        let csum = fold_checksum(ones_complement_sum(self.datagram_bytes(), pseudo_header_sum));
+        self.set_checksum(if csum == 0 { 0xffff } else { csum });
+    }
+
+    /// Compute the checksum over the header, payload and the pseudo-header derived from `ip`, and store it. The same
+    /// caveat as `datagram_bytes` applies.
+    #[inline]
+    pub fn compute_checksum(&mut self, ip: &IpHeader) {
+        let pseudo = ipv4_pseudo_header_sum(ip.src(), ip.dst(), UDP_PROTO, self.length());
+        self.compute_checksum_with(pseudo);
+    }
+
+    /// Same as `compute_checksum`, for datagrams carried over IPv6.
+    #[inline]
+    pub fn compute_checksum_ipv6(&mut self, ip: &Ipv6Header) {
+        let pseudo = ipv6_pseudo_header_sum(&ip.src_octets(), &ip.dst_octets(), UDP_PROTO, self.length() as u32);
+        self.compute_checksum_with(pseudo);
+    }
 }
@@ -0,0 +1,123 @@
+use super::EndOffset;
+use std::fmt;
+use std::default::Default;
+
+/// Tag protocol identifier of 802.1Q (customer) VLAN tags.
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+/// Tag protocol identifier of 802.1ad (service) VLAN tags, i.e., the outer tag of QinQ packets.
+pub const ETHERTYPE_QINQ: u16 = 0x88a8;
+/// Tag protocol identifier used for outer VLAN tags before 802.1ad was standardized.
+pub const ETHERTYPE_QINQ_LEGACY: u16 = 0x9100;
+
+/// Returns true if `etype` (in host order) is the tag protocol identifier of a VLAN tag.
+#[inline]
+pub fn is_vlan_ethertype(etype: u16) -> bool {
+    etype == ETHERTYPE_VLAN || etype == ETHERTYPE_QINQ || etype == ETHERTYPE_QINQ_LEGACY
+}
+
+/// An 802.1Q VLAN tag, minus the tag protocol identifier (which takes the place of the ethertype in the preceding
+/// header): the tag control information (priority, drop eligible indicator and VLAN ID), followed by the ethertype of
+/// what comes next (another tag for QinQ packets). See `MacHeader::vlan` for reading tags in a packet.
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct VlanHeader {
+    tci: u16,
+    etype: u16,
+}
+
+impl fmt::Display for VlanHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "vid: {} pcp: {} dei: {} 0x{:04x}",
+               self.vid(),
+               self.pcp(),
+               self.dei(),
+               self.etype())
+    }
+}
+
+impl EndOffset for VlanHeader {
+    #[inline]
+    fn offset(&self) -> usize {
+        4
+    }
+
+    #[inline]
+    fn size() -> usize {
+        4
+    }
+
+    #[inline]
+    fn payload_size(&self, hint: usize) -> usize {
+        hint.saturating_sub(self.offset())
+    }
+}
+
+impl VlanHeader {
+    #[inline]
+    pub fn new() -> VlanHeader {
+        Default::default()
+    }
+
+    /// Tag control information, i.e., PCP, DEI and VID together.
+    #[inline]
+    pub fn tci(&self) -> u16 {
+        u16::from_be(self.tci)
+    }
+
+    #[inline]
+    pub fn set_tci(&mut self, tci: u16) {
+        self.tci = u16::to_be(tci);
+    }
+
+    /// VLAN ID.
+    #[inline]
+    pub fn vid(&self) -> u16 {
+        self.tci() & 0x0fff
+    }
+
+    #[inline]
+    pub fn set_vid(&mut self, vid: u16) {
+        let tci = (self.tci() & !0x0fff) | (vid & 0x0fff);
+        self.set_tci(tci);
+    }
+
+    /// Priority code point.
+    #[inline]
+    pub fn pcp(&self) -> u8 {
+        (self.tci() >> 13) as u8
+    }
+
+    #[inline]
+    pub fn set_pcp(&mut self, pcp: u8) {
+        let tci = (self.tci() & 0x1fff) | (((pcp & 0x7) as u16) << 13);
+        self.set_tci(tci);
+    }
+
+    /// Drop eligible indicator.
+    #[inline]
+    pub fn dei(&self) -> bool {
+        self.tci() & 0x1000 != 0
+    }
+
+    #[inline]
+    pub fn set_dei(&mut self, dei: bool) {
+        let tci = if dei {
+            self.tci() | 0x1000
+        } else {
+            self.tci() & !0x1000
+        };
+        self.set_tci(tci);
+    }
+
+    /// Ethertype of what follows the tag.
+    #[inline]
+    pub fn etype(&self) -> u16 {
+        u16::from_be(self.etype)
+    }
+
+    #[inline]
+    pub fn set_etype(&mut self, etype: u16) {
+        self.etype = u16::to_be(etype);
+    }
+}
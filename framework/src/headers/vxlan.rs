@@ -0,0 +1,67 @@
+use super::EndOffset;
+use std::fmt;
+use std::default::Default;
+
+/// UDP destination port assigned to VXLAN.
+pub const VXLAN_PORT: u16 = 4789;
+/// Flag set when the VNI is valid, which it must always be.
+const VXLAN_FLAG_VNI: u8 = 0x08;
+const HDR_SIZE: usize = 8;
+
+/// A VXLAN header (RFC 7348), which is followed by an Ethernet frame.
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+pub struct VxlanHeader {
+    flags: u8,
+    reserved: [u8; 3],
+    vni_reserved: u32,
+}
+
+impl fmt::Display for VxlanHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "flags: 0x{:02x} vni: {}", self.flags, self.vni())
+    }
+}
+
+impl EndOffset for VxlanHeader {
+    #[inline]
+    fn offset(&self) -> usize {
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn size() -> usize {
+        HDR_SIZE
+    }
+
+    #[inline]
+    fn payload_size(&self, hint: usize) -> usize {
+        hint.saturating_sub(HDR_SIZE)
+    }
+}
+
+impl VxlanHeader {
+    /// A header with the VNI flag set, and a VNI of 0.
+    #[inline]
+    pub fn new() -> VxlanHeader {
+        VxlanHeader { flags: VXLAN_FLAG_VNI, ..Default::default() }
+    }
+
+    /// Whether the VNI flag is set.
+    #[inline]
+    pub fn vni_valid(&self) -> bool {
+        self.flags & VXLAN_FLAG_VNI != 0
+    }
+
+    /// VXLAN network identifier (24 bits).
+    #[inline]
+    pub fn vni(&self) -> u32 {
+        u32::from_be(self.vni_reserved) >> 8
+    }
+
+    #[inline]
+    pub fn set_vni(&mut self, vni: u32) {
+        self.flags |= VXLAN_FLAG_VNI;
+        self.vni_reserved = u32::to_be((vni & 0xffffff) << 8);
+    }
+}
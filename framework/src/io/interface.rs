@@ -1,4 +1,8 @@
 use std::result;
+#[cfg(any(test, feature = "test-backend"))]
+use super::test_backend as dpdk;
+
+#[cfg(not(any(test, feature = "test-backend")))]
 mod dpdk {
     #[link(name = "zcsi")]
     extern "C" {
@@ -82,6 +86,8 @@ pub enum ZCSIError {
     BadQueue,
     CannotSend,
     BadVdev,
+    BadRssConfig,
+    FailedToAddFilter,
 }
 
 pub type Result<T> = result::Result<T, ZCSIError>;
@@ -1,3 +1,13 @@
+use std::cmp::min;
+use std::marker::PhantomData;
+use std::ptr;
+use std::slice;
+
+/// Receive offload flag set when the NIC stripped a VLAN tag from the packet and stored it in `vlan_tci`.
+pub const PKT_RX_VLAN_PKT: u64 = 1 << 0;
+/// Transmit offload flag asking the NIC to insert a VLAN tag with `vlan_tci`.
+pub const PKT_TX_VLAN_PKT: u64 = 1 << 57;
+
 #[repr(C)]
 pub struct MBuf {
     buf_addr: *mut u8,
@@ -26,6 +36,35 @@ pub struct MBuf {
 // FIXME: Remove this once we start using these functions correctly
 #[allow(dead_code)]
 impl MBuf {
+    /// Create an mbuf describing a `buf_len` byte buffer at `buf_addr`, with `headroom` bytes left free at the front
+    /// and `len` bytes of data. DPDK initializes its own mbufs, this is only used by the heap backed test mempool.
+    #[cfg(any(test, feature = "test-backend"))]
+    pub fn with_buffer(buf_addr: *mut u8, buf_len: u16, headroom: u16, len: u16) -> MBuf {
+        MBuf {
+            buf_addr: buf_addr,
+            phys_addr: 0,
+            buf_len: buf_len,
+            data_off: headroom,
+            refcnt: 1,
+            nb_segs: 1,
+            port: 0,
+            ol_flags: 0,
+            packet_type: 0,
+            pkt_len: len as u32,
+            data_len: len,
+            vlan_tci: 0,
+            hash: 0,
+            seqn: 0,
+            vlan_tci_outer: 0,
+            userdata: 0,
+            pool: 0,
+            next: ::std::ptr::null_mut(),
+            tx_offload: 0,
+            priv_size: 0,
+            timesync: 0,
+        }
+    }
+
     #[inline]
     pub fn data_address(&self, offset: usize) -> *mut u8 {
         unsafe { self.buf_addr.offset(self.data_off as isize).offset(offset as isize) }
@@ -50,6 +89,89 @@ impl MBuf {
         self.pkt_len as usize
     }
 
+    /// Time stamp (in TSC cycles) recorded for this packet, see `Batch::timestamp`. This is kept in the mbuf's
+    /// `userdata` field.
+    #[inline]
+    pub fn timestamp(&self) -> u64 {
+        self.userdata
+    }
+
+    #[inline]
+    pub fn set_timestamp(&mut self, cycles: u64) {
+        self.userdata = cycles;
+    }
+
+    /// Returns the number of references to this segment. Segments referenced more than once (see `reference`) are
+    /// shared between packets and are only freed once every reference has been freed.
+    #[inline]
+    pub fn refcnt(&self) -> u16 {
+        self.refcnt
+    }
+
+    /// Add `value` to the reference count, returning the new count. DPDK manages reference counts itself, this is only
+    /// used by the heap backed test mempool.
+    #[cfg(any(test, feature = "test-backend"))]
+    pub fn update_refcnt(&mut self, value: i16) -> u16 {
+        self.refcnt = (self.refcnt as i16 + value) as u16;
+        self.refcnt
+    }
+
+    /// Returns the number of segments in the packet. Only meaningful for the first segment of a packet.
+    #[inline]
+    pub fn nb_segs(&self) -> usize {
+        self.nb_segs as usize
+    }
+
+    /// Returns the segment following this one, or null if this is the last segment of the packet.
+    #[inline]
+    pub fn next_segment(&self) -> *mut MBuf {
+        self.next
+    }
+
+    /// Whether all the packet's data resides in this segment.
+    #[inline]
+    pub fn is_contiguous(&self) -> bool {
+        self.next.is_null()
+    }
+
+    /// Iterate over the data held in each segment of the packet, starting with this one.
+    #[inline]
+    pub fn segments(&mut self) -> Segments {
+        Segments::new(self as *mut MBuf)
+    }
+
+    /// Copy `data` into the packet starting `offset` bytes from its beginning, spanning segments as necessary. Returns
+    /// the number of bytes written, which is less than `data.len()` if the packet is too short.
+    pub fn write_data(&mut self, offset: usize, data: &[u8]) -> usize {
+        let mut skip = offset;
+        let mut written = 0;
+        for segment in self.segments() {
+            if written == data.len() {
+                break;
+            }
+            if skip >= segment.len() {
+                skip -= segment.len();
+                continue;
+            }
+            let len = min(segment.len() - skip, data.len() - written);
+            segment[skip..skip + len].copy_from_slice(&data[written..written + len]);
+            written += len;
+            skip = 0;
+        }
+        written
+    }
+
+    #[inline]
+    fn last_segment(&mut self) -> &mut MBuf {
+        let mut last = self as *mut MBuf;
+        unsafe {
+            while !(*last).next.is_null() {
+                last = (*last).next;
+            }
+            &mut *last
+        }
+    }
+
     #[inline]
     fn pkt_headroom(&self) -> usize {
         self.data_off as usize
@@ -60,6 +182,51 @@ impl MBuf {
         self.buf_len() - self.data_off as usize - self.data_len()
     }
 
+    /// The VLAN tag (TCI) the NIC stripped from this packet on receive, if any. Such a tag is not part of the packet
+    /// data.
+    #[inline]
+    pub fn rx_vlan_tci(&self) -> Option<u16> {
+        if self.ol_flags & PKT_RX_VLAN_PKT != 0 {
+            Some(self.vlan_tci)
+        } else {
+            None
+        }
+    }
+
+    /// Record that the NIC stripped VLAN tag `tci` from this packet (as DPDK drivers do with VLAN stripping enabled).
+    #[inline]
+    pub fn set_rx_vlan_tci(&mut self, tci: u16) {
+        self.vlan_tci = tci;
+        self.ol_flags |= PKT_RX_VLAN_PKT;
+    }
+
+    /// Forget about a VLAN tag stripped on receive, e.g., once it has been reinserted into the packet data.
+    #[inline]
+    pub fn clear_rx_vlan_tci(&mut self) {
+        self.ol_flags &= !PKT_RX_VLAN_PKT;
+    }
+
+    /// Have the NIC insert a VLAN tag with `tci` when sending this packet (if the port supports VLAN insertion).
+    #[inline]
+    pub fn set_tx_vlan_tci(&mut self, tci: u16) {
+        self.vlan_tci = tci;
+        self.ol_flags |= PKT_TX_VLAN_PKT;
+    }
+
+    /// Copy packet level metadata (everything except for buffer and segment information) from `other`.
+    #[inline]
+    fn copy_metadata(&mut self, other: &MBuf) {
+        self.port = other.port;
+        self.ol_flags = other.ol_flags;
+        self.packet_type = other.packet_type;
+        self.vlan_tci = other.vlan_tci;
+        self.hash = other.hash;
+        self.seqn = other.seqn;
+        self.vlan_tci_outer = other.vlan_tci_outer;
+        self.userdata = other.userdata;
+        self.tx_offload = other.tx_offload;
+    }
+
     /// Add data to the beginning of the packet. This might fail (i.e., return 0) when no more headroom is left.
     #[inline]
     pub fn add_data_beginning(&mut self, len: usize) -> usize {
@@ -74,19 +241,134 @@ impl MBuf {
         }
     }
 
-    /// Add data to the end of a packet buffer. This might fail (i.e., return 0) when no more tailroom is left. We do
-    /// not currently deal with packet with multiple segments.
+    /// Add a new segment holding `len` bytes to the front of packet `head`, for use when `add_data_beginning` fails
+    /// for lack of headroom. The packet's metadata moves to the new segment, which becomes the head of the packet and
+    /// is returned. Returns null (leaving the packet unchanged) if `len` does not fit in a segment or allocation fails.
+    ///
+    /// # Safety
+    /// `head` must be the first segment of a valid packet, and must not be used as the head of the packet afterwards.
+    pub unsafe fn prepend_segment(head: *mut MBuf, len: usize) -> *mut MBuf {
+        if (*head).nb_segs == u8::max_value() {
+            return ptr::null_mut();
+        }
+        let seg = mbuf_alloc();
+        if seg.is_null() {
+            return ptr::null_mut();
+        }
+        if len > (*seg).buf_len() {
+            mbuf_free(seg);
+            return ptr::null_mut();
+        }
+        // Place the data at the end of the buffer, leaving as much headroom as possible for further prepends.
+        (*seg).data_off = (*seg).buf_len - len as u16;
+        (*seg).data_len = len as u16;
+        (*seg).pkt_len = (*head).pkt_len + len as u32;
+        (*seg).nb_segs = (*head).nb_segs + 1;
+        (*seg).copy_metadata(&*head);
+        (*seg).next = head;
+        (*head).pkt_len = (*head).data_len as u32;
+        (*head).nb_segs = 1;
+        seg
+    }
+
+    /// Take an additional reference to packet `mbuf`, returning it. The packet can then be placed in another batch
+    /// (and e.g. sent out several ports) without copying it, and is freed once every reference is freed. Packet data is
+    /// shared between all references, so a referenced packet must not be modified.
+    ///
+    /// # Safety
+    /// `mbuf` must be the first segment of a valid packet.
+    #[inline]
+    pub unsafe fn reference(mbuf: *mut MBuf) -> *mut MBuf {
+        mbuf_refcnt_update(mbuf, 1);
+        mbuf
+    }
+
+    /// Make a copy of packet `mbuf` (data and metadata) in newly allocated mbufs. Returns null if allocation fails.
+    ///
+    /// # Safety
+    /// `mbuf` must be the first segment of a valid packet.
+    pub unsafe fn copy_packet(mbuf: *mut MBuf) -> *mut MBuf {
+        let copy = mbuf_alloc();
+        if copy.is_null() {
+            return copy;
+        }
+        let len = (*mbuf).pkt_len();
+        if (*copy).add_data_end(len) != len && len > 0 {
+            mbuf_free(copy);
+            return ptr::null_mut();
+        }
+        (*copy).copy_metadata(&*mbuf);
+        let mut offset = 0;
+        for segment in (*mbuf).segments() {
+            (*copy).write_data(offset, segment);
+            offset += segment.len();
+        }
+        copy
+    }
+
+    /// Add data to the end of a packet. Data is added to the last segment, and when that runs out of tailroom new
+    /// segments are allocated and chained to the packet. This might fail (i.e., return 0, leaving the packet
+    /// unchanged) if segments cannot be allocated. Must be called on the first segment of a packet.
     #[inline]
     pub fn add_data_end(&mut self, len: usize) -> usize {
-        if len > self.pkt_tailroom() {
-            0
-        } else {
-            self.data_len += len as u16;
+        let tailroom = self.last_segment().pkt_tailroom();
+        if len <= tailroom {
+            self.last_segment().data_len += len as u16;
             self.pkt_len += len as u32;
             len
+        } else {
+            unsafe { self.add_segments_end(len, tailroom) }
         }
     }
 
+    /// Slow path for `add_data_end`: fill the tailroom in the last segment and put the rest of the data into new
+    /// segments.
+    #[inline(never)]
+    unsafe fn add_segments_end(&mut self, len: usize, tailroom: usize) -> usize {
+        // Allocate everything first, so failure leaves the packet untouched.
+        let mut chain: *mut MBuf = ptr::null_mut();
+        let mut chain_tail: *mut MBuf = ptr::null_mut();
+        let mut remaining = len - tailroom;
+        let mut added = 0;
+        while remaining > 0 {
+            let seg = mbuf_alloc();
+            if seg.is_null() || self.nb_segs() + added >= u8::max_value() as usize {
+                if !seg.is_null() {
+                    mbuf_free(seg);
+                }
+                if !chain.is_null() {
+                    mbuf_free(chain);
+                }
+                return 0;
+            }
+            // Segments other than the first do not need headroom.
+            (*seg).data_off = 0;
+            (*seg).data_len = if remaining < (*seg).buf_len() {
+                remaining as u16
+            } else {
+                (*seg).buf_len
+            };
+            remaining -= (*seg).data_len();
+            if chain.is_null() {
+                chain = seg;
+            } else {
+                (*chain_tail).next = seg;
+            }
+            chain_tail = seg;
+            added += 1;
+        }
+        {
+            let last = self.last_segment();
+            last.data_len += tailroom as u16;
+            last.next = chain;
+        }
+        self.nb_segs += added as u8;
+        self.pkt_len += len as u32;
+        len
+    }
+
+    /// Remove data from the beginning of the packet. This only removes data from the first segment, and fails (i.e.,
+    /// returns 0) if `len` is larger than the amount of data in the first segment.
     #[inline]
     pub fn remove_data_beginning(&mut self, len: usize) -> usize {
         if len > self.data_len() {
@@ -99,14 +381,115 @@ impl MBuf {
         }
     }
 
+    /// Remove data from the end of the packet, freeing any segments that are left empty. This fails (i.e., returns 0)
+    /// if `len` is larger than the packet. Must be called on the first segment of a packet.
     #[inline]
     pub fn remove_data_end(&mut self, len: usize) -> usize {
-        if len > self.data_len() {
+        if self.next.is_null() {
+            if len > self.data_len() {
+                0
+            } else {
+                self.data_len -= len as u16;
+                self.pkt_len -= len as u32;
+                len
+            }
+        } else if len > self.pkt_len() {
             0
         } else {
-            self.data_len -= len as u16;
-            self.pkt_len -= len as u32;
-            len
+            unsafe { self.remove_segments_end(len) }
         }
     }
+
+    /// Slow path for `remove_data_end`.
+    #[inline(never)]
+    unsafe fn remove_segments_end(&mut self, len: usize) -> usize {
+        let mut keep = self.pkt_len() - len;
+        let mut segs = 1;
+        let mut seg = self as *mut MBuf;
+        // Find the segment in which the remaining data ends (always keeping the first segment).
+        while keep > (*seg).data_len() && !(*seg).next.is_null() {
+            keep -= (*seg).data_len();
+            seg = (*seg).next;
+            segs += 1;
+        }
+        (*seg).data_len = keep as u16;
+        if !(*seg).next.is_null() {
+            mbuf_free((*seg).next);
+            (*seg).next = ptr::null_mut();
+        }
+        self.nb_segs = segs;
+        self.pkt_len -= len as u32;
+        len
+    }
+
+    /// Copy data from all segments into the first one, freeing the rest, so that the packet can be accessed as a single
+    /// contiguous buffer. Fails (i.e., returns false, leaving the packet unchanged) if the first segment is not large
+    /// enough to hold the entire packet. Must be called on the first segment of a packet.
+    pub fn linearize(&mut self) -> bool {
+        if self.next.is_null() {
+            return true;
+        }
+        if self.pkt_len() > self.buf_len() - self.pkt_headroom() {
+            return false;
+        }
+        unsafe {
+            let mut seg = self.next;
+            while !seg.is_null() {
+                let len = (*seg).data_len();
+                ptr::copy_nonoverlapping((*seg).data_address(0), self.data_address(self.data_len()), len);
+                self.data_len += len as u16;
+                seg = (*seg).next;
+            }
+            mbuf_free(self.next);
+        }
+        self.next = ptr::null_mut();
+        self.nb_segs = 1;
+        true
+    }
+}
+
+/// An iterator over the data held in each segment of a packet, see `MBuf::segments`.
+pub struct Segments<'a> {
+    segment: *mut MBuf,
+    phantom: PhantomData<&'a mut MBuf>,
+}
+
+impl<'a> Segments<'a> {
+    /// Iterate over `segment` and all segments chained after it (if `segment` is null, there is nothing to iterate
+    /// over).
+    #[inline]
+    pub fn new(segment: *mut MBuf) -> Segments<'a> {
+        Segments {
+            segment: segment,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = &'a mut [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a mut [u8]> {
+        if self.segment.is_null() {
+            None
+        } else {
+            unsafe {
+                let seg = &mut *self.segment;
+                self.segment = seg.next;
+                Some(slice::from_raw_parts_mut(seg.data_address(0), seg.data_len()))
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-backend"))]
+use super::test_backend::{mbuf_alloc, mbuf_free, mbuf_refcnt_update};
+
+#[cfg(not(any(test, feature = "test-backend")))]
+#[link(name = "zcsi")]
+extern "C" {
+    fn mbuf_alloc() -> *mut MBuf;
+    fn mbuf_free(buf: *mut MBuf);
+    fn mbuf_refcnt_update(buf: *mut MBuf, value: i16);
 }
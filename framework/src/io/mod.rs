@@ -1,6 +1,12 @@
 pub use self::interface::*;
 pub use self::pmd::*;
+pub use self::rss::*;
 pub use self::mbuf::*;
+pub use self::stats::{HardwareStats, PortStats, QueueStats, TxDrops};
 mod interface;
 mod mbuf;
 mod pmd;
+mod rss;
+mod stats;
+#[cfg(any(test, feature = "test-backend"))]
+pub mod test_backend;
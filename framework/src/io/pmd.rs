@@ -1,11 +1,26 @@
 use super::mbuf::MBuf;
 use super::interface::Result;
 use super::interface::ZCSIError;
+use super::rss::RssConfig;
+use super::stats::{HardwareStats, PortStats, QueueCounters};
 use super::super::headers::MacAddress;
+use super::super::utils::Flow;
+use std::cmp::{max, min};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::mem;
+use std::ptr;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(any(test, feature = "test-backend"))]
+use super::test_backend::{add_fdir_filter, free_pmd_port, get_port_stats, get_port_xstats, init_bess_eth_ring,
+                          init_ovs_eth_ring, init_pcap_port, init_pmd_port, mbuf_free_bulk, num_pmd_ports, recv_pkts,
+                          reset_port_stats, rte_eth_macaddr_get, send_pkts};
+#[cfg(any(test, feature = "test-backend"))]
+use super::test_backend;
 
 // External DPDK calls
+#[cfg(not(any(test, feature = "test-backend")))]
 #[link(name = "zcsi")]
 extern "C" {
     fn init_pmd_port(port: i32,
@@ -17,8 +32,17 @@ extern "C" {
                      ntxd: i32,
                      loopback: i32,
                      tso: i32,
-                     csumoffload: i32)
+                     csumoffload: i32,
+                     rss: *const RssConf)
                      -> i32;
+    fn add_fdir_filter(port: i32,
+                       src_ip: u32,
+                       dst_ip: u32,
+                       src_port: u16,
+                       dst_port: u16,
+                       proto: u8,
+                       queue: i32)
+                       -> i32;
     fn free_pmd_port(port: i32) -> i32;
     fn recv_pkts(port: i32, qid: i32, pkts: *mut *mut MBuf, len: i32) -> i32;
     fn send_pkts(port: i32, qid: i32, pkts: *mut *mut MBuf, len: i32) -> i32;
@@ -26,6 +50,54 @@ extern "C" {
     fn rte_eth_macaddr_get(port: i32, address: *mut MacAddress);
     fn init_bess_eth_ring(ifname: *const u8, core: i32) -> i32;
     fn init_ovs_eth_ring(iface: i32, core: i32) -> i32;
+    fn init_pcap_port(rx_pcap: *const u8, tx_pcap: *const u8) -> i32;
+    fn get_port_stats(port: i32, stats: *mut HardwareStats) -> i32;
+    fn get_port_xstats(port: i32, xstats: *mut PortXstat, len: i32) -> i32;
+    fn reset_port_stats(port: i32);
+    fn mbuf_free_bulk(array: *mut *mut MBuf, cnt: i32) -> i32;
+}
+
+/// RSS configuration as passed to `init_pmd_port`, see `RssConfig`.
+#[repr(C)]
+pub struct RssConf {
+    pub hash_functions: u64,
+    pub key: *const u8,
+    pub key_len: u32,
+    pub reta: *const u16,
+    pub reta_len: u32,
+    pub flow_director: i32,
+}
+
+/// Length of the name field in `PortXstat`, same as `RTE_ETH_XSTATS_NAME_SIZE`.
+pub const XSTAT_NAME_SIZE: usize = 64;
+
+/// An extended statistic as returned by `get_port_xstats`.
+#[repr(C)]
+pub struct PortXstat {
+    pub name: [c_char; XSTAT_NAME_SIZE],
+    pub value: u64,
+}
+
+/// What to do with packets the transmit ring does not accept (because it is full) when sending a batch. Whatever policy
+/// applies, packets that end up not being sent are counted in `QueueStats::tx_drops`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxPolicy {
+    /// Drop packets that are not sent on the first try. This is the default, since it never delays the pipeline.
+    Drop,
+    /// Try sending up to this many more times before dropping what is left.
+    Retry(usize),
+    /// Keep trying until everything has been sent. This makes the pipeline wait for the NIC, i.e., provides
+    /// backpressure, but processing stops entirely if the port stops draining.
+    SpinUntilSent,
+    /// Hold on to packets that are not sent and send them ahead of the next batch sent on the same queue, holding at
+    /// most this many packets per queue (anything beyond that is dropped).
+    Buffer(usize),
+}
+
+impl Default for TxPolicy {
+    fn default() -> TxPolicy {
+        TxPolicy::Drop
+    }
 }
 
 pub struct PmdPort {
@@ -34,12 +106,21 @@ pub struct PmdPort {
     rxqs: i32,
     txqs: i32,
     should_close: bool,
-    stats_rx: Vec<Arc<AtomicUsize>>,
-    stats_tx: Vec<Arc<AtomicUsize>>,
+    /// Software counters, one per queue (receive and transmit queues with the same index share counters).
+    stats: Vec<Arc<QueueCounters>>,
+    tx_policy: TxPolicy,
+    /// Packets held by `TxPolicy::Buffer`, one buffer per transmit queue. Not shared with copies of this port.
+    tx_buffers: Vec<Vec<*mut MBuf>>,
 }
 
+// Buffered mbufs are owned by this port (copies start with empty buffers), so it is safe to move it across threads.
+unsafe impl Send for PmdPort {}
+
 impl Drop for PmdPort {
     fn drop(&mut self) {
+        for queue in 0..self.tx_buffers.len() {
+            self.free_tx_buffer(queue, 0);
+        }
         if self.should_close {
             unsafe {
                 free_pmd_port(self.port);
@@ -52,6 +133,20 @@ impl Drop for PmdPort {
 const NUM_RXD: i32 = 256 * 4;
 const NUM_TXD: i32 = 256;
 
+/// Total length of packets `start..end` in `pkts`.
+#[inline]
+unsafe fn packet_bytes(pkts: *mut *mut MBuf, start: i32, end: i32) -> usize {
+    (start..end).fold(0, |bytes, i| bytes + (**pkts.offset(i as isize)).pkt_len())
+}
+
+fn queue_counters(rxqs: i32, txqs: i32) -> Vec<Arc<QueueCounters>> {
+    (0..max(rxqs, txqs)).map(|_| Arc::new(QueueCounters::default())).collect()
+}
+
+fn tx_buffers(txqs: i32) -> Vec<Vec<*mut MBuf>> {
+    (0..txqs).map(|_| Vec::new()).collect()
+}
+
 impl PmdPort {
     pub fn num_pmd_ports() -> i32 {
         unsafe { num_pmd_ports() }
@@ -61,10 +156,136 @@ impl PmdPort {
         self.port
     }
 
+    /// Number of packets received and sent on `queue`.
     pub fn stats(&self, queue: i32) -> (usize, usize) {
         let idx = queue as usize;
-        (self.stats_rx[idx].load(Ordering::Relaxed),
-         self.stats_tx[idx].load(Ordering::Relaxed))
+        (self.stats[idx].rx_packets(), self.stats[idx].tx_packets())
+    }
+
+    /// Snapshot of the software counters for each queue along with statistics from the NIC. Hardware statistics cover
+    /// the whole port (including traffic from other processes), software counters only this process.
+    pub fn port_stats(&self) -> PortStats {
+        let mut hardware = HardwareStats::default();
+        let mut xstats = Vec::new();
+        if self.connected {
+            unsafe {
+                get_port_stats(self.port, &mut hardware);
+                let len = get_port_xstats(self.port, ptr::null_mut(), 0);
+                if len > 0 {
+                    let mut raw = Vec::<PortXstat>::with_capacity(len as usize);
+                    let filled = get_port_xstats(self.port, raw.as_mut_ptr(), len);
+                    if filled > 0 && filled <= len {
+                        raw.set_len(filled as usize);
+                        xstats = raw.iter()
+                            .map(|x| (CStr::from_ptr(x.name.as_ptr()).to_string_lossy().into_owned(), x.value))
+                            .collect();
+                    }
+                }
+            }
+        }
+        PortStats {
+            queues: self.stats.iter().map(|q| q.snapshot()).collect(),
+            hardware: hardware,
+            xstats: xstats,
+        }
+    }
+
+    /// Reset software and hardware counters to 0. Note that hardware counters are shared with other processes using
+    /// the port.
+    pub fn reset_stats(&self) {
+        for queue in &self.stats {
+            queue.reset();
+        }
+        if self.connected {
+            unsafe {
+                reset_port_stats(self.port);
+            }
+        }
+    }
+
+    /// Record `dropped` packets that were dropped because the transmit ring for `queue` was full.
+    #[inline]
+    pub fn record_tx_dropped(&self, queue: i32, dropped: usize) {
+        if let Some(counters) = self.stats.get(queue as usize) {
+            counters.record_tx_dropped(dropped, self.tx_policy);
+        }
+    }
+
+    /// Record `retries` additional attempts to send packets on `queue`.
+    #[inline]
+    pub fn record_tx_retries(&self, queue: i32, retries: usize) {
+        if let Some(counters) = self.stats.get(queue as usize) {
+            counters.record_tx_retries(retries);
+        }
+    }
+
+    #[inline]
+    pub fn tx_policy(&self) -> TxPolicy {
+        self.tx_policy
+    }
+
+    /// Set the policy used when batches are sent on this port (see `TxPolicy`). Only affects this copy of the port,
+    /// i.e., set it on the port passed to `send`. Buffered packets that no longer fit (e.g., when switching away from
+    /// `TxPolicy::Buffer`) are dropped.
+    pub fn set_tx_policy(&mut self, policy: TxPolicy) {
+        let limit = match policy {
+            TxPolicy::Buffer(limit) => limit,
+            _ => 0,
+        };
+        for queue in 0..self.tx_buffers.len() {
+            let dropped = self.free_tx_buffer(queue, limit);
+            self.record_tx_dropped(queue as i32, dropped);
+        }
+        self.tx_policy = policy;
+    }
+
+    /// Number of packets currently buffered for `queue`.
+    #[inline]
+    pub fn tx_buffered(&self, queue: i32) -> usize {
+        self.tx_buffers.get(queue as usize).map_or(0, |buffer| buffer.len())
+    }
+
+    /// Send as much of the buffer for `queue` as the transmit ring accepts, returning the number of packets sent.
+    #[inline]
+    pub fn flush_tx_buffer(&mut self, queue: i32) -> Result<u32> {
+        if self.tx_buffered(queue) == 0 {
+            return Ok(0);
+        }
+        let mut buffer = mem::replace(&mut self.tx_buffers[queue as usize], Vec::new());
+        let len = buffer.len() as i32;
+        let result = self.send_queue(queue, buffer.as_mut_ptr(), len);
+        if let Ok(sent) = result {
+            buffer.drain(..sent as usize);
+        }
+        self.tx_buffers[queue as usize] = buffer;
+        result
+    }
+
+    /// Move as many of `pkts` (in order) into the buffer for `queue` as fit under the `TxPolicy::Buffer` limit,
+    /// returning the number of packets buffered. The port takes ownership of the buffered packets.
+    #[inline]
+    pub fn buffer_unsent(&mut self, queue: i32, pkts: &[*mut MBuf]) -> usize {
+        match (self.tx_policy, self.tx_buffers.get_mut(queue as usize)) {
+            (TxPolicy::Buffer(limit), Some(buffer)) => {
+                let buffered = min(limit.saturating_sub(buffer.len()), pkts.len());
+                buffer.extend_from_slice(&pkts[..buffered]);
+                buffered
+            }
+            _ => 0,
+        }
+    }
+
+    /// Free all but the first `keep` packets buffered for `queue`, returning the number of packets freed.
+    fn free_tx_buffer(&mut self, queue: usize, keep: usize) -> usize {
+        let buffer = &mut self.tx_buffers[queue];
+        if buffer.len() <= keep {
+            return 0;
+        }
+        let mut freed: Vec<_> = buffer.drain(keep..).collect();
+        unsafe {
+            mbuf_free_bulk(freed.as_mut_ptr(), freed.len() as i32);
+        }
+        freed.len()
     }
 
     pub fn new(port: i32,
@@ -78,6 +299,33 @@ impl PmdPort {
                tso: bool,
                csumoffload: bool)
                -> Result<PmdPort> {
+        PmdPort::new_with_rss(port,
+                              rxqs,
+                              txqs,
+                              rx_cores,
+                              tx_cores,
+                              nrxd,
+                              ntxd,
+                              loopback,
+                              tso,
+                              csumoffload,
+                              None)
+    }
+
+    /// Like `new`, but spreading received packets across queues as specified by `rss` (the defaults described in
+    /// `RssConfig::default` are used if `None`).
+    pub fn new_with_rss(port: i32,
+                        rxqs: i32,
+                        txqs: i32,
+                        rx_cores: &[i32],
+                        tx_cores: &[i32],
+                        nrxd: i32,
+                        ntxd: i32,
+                        loopback: bool,
+                        tso: bool,
+                        csumoffload: bool,
+                        rss: Option<&RssConfig>)
+                        -> Result<PmdPort> {
         assert_eq!(rxqs as usize, rx_cores.len());
         assert_eq!(txqs as usize, tx_cores.len());
         let loopbackv = if loopback {
@@ -95,6 +343,30 @@ impl PmdPort {
         } else {
             0
         };
+        let rss_conf = match rss {
+            Some(rss) => {
+                if rss.reta.iter().any(|q| *q as i32 >= rxqs) || rss.key.bytes().map_or(false, |k| k.is_empty()) {
+                    return Err(ZCSIError::BadRssConfig);
+                }
+                Some(RssConf {
+                    hash_functions: rss.hash_functions,
+                    key: rss.key.bytes().map_or(ptr::null(), |k| k.as_ptr()),
+                    key_len: rss.key.bytes().map_or(0, |k| k.len() as u32),
+                    reta: if rss.reta.is_empty() {
+                        ptr::null()
+                    } else {
+                        rss.reta.as_ptr()
+                    },
+                    reta_len: rss.reta.len() as u32,
+                    flow_director: if rss.flow_director {
+                        1
+                    } else {
+                        0
+                    },
+                })
+            }
+            None => None,
+        };
         let ret = unsafe {
             init_pmd_port(port,
                           rxqs,
@@ -105,7 +377,8 @@ impl PmdPort {
                           ntxd,
                           loopbackv,
                           tsov,
-                          csumoffloadv)
+                          csumoffloadv,
+                          rss_conf.as_ref().map_or(ptr::null(), |c| c as *const RssConf))
         };
         if ret == 0 {
             Ok(PmdPort {
@@ -114,8 +387,9 @@ impl PmdPort {
                 rxqs: rxqs,
                 txqs: txqs,
                 should_close: true,
-                stats_rx: vec![Arc::new(AtomicUsize::new(0)); rxqs as usize],
-                stats_tx: vec![Arc::new(AtomicUsize::new(0)); txqs as usize],
+                stats: queue_counters(rxqs, txqs),
+                tx_policy: TxPolicy::default(),
+                tx_buffers: tx_buffers(txqs),
             })
         } else {
             Err(ZCSIError::FailedToInitializePort)
@@ -166,6 +440,50 @@ impl PmdPort {
                      false)
     }
 
+    /// A multi-queue port distributing packets across receive queues as specified by `rss`. For example,
+    /// `RssConfig::symmetric()` ensures both directions of a connection are received by the same core.
+    pub fn new_mq_port_with_rss(port: i32,
+                                rxqs: i32,
+                                txqs: i32,
+                                rx_cores: &[i32],
+                                tx_cores: &[i32],
+                                rss: &RssConfig)
+                                -> Result<PmdPort> {
+        PmdPort::new_with_rss(port,
+                              rxqs,
+                              txqs,
+                              &rx_cores[..],
+                              &tx_cores[..],
+                              NUM_RXD,
+                              NUM_TXD,
+                              false,
+                              false,
+                              false,
+                              Some(rss))
+    }
+
+    /// Steer packets belonging to the IPv4 `flow` to `queue`. Requires the port to have been created with
+    /// `RssConfig::flow_director` set.
+    pub fn add_flow_filter(&self, flow: &Flow, queue: i32) -> Result<()> {
+        if queue >= self.rxqs {
+            return Err(ZCSIError::BadQueue);
+        }
+        let ret = unsafe {
+            add_fdir_filter(self.port,
+                            flow.src_ip,
+                            flow.dst_ip,
+                            flow.src_port,
+                            flow.dst_port,
+                            flow.proto,
+                            queue)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(ZCSIError::FailedToAddFilter)
+        }
+    }
+
     /// Create a new port that can talk to BESS. Really shouldn't need to do this but fixing this requires patching
     /// DPDK.
     fn new_bess_port(name: &str, core: i32) -> Result<PmdPort> {
@@ -180,8 +498,9 @@ impl PmdPort {
                 rxqs: 1,
                 txqs: 1,
                 should_close: false,
-                stats_rx: vec![Arc::new(AtomicUsize::new(0)); 1],
-                stats_tx: vec![Arc::new(AtomicUsize::new(0)); 1],
+                stats: queue_counters(1, 1),
+                tx_policy: TxPolicy::default(),
+                tx_buffers: tx_buffers(1),
             })
         } else {
             Err(ZCSIError::FailedToInitializePort)
@@ -202,8 +521,9 @@ impl PmdPort {
                         rxqs: 1,
                         txqs: 1,
                         should_close: false,
-                        stats_rx: vec![Arc::new(AtomicUsize::new(0)); 1],
-                        stats_tx: vec![Arc::new(AtomicUsize::new(0)); 1],
+                        stats: queue_counters(1, 1),
+                        tx_policy: TxPolicy::default(),
+                        tx_buffers: tx_buffers(1),
                     })
                 } else {
                     Err(ZCSIError::FailedToInitializePort)
@@ -213,14 +533,52 @@ impl PmdPort {
         }
     }
 
+    /// Create a new port backed by pcap files. `spec` is of the form `in=trace.pcap,out=out.pcap`, packets are received
+    /// from `in` and anything sent on the port is written to `out`. If `out` is not given sent packets are discarded.
+    /// When `in` is exhausted the port simply stops receiving packets.
+    fn new_pcap_port(spec: &str, core: i32) -> Result<PmdPort> {
+        let mut rx_pcap = None;
+        let mut tx_pcap = None;
+        for arg in spec.split(',') {
+            let kv: Vec<_> = arg.splitn(2, '=').collect();
+            if kv.len() != 2 || kv[1].is_empty() {
+                return Err(ZCSIError::BadVdev);
+            }
+            match kv[0] {
+                "in" => rx_pcap = Some(kv[1]),
+                "out" => tx_pcap = Some(kv[1]),
+                _ => return Err(ZCSIError::BadVdev),
+            }
+        }
+        let rx_pcap = match rx_pcap.and_then(|p| CString::new(p).ok()) {
+            Some(p) => p,
+            None => return Err(ZCSIError::BadVdev),
+        };
+        let tx_pcap = match CString::new(tx_pcap.unwrap_or("/dev/null")) {
+            Ok(p) => p,
+            Err(_) => return Err(ZCSIError::BadVdev),
+        };
+        // This call returns the port number
+        let port = unsafe { init_pcap_port(rx_pcap.as_ptr() as *const u8, tx_pcap.as_ptr() as *const u8) };
+        if port >= 0 {
+            // Unlike the ring based ports, the pcap PMD needs to be configured like any other DPDK port.
+            PmdPort::new_with_one_queue(port, core, core, NUM_RXD, NUM_TXD, false, false, false)
+        } else {
+            Err(ZCSIError::FailedToInitializePort)
+        }
+    }
+
+    /// Create a port for a virtual device. `name` is one of `bess:<ifname>`, `ovs:<iface>` or
+    /// `pcap:in=<file>[,out=<file>]`.
     pub fn new_vdev(name: &str, core: i32) -> Result<PmdPort> {
-        let parts: Vec<_> = name.split(':').collect();
+        let parts: Vec<_> = name.splitn(2, ':').collect();
         if parts.len() != 2 {
             Err(ZCSIError::BadVdev)
         } else {
             match parts[0] {
                 "bess" => PmdPort::new_bess_port(parts[1], core),
                 "ovs"  => PmdPort::new_ovs_port(parts[1], core), 
+                "pcap" => PmdPort::new_pcap_port(parts[1], core),
                  _     => Err(ZCSIError::BadVdev),
             }
         }
@@ -233,8 +591,9 @@ impl PmdPort {
             rxqs: 0,
             txqs: 0,
             should_close: false,
-            stats_rx: vec![Arc::new(AtomicUsize::new(0)); 0],
-            stats_tx: vec![Arc::new(AtomicUsize::new(0)); 0],
+            stats: queue_counters(0, 0),
+            tx_policy: TxPolicy::default(),
+            tx_buffers: tx_buffers(0),
         })
     }
 
@@ -246,8 +605,9 @@ impl PmdPort {
             rxqs: self.rxqs,
             txqs: self.txqs,
             should_close: false,
-            stats_rx: self.stats_rx.clone(),
-            stats_tx: self.stats_tx.clone(),
+            stats: self.stats.clone(),
+            tx_policy: self.tx_policy,
+            tx_buffers: tx_buffers(self.txqs),
         }
     }
 
@@ -263,13 +623,15 @@ impl PmdPort {
 
     #[inline]
     pub fn send_queue(&mut self, queue: i32, pkts: *mut *mut MBuf, to_send: i32) -> Result<u32> {
-        if self.txqs < queue {
+        if queue >= self.txqs {
             Err(ZCSIError::BadQueue)
         } else {
             unsafe {
+                // Sent packets belong to the driver (and might already have been freed) once send_pkts returns, so
+                // count bytes beforehand and subtract whatever was not sent.
+                let bytes = packet_bytes(pkts, 0, to_send);
                 let sent = send_pkts(self.port, queue, pkts, to_send);
-                let update = self.stats_tx[queue as usize].load(Ordering::Relaxed) + sent as usize;
-                self.stats_tx[queue as usize].store(update, Ordering::Relaxed);
+                self.stats[queue as usize].record_tx(sent as usize, bytes - packet_bytes(pkts, sent, to_send));
                 Ok(sent as u32)
             }
         }
@@ -277,13 +639,12 @@ impl PmdPort {
 
     #[inline]
     pub fn recv_queue(&self, queue: i32, pkts: *mut *mut MBuf, to_recv: i32) -> Result<u32> {
-        if self.rxqs < queue {
+        if queue >= self.rxqs {
             Err(ZCSIError::BadQueue)
         } else {
             unsafe {
                 let recv = recv_pkts(self.port, queue, pkts, to_recv);
-                let update = self.stats_rx[queue as usize].load(Ordering::Relaxed) + recv as usize;
-                self.stats_rx[queue as usize].store(update, Ordering::Relaxed);
+                self.stats[queue as usize].record_rx(recv as usize, packet_bytes(pkts, 0, recv));
                 Ok(recv as u32)
             }
         }
@@ -297,4 +658,18 @@ impl PmdPort {
             address
         }
     }
+
+    /// Copy `packets` into new mbufs and queue them to be received on `queue`. Returns the number of packets queued,
+    /// which is less than `packets.len()` once the receive ring is full (the rest are counted as missed). Only
+    /// available with the in-memory test backend.
+    #[cfg(any(test, feature = "test-backend"))]
+    pub fn inject_packets<T: AsRef<[u8]>>(&self, queue: i32, packets: &[T]) -> usize {
+        test_backend::inject_packets(self.port, queue, packets)
+    }
+
+    /// Contents of every packet sent on `queue` since the last call. Only available with the in-memory test backend.
+    #[cfg(any(test, feature = "test-backend"))]
+    pub fn take_sent_packets(&self, queue: i32) -> Vec<Vec<u8>> {
+        test_backend::take_sent_packets(self.port, queue)
+    }
 }
@@ -0,0 +1,188 @@
+use utils::Flow;
+
+// Packet types RSS can hash on, same values as DPDK's `ETH_RSS_*` flags.
+pub const RSS_IPV4: u64 = 1 << 2;
+pub const RSS_FRAG_IPV4: u64 = 1 << 3;
+pub const RSS_NONFRAG_IPV4_TCP: u64 = 1 << 4;
+pub const RSS_NONFRAG_IPV4_UDP: u64 = 1 << 5;
+pub const RSS_NONFRAG_IPV4_SCTP: u64 = 1 << 6;
+pub const RSS_NONFRAG_IPV4_OTHER: u64 = 1 << 7;
+pub const RSS_IPV6: u64 = 1 << 8;
+pub const RSS_FRAG_IPV6: u64 = 1 << 9;
+pub const RSS_NONFRAG_IPV6_TCP: u64 = 1 << 10;
+pub const RSS_NONFRAG_IPV6_UDP: u64 = 1 << 11;
+pub const RSS_NONFRAG_IPV6_SCTP: u64 = 1 << 12;
+pub const RSS_NONFRAG_IPV6_OTHER: u64 = 1 << 13;
+pub const RSS_L2_PAYLOAD: u64 = 1 << 14;
+pub const RSS_IPV6_EX: u64 = 1 << 15;
+pub const RSS_IPV6_TCP_EX: u64 = 1 << 16;
+pub const RSS_IPV6_UDP_EX: u64 = 1 << 17;
+
+/// A key for which the Toeplitz hash is the same when source and destination are swapped (see "Scalable TCP Session
+/// Monitoring with Symmetric Receive-side Scaling", Woo and Park), so both directions of a connection are hashed to the
+/// same queue.
+pub const SYMMETRIC_RSS_KEY: [u8; 40] = [0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d,
+                                         0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a,
+                                         0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d,
+                                         0x5a];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RssKey {
+    /// Use the driver's default key.
+    Default,
+    /// Use `SYMMETRIC_RSS_KEY`.
+    Symmetric,
+    /// Use the given key (most NICs expect 40 bytes).
+    Custom(Vec<u8>),
+}
+
+impl RssKey {
+    /// The key bytes, `None` for the driver's default.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match *self {
+            RssKey::Default => None,
+            RssKey::Symmetric => Some(&SYMMETRIC_RSS_KEY[..]),
+            RssKey::Custom(ref key) => Some(&key[..]),
+        }
+    }
+}
+
+/// How a multi-queue port spreads received packets across queues, see `PmdPort::new_mq_port_with_rss`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RssConfig {
+    /// Packet types to hash, a combination of the `RSS_*` flags. Other packets go to queue 0.
+    pub hash_functions: u64,
+    pub key: RssKey,
+    /// Redirection table: packets whose hash (modulo the NIC's table size) is `i` go to queue `reta[i % reta.len()]`.
+    /// Empty to keep the driver's default, which usually spreads hashes evenly across queues.
+    pub reta: Vec<u16>,
+    /// Enable (perfect match) flow director, so individual flows can be steered to a queue with
+    /// `PmdPort::add_flow_filter`. Packets not matching a filter are still distributed using RSS.
+    pub flow_director: bool,
+}
+
+impl Default for RssConfig {
+    /// The configuration used by ports created without an explicit `RssConfig`. IPv4 TCP ports are not hashed, since
+    /// fragments (which carry no ports) would end up on a different queue than the rest of their packet.
+    fn default() -> RssConfig {
+        RssConfig {
+            hash_functions: RSS_IPV4 | RSS_IPV6 | RSS_IPV6_EX | RSS_IPV6_TCP_EX | RSS_IPV6_UDP_EX,
+            key: RssKey::Default,
+            reta: Vec::new(),
+            flow_director: false,
+        }
+    }
+}
+
+impl RssConfig {
+    /// Hash the IPv4 five tuple with a symmetric key, so both directions of a TCP or UDP connection are received on the
+    /// same queue (and hence processed by the same core), as stateful NFs need. IP fragments other than the first carry
+    /// no ports and are hashed on addresses alone, so they usually end up on a different queue than the rest of their
+    /// connection; use `symmetric_addresses` if fragments need to be handled with their connection.
+    pub fn symmetric() -> RssConfig {
+        RssConfig {
+            hash_functions: RSS_IPV4 | RSS_NONFRAG_IPV4_TCP | RSS_NONFRAG_IPV4_UDP | RSS_IPV6 |
+                            RSS_NONFRAG_IPV6_TCP | RSS_NONFRAG_IPV6_UDP,
+            key: RssKey::Symmetric,
+            reta: Vec::new(),
+            flow_director: false,
+        }
+    }
+
+    /// Like `symmetric`, but only hash IP addresses, so fragments are received on the same queue as the rest of their
+    /// packet (at the cost of spreading traffic between few hosts less evenly).
+    pub fn symmetric_addresses() -> RssConfig {
+        RssConfig {
+            hash_functions: RSS_IPV4 | RSS_FRAG_IPV4 | RSS_IPV6 | RSS_FRAG_IPV6,
+            key: RssKey::Symmetric,
+            reta: Vec::new(),
+            flow_director: false,
+        }
+    }
+}
+
+/// The Toeplitz hash of `input` with `key` (which must be at least 4 bytes longer than `input`), as computed by NICs
+/// for RSS.
+pub fn toeplitz_hash(key: &[u8], input: &[u8]) -> u32 {
+    assert!(key.len() >= input.len() + 4, "RSS key too short");
+    let mut hash = 0;
+    // The 32 bits of the key starting at the current input bit.
+    let mut window = ((key[0] as u32) << 24) | ((key[1] as u32) << 16) | ((key[2] as u32) << 8) | key[3] as u32;
+    for (i, byte) in input.iter().enumerate() {
+        let next = key[i + 4];
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+            window = (window << 1) | ((next >> (7 - bit)) & 1) as u32;
+        }
+    }
+    hash
+}
+
+/// The RSS hash a NIC computes for an IPv4 `flow` when hashing on addresses and ports with `key`. Useful, e.g., to find
+/// out which queue (and hence core) will receive a flow.
+pub fn flow_rss_hash(key: &[u8], flow: &Flow) -> u32 {
+    let mut input = [0u8; 12];
+    for (i, word) in [flow.src_ip, flow.dst_ip].iter().enumerate() {
+        for j in 0..4 {
+            input[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+    input[8] = (flow.src_port >> 8) as u8;
+    input[9] = flow.src_port as u8;
+    input[10] = (flow.dst_port >> 8) as u8;
+    input[11] = flow.dst_port as u8;
+    toeplitz_hash(key, &input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::PmdPort;
+
+    fn flow() -> Flow {
+        Flow {
+            src_ip: 0x420995bb,
+            dst_ip: 0xa18e6450,
+            src_port: 2794,
+            dst_port: 1766,
+            proto: 6,
+        }
+    }
+
+    #[test]
+    fn toeplitz_hash_matches_test_vector() {
+        // Test vector from the Microsoft RSS verification suite.
+        let key = [0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f, 0xb0,
+                   0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30, 0xf2, 0x0c,
+                   0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa];
+        let flow = flow();
+        assert_eq!(flow_rss_hash(&key, &flow), 0x51ccc178);
+        let reverse = Flow {
+            src_ip: flow.dst_ip,
+            dst_ip: flow.src_ip,
+            src_port: flow.dst_port,
+            dst_port: flow.src_port,
+            proto: flow.proto,
+        };
+        assert!(flow_rss_hash(&key, &reverse) != 0x51ccc178);
+        assert_eq!(flow_rss_hash(&SYMMETRIC_RSS_KEY, &flow), flow_rss_hash(&SYMMETRIC_RSS_KEY, &reverse));
+    }
+
+    #[test]
+    fn port_configuration() {
+        let flow = flow();
+        let mut rss = RssConfig::symmetric();
+        rss.reta = vec![0, 2];
+        assert!(PmdPort::new_mq_port_with_rss(0, 2, 2, &[0, 0], &[0, 0], &rss).is_err());
+        rss.reta = vec![1, 0];
+        let port = PmdPort::new_mq_port_with_rss(0, 2, 2, &[0, 0], &[0, 0], &rss).expect("Could not create port");
+        assert!(port.add_flow_filter(&flow, 1).is_err());
+        drop(port);
+        rss.flow_director = true;
+        let port = PmdPort::new_mq_port_with_rss(0, 2, 2, &[0, 0], &[0, 0], &rss).expect("Could not create port");
+        assert!(port.add_flow_filter(&flow, 1).is_ok());
+        assert!(port.add_flow_filter(&flow, 2).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use super::pmd::TxPolicy;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Statistics reported by the NIC (from `rte_eth_stats_get`). Not all drivers report all of these.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct HardwareStats {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Packets dropped by the NIC because the receive ring was full.
+    pub rx_missed: u64,
+    /// Erroneous packets received.
+    pub rx_errors: u64,
+    /// Failed transmissions.
+    pub tx_errors: u64,
+    /// Receive mbuf allocation failures.
+    pub rx_no_mbufs: u64,
+}
+
+impl HardwareStats {
+    fn delta(&self, earlier: &HardwareStats) -> HardwareStats {
+        HardwareStats {
+            rx_packets: self.rx_packets.saturating_sub(earlier.rx_packets),
+            tx_packets: self.tx_packets.saturating_sub(earlier.tx_packets),
+            rx_bytes: self.rx_bytes.saturating_sub(earlier.rx_bytes),
+            tx_bytes: self.tx_bytes.saturating_sub(earlier.tx_bytes),
+            rx_missed: self.rx_missed.saturating_sub(earlier.rx_missed),
+            rx_errors: self.rx_errors.saturating_sub(earlier.rx_errors),
+            tx_errors: self.tx_errors.saturating_sub(earlier.tx_errors),
+            rx_no_mbufs: self.rx_no_mbufs.saturating_sub(earlier.rx_no_mbufs),
+        }
+    }
+}
+
+/// Packets dropped on transmit, broken down by the `TxPolicy` in effect when they were dropped (`SpinUntilSent` never
+/// drops packets).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TxDrops {
+    pub drop: u64,
+    /// Packets still not sent after all retries.
+    pub retry: u64,
+    /// Packets that did not fit in the transmit buffer.
+    pub buffer: u64,
+}
+
+impl TxDrops {
+    fn delta(&self, earlier: &TxDrops) -> TxDrops {
+        TxDrops {
+            drop: self.drop.saturating_sub(earlier.drop),
+            retry: self.retry.saturating_sub(earlier.retry),
+            buffer: self.buffer.saturating_sub(earlier.buffer),
+        }
+    }
+}
+
+/// Statistics for one queue, counted in software by `PmdPort` (and hence only covering this process).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Packets that were handed to the port for sending but were not accepted (because the transmit ring was full) and
+    /// were dropped as a result.
+    pub tx_dropped: u64,
+    /// `tx_dropped` broken down by transmit policy.
+    pub tx_drops: TxDrops,
+    /// Additional attempts made to send packets the transmit ring did not accept the first time.
+    pub tx_retries: u64,
+}
+
+impl QueueStats {
+    fn delta(&self, earlier: &QueueStats) -> QueueStats {
+        QueueStats {
+            rx_packets: self.rx_packets.saturating_sub(earlier.rx_packets),
+            rx_bytes: self.rx_bytes.saturating_sub(earlier.rx_bytes),
+            tx_packets: self.tx_packets.saturating_sub(earlier.tx_packets),
+            tx_bytes: self.tx_bytes.saturating_sub(earlier.tx_bytes),
+            tx_dropped: self.tx_dropped.saturating_sub(earlier.tx_dropped),
+            tx_drops: self.tx_drops.delta(&earlier.tx_drops),
+            tx_retries: self.tx_retries.saturating_sub(earlier.tx_retries),
+        }
+    }
+}
+
+/// A snapshot of a port's statistics, see `PmdPort::port_stats`. Counters are cumulative (since the port was created
+/// or last reset); use `delta` to get the change between two snapshots, e.g., when periodically reporting rates.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortStats {
+    /// Software counters, indexed by queue. Receive and transmit queues share an index.
+    pub queues: Vec<QueueStats>,
+    pub hardware: HardwareStats,
+    /// Driver specific extended statistics (`rte_eth_xstats_get`), as name value pairs.
+    pub xstats: Vec<(String, u64)>,
+}
+
+impl PortStats {
+    #[inline]
+    fn sum<F: Fn(&QueueStats) -> u64>(&self, f: F) -> u64 {
+        self.queues.iter().fold(0, |total, queue| total + f(queue))
+    }
+
+    pub fn rx_packets(&self) -> u64 {
+        self.sum(|q| q.rx_packets)
+    }
+
+    pub fn rx_bytes(&self) -> u64 {
+        self.sum(|q| q.rx_bytes)
+    }
+
+    pub fn tx_packets(&self) -> u64 {
+        self.sum(|q| q.tx_packets)
+    }
+
+    pub fn tx_bytes(&self) -> u64 {
+        self.sum(|q| q.tx_bytes)
+    }
+
+    pub fn tx_dropped(&self) -> u64 {
+        self.sum(|q| q.tx_dropped)
+    }
+
+    /// The change in every counter since `earlier` was taken. Extended statistics are matched by name, those missing
+    /// from `earlier` are reported as is.
+    pub fn delta(&self, earlier: &PortStats) -> PortStats {
+        let default = QueueStats::default();
+        PortStats {
+            queues: self.queues
+                .iter()
+                .enumerate()
+                .map(|(i, queue)| queue.delta(earlier.queues.get(i).unwrap_or(&default)))
+                .collect(),
+            hardware: self.hardware.delta(&earlier.hardware),
+            xstats: self.xstats
+                .iter()
+                .map(|&(ref name, value)| {
+                    let before = earlier.xstats.iter().find(|&&(ref n, _)| n == name).map_or(0, |&(_, v)| v);
+                    (name.clone(), value.saturating_sub(before))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Software counters for a queue, shared between copies of a `PmdPort`.
+#[derive(Default)]
+pub struct QueueCounters {
+    rx_packets: AtomicUsize,
+    rx_bytes: AtomicUsize,
+    tx_packets: AtomicUsize,
+    tx_bytes: AtomicUsize,
+    tx_dropped: AtomicUsize,
+    tx_dropped_drop: AtomicUsize,
+    tx_dropped_retry: AtomicUsize,
+    tx_dropped_buffer: AtomicUsize,
+    tx_retries: AtomicUsize,
+}
+
+/// The receive and transmit sides of a queue (and copies of the port) can be driven from different threads, so updates
+/// must be atomic read-modify-writes.
+#[inline]
+fn add(counter: &AtomicUsize, value: usize) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+impl QueueCounters {
+    #[inline]
+    pub fn record_rx(&self, packets: usize, bytes: usize) {
+        add(&self.rx_packets, packets);
+        add(&self.rx_bytes, bytes);
+    }
+
+    #[inline]
+    pub fn record_tx(&self, packets: usize, bytes: usize) {
+        add(&self.tx_packets, packets);
+        add(&self.tx_bytes, bytes);
+    }
+
+    #[inline]
+    pub fn record_tx_dropped(&self, packets: usize, policy: TxPolicy) {
+        add(&self.tx_dropped, packets);
+        match policy {
+            TxPolicy::Drop | TxPolicy::SpinUntilSent => add(&self.tx_dropped_drop, packets),
+            TxPolicy::Retry(_) => add(&self.tx_dropped_retry, packets),
+            TxPolicy::Buffer(_) => add(&self.tx_dropped_buffer, packets),
+        }
+    }
+
+    #[inline]
+    pub fn record_tx_retries(&self, retries: usize) {
+        add(&self.tx_retries, retries);
+    }
+
+    #[inline]
+    pub fn rx_packets(&self) -> usize {
+        self.rx_packets.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn tx_packets(&self) -> usize {
+        self.tx_packets.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> QueueStats {
+        QueueStats {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed) as u64,
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed) as u64,
+            tx_packets: self.tx_packets.load(Ordering::Relaxed) as u64,
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed) as u64,
+            tx_dropped: self.tx_dropped.load(Ordering::Relaxed) as u64,
+            tx_drops: TxDrops {
+                drop: self.tx_dropped_drop.load(Ordering::Relaxed) as u64,
+                retry: self.tx_dropped_retry.load(Ordering::Relaxed) as u64,
+                buffer: self.tx_dropped_buffer.load(Ordering::Relaxed) as u64,
+            },
+            tx_retries: self.tx_retries.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in &[&self.rx_packets,
+                         &self.rx_bytes,
+                         &self.tx_packets,
+                         &self.tx_bytes,
+                         &self.tx_dropped,
+                         &self.tx_dropped_drop,
+                         &self.tx_dropped_retry,
+                         &self.tx_dropped_buffer,
+                         &self.tx_retries] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::TxPolicy;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn concurrent_updates() {
+        let counters = Arc::new(QueueCounters::default());
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let counters = counters.clone();
+                thread::spawn(move || for _ in 0..10000 {
+                    if i % 2 == 0 {
+                        counters.record_rx(1, 64);
+                    } else {
+                        counters.record_tx(1, 64);
+                        counters.record_tx_dropped(1, TxPolicy::Drop);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let stats = counters.snapshot();
+        assert_eq!(stats.rx_packets, 20000);
+        assert_eq!(stats.rx_bytes, 20000 * 64);
+        assert_eq!(stats.tx_packets, 20000);
+        assert_eq!(stats.tx_dropped, 20000);
+        assert_eq!(stats.tx_drops.drop, 20000);
+        counters.reset();
+        assert_eq!(counters.snapshot(), QueueStats::default());
+    }
+}
@@ -0,0 +1,373 @@
+//! An in-memory replacement for the DPDK backed parts of the framework, used so that batches can be exercised with
+//! `cargo test` without initializing EAL or reserving hugepages. This module is compiled in when building tests or when
+//! the `test-backend` feature is enabled, and exports functions with the same names and signatures as the ones in
+//! `libzcsi`, so the rest of the framework does not need to know which backend it is talking to.
+//!
+//! Mbufs are allocated on the heap, with the same layout DPDK uses (the data buffer immediately follows the mbuf
+//! header). Ports are a set of queues: packets injected into a port can be received from it, and anything sent out is
+//! held until the test collects it (or, for loopback ports, becomes available to receive again).
+//!
+//! All state is thread local, so tests running in parallel do not observe each other's ports or allocations.
+use super::mbuf::MBuf;
+use super::pmd::{PortXstat, RssConf};
+use super::stats::HardwareStats;
+use super::super::headers::MacAddress;
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::ptr;
+use time::precise_time_ns;
+
+/// Headroom left at the front of each mbuf, same as `RTE_PKTMBUF_HEADROOM`.
+pub const MBUF_HEADROOM: u16 = 128;
+/// Size of the data buffer attached to each mbuf, same as `RTE_MBUF_DEFAULT_BUF_SIZE`.
+pub const MBUF_BUF_SIZE: u16 = 2048 + MBUF_HEADROOM;
+
+struct MemoryPort {
+    rxqs: Vec<VecDeque<*mut MBuf>>,
+    txqs: Vec<VecDeque<*mut MBuf>>,
+    nrxd: usize,
+    ntxd: usize,
+    loopback: bool,
+    flow_director: bool,
+    stats: HardwareStats,
+}
+
+thread_local! {
+    static PORTS: RefCell<HashMap<i32, MemoryPort>> = RefCell::new(HashMap::new());
+    static IN_USE: Cell<usize> = Cell::new(0);
+}
+
+#[inline]
+fn mbuf_words() -> usize {
+    (mem::size_of::<MBuf>() + MBUF_BUF_SIZE as usize + 7) / 8
+}
+
+unsafe fn heap_mbuf_alloc(len: u16) -> *mut MBuf {
+    let mut mem = Vec::<u64>::with_capacity(mbuf_words());
+    let mbuf = mem.as_mut_ptr() as *mut MBuf;
+    mem::forget(mem);
+    let buf_addr = (mbuf as *mut u8).offset(mem::size_of::<MBuf>() as isize);
+    ptr::write(mbuf, MBuf::with_buffer(buf_addr, MBUF_BUF_SIZE, MBUF_HEADROOM, len));
+    IN_USE.with(|c| c.set(c.get() + 1));
+    mbuf
+}
+
+/// Free `mbuf` along with any segments chained to it (as `rte_pktmbuf_free` does). Segments that are referenced more
+/// than once only have their reference count decremented.
+unsafe fn heap_mbuf_free(mbuf: *mut MBuf) {
+    let mut seg = mbuf;
+    while !seg.is_null() {
+        let next = (*seg).next_segment();
+        if (*seg).update_refcnt(-1) == 0 {
+            IN_USE.with(|c| c.set(c.get() - 1));
+            drop(Vec::from_raw_parts(seg as *mut u64, 0, mbuf_words()));
+        }
+        seg = next;
+    }
+}
+
+/// Number of mbufs allocated (on this thread) and not yet freed. Useful for checking that a batch frees what it drops.
+pub fn mbufs_in_use() -> usize {
+    IN_USE.with(|c| c.get())
+}
+
+pub unsafe fn mbuf_alloc() -> *mut MBuf {
+    heap_mbuf_alloc(0)
+}
+
+pub unsafe fn mbuf_free(buf: *mut MBuf) {
+    heap_mbuf_free(buf)
+}
+
+pub unsafe fn mbuf_refcnt_update(buf: *mut MBuf, value: i16) {
+    let mut seg = buf;
+    while !seg.is_null() {
+        (*seg).update_refcnt(value);
+        seg = (*seg).next_segment();
+    }
+}
+
+pub unsafe fn mbuf_alloc_bulk(array: *mut *mut MBuf, len: u16, cnt: i32) -> i32 {
+    for i in 0..cnt as isize {
+        *array.offset(i) = heap_mbuf_alloc(len);
+    }
+    0
+}
+
+pub unsafe fn mbuf_free_bulk(array: *mut *mut MBuf, cnt: i32) -> i32 {
+    for i in 0..cnt as isize {
+        heap_mbuf_free(*array.offset(i));
+    }
+    0
+}
+
+pub unsafe fn init_pmd_port(port: i32,
+                            rxqs: i32,
+                            txqs: i32,
+                            _rx_cores: *const i32,
+                            _tx_cores: *const i32,
+                            nrxd: i32,
+                            ntxd: i32,
+                            loopback: i32,
+                            _tso: i32,
+                            _csumoffload: i32,
+                            rss: *const RssConf)
+                            -> i32 {
+    PORTS.with(|p| {
+        let mut ports = p.borrow_mut();
+        if ports.contains_key(&port) {
+            -1
+        } else {
+            ports.insert(port,
+                         MemoryPort {
+                             rxqs: (0..rxqs).map(|_| VecDeque::new()).collect(),
+                             txqs: (0..txqs).map(|_| VecDeque::new()).collect(),
+                             nrxd: nrxd as usize,
+                             ntxd: ntxd as usize,
+                             loopback: loopback != 0,
+                             flow_director: !rss.is_null() && (*rss).flow_director != 0,
+                             stats: HardwareStats::default(),
+                         });
+            0
+        }
+    })
+}
+
+/// Filters are accepted (but packets are not steered) if the port was configured with flow director.
+pub unsafe fn add_fdir_filter(port: i32,
+                              _src_ip: u32,
+                              _dst_ip: u32,
+                              _src_port: u16,
+                              _dst_port: u16,
+                              _proto: u8,
+                              _queue: i32)
+                              -> i32 {
+    PORTS.with(|p| {
+        match p.borrow().get(&port) {
+            Some(mport) if mport.flow_director => 0,
+            _ => -1,
+        }
+    })
+}
+
+pub unsafe fn free_pmd_port(port: i32) -> i32 {
+    match PORTS.with(|p| p.borrow_mut().remove(&port)) {
+        Some(mport) => {
+            for mbuf in mport.rxqs.into_iter().chain(mport.txqs.into_iter()).flat_map(|q| q.into_iter()) {
+                heap_mbuf_free(mbuf);
+            }
+            0
+        }
+        None => -1,
+    }
+}
+
+pub unsafe fn recv_pkts(port: i32, qid: i32, pkts: *mut *mut MBuf, len: i32) -> i32 {
+    PORTS.with(|p| {
+        match p.borrow_mut().get_mut(&port).and_then(|mport| {
+            let stats = &mut mport.stats;
+            mport.rxqs.get_mut(qid as usize).map(|q| (q, stats))
+        }) {
+            Some((queue, stats)) => {
+                let mut recv = 0;
+                while recv < len {
+                    match queue.pop_front() {
+                        Some(mbuf) => {
+                            stats.rx_packets += 1;
+                            stats.rx_bytes += (*mbuf).pkt_len() as u64;
+                            *pkts.offset(recv as isize) = mbuf;
+                        }
+                        None => break,
+                    }
+                    recv += 1;
+                }
+                recv
+            }
+            None => 0,
+        }
+    })
+}
+
+pub unsafe fn send_pkts(port: i32, qid: i32, pkts: *mut *mut MBuf, len: i32) -> i32 {
+    PORTS.with(|p| {
+        match p.borrow_mut().get_mut(&port) {
+            Some(mport) => {
+                let (queue, limit) = if mport.loopback {
+                    (mport.rxqs.get_mut(qid as usize), mport.nrxd)
+                } else {
+                    (mport.txqs.get_mut(qid as usize), mport.ntxd)
+                };
+                match queue {
+                    Some(queue) => {
+                        // Emulate a full descriptor ring, the caller retains ownership of anything not sent.
+                        let mut sent = 0;
+                        while sent < len && queue.len() < limit {
+                            let mbuf = *pkts.offset(sent as isize);
+                            mport.stats.tx_packets += 1;
+                            mport.stats.tx_bytes += (*mbuf).pkt_len() as u64;
+                            queue.push_back(mbuf);
+                            sent += 1;
+                        }
+                        sent
+                    }
+                    None => 0,
+                }
+            }
+            None => 0,
+        }
+    })
+}
+
+pub unsafe fn get_port_stats(port: i32, stats: *mut HardwareStats) -> i32 {
+    PORTS.with(|p| {
+        match p.borrow().get(&port) {
+            Some(mport) => {
+                *stats = mport.stats;
+                0
+            }
+            None => -1,
+        }
+    })
+}
+
+/// The in-memory ports have no extended statistics.
+pub unsafe fn get_port_xstats(_port: i32, _xstats: *mut PortXstat, _len: i32) -> i32 {
+    0
+}
+
+pub unsafe fn reset_port_stats(port: i32) {
+    PORTS.with(|p| {
+        if let Some(mport) = p.borrow_mut().get_mut(&port) {
+            mport.stats = HardwareStats::default();
+        }
+    })
+}
+
+pub unsafe fn num_pmd_ports() -> i32 {
+    PORTS.with(|p| p.borrow().len() as i32)
+}
+
+pub unsafe fn rte_eth_macaddr_get(port: i32, address: *mut MacAddress) {
+    // A locally administered address derived from the port number.
+    (*address).addr = [0x02, 0, 0, 0, (port >> 8) as u8, port as u8];
+}
+
+pub unsafe fn init_bess_eth_ring(_ifname: *const u8, _core: i32) -> i32 {
+    -1
+}
+
+pub unsafe fn init_ovs_eth_ring(_iface: i32, _core: i32) -> i32 {
+    -1
+}
+
+pub unsafe fn init_pcap_port(_rx_pcap: *const u8, _tx_pcap: *const u8) -> i32 {
+    -1
+}
+
+pub unsafe fn init_system(_name: *const u8, _nlen: i32, _core: i32) -> i32 {
+    0
+}
+
+pub unsafe fn init_system_whitelisted(_name: *const u8,
+                                      _nlen: i32,
+                                      _core: i32,
+                                      _whitelist: *mut *const u8,
+                                      _wlcount: i32)
+                                      -> i32 {
+    0
+}
+
+pub unsafe fn init_thread(_tid: i32, _core: i32) {}
+
+pub unsafe fn init_secondary(_name: *const u8,
+                             _nlen: i32,
+                             _core: i32,
+                             _vdevs: *mut *const u8,
+                             _vdev_count: i32)
+                             -> i32 {
+    0
+}
+
+/// Software version of `rte_hash_crc` (CRC32-C, without the final inversion).
+pub unsafe fn crc_hash_native(to_hash: *const u8, size: u32, iv: u32) -> u32 {
+    let mut crc = iv;
+    for i in 0..size as isize {
+        crc ^= *to_hash.offset(i) as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// There is no calibrated TSC without EAL, so count nanoseconds instead.
+pub unsafe fn current_cycles() -> u64 {
+    precise_time_ns()
+}
+
+pub unsafe fn cycles_per_second() -> u64 {
+    1_000_000_000
+}
+
+/// Copy `packets` into freshly allocated mbufs and add them to the receive queue `qid` of `port`. Packets that do not
+/// fit in a single mbuf are split across a chain of segments. Returns the number of packets that fit in the queue, the
+/// remainder are counted as missed.
+pub fn inject_packets<T: AsRef<[u8]>>(port: i32, qid: i32, packets: &[T]) -> usize {
+    PORTS.with(|p| {
+        match p.borrow_mut().get_mut(&port).and_then(|mport| {
+            let limit = mport.nrxd;
+            let stats = &mut mport.stats;
+            mport.rxqs.get_mut(qid as usize).map(|q| (q, limit, stats))
+        }) {
+            Some((queue, limit, stats)) => {
+                let mut injected = 0;
+                for packet in packets {
+                    let data = packet.as_ref();
+                    if queue.len() >= limit {
+                        // What a NIC would count as missed.
+                        stats.rx_missed += (packets.len() - injected) as u64;
+                        break;
+                    }
+                    let first = min(data.len(), (MBUF_BUF_SIZE - MBUF_HEADROOM) as usize);
+                    unsafe {
+                        let mbuf = heap_mbuf_alloc(first as u16);
+                        assert_eq!((*mbuf).add_data_end(data.len() - first), data.len() - first);
+                        (*mbuf).write_data(0, data);
+                        queue.push_back(mbuf);
+                    }
+                    injected += 1;
+                }
+                injected
+            }
+            None => 0,
+        }
+    })
+}
+
+/// Remove everything sent out of transmit queue `qid` of `port` so far, returning the contents of each packet (with
+/// the data from all segments concatenated).
+pub fn take_sent_packets(port: i32, qid: i32) -> Vec<Vec<u8>> {
+    PORTS.with(|p| {
+        match p.borrow_mut().get_mut(&port).and_then(|mport| mport.txqs.get_mut(qid as usize)) {
+            Some(queue) => {
+                queue.drain(..)
+                     .map(|mbuf| unsafe {
+                         let mut data = Vec::with_capacity((*mbuf).pkt_len());
+                         for segment in (*mbuf).segments() {
+                             data.extend_from_slice(segment);
+                         }
+                         heap_mbuf_free(mbuf);
+                         data
+                     })
+                     .collect()
+            }
+            None => Vec::new(),
+        }
+    })
+}
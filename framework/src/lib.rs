@@ -10,6 +10,7 @@
 #![cfg_attr(feature = "dev", feature(plugin))]
 #![cfg_attr(feature = "dev", plugin(clippy))]
 extern crate libc;
+extern crate time;
 extern crate byteorder;
 extern crate farmhash;
 extern crate fnv;
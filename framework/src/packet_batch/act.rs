@@ -1,3 +1,4 @@
+use io::MBuf;
 use io::PmdPort;
 use io::Result;
 pub trait Act {
@@ -11,11 +12,27 @@ pub trait Act {
 
     fn capacity(&self) -> i32;
 
+    /// Number of packets that entered the pipeline the last time it acted, i.e., that were received (or generated) by
+    /// the batch at its root. This stays valid after the packets have been sent, so schedulers can tell busy pipelines
+    /// from idle ones without iterating.
+    fn last_received(&self) -> usize;
+
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize>;
 
+    /// Remove packets from the batch without freeing them, returning the removed mbufs in the same order as `idxes`
+    /// (which is expected to be ordered). The caller becomes responsible for the returned mbufs.
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>>;
+
     /// Add bytes at the end of the packet. `size` is the new size requested, returns the new size after adjustment or 0
     /// if not done. Note `size` here is the amount by which packet size should change overall.
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize>;
 
+    /// Add (or, with a negative `size`, remove) bytes at the front of the packet using its headroom. Returns `None` if
+    /// there is not enough headroom.
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize>;
+
+    /// Add `size` bytes at the front of the packet in a newly allocated segment, which becomes the packet's first
+    /// segment. This is meant for when `adjust_headroom` fails, and only for code that can handle packets whose headers
+    /// span several segments. Returns `None` if the segment could not be allocated.
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize>;
 }
@@ -2,6 +2,7 @@ use super::iterator::*;
 use super::act::Act;
 use super::Batch;
 use super::HeaderOperations;
+use io::MBuf;
 use io::PmdPort;
 use headers::EndOffset;
 use io::Result;
@@ -48,11 +49,21 @@ impl<T, V> Act for ReplaceBatch<T, V>
         self.parent.capacity()
     }
 
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
     #[inline]
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
         self.parent.drop_packets(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_payload_size(idx, size)
@@ -62,6 +73,11 @@ impl<T, V> Act for ReplaceBatch<T, V>
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_headroom(idx, size)
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
 }
 
 impl<T, V> BatchIterator for ReplaceBatch<T, V>
@@ -0,0 +1,204 @@
+use headers::{ArpHeader, EndOffset, MacHeader, ETHERTYPE_ARP};
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use state::NeighbourCache;
+use super::act::Act;
+use super::Batch;
+use super::HeaderOperations;
+use super::iterator::*;
+use super::packet_batch::{cast_from_u8, free_mbufs};
+use std::any::Any;
+
+/// Answers ARP requests and learns neighbours from ARP packets, see `HeaderOperations::respond_arp`.
+pub struct ArpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    parent: V,
+    port: PmdPort,
+    queue: i32,
+    mac: [u8; 6],
+    addresses: Vec<u32>,
+    cache: NeighbourCache,
+    capacity: usize,
+}
+
+impl<V> ArpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V, port: PmdPort, queue: i32, addresses: Vec<u32>, cache: NeighbourCache) -> ArpBatch<V> {
+        let capacity = parent.capacity() as usize;
+        let mac = port.mac_address().addr;
+        ArpBatch {
+            parent: parent,
+            port: port,
+            queue: queue,
+            mac: mac,
+            addresses: addresses,
+            cache: cache,
+            capacity: capacity,
+        }
+    }
+}
+
+/// Learn the sender of an ARP packet, and turn it into a reply if it is a request for one of `addresses`. Returns true
+/// if the packet should be sent back.
+///
+/// As in RFC 826, senders already in the cache are refreshed by any ARP packet, but new senders are only learned from
+/// packets addressed to one of `addresses`. Otherwise every host on the segment would end up in the cache, and anyone
+/// could add entries for addresses we never talk to.
+#[inline]
+fn answer(mac: &mut MacHeader,
+          payload: &mut [u8],
+          our_mac: &[u8; 6],
+          addresses: &[u32],
+          cache: &NeighbourCache)
+          -> bool {
+    if payload.len() < ArpHeader::size() {
+        return false;
+    }
+    let arp = cast_from_u8::<ArpHeader>(payload.as_mut_ptr());
+    if !arp.is_ipv4_over_ethernet() {
+        return false;
+    }
+    let for_us = addresses.contains(&arp.target_ip());
+    // Probes (RFC 5227) come from 0.0.0.0, and tell us nothing.
+    if arp.sender_ip() != 0 && !cache.update(arp.sender_ip(), arp.sender_mac()) && for_us {
+        cache.insert(arp.sender_ip(), arp.sender_mac());
+    }
+    if arp.is_request() && for_us {
+        arp.make_reply(our_mac);
+        mac.dst = arp.target_mac();
+        mac.src = *our_mac;
+        true
+    } else {
+        false
+    }
+}
+
+impl<V> Batch for ArpBatch<V> where V: Batch + BatchIterator + Act {}
+
+impl<V> HeaderOperations for ArpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    type Header = MacHeader;
+}
+
+impl<V> BatchIterator for ArpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
+
+/// Internal interface for packets.
+impl<V> Act for ArpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    /// ARP packets are taken out of the batch: replies are sent right away, on the port and queue they are for, and
+    /// all other ARP packets are dropped once they have been learned from.
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        let mut arp = Vec::<usize>::with_capacity(self.capacity);
+        let mut reply = Vec::<bool>::with_capacity(self.capacity);
+        {
+            let iter = PayloadEnumerator::<MacHeader>::new(&mut self.parent);
+            while let Some(ParsedDescriptor { index: idx, header: mac, payload, .. }) = iter.next(&mut self.parent) {
+                if mac.etype() == ETHERTYPE_ARP {
+                    arp.push(idx);
+                    reply.push(answer(mac, payload, &self.mac, &self.addresses, &self.cache));
+                }
+            }
+        }
+        if !arp.is_empty() {
+            let mbufs = self.parent.remove_packets(arp).expect("ARP handling was performed incorrectly");
+            let mut replies = Vec::<*mut MBuf>::with_capacity(mbufs.len());
+            let mut others = Vec::<*mut MBuf>::with_capacity(mbufs.len());
+            for (mbuf, is_reply) in mbufs.into_iter().zip(reply.into_iter()) {
+                if is_reply {
+                    replies.push(mbuf);
+                } else {
+                    others.push(mbuf);
+                }
+            }
+            if !replies.is_empty() {
+                let sent = match self.port.send_queue(self.queue, replies.as_mut_ptr(), replies.len() as i32) {
+                    Ok(sent) => sent as usize,
+                    Err(_) => 0,
+                };
+                let mut unsent = replies.split_off(sent);
+                if !unsent.is_empty() {
+                    self.port.record_tx_dropped(self.queue, unsent.len());
+                    free_mbufs(&mut unsent).expect("Could not free unsent ARP replies");
+                }
+            }
+            free_mbufs(&mut others).expect("Could not free ARP packets");
+        }
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.parent.done();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
@@ -1,6 +1,7 @@
 use super::act::Act;
 use super::Batch;
 use super::iterator::{BatchIterator, PacketDescriptor};
+use io::MBuf;
 use io::PmdPort;
 use io::Result;
 use std::any::Any;
@@ -64,11 +65,21 @@ impl Act for CompositionBatch {
         self.parent.capacity()
     }
 
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
     #[inline]
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
         self.parent.drop_packets(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_payload_size(idx, size)
@@ -78,4 +89,9 @@ impl Act for CompositionBatch {
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_headroom(idx, size)
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
 }
@@ -1,3 +1,4 @@
+use io::MBuf;
 use io::PmdPort;
 use io::Result;
 use super::act::Act;
@@ -27,6 +28,35 @@ impl<T, V> ContextBatch<T, V>
             _context_size: capacity,
         }
     }
+
+    /// Move context around so it stays with the packets that remain after `idxes` are removed from the parent.
+    #[inline]
+    fn compact_context(&mut self, idxes: &[usize]) {
+        // Need to adjust data
+        let mut idx_orig = self.parent.start();
+        let mut idx_new = 0;
+        let mut remove_idx = 0;
+        let end = self.context.len();
+
+        // First go through the list of indexes to be filtered and get rid of them.
+        while idx_orig < end && (remove_idx < idxes.len()) {
+            let test_idx = idxes[remove_idx];
+            assert!(idx_orig <= test_idx);
+            if idx_orig == test_idx {
+                remove_idx += 1;
+            } else {
+                self.context.swap(idx_orig, idx_new);
+                idx_new += 1;
+            }
+            idx_orig += 1;
+        }
+        // Then copy over any left over packets.
+        while idx_orig < end {
+            self.context.swap(idx_orig, idx_new);
+            idx_orig += 1;
+            idx_new += 1;
+        }
+    }
 }
 
 impl<T, V> Batch for ContextBatch<T, V>
@@ -61,34 +91,22 @@ impl<T, V> Act for ContextBatch<T, V>
     }
 
     #[inline]
-    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
-        // Need to adjust data
-        let mut idx_orig = self.parent.start();
-        let mut idx_new = 0;
-        let mut remove_idx = 0;
-        let end = self.context.len();
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
 
-        // First go through the list of indexes to be filtered and get rid of them.
-        while idx_orig < end && (remove_idx < idxes.len()) {
-            let test_idx = idxes[remove_idx];
-            assert!(idx_orig <= test_idx);
-            if idx_orig == test_idx {
-                remove_idx += 1;
-            } else {
-                self.context.swap(idx_orig, idx_new);
-                idx_new += 1;
-            }
-            idx_orig += 1;
-        }
-        // Then copy over any left over packets.
-        while idx_orig < end {
-            self.context.swap(idx_orig, idx_new);
-            idx_orig += 1;
-            idx_new += 1;
-        }
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.compact_context(&idxes);
         self.parent.drop_packets(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.compact_context(&idxes);
+        self.parent.remove_packets(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_payload_size(idx, size)
@@ -98,6 +116,11 @@ impl<T, V> Act for ContextBatch<T, V>
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_headroom(idx, size)
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
 }
 
 impl<T, V> BatchIterator for ContextBatch<T, V>
@@ -0,0 +1,274 @@
+use io::MBuf;
+use io::PmdPort;
+use headers::EndOffset;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::HeaderOperations;
+use super::iterator::*;
+use super::packet_batch::{free_mbufs, queued_payload, retain_offsets, PacketBatch};
+use super::replicate_batch::Replication;
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Classify a packet. Returns the index of the branch the packet should be sent to, or `None` if the packet should be
+/// dropped.
+pub type DemuxFn<T> = Box<FnMut(&T, &[u8], Option<&mut Any>) -> Option<usize>>;
+
+/// State shared between all the branches of a demultiplexer.
+pub struct DemuxState<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    parent: V,
+    classifier: DemuxFn<T>,
+    /// Packets waiting to be processed by each branch.
+    queues: Vec<PacketBatch>,
+    /// Offset (from the start of the packet) at which the header resides, for every packet in `queues`.
+    offsets: Vec<Vec<usize>>,
+    capacity: usize,
+    /// Packets dropped because a branch's queue was full (or because a copy could not be allocated).
+    overflow: u64,
+    /// When set, packets classified into the first branch are also replicated into all other branches.
+    replication: Option<Replication>,
+    /// Whether branches pull packets from the parent when they run out (as opposed to the owner of this state, e.g.,
+    /// `GroupBy`, filling queues).
+    pull: bool,
+}
+
+impl<T, V> DemuxState<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V, branches: usize, classifier: DemuxFn<T>, pull: bool) -> Rc<RefCell<DemuxState<T, V>>> {
+        let capacity = parent.capacity() as usize;
+        Rc::new(RefCell::new(DemuxState {
+            parent: parent,
+            classifier: classifier,
+            queues: (0..branches).map(|_| PacketBatch::new(capacity as i32)).collect(),
+            offsets: (0..branches).map(|_| Vec::with_capacity(capacity)).collect(),
+            capacity: capacity,
+            overflow: 0,
+            replication: None,
+            pull: pull,
+        }))
+    }
+
+    /// Also send a copy (made according to `replication`) of every packet classified into the first branch to each of
+    /// the other branches.
+    pub fn set_replication(&mut self, replication: Replication) {
+        self.replication = Some(replication);
+    }
+
+    /// Add `mbuf` to a branch's queue, or to `unmatched` if the queue is full.
+    #[inline]
+    fn enqueue(&mut self, branch: usize, mbuf: *mut MBuf, header_offset: usize, unmatched: &mut Vec<*mut MBuf>) {
+        if self.queues[branch].add_packet(mbuf) {
+            self.offsets[branch].push(header_offset);
+        } else {
+            self.overflow += 1;
+            unmatched.push(mbuf);
+        }
+    }
+
+    /// Add copies of `mbuf` to every branch but the first.
+    #[inline]
+    fn enqueue_copies(&mut self,
+                      replication: Replication,
+                      mbuf: *mut MBuf,
+                      header_offset: usize,
+                      unmatched: &mut Vec<*mut MBuf>) {
+        for branch in 1..self.queues.len() {
+            let copy = unsafe { replication.copy(mbuf) };
+            if copy.is_null() {
+                self.overflow += 1;
+            } else {
+                self.enqueue(branch, copy, header_offset, unmatched);
+            }
+        }
+    }
+
+    /// Pull a batch of packets from the parent and distribute them to the branch queues.
+    pub fn fill(&mut self) {
+        self.parent.act();
+        let mut idxes = Vec::<usize>::with_capacity(self.capacity);
+        let mut classes = Vec::<Option<(usize, usize)>>::with_capacity(self.capacity);
+        {
+            let branches = self.queues.len();
+            let iter = PayloadEnumerator::<T>::new(&mut self.parent);
+            while let Some(ParsedDescriptor { index: idx, header: head, payload, ctx, offset, .. }) =
+                      iter.next(&mut self.parent) {
+                let header_offset = offset - head.offset();
+                idxes.push(idx);
+                classes.push(match (self.classifier)(head, payload, ctx) {
+                    Some(branch) if branch < branches => Some((branch, header_offset)),
+                    _ => None,
+                });
+            }
+        }
+        if !idxes.is_empty() {
+            let mbufs = self.parent.remove_packets(idxes).expect("Demultiplexing was performed incorrectly");
+            let mut unmatched = Vec::<*mut MBuf>::with_capacity(mbufs.len());
+            for (mbuf, class) in mbufs.into_iter().zip(classes.into_iter()) {
+                match class {
+                    Some((branch, header_offset)) => {
+                        if let Some(replication) = self.replication {
+                            self.enqueue_copies(replication, mbuf, header_offset, &mut unmatched);
+                        }
+                        self.enqueue(branch, mbuf, header_offset, &mut unmatched);
+                    }
+                    None => unmatched.push(mbuf),
+                }
+            }
+            free_mbufs(&mut unmatched).expect("Could not free unmatched packets");
+        }
+        self.parent.done();
+    }
+}
+
+/// One output of a demultiplexer (see `HeaderOperations::demux`). Each branch receives the packets classified into it,
+/// with the header parsed before demultiplexing still parsed, so a branch can continue parsing from there. Per-packet
+/// context is not carried across a demultiplexer.
+///
+/// Branches pull packets from the parent when they run out: whichever branch is scheduled with an empty queue
+/// processes a batch from the parent and distributes packets to all branches. A branch therefore holds at most a
+/// parent batch worth of pending packets, any more (e.g., when a branch is not scheduled often enough) are dropped.
+/// Branches belonging to a `GroupBy` do not pull, `GroupBy` fills them and schedules them together instead.
+pub struct DemuxBranch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    state: Rc<RefCell<DemuxState<T, V>>>,
+    branch: usize,
+    /// Packets queued for this branch when it last acted.
+    pending: usize,
+}
+
+impl<T, V> DemuxBranch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    /// Create `branches` branches, classifying packets from `parent` using `classifier`.
+    pub fn new_branches(parent: V, branches: usize, classifier: DemuxFn<T>) -> Vec<DemuxBranch<T, V>> {
+        let state = DemuxState::new(parent, branches, classifier, true);
+        (0..branches).map(|branch| DemuxBranch::with_state(state.clone(), branch)).collect()
+    }
+
+    pub fn with_state(state: Rc<RefCell<DemuxState<T, V>>>, branch: usize) -> DemuxBranch<T, V> {
+        DemuxBranch {
+            state: state,
+            branch: branch,
+            pending: 0,
+        }
+    }
+
+    /// Number of packets (across all branches) dropped because a branch had too many pending packets (or a copy could
+    /// not be allocated).
+    pub fn overflow(&self) -> u64 {
+        self.state.borrow().overflow
+    }
+}
+
+batch_no_new!{DemuxBranch}
+
+impl<T, V> Act for DemuxBranch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn act(&mut self) {
+        let mut state = self.state.borrow_mut();
+        if state.pull && state.queues[self.branch].available() == 0 {
+            state.fill();
+        }
+        self.pending = state.queues[self.branch].available();
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        let mut state = self.state.borrow_mut();
+        // Free anything that was not sent.
+        state.queues[self.branch].deallocate_batch().expect("Deallocation failed");
+        state.offsets[self.branch].clear();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.state.borrow_mut().queues[self.branch].send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.state.borrow().capacity as i32
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.pending
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        let mut state = self.state.borrow_mut();
+        let start = state.queues[self.branch].start();
+        retain_offsets(&mut state.offsets[self.branch], start, &idxes);
+        state.queues[self.branch].drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        let mut state = self.state.borrow_mut();
+        let start = state.queues[self.branch].start();
+        retain_offsets(&mut state.offsets[self.branch], start, &idxes);
+        state.queues[self.branch].remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.state.borrow_mut().queues[self.branch].adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.state.borrow_mut().queues[self.branch].adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.state.borrow_mut().queues[self.branch].prepend_headroom_segment(idx, size)
+    }
+}
+
+impl<T, V> BatchIterator for DemuxBranch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.state.borrow_mut().queues[self.branch].start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        let mut state = self.state.borrow_mut();
+        let DemuxState { ref mut queues, ref offsets, .. } = *state;
+        match queued_payload::<T>(&mut queues[self.branch], &offsets[self.branch], idx) {
+            Some((descriptor, next_idx)) => Some((descriptor, None, next_idx)),
+            None => None,
+        }
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        match self.state.borrow_mut().queues[self.branch].next_base_payload(idx) {
+            Some((descriptor, _, next_idx)) => Some((descriptor, None, next_idx)),
+            None => None,
+        }
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self, _: usize, _: i32) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        panic!("Cannot pop beyond a demux batch")
+    }
+}
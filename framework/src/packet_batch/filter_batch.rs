@@ -2,11 +2,14 @@ use super::iterator::*;
 use super::act::Act;
 use super::Batch;
 use super::HeaderOperations;
+use io::MBuf;
 use io::PmdPort;
 use headers::EndOffset;
 use io::Result;
 use std::any::Any;
 
+/// Called for each packet, packets for which this returns `true` are dropped (and those for which it returns `false`
+/// are kept).
 pub type FilterFn<T> = Box<FnMut(&T, &[u8], Option<&mut Any>) -> bool>;
 
 pub struct FilterBatch<T, V>
@@ -73,11 +76,21 @@ impl<T, V> Act for FilterBatch<T, V>
         self.parent.capacity()
     }
 
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
     #[inline]
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
         self.parent.drop_packets(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_payload_size(idx, size)
@@ -87,6 +100,11 @@ impl<T, V> Act for FilterBatch<T, V>
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_headroom(idx, size)
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
 }
 
 impl<T, V> BatchIterator for FilterBatch<T, V>
@@ -0,0 +1,355 @@
+use headers::{EndOffset, IpHeader, MacHeader, TcpHeader, UdpHeader};
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::packet_batch::{cast_from_u8, PacketBatch};
+use super::iterator::*;
+use std::any::Any;
+use std::cmp::min;
+use std::slice;
+use time::precise_time_ns;
+use utils::Flow;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const TCP_PROTO: u8 = 6;
+const UDP_PROTO: u8 = 17;
+
+/// Largest packet (in bytes, including the Ethernet header) that can be generated. Packets are built in a single mbuf,
+/// so this is the data room left after the headroom in a default sized mbuf (`RTE_MBUF_DEFAULT_DATAROOM`).
+pub const MAX_GENERATED_SIZE: usize = 2048;
+
+/// Fill in a generated packet. Called with the packet's data (in the first segment) after the template has been copied
+/// in and the flow applied, along with the number of packets generated so far.
+pub type GeneratorFn = Box<FnMut(&mut [u8], u64)>;
+
+/// Distribution of generated packet sizes (in bytes, including the Ethernet header).
+#[derive(Clone, Debug)]
+pub enum PacketSizes {
+    /// Every packet has the same size.
+    Fixed(usize),
+    /// Sizes are drawn uniformly from the inclusive range.
+    Uniform(usize, usize),
+    /// Sizes are drawn from a set of sizes with relative weights, e.g., `[(64, 7), (576, 4), (1500, 1)]` for IMIX.
+    Weighted(Vec<(usize, u32)>),
+}
+
+impl PacketSizes {
+    #[inline]
+    fn max(&self) -> usize {
+        match *self {
+            PacketSizes::Fixed(size) => size,
+            PacketSizes::Uniform(_, max) => max,
+            PacketSizes::Weighted(ref sizes) => sizes.iter().map(|&(size, _)| size).max().unwrap_or(0),
+        }
+    }
+
+    /// Panics if no size can be drawn from the distribution, or it contains sizes larger than `MAX_GENERATED_SIZE`.
+    fn validate(&self) {
+        assert!(self.max() <= MAX_GENERATED_SIZE,
+                "Packet sizes are limited to {} bytes",
+                MAX_GENERATED_SIZE);
+        match *self {
+            PacketSizes::Fixed(_) => {}
+            PacketSizes::Uniform(min, max) => assert!(min <= max, "Uniform packet sizes need min <= max"),
+            PacketSizes::Weighted(ref sizes) => {
+                assert!(sizes.iter().any(|&(_, weight)| weight > 0),
+                        "Weighted packet sizes need a positive total weight")
+            }
+        }
+    }
+
+    #[inline]
+    fn sample(&self, rng: &mut XorShift) -> usize {
+        match *self {
+            PacketSizes::Fixed(size) => size,
+            PacketSizes::Uniform(min, max) => min + (rng.next() % (max - min + 1) as u64) as usize,
+            PacketSizes::Weighted(ref sizes) => {
+                let total = sizes.iter().fold(0, |total, &(_, weight)| total + weight as u64);
+                let mut pick = rng.next() % total;
+                for &(size, weight) in sizes {
+                    if pick < weight as u64 {
+                        return size;
+                    }
+                    pick -= weight as u64;
+                }
+                unreachable!()
+            }
+        }
+    }
+}
+
+/// A small, fast PRNG (xorshift64*); generated traffic has no need for anything better.
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    #[inline]
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+}
+
+/// A batch which generates packets instead of receiving them, so a pipeline can act as its own traffic source (e.g., to
+/// benchmark other NFs). Can be used anywhere a `ReceiveBatch` would be.
+///
+/// Packets are built by copying in a template (zero padded or truncated to the packet's size) and, if the template is
+/// an Ethernet/IPv4 packet, rewriting the IP length and checksum, the UDP length (clearing the UDP checksum) or the TCP
+/// checksum and, when a flow mix is set, the five tuple. A `GeneratorFn` can further customize each packet (and needs
+/// to fix up checksums if it changes anything they cover). By default a full batch is generated every time the batch
+/// is processed, `set_rate` limits this.
+pub struct GeneratorBatch {
+    parent: PacketBatch,
+    template: Vec<u8>,
+    generator: Option<GeneratorFn>,
+    sizes: PacketSizes,
+    flows: Vec<Flow>,
+    next_flow: usize,
+    /// Rate in packets per second.
+    rate: Option<u64>,
+    tokens: f64,
+    last_time: u64,
+    rng: XorShift,
+    last_generated: usize,
+    pub generated: u64,
+}
+
+impl GeneratorBatch {
+    /// Generate batches of up to `batch_size` packets from `template`. Packets are the size of the template unless
+    /// changed with `set_sizes`. Panics if the template is larger than `MAX_GENERATED_SIZE`.
+    pub fn new(template: Vec<u8>, batch_size: i32) -> GeneratorBatch {
+        let size = template.len();
+        PacketSizes::Fixed(size).validate();
+        GeneratorBatch {
+            parent: PacketBatch::new(batch_size),
+            template: template,
+            generator: None,
+            sizes: PacketSizes::Fixed(size),
+            flows: Vec::new(),
+            next_flow: 0,
+            rate: None,
+            tokens: 0.0,
+            last_time: precise_time_ns(),
+            rng: XorShift { state: 0x9e3779b97f4a7c15 },
+            last_generated: 0,
+            generated: 0,
+        }
+    }
+
+    /// Generate `size` byte packets which are entirely filled in by `generator`.
+    pub fn new_with_generator(generator: GeneratorFn, size: usize, batch_size: i32) -> GeneratorBatch {
+        let mut batch = GeneratorBatch::new(vec![0; size], batch_size);
+        batch.generator = Some(generator);
+        batch
+    }
+
+    /// Customize packets built from the template using `generator`.
+    pub fn set_generator(&mut self, generator: GeneratorFn) {
+        self.generator = Some(generator);
+    }
+
+    /// Set the distribution of packet sizes. Panics if the distribution is empty, i.e., for a uniform range with
+    /// `min > max`, or weighted sizes whose weights add up to 0, or if it contains sizes larger than
+    /// `MAX_GENERATED_SIZE`.
+    pub fn set_sizes(&mut self, sizes: PacketSizes) {
+        sizes.validate();
+        let max = sizes.max();
+        if self.template.len() < max {
+            self.template.resize(max, 0);
+        }
+        self.sizes = sizes;
+    }
+
+    /// Cycle through `flows`, rewriting the addresses, protocol and ports of each packet with the next flow. Only
+    /// applies to IPv4 templates.
+    pub fn set_flows(&mut self, flows: Vec<Flow>) {
+        self.flows = flows;
+        self.next_flow = 0;
+    }
+
+    /// Limit generation to `rate` packets per second (or remove the limit if `None`). The rate is enforced by
+    /// generating as many packets as have accrued since the batch was last processed, so the batch should be
+    /// processed often enough for each call to produce less than a full batch.
+    pub fn set_rate(&mut self, rate: Option<u64>) {
+        self.rate = rate;
+        self.tokens = 0.0;
+        self.last_time = precise_time_ns();
+    }
+
+    /// Seed the random number generator used to pick packet sizes.
+    pub fn set_seed(&mut self, seed: u64) {
+        // xorshift gets stuck at 0.
+        self.rng.state = if seed == 0 { 0x9e3779b97f4a7c15 } else { seed };
+    }
+
+    /// Number of packets to generate now.
+    #[inline]
+    fn packets_due(&mut self) -> usize {
+        let max = self.parent.max_size() as usize;
+        match self.rate {
+            None => max,
+            Some(rate) => {
+                let now = precise_time_ns();
+                let elapsed = now - self.last_time;
+                self.last_time = now;
+                // Do not accumulate more than a batch, that would lead to bursts after the pipeline has stalled.
+                self.tokens = (self.tokens + elapsed as f64 * rate as f64 / 1e9).min(max as f64);
+                let due = self.tokens as usize;
+                self.tokens -= due as f64;
+                due
+            }
+        }
+    }
+
+    /// Write the template into `mbuf` and fix up headers for a `size` byte packet.
+    #[inline]
+    unsafe fn build_packet(&mut self, mbuf: *mut MBuf, size: usize) {
+        (*mbuf).write_data(0, &self.template[..min(size, self.template.len())]);
+        let data = slice::from_raw_parts_mut((*mbuf).data_address(0), (*mbuf).data_len());
+        let ip_offset = MacHeader::size();
+        if data.len() >= ip_offset + IpHeader::size() &&
+           cast_from_u8::<MacHeader>(data.as_mut_ptr()).etype() == ETHERTYPE_IPV4 {
+            let ip = cast_from_u8::<IpHeader>(data.as_mut_ptr().offset(ip_offset as isize));
+            let l4_offset = ip_offset + ip.ihl() as usize * 4;
+            if !self.flows.is_empty() {
+                let flow = self.flows[self.next_flow];
+                self.next_flow = (self.next_flow + 1) % self.flows.len();
+                ip.set_src(flow.src_ip);
+                ip.set_dst(flow.dst_ip);
+                ip.set_protocol(flow.proto);
+                if flow.proto == TCP_PROTO && data.len() >= l4_offset + TcpHeader::size() {
+                    let tcp = cast_from_u8::<TcpHeader>(data.as_mut_ptr().offset(l4_offset as isize));
+                    tcp.set_src_port(flow.src_port);
+                    tcp.set_dst_port(flow.dst_port);
+                } else if flow.proto == UDP_PROTO && data.len() >= l4_offset + UdpHeader::size() {
+                    let udp = cast_from_u8::<UdpHeader>(data.as_mut_ptr().offset(l4_offset as isize));
+                    udp.set_src_port(flow.src_port);
+                    udp.set_dst_port(flow.dst_port);
+                }
+            }
+            ip.set_length((size - ip_offset) as u16);
+            ip.compute_checksum();
+            if ip.protocol() == UDP_PROTO && data.len() >= l4_offset + UdpHeader::size() {
+                let udp = cast_from_u8::<UdpHeader>(data.as_mut_ptr().offset(l4_offset as isize));
+                udp.set_length((size - l4_offset) as u16);
+                udp.set_checksum(0);
+            } else if ip.protocol() == TCP_PROTO && data.len() >= size && size >= l4_offset + TcpHeader::size() {
+                let tcp = cast_from_u8::<TcpHeader>(data.as_mut_ptr().offset(l4_offset as isize));
+                tcp.compute_checksum(ip);
+            }
+        }
+        if let Some(ref mut generator) = self.generator {
+            generator(data, self.generated);
+        }
+    }
+}
+
+impl Batch for GeneratorBatch {}
+
+impl BatchIterator for GeneratorBatch {
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
+
+/// Internal interface for packets.
+impl Act for GeneratorBatch {
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        let due = self.packets_due();
+        self.last_generated = due;
+        if due == 0 {
+            return;
+        }
+        self.parent.allocate_partial_batch_with_size(0, due as i32).expect("Allocation failed");
+        let mut mbufs = Vec::<*mut MBuf>::with_capacity(due);
+        {
+            let mut idx = self.parent.start();
+            while let Some((PacketDescriptor { mbuf, .. }, _, next_idx)) = unsafe { self.parent.next_payload(idx) } {
+                mbufs.push(mbuf);
+                idx = next_idx;
+            }
+        }
+        for mbuf in mbufs {
+            let size = self.sizes.sample(&mut self.rng);
+            unsafe {
+                if (*mbuf).add_data_end(size) != size {
+                    panic!("Could not allocate a {} byte packet", size);
+                }
+                self.build_packet(mbuf, size);
+            }
+            self.generated += 1;
+        }
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        // Free up memory
+        self.parent.deallocate_batch().expect("Deallocation failed");
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.last_generated
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
@@ -0,0 +1,163 @@
+use headers::EndOffset;
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::CompositionBatch;
+use super::demux_batch::{DemuxBranch, DemuxFn, DemuxState};
+use super::iterator::{BatchIterator, PacketDescriptor};
+use super::merge_batch::MergeBatch;
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Build the pipeline for a group, given the group's index and the batch of packets belonging to it. The pipeline
+/// usually ends by sending packets out (e.g., `branch.parse::<UdpHeader>().send(port, queue).compose()`).
+pub type GroupFn<T, V> = Box<FnMut(usize, DemuxBranch<T, V>) -> CompositionBatch>;
+
+/// Split a batch into several groups, each with its own pipeline (and egress port). Unlike `demux`, where each branch
+/// is scheduled independently, groups are scheduled together: a batch is received from the parent and classified, and
+/// then each group's pipeline is run in turn over the packets in that group before the next batch is received. Packets
+/// are moved (not copied) into their group.
+///
+/// As a batch, `GroupBy` behaves like a `MergeBatch` of the group pipelines: each `act` and `done` runs one group, in
+/// order, so it can be composed or merged with other pipelines. `process` runs all groups at once.
+pub struct GroupBy<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    state: Rc<RefCell<DemuxState<T, V>>>,
+    pipelines: MergeBatch,
+    groups: usize,
+    /// The group run by the next call to `act`, a new batch is received from the parent before running the first.
+    next_group: usize,
+}
+
+impl<T, V> GroupBy<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V, groups: usize, classifier: DemuxFn<T>, mut pipeline: GroupFn<T, V>) -> GroupBy<T, V> {
+        assert!(groups > 0, "GroupBy needs at least one group");
+        let state = DemuxState::new(parent, groups, classifier, false);
+        let pipelines = (0..groups)
+            .map(|group| pipeline(group, DemuxBranch::with_state(state.clone(), group)))
+            .collect();
+        GroupBy {
+            state: state,
+            // Round robin runs groups in order, matching `next_group`.
+            pipelines: MergeBatch::new(pipelines),
+            groups: groups,
+            next_group: 0,
+        }
+    }
+
+    /// Number of groups.
+    #[inline]
+    pub fn groups(&self) -> usize {
+        self.groups
+    }
+
+    /// Receive and classify a batch of packets, then run every group's pipeline.
+    #[inline]
+    pub fn process(&mut self) {
+        for _ in 0..self.groups {
+            self.act();
+            self.done();
+        }
+    }
+}
+
+impl<T, V> Batch for GroupBy<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+}
+
+impl<T, V> BatchIterator for GroupBy<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.pipelines.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.pipelines.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.pipelines.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.pipelines.next_payload_popped(idx, pop)
+    }
+}
+
+impl<T, V> Act for GroupBy<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn act(&mut self) {
+        if self.next_group == 0 {
+            self.state.borrow_mut().fill();
+        }
+        self.pipelines.act();
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.pipelines.done();
+        self.next_group = (self.next_group + 1) % self.groups;
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.pipelines.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.pipelines.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.pipelines.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.pipelines.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.pipelines.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.pipelines.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.pipelines.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.pipelines.prepend_headroom_segment(idx, size)
+    }
+}
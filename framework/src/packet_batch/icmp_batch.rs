@@ -0,0 +1,374 @@
+use headers::{EndOffset, IcmpHeader, IpHeader, MacHeader, ICMP_DEST_UNREACHABLE, ICMP_FRAGMENTATION_NEEDED, ICMP_PROTO,
+              ICMP_TIME_EXCEEDED, ICMP_TTL_EXCEEDED};
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::HeaderOperations;
+use super::iterator::*;
+use super::packet_batch::{cast_from_u8, free_mbufs};
+use std::any::Any;
+use std::cmp;
+use std::ptr;
+
+/// Bytes added in front of the offending packet's IP header when turning it into an ICMP error: the new IP header and
+/// the ICMP header.
+const ERROR_HEADERS: usize = 20 + 8;
+/// Bytes of the offending packet's payload quoted in an error, after its IP header (RFC 792).
+const ERROR_QUOTE: usize = 8;
+const MAC_HDR_SIZE: usize = 14;
+const TTL: u8 = 64;
+/// IP flag set on packets which may not be fragmented.
+const DONT_FRAGMENT: u8 = 0x2;
+
+/// ICMP messages `HeaderOperations::respond_icmp` can send in response to a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpResponse {
+    /// Answer an echo request (ping).
+    EchoReply,
+    /// The TTL reached zero in transit.
+    TimeExceeded,
+    /// The packet is larger than the given next hop MTU, and may not be fragmented.
+    FragmentationNeeded(u16),
+    /// The destination is unreachable, with the given code (e.g., 1 for host unreachable).
+    Unreachable(u8),
+}
+
+/// Takes in the IP header, payload and context, and returns the ICMP message to respond with, if any. Packets for which
+/// a response is returned are taken out of the batch.
+pub type IcmpFn = Box<FnMut(&IpHeader, &[u8], Option<&mut Any>) -> Option<IcmpResponse>>;
+
+/// Responses for an NF forwarding packets like a router, with `addresses` as its own addresses: answer pings to
+/// `addresses`, and report packets whose TTL expires or that exceed `mtu` (but may not be fragmented). The TTL is only
+/// checked, it needs to be decremented separately.
+pub fn router_icmp(addresses: Vec<u32>, mtu: u16) -> IcmpFn {
+    box move |ip, payload, _| {
+        if addresses.contains(&ip.dst()) {
+            if ip.protocol() == ICMP_PROTO && payload.len() >= IcmpHeader::size() &&
+               cast_from_u8::<IcmpHeader>(payload.as_ptr() as *mut u8).is_echo_request() {
+                Some(IcmpResponse::EchoReply)
+            } else {
+                None
+            }
+        } else if ip.ttl() <= 1 {
+            Some(IcmpResponse::TimeExceeded)
+        } else if ip.length() > mtu && ip.flags() & DONT_FRAGMENT != 0 {
+            Some(IcmpResponse::FragmentationNeeded(mtu))
+        } else {
+            None
+        }
+    }
+}
+
+/// Sends ICMP messages in response to packets, see `HeaderOperations::respond_icmp`.
+pub struct IcmpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    parent: V,
+    port: PmdPort,
+    queue: i32,
+    mac: [u8; 6],
+    source: u32,
+    responder: IcmpFn,
+    capacity: usize,
+}
+
+impl<V> IcmpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V, port: PmdPort, queue: i32, source: u32, responder: IcmpFn) -> IcmpBatch<V> {
+        let capacity = parent.capacity() as usize;
+        let mac = port.mac_address().addr;
+        IcmpBatch {
+            parent: parent,
+            port: port,
+            queue: queue,
+            mac: mac,
+            source: source,
+            responder: responder,
+            capacity: capacity,
+        }
+    }
+}
+
+/// A packet being turned into a response (an `IcmpResponse` or an `Icmpv6Response`).
+pub struct Pending<R> {
+    pub index: usize,
+    pub response: R,
+    /// Offset of the IP header, everything before it is assumed to be the Ethernet header.
+    pub ip_start: usize,
+    /// Bytes of the original packet quoted in an error (starting at its IP header), 0 for echo replies.
+    pub quote: usize,
+    /// Whether the response is an error, built in front of the quoted part of the packet, rather than an echo reply
+    /// built in place.
+    pub error: bool,
+    /// Whether a response can be sent, the packet is dropped otherwise.
+    pub respond: bool,
+}
+
+/// Check whether a response may be sent for a packet, turning echo requests into replies right away. Returns the
+/// number of bytes to quote for errors (0 for echo replies), `None` if no response should be sent.
+#[inline]
+fn prepare(ip: &mut IpHeader, payload: &mut [u8], response: IcmpResponse) -> Option<usize> {
+    let is_icmp = ip.protocol() == ICMP_PROTO && payload.len() >= IcmpHeader::size();
+    if response == IcmpResponse::EchoReply {
+        if !is_icmp {
+            return None;
+        }
+        let icmp = cast_from_u8::<IcmpHeader>(payload.as_mut_ptr());
+        if !icmp.is_echo_request() {
+            return None;
+        }
+        icmp.make_echo_reply();
+        let (src, dst) = (ip.src(), ip.dst());
+        ip.set_src(dst);
+        ip.set_dst(src);
+        ip.set_ttl(TTL);
+        return Some(0);
+    }
+    // Never send errors about errors, non-initial fragments, or packets that cannot be answered (RFC 1122).
+    let src = ip.src();
+    if (is_icmp && cast_from_u8::<IcmpHeader>(payload.as_mut_ptr()).is_error()) || ip.fragment_offset() != 0 ||
+       src == 0 || src == 0xffffffff || src >> 28 == 0xe {
+        return None;
+    }
+    Some(cmp::min(ip.offset() + ERROR_QUOTE, ip.length() as usize))
+}
+
+/// Write the IP and ICMP headers of an error into the `ERROR_HEADERS` bytes in front of the quoted packet.
+#[inline]
+unsafe fn write_error(data: *mut u8, pending: &Pending<IcmpResponse>, source: u32) {
+    let requester = cast_from_u8::<IpHeader>(data.offset((pending.ip_start + ERROR_HEADERS) as isize)).src();
+    let ip_data = data.offset(pending.ip_start as isize);
+    ptr::write_bytes(ip_data, 0, ERROR_HEADERS);
+    let ip = cast_from_u8::<IpHeader>(ip_data);
+    ip.set_version(4);
+    ip.set_ihl(5);
+    ip.set_length((ERROR_HEADERS + pending.quote) as u16);
+    ip.set_ttl(TTL);
+    ip.set_protocol(ICMP_PROTO);
+    ip.set_src(source);
+    ip.set_dst(requester);
+    ip.compute_checksum();
+    let icmp = cast_from_u8::<IcmpHeader>(ip_data.offset(20));
+    let (msg_type, code, rest) = match pending.response {
+        IcmpResponse::TimeExceeded => (ICMP_TIME_EXCEEDED, ICMP_TTL_EXCEEDED, 0),
+        IcmpResponse::FragmentationNeeded(mtu) => (ICMP_DEST_UNREACHABLE, ICMP_FRAGMENTATION_NEEDED, mtu as u32),
+        IcmpResponse::Unreachable(code) => (ICMP_DEST_UNREACHABLE, code, 0),
+        IcmpResponse::EchoReply => unreachable!(),
+    };
+    icmp.set_msg_type(msg_type);
+    icmp.set_code(code);
+    icmp.set_rest(rest);
+    icmp.compute_checksum(IcmpHeader::size() + pending.quote);
+}
+
+/// Trim the packet of an error down to the quoted part, and make `headers` bytes of room for the new headers in front
+/// of it (moving the Ethernet header along). Returns false if the packet cannot be turned into an error.
+fn make_room<V: BatchIterator + Act, R>(parent: &mut V, pending: &Pending<R>, headers: usize) -> bool {
+    let pkt_len = match unsafe { parent.next_base_payload(pending.index) } {
+        Some((PacketDescriptor { mbuf, .. }, _, _)) => unsafe { (*mbuf).pkt_len() },
+        None => return false,
+    };
+    let trim = (pending.ip_start + pending.quote) as isize - pkt_len as isize;
+    if trim != 0 && parent.adjust_payload_size(pending.index, trim).is_none() {
+        return false;
+    }
+    if parent.adjust_headroom(pending.index, headers as isize).is_none() {
+        return false;
+    }
+    match unsafe { parent.next_base_payload(pending.index) } {
+        Some((PacketDescriptor { mbuf, .. }, _, _)) => unsafe {
+            // The new headers and the quoted part are written in place, so they must all be in the first segment.
+            if (*mbuf).data_len() < pending.ip_start + headers + pending.quote {
+                return false;
+            }
+            let data = (*mbuf).data_address(0);
+            ptr::copy(data.offset(headers as isize), data, pending.ip_start);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Take the packets in `pending` out of `parent` and turn them into responses, sent out `port` and `queue` with `mac`
+/// as their source address. Errors get `headers` bytes of room in front of the quoted packet, which `write_error`
+/// fills in given the start of the packet; echo replies are expected to have been built already. Packets which cannot
+/// be answered are dropped.
+pub fn respond<V, R, F>(parent: &mut V,
+                        port: &mut PmdPort,
+                        queue: i32,
+                        mac: [u8; 6],
+                        headers: usize,
+                        mut pending: Vec<Pending<R>>,
+                        mut write_error: F)
+    where V: BatchIterator + Act,
+          F: FnMut(*mut u8, &Pending<R>)
+{
+    for p in pending.iter_mut() {
+        if p.respond && p.error {
+            p.respond = make_room(parent, p, headers);
+        }
+    }
+    let idxes = pending.iter().map(|p| p.index).collect();
+    let mbufs = parent.remove_packets(idxes).expect("ICMP responses were performed incorrectly");
+    let mut responses = Vec::<*mut MBuf>::with_capacity(mbufs.len());
+    let mut dropped = Vec::<*mut MBuf>::with_capacity(mbufs.len());
+    for (mbuf, p) in mbufs.into_iter().zip(pending.iter()) {
+        if !p.respond {
+            dropped.push(mbuf);
+            continue;
+        }
+        unsafe {
+            let data = (*mbuf).data_address(0);
+            if p.error {
+                write_error(data, p);
+            }
+            let eth = cast_from_u8::<MacHeader>(data);
+            eth.dst = eth.src;
+            eth.src = mac;
+        }
+        responses.push(mbuf);
+    }
+    if !responses.is_empty() {
+        let sent = match port.send_queue(queue, responses.as_mut_ptr(), responses.len() as i32) {
+            Ok(sent) => sent as usize,
+            Err(_) => 0,
+        };
+        let mut unsent = responses.split_off(sent);
+        if !unsent.is_empty() {
+            port.record_tx_dropped(queue, unsent.len());
+            free_mbufs(&mut unsent).expect("Could not free unsent ICMP responses");
+        }
+    }
+    free_mbufs(&mut dropped).expect("Could not free packets");
+}
+
+impl<V> Batch for IcmpBatch<V> where V: Batch + BatchIterator + Act {}
+
+impl<V> HeaderOperations for IcmpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    type Header = IpHeader;
+}
+
+impl<V> BatchIterator for IcmpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
+
+/// Internal interface for packets.
+impl<V> Act for IcmpBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    /// Packets the responder picks are taken out of the batch, and turned into responses in place: echo requests are
+    /// answered by swapping addresses, for errors the packet is trimmed to the part quoted and new IP and ICMP headers
+    /// are prepended. Responses are sent right away, out the port and queue given (usually the ones the packets were
+    /// received on); packets which cannot be answered are dropped.
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        let mut pending = Vec::<Pending<IcmpResponse>>::with_capacity(self.capacity);
+        {
+            let iter = PayloadEnumerator::<IpHeader>::new(&mut self.parent);
+            while let Some(ParsedDescriptor { index: idx, header: ip, payload, ctx, offset, .. }) =
+                      iter.next(&mut self.parent) {
+                if let Some(response) = (self.responder)(ip, payload, ctx) {
+                    let ip_start = offset - ip.offset();
+                    let quote = if ip_start < MAC_HDR_SIZE {
+                        None
+                    } else {
+                        prepare(ip, payload, response)
+                    };
+                    pending.push(Pending {
+                        index: idx,
+                        response: response,
+                        ip_start: ip_start,
+                        quote: quote.unwrap_or(0),
+                        error: response != IcmpResponse::EchoReply,
+                        respond: quote.is_some(),
+                    });
+                }
+            }
+        }
+        if pending.is_empty() {
+            return;
+        }
+        let source = self.source;
+        respond(&mut self.parent,
+                &mut self.port,
+                self.queue,
+                self.mac,
+                ERROR_HEADERS,
+                pending,
+                |data, p| unsafe { write_error(data, p, source) });
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.parent.done();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
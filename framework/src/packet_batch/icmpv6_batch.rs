@@ -0,0 +1,294 @@
+use headers::{EndOffset, Icmpv6Header, Ipv6Header, ICMPV6_DEST_UNREACHABLE, ICMPV6_HOP_LIMIT_EXCEEDED,
+              ICMPV6_PACKET_TOO_BIG, ICMPV6_PROTO, ICMPV6_TIME_EXCEEDED};
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::HeaderOperations;
+use super::icmp_batch::{respond, Pending};
+use super::iterator::*;
+use super::packet_batch::cast_from_u8;
+use std::any::Any;
+use std::cmp;
+use std::net::Ipv6Addr;
+use std::ptr;
+
+/// Bytes added in front of the offending packet's IPv6 header when turning it into an ICMPv6 error: the new IPv6
+/// header and the ICMPv6 header.
+const ERROR_HEADERS: usize = 40 + 8;
+/// Errors quote as much of the offending packet as fits without exceeding the minimum IPv6 MTU (RFC 4443).
+const MIN_MTU: usize = 1280;
+const MAC_HDR_SIZE: usize = 14;
+const HOP_LIMIT: u8 = 64;
+
+/// ICMPv6 messages `HeaderOperations::respond_icmpv6` can send in response to a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Icmpv6Response {
+    /// Answer an echo request (ping).
+    EchoReply,
+    /// The hop limit reached zero in transit.
+    TimeExceeded,
+    /// The packet is larger than the given next hop MTU (IPv6 packets are never fragmented in transit).
+    PacketTooBig(u32),
+    /// The destination is unreachable, with the given code (e.g., 3 for address unreachable).
+    Unreachable(u8),
+}
+
+/// Takes in the IPv6 header, payload and context, and returns the ICMPv6 message to respond with, if any. Packets for
+/// which a response is returned are taken out of the batch.
+pub type Icmpv6Fn = Box<FnMut(&Ipv6Header, &[u8], Option<&mut Any>) -> Option<Icmpv6Response>>;
+
+/// The ICMPv6 header in `payload`, if the packet carries ICMPv6 (after any extension headers).
+#[inline]
+fn icmpv6_header<'a>(ip: &Ipv6Header, payload: &'a [u8]) -> Option<&'a mut Icmpv6Header> {
+    match ip.upper_layer(payload) {
+        Some((ICMPV6_PROTO, offset)) if payload.len() >= offset + Icmpv6Header::size() => {
+            Some(cast_from_u8::<Icmpv6Header>(payload[offset..].as_ptr() as *mut u8))
+        }
+        _ => None,
+    }
+}
+
+/// Responses for an NF forwarding IPv6 packets like a router, with `addresses` as its own addresses: answer pings to
+/// `addresses`, and report packets whose hop limit expires or that exceed `mtu`. The hop limit is only checked, it
+/// needs to be decremented separately.
+pub fn router_icmpv6(addresses: Vec<Ipv6Addr>, mtu: u16) -> Icmpv6Fn {
+    box move |ip, payload, _| {
+        if addresses.contains(&ip.dst()) {
+            match icmpv6_header(ip, payload) {
+                Some(icmp) if icmp.is_echo_request() => Some(Icmpv6Response::EchoReply),
+                _ => None,
+            }
+        } else if ip.hop_limit() <= 1 {
+            Some(Icmpv6Response::TimeExceeded)
+        } else if Ipv6Header::size() + ip.payload_len() as usize > mtu as usize {
+            Some(Icmpv6Response::PacketTooBig(mtu as u32))
+        } else {
+            None
+        }
+    }
+}
+
+/// Sends ICMPv6 messages in response to packets, see `HeaderOperations::respond_icmpv6`.
+pub struct Icmpv6Batch<V>
+    where V: Batch + BatchIterator + Act
+{
+    parent: V,
+    port: PmdPort,
+    queue: i32,
+    mac: [u8; 6],
+    source: Ipv6Addr,
+    responder: Icmpv6Fn,
+    capacity: usize,
+}
+
+impl<V> Icmpv6Batch<V>
+    where V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V, port: PmdPort, queue: i32, source: Ipv6Addr, responder: Icmpv6Fn) -> Icmpv6Batch<V> {
+        let capacity = parent.capacity() as usize;
+        let mac = port.mac_address().addr;
+        Icmpv6Batch {
+            parent: parent,
+            port: port,
+            queue: queue,
+            mac: mac,
+            source: source,
+            responder: responder,
+            capacity: capacity,
+        }
+    }
+}
+
+#[inline]
+fn is_multicast(addr: &Ipv6Addr) -> bool {
+    addr.octets()[0] == 0xff
+}
+
+/// Check whether a response may be sent for a packet, turning echo requests into replies right away. Returns the
+/// number of bytes to quote for errors (0 for echo replies), `None` if no response should be sent.
+#[inline]
+fn prepare(ip: &mut Ipv6Header, payload: &mut [u8], response: Icmpv6Response) -> Option<usize> {
+    let icmp = icmpv6_header(ip, payload);
+    if response == Icmpv6Response::EchoReply {
+        match icmp {
+            Some(icmp) if icmp.is_echo_request() => icmp.make_echo_reply(),
+            _ => return None,
+        }
+        // Swapping the addresses leaves the pseudo-header, and hence the checksum, unchanged.
+        let (src, dst) = (ip.src(), ip.dst());
+        ip.set_src(dst);
+        ip.set_dst(src);
+        ip.set_hop_limit(HOP_LIMIT);
+        return Some(0);
+    }
+    // Never send errors about errors, packets from addresses that cannot be answered, or (except for packet too big,
+    // which path MTU discovery relies on) packets sent to multicast addresses (RFC 4443).
+    let src = ip.src();
+    if icmp.map_or(false, |icmp| icmp.is_error()) || src.is_unspecified() || is_multicast(&src) ||
+       (is_multicast(&ip.dst()) && !is_packet_too_big(response)) {
+        return None;
+    }
+    Some(cmp::min(Ipv6Header::size() + ip.payload_len() as usize, MIN_MTU - ERROR_HEADERS))
+}
+
+#[inline]
+fn is_packet_too_big(response: Icmpv6Response) -> bool {
+    match response {
+        Icmpv6Response::PacketTooBig(_) => true,
+        _ => false,
+    }
+}
+
+/// Write the IPv6 and ICMPv6 headers of an error into the `ERROR_HEADERS` bytes in front of the quoted packet.
+#[inline]
+unsafe fn write_error(data: *mut u8, pending: &Pending<Icmpv6Response>, source: Ipv6Addr) {
+    let requester = cast_from_u8::<Ipv6Header>(data.offset((pending.ip_start + ERROR_HEADERS) as isize)).src();
+    let ip_data = data.offset(pending.ip_start as isize);
+    ptr::write_bytes(ip_data, 0, ERROR_HEADERS);
+    let ip = cast_from_u8::<Ipv6Header>(ip_data);
+    ip.set_version(6);
+    ip.set_payload_len((Icmpv6Header::size() + pending.quote) as u16);
+    ip.set_next_header(ICMPV6_PROTO);
+    ip.set_hop_limit(HOP_LIMIT);
+    ip.set_src(source);
+    ip.set_dst(requester);
+    let icmp = cast_from_u8::<Icmpv6Header>(ip_data.offset(Ipv6Header::size() as isize));
+    let (msg_type, code, rest) = match pending.response {
+        Icmpv6Response::TimeExceeded => (ICMPV6_TIME_EXCEEDED, ICMPV6_HOP_LIMIT_EXCEEDED, 0),
+        Icmpv6Response::PacketTooBig(mtu) => (ICMPV6_PACKET_TOO_BIG, 0, mtu),
+        Icmpv6Response::Unreachable(code) => (ICMPV6_DEST_UNREACHABLE, code, 0),
+        Icmpv6Response::EchoReply => unreachable!(),
+    };
+    icmp.set_msg_type(msg_type);
+    icmp.set_code(code);
+    icmp.set_rest(rest);
+    icmp.compute_checksum(ip, Icmpv6Header::size() + pending.quote);
+}
+
+impl<V> Batch for Icmpv6Batch<V> where V: Batch + BatchIterator + Act {}
+
+impl<V> HeaderOperations for Icmpv6Batch<V>
+    where V: Batch + BatchIterator + Act
+{
+    type Header = Ipv6Header;
+}
+
+impl<V> BatchIterator for Icmpv6Batch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
+
+/// Internal interface for packets.
+impl<V> Act for Icmpv6Batch<V>
+    where V: Batch + BatchIterator + Act
+{
+    /// Works like `IcmpBatch`: packets the responder picks are taken out of the batch and turned into responses in
+    /// place, which are sent right away out the port and queue given; packets which cannot be answered are dropped.
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        let mut pending = Vec::<Pending<Icmpv6Response>>::with_capacity(self.capacity);
+        {
+            let iter = PayloadEnumerator::<Ipv6Header>::new(&mut self.parent);
+            while let Some(ParsedDescriptor { index: idx, header: ip, payload, ctx, offset, .. }) =
+                      iter.next(&mut self.parent) {
+                if let Some(response) = (self.responder)(ip, payload, ctx) {
+                    let ip_start = offset - ip.offset();
+                    let quote = if ip_start < MAC_HDR_SIZE {
+                        None
+                    } else {
+                        prepare(ip, payload, response)
+                    };
+                    pending.push(Pending {
+                        index: idx,
+                        response: response,
+                        ip_start: ip_start,
+                        quote: quote.unwrap_or(0),
+                        error: response != Icmpv6Response::EchoReply,
+                        respond: quote.is_some(),
+                    });
+                }
+            }
+        }
+        if pending.is_empty() {
+            return;
+        }
+        let source = self.source;
+        respond(&mut self.parent,
+                &mut self.port,
+                self.queue,
+                self.mac,
+                ERROR_HEADERS,
+                pending,
+                |data, p| unsafe { write_error(data, p, source) });
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.parent.done();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
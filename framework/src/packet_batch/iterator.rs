@@ -1,3 +1,4 @@
+use io::{MBuf, Segments};
 use super::packet_batch::cast_from_u8;
 use std::marker::PhantomData;
 use headers::EndOffset;
@@ -12,8 +13,11 @@ pub struct PacketDescriptor {
     pub header: *mut u8,
     /// Address for the payload (this comes after the header).
     pub payload: *mut u8,
-    /// Payload size, useful for making bounded vectors into the packet.
+    /// Payload size, useful for making bounded vectors into the packet. This only covers data in the first segment of
+    /// the packet.
     pub payload_size: usize,
+    /// The packet's first segment.
+    pub mbuf: *mut MBuf,
 }
 
 /// An interface implemented by all batches for iterating through the set of packets in a batch.
@@ -58,6 +62,10 @@ pub struct ParsedDescriptor<'a, T>
     pub ctx: Option<&'a mut Any>,
     /// Offset (from 0) at which the current payload resides.
     pub offset: usize,
+    /// Packet data beyond the first segment, for packets spread across several mbufs (e.g., jumbo frames). `payload`
+    /// stops at the end of the first segment, the rest of the packet can be accessed through this iterator or made
+    /// contiguous with `Batch::linearize`.
+    pub segments: Segments<'a>,
 }
 
 /// An enumerator over both the header and the payload. The payload is represented as an appropriately sized slice of
@@ -93,7 +101,7 @@ impl<T> PayloadEnumerator<T>
         let original_idx = self.idx.get();
         let item = unsafe { batch.next_payload(original_idx) };
         match item {
-            Some((PacketDescriptor{offset, header: haddr, payload, payload_size},
+            Some((PacketDescriptor { offset, header: haddr, payload, payload_size, mbuf },
                   ctx,
                   next_idx)) => {
                 let header = cast_from_u8::<T>(haddr);
@@ -107,6 +115,7 @@ impl<T> PayloadEnumerator<T>
                     header: header,
                     payload: payload_slice,
                     ctx: ctx,
+                    segments: Segments::new(unsafe { (*mbuf).next_segment() }),
                 })
             }
             None => None,
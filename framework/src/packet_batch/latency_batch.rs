@@ -0,0 +1,126 @@
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::iterator::*;
+use std::any::Any;
+use utils::{cycles_to_ns, rdtsc, tsc_hz, LatencyHistogram};
+
+/// Record the time elapsed since each packet was time stamped into a histogram, see `Batch::measure_latency`.
+pub struct LatencyBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    parent: V,
+    histogram: LatencyHistogram,
+    /// TSC frequency, read when the first batch is processed since it is only known once the system is initialized
+    /// (and pipelines may be built before that).
+    hz: u64,
+}
+
+impl<V> LatencyBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V, histogram: LatencyHistogram) -> LatencyBatch<V> {
+        LatencyBatch {
+            parent: parent,
+            histogram: histogram,
+            hz: 0,
+        }
+    }
+}
+
+impl<V> Batch for LatencyBatch<V> where V: Batch + BatchIterator + Act {}
+
+impl<V> BatchIterator for LatencyBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
+
+/// Internal interface for packets.
+impl<V> Act for LatencyBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        if self.hz == 0 {
+            self.hz = tsc_hz();
+            assert!(self.hz > 0, "TSC frequency unknown, is the system initialized?");
+        }
+        let now = rdtsc();
+        let mut idx = self.parent.start();
+        while let Some((PacketDescriptor { mbuf, .. }, _, next_idx)) = unsafe { self.parent.next_base_payload(idx) } {
+            let elapsed = now.saturating_sub(unsafe { (*mbuf).timestamp() });
+            self.histogram.record(cycles_to_ns(elapsed, self.hz));
+            idx = next_idx;
+        }
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.parent.done();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
@@ -0,0 +1,134 @@
+use super::iterator::*;
+use super::act::Act;
+use super::Batch;
+use super::HeaderOperations;
+use io::MBuf;
+use io::PmdPort;
+use headers::EndOffset;
+use io::Result;
+use std::any::Any;
+use std::marker::PhantomData;
+
+/// Copy packets spread across several mbuf segments into their first segment, so that subsequent operations see the
+/// entire packet as their payload. Packets that are too large to fit in a single segment are dropped.
+pub struct LinearizeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    parent: V,
+    capacity: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T, V> LinearizeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    pub fn new(parent: V) -> LinearizeBatch<T, V> {
+        let capacity = parent.capacity() as usize;
+        LinearizeBatch {
+            parent: parent,
+            capacity: capacity,
+            phantom: PhantomData,
+        }
+    }
+}
+
+batch_no_new!{LinearizeBatch}
+
+impl<T, V> Act for LinearizeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        let mut remove = Vec::<usize>::with_capacity(self.capacity);
+        {
+            let mut idx = self.parent.start();
+            while let Some((PacketDescriptor { mbuf, .. }, _, next_idx)) = unsafe { self.parent.next_payload(idx) } {
+                if unsafe { !(*mbuf).linearize() } {
+                    remove.push(idx);
+                }
+                idx = next_idx;
+            }
+        }
+        if !remove.is_empty() {
+            self.parent.drop_packets(remove).expect("Linearization was performed incorrectly");
+        }
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.parent.done();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
+
+impl<T, V> BatchIterator for LinearizeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
@@ -1,3 +1,4 @@
+use io::MBuf;
 use io::PmdPort;
 use headers::EndOffset;
 use io::Result;
@@ -49,11 +50,21 @@ impl<T, V> Act for MapBatch<T, V>
         self.parent.capacity()
     }
 
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
     #[inline]
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
         self.parent.drop_packets(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_payload_size(idx, size)
@@ -63,6 +74,11 @@ impl<T, V> Act for MapBatch<T, V>
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_headroom(idx, size)
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
 }
 
 impl<T, V> BatchIterator for MapBatch<T, V>
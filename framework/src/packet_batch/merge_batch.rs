@@ -1,25 +1,45 @@
+use io::MBuf;
 use io::PmdPort;
 use io::Result;
 use super::act::Act;
 use super::Batch;
 use super::CompositionBatch;
 use super::iterator::{BatchIterator, PacketDescriptor};
+use super::merge_scheduler::{MergeScheduler, RoundRobin};
 use std::cmp;
 use std::any::Any;
 
 pub struct MergeBatch {
     parents: Vec<CompositionBatch>,
     which: usize,
+    scheduler: Box<MergeScheduler>,
+    /// Number of packets each parent received the last time it was processed (only kept up to date if the scheduler
+    /// uses it).
+    occupancy: Vec<usize>,
 }
 
 impl MergeBatch {
     pub fn new(parents: Vec<CompositionBatch>) -> MergeBatch {
+        MergeBatch::new_with_scheduler(parents, box RoundRobin::new())
+    }
+
+    pub fn new_with_scheduler(parents: Vec<CompositionBatch>, scheduler: Box<MergeScheduler>) -> MergeBatch {
+        let len = parents.len();
         MergeBatch {
             parents: parents,
             which: 0,
+            scheduler: scheduler,
+            occupancy: vec![0; len],
         }
     }
 
+    /// Number of packets each merged batch received the last time it was processed. Only kept up to date if the
+    /// scheduler uses occupancy.
+    #[inline]
+    pub fn occupancy(&self) -> &[usize] {
+        &self.occupancy
+    }
+
     #[inline]
     pub fn process(&mut self) {
         self.act();
@@ -58,13 +78,16 @@ impl BatchIterator for MergeBatch {
 impl Act for MergeBatch {
     #[inline]
     fn act(&mut self) {
-        self.parents[self.which].act()
+        self.parents[self.which].act();
+        if self.scheduler.uses_occupancy() {
+            self.occupancy[self.which] = self.parents[self.which].last_received();
+        }
     }
 
     #[inline]
     fn done(&mut self) {
         self.parents[self.which].done();
-        self.which = (self.which + 1) % self.parents.len();
+        self.which = self.scheduler.next(self.which, &self.occupancy);
     }
 
     #[inline]
@@ -77,11 +100,21 @@ impl Act for MergeBatch {
         self.parents.iter().fold(0, |acc, x| cmp::max(acc, x.capacity()))
     }
 
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parents[self.which].last_received()
+    }
+
     #[inline]
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
         self.parents[self.which].drop_packets(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parents[self.which].remove_packets(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parents[self.which].adjust_payload_size(idx, size)
@@ -91,4 +124,9 @@ impl Act for MergeBatch {
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parents[self.which].adjust_headroom(idx, size)
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parents[self.which].prepend_headroom_segment(idx, size)
+    }
 }
@@ -0,0 +1,156 @@
+/// Decides which of the batches being merged by a `MergeBatch` is processed next.
+pub trait MergeScheduler {
+    /// Called once the batch at index `current` has been processed. `occupancy` holds the number of packets each batch
+    /// received the last time it was processed (its length is the number of batches being merged). Returns the index of
+    /// the batch to process next.
+    fn next(&mut self, current: usize, occupancy: &[usize]) -> usize;
+
+    /// Whether this scheduler looks at occupancy. If not, `MergeBatch` does not bother keeping track of it, and
+    /// `occupancy` is all zeros.
+    fn uses_occupancy(&self) -> bool {
+        false
+    }
+}
+
+/// Process each batch in turn. This is the default policy.
+#[derive(Default)]
+pub struct RoundRobin;
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin
+    }
+}
+
+impl MergeScheduler for RoundRobin {
+    #[inline]
+    fn next(&mut self, current: usize, occupancy: &[usize]) -> usize {
+        (current + 1) % occupancy.len()
+    }
+}
+
+/// Weighted round-robin: each batch is processed `weight` times in a row before moving on to the next one.
+pub struct WeightedRoundRobin {
+    weights: Vec<usize>,
+    processed: usize,
+}
+
+impl WeightedRoundRobin {
+    /// `weights` has one (positive) entry per merged batch.
+    pub fn new(weights: Vec<usize>) -> WeightedRoundRobin {
+        assert!(weights.iter().all(|w| *w > 0), "Weights must be positive");
+        WeightedRoundRobin {
+            weights: weights,
+            processed: 0,
+        }
+    }
+}
+
+impl MergeScheduler for WeightedRoundRobin {
+    #[inline]
+    fn next(&mut self, current: usize, occupancy: &[usize]) -> usize {
+        assert_eq!(self.weights.len(), occupancy.len());
+        self.processed += 1;
+        if self.processed < self.weights[current] {
+            current
+        } else {
+            self.processed = 0;
+            (current + 1) % occupancy.len()
+        }
+    }
+}
+
+/// Strict priority: after processing a batch that produced packets, go back to the highest priority batch. Lower
+/// priority batches are only processed after every batch with a higher priority came up empty, so a latency sensitive
+/// pipeline can be given precedence over bulk pipelines (at the risk of starving them).
+pub struct StrictPriority {
+    /// Batch indices, from highest to lowest priority.
+    order: Vec<usize>,
+    position: usize,
+}
+
+impl StrictPriority {
+    /// `priorities` has one entry per merged batch, batches with larger values have higher priority. Batches with the
+    /// same priority are visited in index order.
+    pub fn new(priorities: &[u32]) -> StrictPriority {
+        let mut order: Vec<usize> = (0..priorities.len()).collect();
+        // Sort is stable, so equal priorities stay in index order.
+        order.sort_by(|a, b| priorities[*b].cmp(&priorities[*a]));
+        StrictPriority {
+            order: order,
+            position: 0,
+        }
+    }
+}
+
+impl MergeScheduler for StrictPriority {
+    #[inline]
+    fn uses_occupancy(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn next(&mut self, current: usize, occupancy: &[usize]) -> usize {
+        assert_eq!(self.order.len(), occupancy.len());
+        if self.order[self.position] != current {
+            // Someone else picked the current batch, resynchronize.
+            self.position = self.order.iter().position(|b| *b == current).unwrap_or(0);
+        }
+        self.position = if occupancy[current] > 0 {
+            0
+        } else {
+            (self.position + 1) % self.order.len()
+        };
+        self.order[self.position]
+    }
+}
+
+/// Process the batch that had the most packets the last time it was processed, on the assumption that it is the most
+/// likely to have packets waiting. To avoid starving batches which were empty (and hence to notice when they receive
+/// traffic), a batch that has not been processed for `max_wait` decisions is processed next regardless of occupancy.
+pub struct OccupancyBased {
+    waited: Vec<usize>,
+    max_wait: usize,
+}
+
+impl OccupancyBased {
+    /// `batches` is the number of batches being merged.
+    pub fn new(batches: usize, max_wait: usize) -> OccupancyBased {
+        OccupancyBased {
+            waited: vec![0; batches],
+            max_wait: max_wait,
+        }
+    }
+}
+
+impl MergeScheduler for OccupancyBased {
+    #[inline]
+    fn uses_occupancy(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn next(&mut self, current: usize, occupancy: &[usize]) -> usize {
+        let len = occupancy.len();
+        assert_eq!(self.waited.len(), len);
+        self.waited[current] = 0;
+        // Look at batches starting after the current one, so ties are broken round-robin.
+        let mut best = (current + 1) % len;
+        for i in (current + 1)..(current + 1 + len) {
+            let candidate = i % len;
+            if self.waited[candidate] >= self.max_wait {
+                best = candidate;
+                break;
+            }
+            if occupancy[candidate] > occupancy[best] {
+                best = candidate;
+            }
+        }
+        for (i, waited) in self.waited.iter_mut().enumerate() {
+            if i != best {
+                *waited += 1;
+            }
+        }
+        best
+    }
+}
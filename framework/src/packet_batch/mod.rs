@@ -2,53 +2,95 @@ use self::act::Act;
 use self::iterator::BatchIterator;
 
 pub use self::apply_batch::ReplaceBatch;
+pub use self::arp_batch::ArpBatch;
 pub use self::composition_batch::CompositionBatch;
 pub use self::context_batch::ContextBatch;
 pub use self::deparsed_batch::DeparsedBatch;
+pub use self::demux_batch::DemuxBranch;
 pub use self::filter_batch::FilterBatch;
+pub use self::generator_batch::{GeneratorBatch, GeneratorFn, PacketSizes, MAX_GENERATED_SIZE};
+pub use self::group_by::GroupBy;
+pub use self::icmp_batch::{router_icmp, IcmpBatch, IcmpFn, IcmpResponse};
+pub use self::icmpv6_batch::{router_icmpv6, Icmpv6Batch, Icmpv6Fn, Icmpv6Response};
+pub use self::latency_batch::LatencyBatch;
+pub use self::linearize_batch::LinearizeBatch;
 pub use self::map_batch::MapBatch;
 pub use self::merge_batch::MergeBatch;
+pub use self::merge_scheduler::{MergeScheduler, OccupancyBased, RoundRobin, StrictPriority, WeightedRoundRobin};
 pub use self::parsed_batch::ParsedBatch;
-pub use self::receive_batch::ReceiveBatch;
+pub use self::receive_batch::{ReceiveBatch, DEFAULT_BURST_SIZE};
+pub use self::replicate_batch::Replication;
 pub use self::resize_payload::ResizePayload;
 pub use self::send_batch::SendBatch;
+pub use self::shape_batch::{RateUnit, ShapeBatch, ShaperRate, ShapingMode};
+pub use self::timestamp_batch::TimestampBatch;
 pub use self::transform_batch::TransformBatch;
+pub use self::tunnel_batch::{Tunnel, TunnelBatch, TunnelOp, TunnelTemplate};
+pub use self::vlan_batch::{VlanBatch, VlanOp};
 
 use self::map_batch::MapFn;
+use self::demux_batch::DemuxFn;
+use self::group_by::GroupFn;
 use self::filter_batch::FilterFn;
 use self::resize_payload::ResizeFn;
+use self::shape_batch::ShapeKeyFn;
 pub use self::reset_parse::ResetParsingBatch;
 use super::io::*;
 use super::headers::*;
+use super::state::NeighbourCache;
+use super::utils::LatencyHistogram;
 use std::any::Any;
+use std::net::Ipv6Addr;
 
 #[macro_use]
 mod macros;
 
 mod act;
 mod apply_batch;
+mod arp_batch;
 mod composition_batch;
 mod context_batch;
 mod deparsed_batch;
+mod demux_batch;
 mod filter_batch;
+mod generator_batch;
+mod group_by;
+mod icmp_batch;
+mod icmpv6_batch;
 mod iterator;
+mod latency_batch;
+mod linearize_batch;
 mod map_batch;
 mod merge_batch;
+mod merge_scheduler;
 mod packet_batch;
 mod parsed_batch;
 mod receive_batch;
+mod replicate_batch;
 mod reset_parse;
 mod resize_payload;
 mod send_batch;
+mod shape_batch;
+mod timestamp_batch;
 mod transform_batch;
+mod tunnel_batch;
+mod vlan_batch;
 
-/// Merge a vector of batches into one batch. Currently this just round-robins between merged batches, but in the future
-/// the precise batch being processed will be determined by the scheduling policy used.
+#[cfg(test)]
+mod tests;
+
+/// Merge a vector of batches into one batch, round-robining between merged batches.
 #[inline]
 pub fn merge(batches: Vec<CompositionBatch>) -> MergeBatch {
     MergeBatch::new(batches)
 }
 
+/// Merge a vector of batches into one batch, using `scheduler` to determine which batch is processed next.
+#[inline]
+pub fn merge_with_scheduler(batches: Vec<CompositionBatch>, scheduler: Box<MergeScheduler>) -> MergeBatch {
+    MergeBatch::new_with_scheduler(batches, scheduler)
+}
+
 /// Public trait implemented by every packet batch type. This trait should be used as a constraint for any functions or
 /// places where a Batch type is required.
 pub trait Batch : BatchIterator + Act {
@@ -85,6 +127,22 @@ pub trait Batch : BatchIterator + Act {
     {
         ContextBatch::<T, Self>::new(self)
     }
+
+    /// Record the current time (TSC) in every packet. Use right after receiving packets, together with
+    /// `measure_latency`, to measure how long packets spend in a pipeline.
+    fn timestamp(self) -> TimestampBatch<Self>
+        where Self: Sized
+    {
+        TimestampBatch::<Self>::new(self)
+    }
+
+    /// Record how long it has been since each packet was time stamped (see `timestamp`) in `histogram`. Use right
+    /// before sending packets; keep a clone of `histogram` to report percentiles.
+    fn measure_latency(self, histogram: LatencyHistogram) -> LatencyBatch<Self>
+        where Self: Sized
+    {
+        LatencyBatch::<Self>::new(self, histogram)
+    }
 }
 
 /// Public interface implemented by packet batches which manipulate headers.
@@ -108,7 +166,7 @@ pub trait HeaderOperations : Batch + Sized {
         ReplaceBatch::<Self::Header, Self>::new(self, template)
     }
 
-    /// Filter out packets, any packets for which `filter_f` returns false are dropped from the batch.
+    /// Filter out packets, any packets for which `filter_f` returns true are dropped from the batch.
     fn filter(self, filter_f: FilterFn<Self::Header>) -> FilterBatch<Self::Header, Self> {
         FilterBatch::<Self::Header, Self>::new(self, filter_f)
     }
@@ -128,7 +186,137 @@ pub trait HeaderOperations : Batch + Sized {
         DeparsedBatch::<T, Self>::new(self)
     }
 
+    /// Copy packets spread across several mbuf segments (e.g., jumbo frames, or packets extended past the end of
+    /// their buffer) into a single segment, so that the payload seen by subsequent operations covers the whole packet.
+    /// Packets that do not fit in a single segment are dropped.
+    fn linearize(self) -> LinearizeBatch<Self::Header, Self> {
+        LinearizeBatch::<Self::Header, Self>::new(self)
+    }
+
     fn resize(self, resize_f: ResizeFn<Self::Header>) -> ResizePayload<Self::Header, Self> {
         ResizePayload::<Self::Header, Self>::new(self, resize_f)
     }
+
+    /// Split packets into `branches` batches, using `classifier` to pick the branch (by index) for each packet, e.g.,
+    /// based on `MacHeader`'s ethertype or `IpHeader::protocol`. Packets for which `classifier` returns `None` (or an
+    /// out of range index) are dropped; to have a default path instead, return the index of a catch-all branch.
+    ///
+    /// The returned branches are independent batches that can each have their own pipeline, and need to be scheduled
+    /// separately. Each branch continues from the current header, so for instance branches of a demux on `MacHeader`
+    /// can directly parse `IpHeader`.
+    fn demux(self, branches: usize, classifier: DemuxFn<Self::Header>) -> Vec<DemuxBranch<Self::Header, Self>> {
+        DemuxBranch::<Self::Header, Self>::new_branches(self, branches, classifier)
+    }
+
+    /// Split packets into `groups` groups using `classifier` (with the same conventions as `demux`), and build a
+    /// separate pipeline for each group by calling `pipeline` with the group index and the group's batch. Unlike
+    /// `demux`, all groups are scheduled together, either by calling `process` on the result or by composing it like
+    /// any other batch.
+    fn group_by(self,
+                groups: usize,
+                classifier: DemuxFn<Self::Header>,
+                pipeline: GroupFn<Self::Header, Self>)
+                -> GroupBy<Self::Header, Self> {
+        GroupBy::<Self::Header, Self>::new(self, groups, classifier, pipeline)
+    }
+
+    /// Replicate packets into `copies` batches (including the original), e.g., to mirror traffic or send a packet out
+    /// several ports. The first batch carries the original packets, the others carry copies made according to
+    /// `replication`. Batches are scheduled independently and buffer packets the same way as `demux` branches.
+    fn replicate(self, copies: usize, replication: Replication) -> Vec<DemuxBranch<Self::Header, Self>> {
+        DemuxBranch::<Self::Header, Self>::new_replicas(self, copies, replication)
+    }
+
+    /// Rate limit packets to `rate` (measured in `unit`s) using a single token bucket. Packets exceeding the rate are
+    /// dropped or held until they conform, depending on `mode`.
+    fn shape(self, rate: ShaperRate, unit: RateUnit, mode: ShapingMode) -> ShapeBatch<Self::Header, Self> {
+        ShapeBatch::<Self::Header, Self>::new(self, rate, unit, mode, None)
+    }
+
+    /// Same as `shape`, except each flow (as returned by `key`) is limited to `rate` separately.
+    fn shape_flows(self,
+                   rate: ShaperRate,
+                   unit: RateUnit,
+                   mode: ShapingMode,
+                   key: ShapeKeyFn<Self::Header>)
+                   -> ShapeBatch<Self::Header, Self> {
+        ShapeBatch::<Self::Header, Self>::new(self, rate, unit, mode, Some(key))
+    }
+
+    /// Answer ARP requests for any of `addresses` (IPv4 addresses in host order) with `port`'s MAC address, sending
+    /// replies out `port` and `queue` (usually the ones packets are received on). All ARP packets are taken out of the
+    /// batch, after their senders have been learned: senders already in `cache` are refreshed, and new ones are added
+    /// if the packet is addressed to one of `addresses` (as in RFC 826). Clones of `cache` can then be used to fill in
+    /// `MacHeader.dst` elsewhere. Other packets are left alone.
+    fn respond_arp(self,
+                   port: PmdPort,
+                   queue: i32,
+                   addresses: Vec<u32>,
+                   cache: NeighbourCache)
+                   -> ArpBatch<Self>
+        where Self: HeaderOperations<Header = MacHeader>
+    {
+        ArpBatch::<Self>::new(self, port, queue, addresses, cache)
+    }
+
+    /// Respond to packets with ICMP messages as decided by `responder` (e.g., `router_icmp`): echo replies, or errors
+    /// such as time exceeded, sent from `source` (an IPv4 address in host order). Responses are built in place and sent
+    /// out `port` and `queue` (usually the ones packets are received on) with `port`'s MAC address; packets answered
+    /// this way are taken out of the batch. See `respond_icmpv6` for IPv6 packets.
+    fn respond_icmp(self, port: PmdPort, queue: i32, source: u32, responder: IcmpFn) -> IcmpBatch<Self>
+        where Self: HeaderOperations<Header = IpHeader>
+    {
+        IcmpBatch::<Self>::new(self, port, queue, source, responder)
+    }
+
+    /// The ICMPv6 equivalent of `respond_icmp`: respond to IPv6 packets with echo replies, or errors such as packet too
+    /// big or time exceeded (e.g., as decided by `router_icmpv6`), sent from `source`. Errors quote as much of the
+    /// offending packet as fits in the minimum IPv6 MTU.
+    fn respond_icmpv6(self, port: PmdPort, queue: i32, source: Ipv6Addr, responder: Icmpv6Fn) -> Icmpv6Batch<Self>
+        where Self: HeaderOperations<Header = Ipv6Header>
+    {
+        Icmpv6Batch::<Self>::new(self, port, queue, source, responder)
+    }
+
+    /// Push an 802.1Q tag with `tci` onto every packet, in front of any existing tags. A tag the NIC stripped on
+    /// receive (see `MBuf::rx_vlan_tci`) is put back into the packet first, so it ends up as the inner tag. Packets are
+    /// grown using their headroom; those that cannot be are dropped.
+    fn push_vlan(self, tci: u16) -> VlanBatch<Self>
+        where Self: HeaderOperations<Header = MacHeader>
+    {
+        self.push_vlan_with_tpid(ETHERTYPE_VLAN, tci)
+    }
+
+    /// Same as `push_vlan`, with the tag protocol identifier given explicitly (e.g., `ETHERTYPE_QINQ` for the outer tag
+    /// of QinQ packets).
+    fn push_vlan_with_tpid(self, tpid: u16, tci: u16) -> VlanBatch<Self>
+        where Self: HeaderOperations<Header = MacHeader>
+    {
+        VlanBatch::<Self>::new(self, VlanOp::Push(tpid, tci))
+    }
+
+    /// Pop the outer VLAN tag off every tagged packet (including tags stripped by the NIC on receive). Untagged packets
+    /// are left as is.
+    fn pop_vlan(self) -> VlanBatch<Self>
+        where Self: HeaderOperations<Header = MacHeader>
+    {
+        VlanBatch::<Self>::new(self, VlanOp::Pop)
+    }
+
+    /// Encapsulate every packet (an Ethernet frame) in a tunnel, prepending the outer headers in `template`. Packets
+    /// are grown using their headroom; those that cannot be are dropped.
+    fn encap(self, template: TunnelTemplate) -> TunnelBatch<Self>
+        where Self: HeaderOperations<Header = MacHeader>
+    {
+        TunnelBatch::<Self>::new(self, TunnelOp::Encap(template))
+    }
+
+    /// Strip the outer headers from packets encapsulated in `tunnel`, so the inner packets can be parsed starting with
+    /// their MAC header. Other packets are left as is. Tunnel headers (e.g., to read the VNI) need to be looked at
+    /// before this, by parsing them.
+    fn decap(self, tunnel: Tunnel) -> TunnelBatch<Self>
+        where Self: HeaderOperations<Header = MacHeader>
+    {
+        TunnelBatch::<Self>::new(self, TunnelOp::Decap(tunnel))
+    }
 }
@@ -1,4 +1,6 @@
+use headers::EndOffset;
 use io::*;
+use std::cmp;
 use std::result;
 use super::act::Act;
 use super::Batch;
@@ -69,10 +71,18 @@ impl PacketBatch {
     /// Receive packets from a PMD port queue.
     #[inline]
     pub fn recv_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        let cnt = self.max_size();
+        self.recv_queue_burst(port, queue, cnt)
+    }
+
+    /// Receive up to `cnt` packets (at most `self.max_size()`) from a PMD port queue.
+    #[inline]
+    pub fn recv_queue_burst(&mut self, port: &mut PmdPort, queue: i32, cnt: i32) -> Result<u32> {
+        let cnt = cmp::min(cnt, self.max_size());
         unsafe {
             match self.deallocate_batch() {
                 Err(err) => Err(err),
-                Ok(_) => self.recv_internal(port, queue),
+                Ok(_) => self.recv_internal(port, queue, cnt),
             }
         }
     }
@@ -117,14 +127,14 @@ impl PacketBatch {
         }
     }
 
-    /// This drops packet buffers and keeps things ordered. We expect that idxes is an ordered vector of indices, no
-    /// guarantees are made when this is not the case.
+    /// This removes packet buffers (without freeing them) and keeps things ordered. We expect that idxes is an ordered
+    /// vector of indices, no guarantees are made when this is not the case.
     #[inline]
-    fn drop_packets_stable(&mut self, idxes: Vec<usize>) -> Option<usize> {
-        let mut to_free = Vec::<*mut MBuf>::with_capacity(idxes.len());
+    fn remove_packets_stable(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        let mut removed = Vec::<*mut MBuf>::with_capacity(idxes.len());
         // Short circuit when we don't have to do this work.
         if idxes.is_empty() {
-            return Some(0);
+            return Some(removed);
         }
         unsafe {
             let mut idx_orig = self.start;
@@ -137,7 +147,7 @@ impl PacketBatch {
                 let test_idx: usize = idxes[remove_idx];
                 assert!(idx_orig <= test_idx);
                 if idx_orig == test_idx {
-                    to_free.push(self.array[idx_orig]);
+                    removed.push(self.array[idx_orig]);
                     remove_idx += 1;
                 } else {
                     self.array.swap(idx_orig, idx_new);
@@ -158,24 +168,30 @@ impl PacketBatch {
             } else {
                 self.start = 0;
                 self.array.set_len(idx_new);
-                if to_free.is_empty() {
-                    Some(0)
-                } else {
-                    // Now free the dropped packets
-                    let len = to_free.len();
-                    // No need to offset here since to_free is tight.
-                    let array_ptr = to_free.as_mut_ptr();
-                    let ret = mbuf_free_bulk(array_ptr, (len as i32));
-                    if ret == 0 {
-                        Some(len)
-                    } else {
-                        None
-                    }
-                }
+                Some(removed)
             }
         }
     }
 
+    /// This drops packet buffers and keeps things ordered. We expect that idxes is an ordered vector of indices, no
+    /// guarantees are made when this is not the case.
+    #[inline]
+    fn drop_packets_stable(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.remove_packets_stable(idxes).and_then(|mut to_free| free_mbufs(&mut to_free))
+    }
+
+    /// Add a packet at the end of this batch, taking ownership of the mbuf. Returns false (leaving ownership with the
+    /// caller) if the batch is already full.
+    #[inline]
+    pub fn add_packet(&mut self, mbuf: *mut MBuf) -> bool {
+        if self.array.len() < self.cnt as usize {
+            self.array.push(mbuf);
+            true
+        } else {
+            false
+        }
+    }
+
     // Some private utility functions.
     #[inline]
     unsafe fn packet_ptr(&mut self) -> *mut *mut MBuf {
@@ -200,8 +216,8 @@ impl PacketBatch {
 
     // Assumes we have already deallocated batch.
     #[inline]
-    unsafe fn recv_internal(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
-        match port.recv_queue(queue, self.packet_ptr(), self.max_size() as i32) {
+    unsafe fn recv_internal(&mut self, port: &mut PmdPort, queue: i32, cnt: i32) -> Result<u32> {
+        match port.recv_queue(queue, self.packet_ptr(), cnt) {
             e @ Err(_) => e,
             Ok(recv) => {
                 self.add_to_batch(recv as usize);
@@ -309,6 +325,17 @@ impl PacketBatch {
             Some(0)
         }
     }
+
+    #[inline]
+    unsafe fn prepend_packet_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        let head = MBuf::prepend_segment(self.array[idx], size);
+        if head.is_null() {
+            None
+        } else {
+            self.array[idx] = head;
+            Some(size as isize)
+        }
+    }
 }
 
 // A packet batch is also a batch (just a special kind)
@@ -328,6 +355,7 @@ impl BatchIterator for PacketBatch {
                 header: self.address(idx).0,
                 payload: self.payload(idx).0,
                 payload_size: self.payload(idx).1,
+                mbuf: self.array[idx],
             },
                   None,
                   idx + 1))
@@ -358,11 +386,19 @@ impl Act for PacketBatch {
     #[inline]
     fn done(&mut self) {}
 
+    /// Send packets according to `port`'s `TxPolicy`. Packets which are not sent are either buffered by the port or
+    /// left in the batch, to be freed (and counted as dropped) when the batch is done.
     #[inline]
     fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
-        let mut total_sent = 0;
-        // FIXME: Make it optionally possible to wait for all packets to be sent.
-        while self.available() > 0 {
+        let policy = port.tx_policy();
+        // Packets buffered from earlier batches go first. If they cannot all be sent, neither can this batch without
+        // reordering packets, so this batch is buffered after them.
+        let mut total_sent = match port.flush_tx_buffer(queue) {
+            Ok(sent) => sent,
+            e @ Err(_) => return e,
+        };
+        let mut attempts = 0;
+        while self.available() > 0 && port.tx_buffered(queue) == 0 {
             unsafe {
                 match port.send_queue(queue, self.packet_ptr(), self.available() as i32)
                     .and_then(|sent| {
@@ -373,7 +409,27 @@ impl Act for PacketBatch {
                     e  @ _ => return e
                 }
             }
-            break;
+            attempts += 1;
+            let retry = match policy {
+                TxPolicy::Drop | TxPolicy::Buffer(_) => false,
+                TxPolicy::Retry(retries) => attempts <= retries,
+                TxPolicy::SpinUntilSent => true,
+            };
+            if !retry {
+                break;
+            }
+        }
+        if attempts > 1 {
+            port.record_tx_retries(queue, attempts - 1);
+        }
+        if self.available() > 0 {
+            let buffered = port.buffer_unsent(queue, &self.array[self.start..]);
+            unsafe {
+                self.consumed_batch(buffered);
+            }
+        }
+        if self.available() > 0 {
+            port.record_tx_dropped(queue, self.available());
         }
         Ok(total_sent)
     }
@@ -383,11 +439,22 @@ impl Act for PacketBatch {
         self.max_size()
     }
 
+    /// Packet batches are filled by whoever owns them, so this is simply the number of packets currently held.
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.available()
+    }
+
     #[inline]
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
         self.drop_packets_stable(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.remove_packets_stable(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         unsafe { self.adjust_packet_size(idx, size) }
@@ -397,6 +464,11 @@ impl Act for PacketBatch {
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         unsafe { self.adjust_packet_headroom(idx, size) }
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        unsafe { self.prepend_packet_segment(idx, size) }
+    }
 }
 
 impl Batch for PacketBatch {}
@@ -407,13 +479,85 @@ impl Drop for PacketBatch {
     }
 }
 
+/// Free mbufs that are not (or no longer) part of a batch, e.g., those returned by `remove_packets`. Returns the number
+/// of mbufs freed.
+#[inline]
+pub fn free_mbufs(mbufs: &mut Vec<*mut MBuf>) -> Option<usize> {
+    if mbufs.is_empty() {
+        Some(0)
+    } else {
+        let len = mbufs.len();
+        // No need to offset here since mbufs is tight.
+        let ret = unsafe { mbuf_free_bulk(mbufs.as_mut_ptr(), (len as i32)) };
+        mbufs.clear();
+        if ret == 0 {
+            Some(len)
+        } else {
+            None
+        }
+    }
+}
+
 #[inline]
 pub fn cast_from_u8<'a, T: 'a>(data: *mut u8) -> &'a mut T {
     let typecast = data as *mut T;
     unsafe { &mut *typecast }
 }
 
+/// Keep header offsets in sync with a queue from which `idxes` are being removed. Removal compacts the queue so the
+/// first remaining packet ends up at index 0.
+#[inline]
+pub fn retain_offsets(offsets: &mut Vec<usize>, start: usize, idxes: &[usize]) {
+    let mut remove = idxes.iter().peekable();
+    let mut retained = Vec::<usize>::with_capacity(offsets.len());
+    for (idx, offset) in offsets.iter().enumerate().skip(start) {
+        if remove.peek() == Some(&&idx) {
+            remove.next();
+        } else {
+            retained.push(*offset);
+        }
+    }
+    *offsets = retained;
+}
+
+/// Descriptor for packet `idx` in `queue`, whose header (of type `T`) resides `offsets[idx]` bytes into the packet.
+/// This is how batches that hold packets in their own queue (rather than a parent's) continue from a previously
+/// parsed header.
+#[inline]
+pub unsafe fn queued_payload<T: EndOffset>(queue: &mut PacketBatch,
+                                           offsets: &[usize],
+                                           idx: usize)
+                                           -> Option<(PacketDescriptor, usize)> {
+    let header_offset = match offsets.get(idx) {
+        Some(offset) => *offset,
+        None => return None,
+    };
+    match queue.next_payload(idx) {
+        Some((PacketDescriptor { payload: packet, payload_size: size, mbuf, .. }, _, next_idx)) => {
+            let header = packet.offset(header_offset as isize);
+            let hdr_as_t = cast_from_u8::<T>(header);
+            let offset = T::offset(hdr_as_t);
+            let remaining = size - header_offset;
+            // Under no circumstances should we allow an incorrectly reported payload size to cause problems.
+            let payload_size = cmp::min(T::payload_size(hdr_as_t, remaining), remaining - offset);
+            Some((PacketDescriptor {
+                header: header,
+                offset: header_offset + offset,
+                payload: header.offset(offset as isize),
+                payload_size: payload_size,
+                mbuf: mbuf,
+            },
+                  next_idx))
+        }
+        None => None,
+    }
+}
+
+#[cfg(any(test, feature = "test-backend"))]
+use io::test_backend::{mbuf_alloc_bulk, mbuf_free_bulk};
+
 // Some low level functions that need access to private members.
+#[cfg(not(any(test, feature = "test-backend")))]
 #[link(name = "zcsi")]
 extern "C" {
     fn mbuf_alloc_bulk(array: *mut *mut MBuf, len: u16, cnt: i32) -> i32;
@@ -1,3 +1,4 @@
+use io::MBuf;
 use io::PmdPort;
 use headers::EndOffset;
 use io::Result;
@@ -41,11 +42,21 @@ impl<T, V> Act for ParsedBatch<T, V>
         self.parent.capacity()
     }
 
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
     #[inline]
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
         self.parent.drop_packets(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_payload_size(idx, size)
@@ -55,6 +66,11 @@ impl<T, V> Act for ParsedBatch<T, V>
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_headroom(idx, size)
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
 }
 
 batch!{ParsedBatch, [parent: V], [phantom: PhantomData]}
@@ -72,7 +88,7 @@ impl<T, V> BatchIterator for ParsedBatch<T, V>
     unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
         let parent_payload = self.parent.next_payload(idx);
         match parent_payload {
-            Some((PacketDescriptor { offset: prev_offset, payload: packet, payload_size: size, .. },
+            Some((PacketDescriptor { offset: prev_offset, payload: packet, payload_size: size, mbuf, .. },
                   arg,
                   idx)) => {
                 let pkt_as_t = cast_from_u8::<T>(packet);
@@ -84,6 +100,7 @@ impl<T, V> BatchIterator for ParsedBatch<T, V>
                     offset: prev_offset + offset,
                     payload: packet.offset(offset as isize),
                     payload_size: payload_size,
+                    mbuf: mbuf,
                 },
                       arg,
                       idx))
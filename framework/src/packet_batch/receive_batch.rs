@@ -1,3 +1,4 @@
+use io::MBuf;
 use io::PmdPort;
 use io::Result;
 use super::act::Act;
@@ -5,33 +6,165 @@ use super::Batch;
 use super::packet_batch::PacketBatch;
 use super::iterator::*;
 use std::any::Any;
+use utils::rdtsc;
+
+/// Number of packets requested from the port per receive, unless configured otherwise.
+pub const DEFAULT_BURST_SIZE: i32 = 32;
+
+/// Number of receives between adjustments of an adaptive burst size.
+const ADAPT_INTERVAL: usize = 64;
+/// Weight given to each new sample in the moving averages.
+const EWMA_WEIGHT: f64 = 1.0 / 16.0;
+/// Grow the burst when the average fraction of the burst filled by a receive is above this.
+const GROW_OCCUPANCY: f64 = 0.9;
+/// Shrink the burst when the average fraction of the burst filled by a receive is below this.
+const SHRINK_OCCUPANCY: f64 = 0.5;
+/// How much more a larger burst may cost per packet (relative to the current one) and still be worth growing to.
+const CYCLES_TOLERANCE: f64 = 1.1;
+/// Costs measured for burst sizes that are not in use decay by this much at every adjustment, so a size that once
+/// measured as too expensive (e.g., during a noisy interval) is eventually tried again.
+const UNUSED_DECAY: f64 = 1.0 - EWMA_WEIGHT;
+
+/// Picks the burst size (between a minimum and a maximum, in powers of two) for a `ReceiveBatch`. Small bursts keep
+/// latency low when the port is lightly loaded while large bursts amortize per-batch costs when traffic backs up, so
+/// the burst grows while receives keep filling it and shrinks when they mostly come back partially full. The cost per
+/// packet (cycles between receives divided by the packets received) is tracked for each burst size, and the burst does
+/// not grow to a size that was measured to be more expensive per packet, e.g., because the larger batches no longer
+/// fit in cache. Measurements for sizes that are not in use decay over time, so they are eventually probed again.
+struct AdaptiveBurst {
+    sizes: Vec<i32>,
+    current: usize,
+    /// The size used by the last receive, which the cycles until the next receive are charged to.
+    last_size: usize,
+    occupancy: f64,
+    cycles_per_packet: Vec<f64>,
+    last_cycles: u64,
+    last_received: usize,
+    receives: usize,
+}
+
+impl AdaptiveBurst {
+    fn new(min: i32, max: i32) -> AdaptiveBurst {
+        assert!(min > 0 && min <= max, "Burst sizes must satisfy 0 < min <= max");
+        let mut sizes = vec![min];
+        while sizes[sizes.len() - 1] < max {
+            let next = sizes[sizes.len() - 1] * 2;
+            sizes.push(if next > max { max } else { next });
+        }
+        let levels = sizes.len();
+        AdaptiveBurst {
+            sizes: sizes,
+            current: 0,
+            last_size: 0,
+            occupancy: 0.0,
+            cycles_per_packet: vec![0.0; levels],
+            last_cycles: 0,
+            last_received: 0,
+            receives: 0,
+        }
+    }
+
+    #[inline]
+    fn size(&self) -> i32 {
+        self.sizes[self.current]
+    }
+
+    /// Called just before each receive, once the pipeline has finished processing the packets from the previous one.
+    #[inline]
+    fn update(&mut self) {
+        self.update_at(rdtsc());
+    }
+
+    #[inline]
+    fn update_at(&mut self, now: u64) {
+        if self.last_received > 0 && self.last_cycles > 0 {
+            let cost = (now - self.last_cycles) as f64 / self.last_received as f64;
+            let average = &mut self.cycles_per_packet[self.last_size];
+            *average = if *average == 0.0 {
+                cost
+            } else {
+                *average + (cost - *average) * EWMA_WEIGHT
+            };
+        }
+        self.last_cycles = now;
+    }
+
+    /// Called after each receive with the number of packets received.
+    #[inline]
+    fn received(&mut self, received: usize) {
+        self.last_received = received;
+        self.last_size = self.current;
+        let filled = received as f64 / self.size() as f64;
+        self.occupancy += (filled - self.occupancy) * EWMA_WEIGHT;
+        self.receives += 1;
+        if self.receives < ADAPT_INTERVAL {
+            return;
+        }
+        self.receives = 0;
+        for (size, average) in self.cycles_per_packet.iter_mut().enumerate() {
+            if size != self.current {
+                *average *= UNUSED_DECAY;
+            }
+        }
+        if self.occupancy > GROW_OCCUPANCY && self.current + 1 < self.sizes.len() {
+            let next = self.cycles_per_packet[self.current + 1];
+            if next == 0.0 || next <= self.cycles_per_packet[self.current] * CYCLES_TOLERANCE {
+                self.current += 1;
+            }
+        } else if self.occupancy < SHRINK_OCCUPANCY && self.current > 0 {
+            self.current -= 1;
+        }
+    }
+}
 
 // FIXME: Should we be handling multiple queues and ports here?
 pub struct ReceiveBatch {
     parent: PacketBatch,
     port: PmdPort,
     queue: i32,
+    burst: i32,
+    adaptive: Option<AdaptiveBurst>,
+    last_received: usize,
     pub received: u64,
 }
 
 impl ReceiveBatch {
     pub fn new_with_parent(parent: PacketBatch, port: PmdPort, queue: i32) -> ReceiveBatch {
+        let burst = parent.max_size();
         ReceiveBatch {
             parent: parent,
             port: port,
             queue: queue,
+            burst: burst,
+            adaptive: None,
+            last_received: 0,
             received: 0,
         }
     }
 
     pub fn new(port: PmdPort, queue: i32) -> ReceiveBatch {
-        ReceiveBatch {
-            parent: PacketBatch::new(32),
-            port: port,
-            queue: queue,
-            received: 0,
-        }
+        ReceiveBatch::new_with_burst_size(port, queue, DEFAULT_BURST_SIZE)
+    }
+
+    /// Receive up to `burst` packets at a time.
+    pub fn new_with_burst_size(port: PmdPort, queue: i32, burst: i32) -> ReceiveBatch {
+        ReceiveBatch::new_with_parent(PacketBatch::new(burst), port, queue)
+    }
 
+    /// Receive between `min` and `max` packets at a time, adapting the burst size to the load: it grows while receives
+    /// keep filling it and shrinks when they come back mostly empty. The batch's capacity is `max`.
+    pub fn new_adaptive(port: PmdPort, queue: i32, min: i32, max: i32) -> ReceiveBatch {
+        let mut batch = ReceiveBatch::new_with_burst_size(port, queue, max);
+        let adaptive = AdaptiveBurst::new(min, max);
+        batch.burst = adaptive.size();
+        batch.adaptive = Some(adaptive);
+        batch
+    }
+
+    /// Number of packets requested from the port by the next receive.
+    #[inline]
+    pub fn burst_size(&self) -> i32 {
+        self.burst
     }
 }
 
@@ -67,13 +200,18 @@ impl Act for ReceiveBatch {
     #[inline]
     fn act(&mut self) {
         self.parent.act();
-        self.parent
-            .recv_queue(&mut self.port, self.queue)
-            .and_then(|x| {
-                self.received += x as u64;
-                Ok(x)
-            })
+        if let Some(ref mut adaptive) = self.adaptive {
+            adaptive.update();
+        }
+        let received = self.parent
+            .recv_queue_burst(&mut self.port, self.queue, self.burst)
             .expect("Receive failed");
+        self.received += received as u64;
+        self.last_received = received as usize;
+        if let Some(ref mut adaptive) = self.adaptive {
+            adaptive.received(received as usize);
+            self.burst = adaptive.size();
+        }
     }
 
     #[inline]
@@ -92,11 +230,21 @@ impl Act for ReceiveBatch {
         self.parent.capacity()
     }
 
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.last_received
+    }
+
     #[inline]
     fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
         self.parent.drop_packets(idxes)
     }
 
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_payload_size(idx, size)
@@ -106,4 +254,59 @@ impl Act for ReceiveBatch {
     fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
         self.parent.adjust_headroom(idx, size)
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `receives` receives of `received` packets, each followed by `cycles_per_packet` cycles of processing per
+    /// packet, starting at time `now`. Returns the time after the last one.
+    fn run(burst: &mut AdaptiveBurst, mut now: u64, receives: usize, received: usize, cycles_per_packet: u64) -> u64 {
+        for _ in 0..receives {
+            burst.update_at(now);
+            burst.received(received);
+            now += received as u64 * cycles_per_packet;
+        }
+        now
+    }
+
+    #[test]
+    fn costs_are_charged_to_the_size_used() {
+        let mut burst = AdaptiveBurst::new(4, 8);
+        // Full receives grow the burst at the end of the first interval.
+        let now = run(&mut burst, 1, ADAPT_INTERVAL, 4, 10);
+        assert_eq!(burst.size(), 8);
+        // The cost of the last receive (made with the smaller burst) is only charged once the next one happens.
+        run(&mut burst, now, 1, 8, 50);
+        assert_eq!(burst.cycles_per_packet[0].round(), 10.0);
+        assert_eq!(burst.cycles_per_packet[1], 0.0);
+    }
+
+    #[test]
+    fn expensive_sizes_are_probed_again() {
+        let mut burst = AdaptiveBurst::new(4, 8);
+        let mut now = run(&mut burst, 1, ADAPT_INTERVAL, 4, 10);
+        assert_eq!(burst.size(), 8);
+        // A noisy interval makes the larger burst look much more expensive, and traffic drops off.
+        now = run(&mut burst, now, ADAPT_INTERVAL, 2, 100);
+        assert_eq!(burst.size(), 4);
+        // Once traffic is back the larger size is not used at first, but is tried again before long.
+        now = run(&mut burst, now, ADAPT_INTERVAL, 4, 10);
+        assert_eq!(burst.size(), 4);
+        let mut grown = false;
+        for _ in 0..64 {
+            now = run(&mut burst, now, ADAPT_INTERVAL, 4, 10);
+            if burst.size() == 8 {
+                grown = true;
+                break;
+            }
+        }
+        assert!(grown);
+    }
 }
@@ -0,0 +1,41 @@
+use headers::EndOffset;
+use io::MBuf;
+use super::act::Act;
+use super::Batch;
+use super::demux_batch::{DemuxBranch, DemuxState};
+use super::iterator::BatchIterator;
+
+/// How packets are replicated, see `HeaderOperations::replicate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Replication {
+    /// Each copy is a separate packet (data is copied into newly allocated mbufs), and can be modified independently.
+    Copy,
+    /// Copies share the original mbuf, whose reference count is incremented. This avoids copying packet data, but
+    /// neither the original nor its copies may be modified (including adjusting their size or headroom).
+    Reference,
+}
+
+impl Replication {
+    /// Make a copy of `mbuf`, returns null if the copy could not be allocated.
+    #[inline]
+    pub unsafe fn copy(&self, mbuf: *mut MBuf) -> *mut MBuf {
+        match *self {
+            Replication::Copy => MBuf::copy_packet(mbuf),
+            Replication::Reference => MBuf::reference(mbuf),
+        }
+    }
+}
+
+/// Replicas are demux branches sharing a demultiplexer that sends every packet to the first branch and a copy of it to
+/// each of the others, so they are scheduled and buffer packets the same way.
+impl<T, V> DemuxBranch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    /// Create `copies` branches, the first receiving packets from `parent` and the others receiving copies of them.
+    pub fn new_replicas(parent: V, copies: usize, replication: Replication) -> Vec<DemuxBranch<T, V>> {
+        let state = DemuxState::new(parent, copies, box |_, _, _| Some(0), true);
+        state.borrow_mut().set_replication(replication);
+        (0..copies).map(|branch| DemuxBranch::with_state(state.clone(), branch)).collect()
+    }
+}
@@ -1,3 +1,4 @@
+use io::MBuf;
 use io::PmdPort;
 use io::Result;
 use super::act::Act;
@@ -87,11 +88,21 @@ impl<V> Act for SendBatch<V>
         self.parent.capacity()
     }
 
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
     #[inline]
     fn drop_packets(&mut self, _: Vec<usize>) -> Option<usize> {
         panic!("Cannot drop packets from a sent batch")
     }
 
+    #[inline]
+    fn remove_packets(&mut self, _: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        panic!("Cannot remove packets from a sent batch")
+    }
+
     #[inline]
     fn adjust_payload_size(&mut self, _: usize, _: isize) -> Option<isize> {
         panic!("Cannot resize a sent batch")
@@ -101,4 +112,9 @@ impl<V> Act for SendBatch<V>
     fn adjust_headroom(&mut self, _: usize, _: isize) -> Option<isize> {
         panic!("Cannot resize a sent batch")
     }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, _: usize, _: usize) -> Option<isize> {
+        panic!("Cannot resize a sent batch")
+    }
 }
@@ -0,0 +1,409 @@
+use fnv::FnvHasher;
+use headers::EndOffset;
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::HeaderOperations;
+use super::iterator::*;
+use super::packet_batch::{free_mbufs, queued_payload, retain_offsets, PacketBatch};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasherDefault;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use time::precise_time_ns;
+use utils::Flow;
+
+type FnvHash = BuildHasherDefault<FnvHasher>;
+
+/// By default, hold up to this many batches worth of packets when shaping.
+const HELD_BATCHES: usize = 8;
+/// Idle token buckets are garbage collected once there are more than this many.
+const MAX_IDLE_BUCKETS: usize = 1 << 16;
+
+/// Pick the token bucket a packet is charged to, e.g., by extracting its flow.
+pub type ShapeKeyFn<T> = Box<FnMut(&T, &[u8], Option<&mut Any>) -> Flow>;
+
+/// What to do with packets that exceed the rate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShapingMode {
+    /// Drop packets that exceed the rate.
+    Police,
+    /// Hold packets that exceed the rate until enough tokens accumulate to send them. Packets that cannot be held
+    /// (because too many packets are already being held) are dropped.
+    Shape,
+}
+
+/// What tokens represent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateUnit {
+    Packets,
+    /// Bytes, including the Ethernet header.
+    Bytes,
+}
+
+/// Rate (tokens per second) and burst size (bucket depth, in tokens) of a shaper. Packets costing more than the burst
+/// size (e.g., when shaping bytes with a burst below the MTU) are let through once the bucket is full, after which the
+/// bucket has to refill from below zero. Clones share the same values, so the
+/// rate of a running pipeline can be changed by keeping a clone around and calling `set`.
+#[derive(Clone)]
+pub struct ShaperRate {
+    rate: Arc<AtomicUsize>,
+    burst: Arc<AtomicUsize>,
+}
+
+impl ShaperRate {
+    pub fn new(rate: usize, burst: usize) -> ShaperRate {
+        ShaperRate {
+            rate: Arc::new(AtomicUsize::new(rate)),
+            burst: Arc::new(AtomicUsize::new(burst)),
+        }
+    }
+
+    /// Change the rate and burst size. Takes effect the next time the shaper is processed.
+    pub fn set(&self, rate: usize, burst: usize) {
+        self.rate.store(rate, Ordering::Relaxed);
+        self.burst.store(burst, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn rate(&self) -> usize {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn burst(&self) -> usize {
+        self.burst.load(Ordering::Relaxed)
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_time: u64,
+    /// Number of this bucket's packets currently held.
+    held: usize,
+    /// Set to the current round once a held packet could not be released, so later packets (in the same bucket) are
+    /// not released ahead of it.
+    blocked_round: u64,
+}
+
+impl TokenBucket {
+    #[inline]
+    fn new(burst: usize, now: u64) -> TokenBucket {
+        TokenBucket {
+            tokens: burst as f64,
+            last_time: now,
+            held: 0,
+            blocked_round: 0,
+        }
+    }
+
+    #[inline]
+    fn refill(&mut self, now: u64, rate: usize, burst: usize) {
+        let elapsed = now.saturating_sub(self.last_time);
+        self.last_time = now;
+        self.tokens = (self.tokens + elapsed as f64 * rate as f64 / 1e9).min(burst as f64);
+    }
+
+    /// Take `cost` tokens if there are enough. A packet costing more than the burst size could never be paid for, so
+    /// it is let through whenever the bucket is full instead, leaving the bucket in debt.
+    #[inline]
+    fn consume(&mut self, cost: usize, burst: usize) -> bool {
+        if self.tokens >= cost as f64 || self.tokens >= burst as f64 {
+            self.tokens -= cost as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct HeldPacket {
+    mbuf: *mut MBuf,
+    header_offset: usize,
+    key: Flow,
+    cost: usize,
+}
+
+/// Rate limit packets using token buckets, either one for the whole batch (i.e., for the port and queue it was
+/// received on) or one per flow, as determined by a `ShapeKeyFn`. See `HeaderOperations::shape`.
+///
+/// Buckets are refilled based on the time elapsed since they were last used, each time the batch is processed, so no
+/// timers are needed; held packets are only released when the batch is processed, so the batch should be scheduled
+/// often enough for the configured rate. Per-packet context is not carried across a shaper.
+pub struct ShapeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    parent: V,
+    key: Option<ShapeKeyFn<T>>,
+    rate: ShaperRate,
+    unit: RateUnit,
+    mode: ShapingMode,
+    buckets: HashMap<Flow, TokenBucket, FnvHash>,
+    /// Packets to be processed by the rest of the pipeline.
+    queue: PacketBatch,
+    /// Offset (from the start of the packet) at which the header resides, for every packet in `queue`.
+    offsets: Vec<usize>,
+    held: VecDeque<HeldPacket>,
+    max_held: usize,
+    capacity: usize,
+    round: u64,
+    /// Packets dropped for exceeding the rate (or because too many packets were held).
+    pub dropped: u64,
+}
+
+impl<T, V> ShapeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V,
+               rate: ShaperRate,
+               unit: RateUnit,
+               mode: ShapingMode,
+               key: Option<ShapeKeyFn<T>>)
+               -> ShapeBatch<T, V> {
+        let capacity = parent.capacity() as usize;
+        let buckets = if key.is_some() { 1024 } else { 1 };
+        ShapeBatch {
+            parent: parent,
+            key: key,
+            rate: rate,
+            unit: unit,
+            mode: mode,
+            buckets: HashMap::with_capacity_and_hasher(buckets, Default::default()),
+            queue: PacketBatch::new(capacity as i32),
+            offsets: Vec::with_capacity(capacity),
+            held: VecDeque::with_capacity(capacity * HELD_BATCHES),
+            max_held: capacity * HELD_BATCHES,
+            capacity: capacity,
+            round: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Limit the number of packets held when shaping.
+    pub fn set_max_held(&mut self, max_held: usize) {
+        self.max_held = max_held;
+    }
+
+    /// Number of packets currently held.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    #[inline]
+    fn enqueue(&mut self, mbuf: *mut MBuf, header_offset: usize) {
+        // Callers make sure there is room.
+        assert!(self.queue.add_packet(mbuf));
+        self.offsets.push(header_offset);
+    }
+
+    /// Release held packets (in order) for which tokens are now available.
+    #[inline]
+    fn release_held(&mut self, now: u64, rate: usize, burst: usize) {
+        let round = self.round;
+        let mut still_held = VecDeque::with_capacity(self.held.len());
+        while let Some(packet) = self.held.pop_front() {
+            if self.queue.available() == self.capacity {
+                still_held.push_back(packet);
+                continue;
+            }
+            let released = {
+                let bucket = self.buckets.get_mut(&packet.key).expect("Held packet without a bucket");
+                if bucket.blocked_round != round {
+                    bucket.refill(now, rate, burst);
+                    if bucket.consume(packet.cost, burst) {
+                        bucket.held -= 1;
+                        true
+                    } else {
+                        bucket.blocked_round = round;
+                        false
+                    }
+                } else {
+                    false
+                }
+            };
+            if released {
+                self.enqueue(packet.mbuf, packet.header_offset);
+            } else {
+                still_held.push_back(packet);
+            }
+        }
+        self.held = still_held;
+    }
+
+    /// Charge packets from the parent, moving those that conform to `queue`.
+    #[inline]
+    fn admit(&mut self, now: u64, rate: usize, burst: usize) {
+        let mut idxes = Vec::<usize>::with_capacity(self.capacity);
+        let mut charges = Vec::<(Flow, usize, usize)>::with_capacity(self.capacity);
+        {
+            let iter = PayloadEnumerator::<T>::new(&mut self.parent);
+            while let Some(ParsedDescriptor { index: idx, header: head, payload, ctx, offset, segments }) =
+                      iter.next(&mut self.parent) {
+                let header_offset = offset - head.offset();
+                let cost = match self.unit {
+                    RateUnit::Packets => 1,
+                    RateUnit::Bytes => offset + payload.len() + segments.fold(0, |len, s| len + s.len()),
+                };
+                let key = match self.key {
+                    Some(ref mut key) => key(head, payload, ctx),
+                    None => Flow::default(),
+                };
+                idxes.push(idx);
+                charges.push((key, cost, header_offset));
+            }
+        }
+        if idxes.is_empty() {
+            return;
+        }
+        let mbufs = self.parent.remove_packets(idxes).expect("Shaping was performed incorrectly");
+        let mut dropped = Vec::<*mut MBuf>::with_capacity(mbufs.len());
+        for (mbuf, (key, cost, header_offset)) in mbufs.into_iter().zip(charges.into_iter()) {
+            let room = self.queue.available() < self.capacity;
+            let conforms = {
+                let bucket = self.buckets.entry(key).or_insert_with(|| TokenBucket::new(burst, now));
+                bucket.refill(now, rate, burst);
+                // Packets may not overtake held packets from the same bucket.
+                room && bucket.held == 0 && bucket.consume(cost, burst)
+            };
+            if conforms {
+                self.enqueue(mbuf, header_offset);
+            } else if self.mode == ShapingMode::Shape && self.held.len() < self.max_held {
+                self.buckets.get_mut(&key).expect("Missing bucket").held += 1;
+                self.held.push_back(HeldPacket {
+                    mbuf: mbuf,
+                    header_offset: header_offset,
+                    key: key,
+                    cost: cost,
+                });
+            } else {
+                dropped.push(mbuf);
+            }
+        }
+        self.dropped += dropped.len() as u64;
+        free_mbufs(&mut dropped).expect("Could not free dropped packets");
+    }
+
+    /// Forget buckets that are full and have nothing held, they are indistinguishable from new buckets.
+    #[inline]
+    fn collect_idle_buckets(&mut self, now: u64, rate: usize, burst: usize) {
+        if self.buckets.len() > MAX_IDLE_BUCKETS {
+            self.buckets.retain(|_, bucket| {
+                bucket.refill(now, rate, burst);
+                bucket.held > 0 || bucket.tokens < burst as f64
+            });
+        }
+    }
+}
+
+batch_no_new!{ShapeBatch}
+
+impl<T, V> Act for ShapeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn act(&mut self) {
+        self.round += 1;
+        let now = precise_time_ns();
+        let (rate, burst) = (self.rate.rate(), self.rate.burst());
+        self.release_held(now, rate, burst);
+        self.parent.act();
+        self.admit(now, rate, burst);
+        self.parent.done();
+        self.collect_idle_buckets(now, rate, burst);
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        // Free anything that was not sent.
+        self.queue.deallocate_batch().expect("Deallocation failed");
+        self.offsets.clear();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.queue.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.capacity as i32
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        let start = self.queue.start();
+        retain_offsets(&mut self.offsets, start, &idxes);
+        self.queue.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        let start = self.queue.start();
+        retain_offsets(&mut self.offsets, start, &idxes);
+        self.queue.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.queue.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.queue.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.queue.prepend_headroom_segment(idx, size)
+    }
+}
+
+impl<T, V> BatchIterator for ShapeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.queue.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        match queued_payload::<T>(&mut self.queue, &self.offsets, idx) {
+            Some((descriptor, next_idx)) => Some((descriptor, None, next_idx)),
+            None => None,
+        }
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.queue.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self, _: usize, _: i32) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        panic!("Cannot pop beyond a shaper")
+    }
+}
+
+impl<T, V> Drop for ShapeBatch<T, V>
+    where T: EndOffset,
+          V: Batch + BatchIterator + Act
+{
+    fn drop(&mut self) {
+        let mut held: Vec<_> = self.held.drain(..).map(|packet| packet.mbuf).collect();
+        let _ = free_mbufs(&mut held);
+    }
+}
@@ -0,0 +1,968 @@
+use io::{HardwareStats, PmdPort, QueueStats};
+use io::test_backend::mbufs_in_use;
+use utils::{ipv4_extract_flow, Flow, LatencyHistogram};
+use super::*;
+use super::act::Act;
+use std::ptr;
+
+const MAC_SRC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const MAC_DST: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+/// Build an Ethernet/IPv4 packet carrying `proto`, with a UDP style (ports + length) L4 header followed by `payload`
+/// bytes.
+fn packet(proto: u8, src_port: u16, payload: usize) -> Vec<u8> {
+    let l4_len = 8 + payload;
+    let ip_len = 20 + l4_len;
+    let mut pkt = vec![0u8; 14 + ip_len];
+    pkt[0..6].copy_from_slice(&MAC_DST);
+    pkt[6..12].copy_from_slice(&MAC_SRC);
+    pkt[12] = 0x08;
+    pkt[14] = 0x45;
+    pkt[16] = (ip_len >> 8) as u8;
+    pkt[17] = ip_len as u8;
+    pkt[22] = 64;
+    pkt[23] = proto;
+    pkt[26..30].copy_from_slice(&[10, 0, 0, 1]);
+    pkt[30..34].copy_from_slice(&[10, 0, 0, 2]);
+    pkt[34] = (src_port >> 8) as u8;
+    pkt[35] = src_port as u8;
+    pkt[36..38].copy_from_slice(&[0, 53]);
+    pkt[38] = (l4_len >> 8) as u8;
+    pkt[39] = l4_len as u8;
+    pkt
+}
+
+fn test_port() -> PmdPort {
+    PmdPort::new_simple_port(0, 0).expect("Could not create in-memory port")
+}
+
+#[test]
+fn receive_and_send() {
+    let port = test_port();
+    let pkts: Vec<_> = (0..3).map(|i| packet(17, i, 10)).collect();
+    assert_eq!(port.inject_packets(0, &pkts), 3);
+    ReceiveBatch::new(port.copy(), 0).send(port.copy(), 0).process();
+    assert_eq!(port.take_sent_packets(0), pkts);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn filter_drops_and_frees() {
+    let port = test_port();
+    let pkts = vec![packet(17, 1, 10), packet(6, 2, 10), packet(17, 3, 10), packet(6, 4, 10)];
+    port.inject_packets(0, &pkts);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .parse::<IpHeader>()
+        .filter(box |hdr, _, _| hdr.protocol() != 17)
+        .send(port.copy(), 0)
+        .process();
+    assert_eq!(port.take_sent_packets(0), vec![pkts[0].clone(), pkts[2].clone()]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn filter_drops_packets_it_matches() {
+    let port = test_port();
+    let pkts: Vec<_> = (0..8).map(|i| packet(17, i, 10)).collect();
+    for &(drop, ref expected) in &[(false, pkts.clone()), (true, vec![])] {
+        port.inject_packets(0, &pkts);
+        let mut calls = 0;
+        ReceiveBatch::new(port.copy(), 0)
+            .parse::<MacHeader>()
+            .parse::<IpHeader>()
+            .filter(box move |_, _, _| {
+                calls += 1;
+                assert!(calls <= 8, "Filter called more than once per packet");
+                drop
+            })
+            .send(port.copy(), 0)
+            .process();
+        assert_eq!(port.take_sent_packets(0), *expected);
+        assert_eq!(mbufs_in_use(), 0);
+    }
+}
+
+#[test]
+fn resize_payload() {
+    let port = test_port();
+    port.inject_packets(0, &[packet(17, 1, 10), packet(17, 2, 40)]);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .resize(box |_, payload, _| if payload.len() > 50 { -4 } else { 4 })
+        .send(port.copy(), 0)
+        .process();
+    let lens: Vec<_> = port.take_sent_packets(0).iter().map(|p| p.len()).collect();
+    assert_eq!(lens, vec![14 + 38 + 4, 14 + 68 - 4]);
+}
+
+#[test]
+fn deparse_returns_to_previous_header() {
+    let port = test_port();
+    port.inject_packets(0, &[packet(17, 1, 10)]);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .parse::<IpHeader>()
+        .transform(box |hdr, _, _| hdr.set_ttl(1))
+        .deparse::<MacHeader>()
+        .transform(box |hdr, payload, _| {
+            // Payload after deparsing is the IP header again.
+            assert_eq!(payload[8], 1);
+            let src = hdr.src;
+            hdr.src = hdr.dst;
+            hdr.dst = src;
+        })
+        .send(port.copy(), 0)
+        .process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(&sent[0][0..6], &MAC_SRC);
+    assert_eq!(&sent[0][6..12], &MAC_DST);
+    assert_eq!(sent[0][22], 1);
+}
+
+#[test]
+fn context_follows_packets_after_drop() {
+    let port = test_port();
+    let pkts: Vec<_> = (0..8).map(|i| packet(17, i, 10)).collect();
+    port.inject_packets(0, &pkts);
+    ReceiveBatch::new(port.copy(), 0)
+        .context::<u16>()
+        .parse::<MacHeader>()
+        .parse::<IpHeader>()
+        .parse::<UdpHeader>()
+        .transform(box |hdr, _, ctx| {
+            *ctx.and_then(|c| c.downcast_mut::<u16>()).expect("No context") = hdr.src_port();
+        })
+        .filter(box |hdr, _, _| hdr.src_port() % 3 != 0)
+        .map(box |hdr, _, ctx| {
+            assert_eq!(*ctx.and_then(|c| c.downcast_mut::<u16>()).expect("No context"),
+                       hdr.src_port());
+        })
+        .send(port.copy(), 0)
+        .process();
+    assert_eq!(port.take_sent_packets(0), vec![pkts[0].clone(), pkts[3].clone(), pkts[6].clone()]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn unsent_packets_are_freed() {
+    // A port whose transmit ring only holds 2 packets.
+    let port = PmdPort::new_with_one_queue(0, 0, 0, 32, 2, false, false, false).expect("Could not create port");
+    let pkts: Vec<_> = (0..5).map(|i| packet(17, i, 10)).collect();
+    port.inject_packets(0, &pkts);
+    let mut batch = ReceiveBatch::new(port.copy(), 0).send(port.copy(), 0);
+    batch.process();
+    assert_eq!(batch.sent, 2);
+    assert_eq!(port.take_sent_packets(0).len(), 2);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn port_stats_count_drops() {
+    // Room for 4 received and 2 sent packets.
+    let port = PmdPort::new_with_one_queue(0, 0, 0, 4, 2, false, false, false).expect("Could not create port");
+    let pkts: Vec<_> = (0..6).map(|i| packet(17, i, 10)).collect();
+    assert_eq!(port.inject_packets(0, &pkts), 4);
+    let before = port.port_stats();
+    ReceiveBatch::new(port.copy(), 0).send(port.copy(), 0).process();
+    let stats = port.port_stats();
+    assert_eq!((stats.rx_packets(), stats.tx_packets(), stats.tx_dropped()), (4, 2, 2));
+    assert_eq!(stats.rx_bytes(), 4 * pkts[0].len() as u64);
+    assert_eq!(stats.tx_bytes(), 2 * pkts[0].len() as u64);
+    assert_eq!(stats.hardware.rx_missed, 2);
+    assert_eq!(stats.hardware.tx_packets, 2);
+
+    port.take_sent_packets(0);
+    port.inject_packets(0, &pkts[..1]);
+    ReceiveBatch::new(port.copy(), 0).send(port.copy(), 0).process();
+    let delta = port.port_stats().delta(&stats);
+    assert_eq!((delta.rx_packets(), delta.tx_packets(), delta.tx_dropped()), (1, 1, 0));
+    assert_eq!(port.port_stats().delta(&before).tx_packets(), 3);
+    port.take_sent_packets(0);
+
+    port.reset_stats();
+    assert_eq!(port.port_stats().queues[0], QueueStats::default());
+    assert_eq!(port.port_stats().hardware, HardwareStats::default());
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn tx_policies() {
+    let port = PmdPort::new_with_one_queue(0, 0, 0, 32, 2, false, false, false).expect("Could not create port");
+    let pkts: Vec<_> = (0..5).map(|i| packet(17, i, 10)).collect();
+
+    // The in-memory transmit ring never drains by itself, so retrying does not help.
+    let mut retry_port = port.copy();
+    retry_port.set_tx_policy(TxPolicy::Retry(3));
+    port.inject_packets(0, &pkts);
+    ReceiveBatch::new(port.copy(), 0).send(retry_port, 0).process();
+    let stats = port.port_stats().queues[0];
+    assert_eq!((stats.tx_packets, stats.tx_retries, stats.tx_drops.retry), (2, 3, 3));
+    assert_eq!(port.take_sent_packets(0), &pkts[..2]);
+
+    let mut buffer_port = port.copy();
+    buffer_port.set_tx_policy(TxPolicy::Buffer(2));
+    port.inject_packets(0, &pkts);
+    let mut batch = ReceiveBatch::new(port.copy(), 0).send(buffer_port, 0);
+    batch.process();
+    assert_eq!(port.take_sent_packets(0), &pkts[..2]);
+    // The two buffered packets go out ahead of the next batch, the ring is full again before the new ones are sent.
+    port.inject_packets(0, &pkts[..1]);
+    batch.process();
+    assert_eq!(port.take_sent_packets(0), &pkts[2..4]);
+    batch.process();
+    assert_eq!(port.take_sent_packets(0), &pkts[..1]);
+    let stats = port.port_stats().queues[0];
+    assert_eq!((stats.tx_drops.drop, stats.tx_drops.retry, stats.tx_drops.buffer), (0, 3, 1));
+    assert_eq!(stats.tx_dropped, 4);
+    drop(batch);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn adaptive_burst_size() {
+    let port = test_port();
+    let pkts: Vec<_> = (0..32).map(|i| packet(17, i, 10)).collect();
+    let mut batch = ReceiveBatch::new_adaptive(port.copy(), 0, 4, 32);
+    assert_eq!((batch.burst_size(), batch.capacity()), (4, 32));
+    // Keep the receive ring full, the burst should grow to the maximum.
+    for _ in 0..256 {
+        port.inject_packets(0, &pkts);
+        batch.act();
+        batch.done();
+    }
+    assert_eq!(batch.burst_size(), 32);
+    // Once traffic stops it should shrink back.
+    for _ in 0..512 {
+        batch.act();
+        batch.done();
+    }
+    assert_eq!(batch.burst_size(), 4);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn demux_by_protocol() {
+    let port = test_port();
+    let pkts = vec![packet(17, 1, 10), packet(6, 2, 10), packet(1, 3, 10), packet(17, 4, 10)];
+    port.inject_packets(0, &pkts);
+    let mut branches = ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .parse::<IpHeader>()
+        .demux(2,
+               box |hdr, _, _| match hdr.protocol() {
+                   17 => Some(0),
+                   6 => Some(1),
+                   _ => None,
+               });
+    let tcp = branches.pop().unwrap();
+    let udp = branches.pop().unwrap();
+    let mut udp = udp.parse::<UdpHeader>()
+        .map(box |hdr, _, _| assert!(hdr.src_port() == 1 || hdr.src_port() == 4))
+        .send(port.copy(), 0);
+    let mut tcp = tcp.send(port.copy(), 0);
+    // Whichever branch runs first pulls packets for both.
+    tcp.process();
+    assert_eq!(port.take_sent_packets(0), vec![pkts[1].clone()]);
+    udp.process();
+    assert_eq!(port.take_sent_packets(0), vec![pkts[0].clone(), pkts[3].clone()]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn group_by_sends_each_group_out_its_own_port() {
+    let udp_port = test_port();
+    let tcp_port = PmdPort::new_simple_port(1, 0).expect("Could not create in-memory port");
+    let pkts = vec![packet(17, 1, 10), packet(6, 2, 10), packet(17, 3, 10), packet(1, 4, 10)];
+    udp_port.inject_packets(0, &pkts);
+    let (udp_out, tcp_out) = (udp_port.copy(), tcp_port.copy());
+    let mut groups = ReceiveBatch::new(udp_port.copy(), 0)
+        .parse::<MacHeader>()
+        .parse::<IpHeader>()
+        .group_by(2,
+                  box |hdr, _, _| match hdr.protocol() {
+                      17 => Some(0),
+                      6 => Some(1),
+                      _ => None,
+                  },
+                  box move |group, batch| match group {
+                      0 => batch.transform(box |hdr, _, _| hdr.set_ttl(1)).send(udp_out.copy(), 0).compose(),
+                      _ => batch.send(tcp_out.copy(), 0).compose(),
+                  });
+    assert_eq!(groups.groups(), 2);
+    groups.process();
+    let udp_sent = udp_port.take_sent_packets(0);
+    assert_eq!(udp_sent.len(), 2);
+    assert!(udp_sent.iter().all(|p| p[22] == 1));
+    assert_eq!(tcp_port.take_sent_packets(0), vec![pkts[1].clone()]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn group_by_composes_with_other_pipelines() {
+    let port = PmdPort::new_mq_port(0, 1, 2, &[0], &[0, 0]).expect("Could not create in-memory port");
+    let other = PmdPort::new_simple_port(1, 0).expect("Could not create in-memory port");
+    let pkts = vec![packet(17, 1, 10), packet(6, 2, 10), packet(17, 3, 10)];
+    port.inject_packets(0, &pkts);
+    other.inject_packets(0, &[packet(17, 4, 10)]);
+    let out = port.copy();
+    let groups = ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .parse::<IpHeader>()
+        .group_by(2,
+                  box |hdr, _, _| if hdr.protocol() == 17 { Some(0) } else { Some(1) },
+                  box move |group, batch| batch.send(out.copy(), group as i32).compose());
+    let mut merged = merge(vec![groups.compose(), ReceiveBatch::new(other.copy(), 0).send(other.copy(), 0).compose()]);
+    // Groups take turns with the other pipeline, the second group runs over the batch the first one was received with.
+    merged.process();
+    assert_eq!(port.take_sent_packets(0), vec![pkts[0].clone(), pkts[2].clone()]);
+    assert!(port.take_sent_packets(1).is_empty());
+    merged.process();
+    assert_eq!(other.take_sent_packets(0).len(), 1);
+    port.inject_packets(0, &pkts);
+    merged.process();
+    assert_eq!(port.take_sent_packets(1), vec![pkts[1].clone()]);
+    assert!(port.take_sent_packets(0).is_empty());
+    // The next round receives the packets injected in the meantime.
+    merged.process();
+    merged.process();
+    assert_eq!(port.take_sent_packets(0).len(), 2);
+    assert_eq!(mbufs_in_use(), 1);
+    merged.process();
+    merged.process();
+    assert_eq!(port.take_sent_packets(1).len(), 1);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn merge_with_strict_priority() {
+    let bulk = test_port();
+    let urgent = PmdPort::new_simple_port(1, 0).expect("Could not create in-memory port");
+    let out = PmdPort::new_simple_port(2, 0).expect("Could not create in-memory port");
+    bulk.inject_packets(0, &[packet(17, 1, 10)]);
+    urgent.inject_packets(0, &[packet(17, 2, 10)]);
+    let mut merged = merge_with_scheduler(vec![ReceiveBatch::new(bulk.copy(), 0).compose(),
+                                               ReceiveBatch::new(urgent.copy(), 0).compose()],
+                                          box StrictPriority::new(&[0, 1]))
+        .send(out.copy(), 0);
+    let src_ports = |sent: Vec<Vec<u8>>| sent.iter().map(|p| p[35]).collect::<Vec<_>>();
+    // The first batch is processed before the scheduler has had a say.
+    merged.process();
+    assert_eq!(src_ports(out.take_sent_packets(0)), vec![1]);
+    // The urgent batch is polled next, and is polled again as long as it keeps producing packets.
+    merged.process();
+    assert_eq!(src_ports(out.take_sent_packets(0)), vec![2]);
+    urgent.inject_packets(0, &[packet(17, 3, 10)]);
+    bulk.inject_packets(0, &[packet(17, 4, 10)]);
+    merged.process();
+    merged.process();
+    assert_eq!(src_ports(out.take_sent_packets(0)), vec![3]);
+    // Once the urgent batch is empty, the bulk batch gets a turn.
+    merged.process();
+    assert_eq!(src_ports(out.take_sent_packets(0)), vec![4]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn merge_sent_pipelines() {
+    // Real NFs merge pipelines that end in a send, which cannot be iterated once they have acted.
+    let bulk = test_port();
+    let urgent = PmdPort::new_simple_port(1, 0).expect("Could not create in-memory port");
+    let pipelines = |scheduler: Option<Box<MergeScheduler>>| {
+        let batches = vec![ReceiveBatch::new(bulk.copy(), 0).send(bulk.copy(), 0).compose(),
+                           ReceiveBatch::new(urgent.copy(), 0).send(urgent.copy(), 0).compose()];
+        match scheduler {
+            Some(scheduler) => merge_with_scheduler(batches, scheduler),
+            None => merge(batches),
+        }
+    };
+    let mut round_robin = pipelines(None);
+    bulk.inject_packets(0, &[packet(17, 1, 10)]);
+    urgent.inject_packets(0, &[packet(17, 2, 10)]);
+    round_robin.process();
+    round_robin.process();
+    assert_eq!(bulk.take_sent_packets(0).len(), 1);
+    assert_eq!(urgent.take_sent_packets(0).len(), 1);
+
+    let mut priority = pipelines(Some(box StrictPriority::new(&[0, 1])));
+    bulk.inject_packets(0, &[packet(17, 3, 10)]);
+    priority.process();
+    assert_eq!(priority.occupancy(), &[1, 0]);
+    assert_eq!(bulk.take_sent_packets(0).len(), 1);
+    urgent.inject_packets(0, &[packet(17, 4, 10), packet(17, 5, 10)]);
+    bulk.inject_packets(0, &[packet(17, 6, 10)]);
+    priority.process();
+    assert_eq!(priority.occupancy(), &[1, 2]);
+    // The urgent pipeline received packets, so it is polled again before the bulk one gets its turn.
+    priority.process();
+    assert_eq!(priority.occupancy(), &[1, 0]);
+    assert_eq!(bulk.take_sent_packets(0).len(), 0);
+    priority.process();
+    assert_eq!(bulk.take_sent_packets(0).len(), 1);
+    assert_eq!(urgent.take_sent_packets(0).len(), 2);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn chained_packets() {
+    let port = test_port();
+    // Larger than a single mbuf, so these are received as a chain of two segments.
+    let pkts = vec![packet(17, 1, 3000), packet(17, 2, 2058)];
+    port.inject_packets(0, &pkts);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .resize(box |_, _, _| -200)
+        .linearize()
+        .map(box |_, payload, _| assert_eq!(payload.len(), 2100 - 200 - 14))
+        .send(port.copy(), 0)
+        .process();
+    // The first packet is still too large for a single segment after shrinking, and is dropped.
+    assert_eq!(port.take_sent_packets(0), vec![pkts[1][..1900].to_vec()]);
+
+    // Growing a packet past the end of its buffer adds a segment.
+    let pkt = packet(17, 3, 2000);
+    port.inject_packets(0, &[pkt.clone()]);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .resize(box |_, _, _| 100)
+        .send(port.copy(), 0)
+        .process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(sent[0].len(), pkt.len() + 100);
+    assert_eq!(&sent[0][..pkt.len()], &pkt[..]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn prepend_headroom_segment() {
+    let mut port = test_port();
+    let pkt = packet(17, 1, 10);
+    port.inject_packets(0, &[pkt.clone()]);
+    let mut batch = ReceiveBatch::new(port.copy(), 0).parse::<MacHeader>();
+    batch.act();
+    // Running out of headroom fails rather than adding a segment behind the caller's back.
+    assert_eq!(batch.adjust_headroom(0, 4096), None);
+    assert_eq!(batch.prepend_headroom_segment(0, 4096), None);
+    assert_eq!(batch.prepend_headroom_segment(0, 200), Some(200));
+    unsafe {
+        let (desc, _, _) = batch.next_base_payload(0).unwrap();
+        assert_eq!((*desc.mbuf).data_len(), 200);
+        assert_eq!((*desc.mbuf).pkt_len(), pkt.len() + 200);
+        ptr::write_bytes((*desc.mbuf).data_address(0), 0xff, 200);
+    }
+    batch.send_queue(&mut port, 0).unwrap();
+    batch.done();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(&sent[0][..200], &[0xff; 200][..]);
+    assert_eq!(&sent[0][200..], &pkt[..]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn replicate_to_several_ports() {
+    for replication in vec![Replication::Copy, Replication::Reference] {
+        {
+            let port = test_port();
+            let mirror = PmdPort::new_simple_port(1, 0).expect("Could not create in-memory port");
+            let pkts: Vec<_> = (0..4).map(|i| packet(17, i, 10)).collect();
+            port.inject_packets(0, &pkts);
+            let mut copies = ReceiveBatch::new(port.copy(), 0).parse::<MacHeader>().replicate(2, replication);
+            let mut mirrored = copies.pop().unwrap().send(mirror.copy(), 0);
+            let mut original = copies.pop().unwrap().send(port.copy(), 0);
+            original.process();
+            mirrored.process();
+            assert_eq!(port.take_sent_packets(0), pkts);
+            assert_eq!(mirror.take_sent_packets(0), pkts);
+        }
+        assert_eq!(mbufs_in_use(), 0);
+    }
+}
+
+#[test]
+fn generate_flow_mix() {
+    let port = test_port();
+    let mut generator = GeneratorBatch::new(packet(17, 0, 10), 4);
+    generator.set_sizes(PacketSizes::Fixed(100));
+    generator.set_flows((0..2)
+        .map(|i| {
+            Flow {
+                src_ip: 0x0a000001,
+                dst_ip: 0x0a000002,
+                src_port: 1000 + i,
+                dst_port: 53,
+                proto: 17,
+            }
+        })
+        .collect());
+    generator.parse::<MacHeader>()
+        .parse::<IpHeader>()
+        .map(box |hdr, _, _| assert!(hdr.checksum_valid()))
+        .send(port.copy(), 0)
+        .process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(sent.len(), 4);
+    assert!(sent.iter().all(|p| p.len() == 100 && p[17] == 86 && p[39] == 66));
+    assert_eq!(sent.iter().map(|p| p[35]).collect::<Vec<_>>(), vec![0xe8, 0xe9, 0xe8, 0xe9]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn generate_tcp_flows() {
+    use headers::{fold_checksum, ipv4_pseudo_header_sum, ones_complement_sum};
+
+    let port = test_port();
+    let mut template = packet(6, 0, 20);
+    template[38..42].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+    template[46] = 0x50;
+    let mut generator = GeneratorBatch::new(template, 2);
+    generator.set_flows(vec![Flow {
+                                 src_ip: 0x0a000001,
+                                 dst_ip: 0x0a000002,
+                                 src_port: 1000,
+                                 dst_port: 80,
+                                 proto: 6,
+                             }]);
+    generator.send(port.copy(), 0).process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(sent.len(), 2);
+    for pkt in sent {
+        assert_eq!(&pkt[34..38], &[0x03, 0xe8, 0, 80]);
+        // Rewriting the ports must leave the sequence number alone, and the checksum must be correct.
+        assert_eq!(&pkt[38..42], &[0x12, 0x34, 0x56, 0x78]);
+        let pseudo = ipv4_pseudo_header_sum(0x0a000001, 0x0a000002, 6, (pkt.len() - 34) as u16);
+        assert_eq!(fold_checksum(ones_complement_sum(&pkt[34..], pseudo)), 0);
+    }
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+#[should_panic(expected = "min <= max")]
+fn generate_empty_size_range() {
+    GeneratorBatch::new(packet(17, 0, 10), 4).set_sizes(PacketSizes::Uniform(100, 64));
+}
+
+#[test]
+#[should_panic(expected = "limited to 2048 bytes")]
+fn generate_oversized_packets() {
+    GeneratorBatch::new(packet(17, 0, 10), 4).set_sizes(PacketSizes::Weighted(vec![(64, 7), (9000, 1)]));
+}
+
+#[test]
+#[should_panic(expected = "limited to 2048 bytes")]
+fn generate_from_oversized_template() {
+    GeneratorBatch::new(vec![0; MAX_GENERATED_SIZE + 1], 4);
+}
+
+#[test]
+fn police_per_flow() {
+    let port = test_port();
+    let pkts: Vec<_> = (0..6).map(|i| packet(17, i % 2, 10)).collect();
+    port.inject_packets(0, &pkts);
+    // Each flow may send a burst of 2 packets, and does not earn any more tokens.
+    let mut batch = ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .shape_flows(ShaperRate::new(0, 2),
+                     RateUnit::Packets,
+                     ShapingMode::Police,
+                     box |_, payload, _| ipv4_extract_flow(payload))
+        .send(port.copy(), 0);
+    batch.process();
+    assert_eq!(port.take_sent_packets(0), pkts[0..4].to_vec());
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn shaping_holds_packets_until_rate_allows() {
+    let port = test_port();
+    let pkts: Vec<_> = (0..5).map(|i| packet(17, i, 10)).collect();
+    port.inject_packets(0, &pkts);
+    let rate = ShaperRate::new(0, 2);
+    let mut batch = ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .shape(rate.clone(), RateUnit::Packets, ShapingMode::Shape)
+        .send(port.copy(), 0);
+    batch.process();
+    assert_eq!(port.take_sent_packets(0), pkts[0..2].to_vec());
+    batch.process();
+    assert_eq!(port.take_sent_packets(0).len(), 0);
+    // Raising the rate lets held packets through, in order.
+    rate.set(1000000000, 3);
+    ::std::thread::sleep(::std::time::Duration::from_millis(1));
+    batch.process();
+    assert_eq!(port.take_sent_packets(0), pkts[2..5].to_vec());
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn shape_packets_larger_than_burst() {
+    let port = test_port();
+    let pkts: Vec<_> = (0..3).map(|i| packet(17, i, 10)).collect();
+    port.inject_packets(0, &pkts);
+    // Every packet costs more than the bucket holds.
+    let rate = ShaperRate::new(0, 40);
+    let mut batch = ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .shape(rate.clone(), RateUnit::Bytes, ShapingMode::Shape)
+        .send(port.copy(), 0);
+    batch.process();
+    assert_eq!(port.take_sent_packets(0), pkts[0..1].to_vec());
+    // Each time the bucket fills up again, the next packet goes out.
+    rate.set(1000000000, 40);
+    for pkt in &pkts[1..] {
+        ::std::thread::sleep(::std::time::Duration::from_millis(1));
+        batch.process();
+        assert_eq!(&port.take_sent_packets(0), &[pkt.clone()]);
+    }
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn measure_latency() {
+    let port = test_port();
+    port.inject_packets(0, &[packet(17, 1, 10), packet(17, 2, 10), packet(17, 3, 10)]);
+    let histogram = LatencyHistogram::new();
+    ReceiveBatch::new(port.copy(), 0)
+        .timestamp()
+        .parse::<MacHeader>()
+        .measure_latency(histogram.clone())
+        .send(port.copy(), 0)
+        .process();
+    assert_eq!(port.take_sent_packets(0).len(), 3);
+    assert_eq!(histogram.count(), 3);
+
+    histogram.reset();
+    for ns in 1..1001 {
+        histogram.record(ns);
+    }
+    assert_eq!(histogram.percentile(0.0), 1);
+    for &(percentile, expected) in &[(50.0, 500), (99.0, 990), (99.9, 999)] {
+        let value = histogram.percentile(percentile);
+        assert!(value <= expected && value as f64 >= expected as f64 * 0.97);
+    }
+}
+
+#[test]
+fn arp_responder() {
+    use state::NeighbourCache;
+
+    let port = test_port();
+    let our_mac = port.mac_address().addr;
+    let arp = |oper: u8, sender_ip: [u8; 4], target_ip: [u8; 4]| {
+        let mut pkt = vec![0u8; 60];
+        pkt[0..6].copy_from_slice(&[0xff; 6]);
+        pkt[6..12].copy_from_slice(&MAC_SRC);
+        pkt[12..14].copy_from_slice(&[0x08, 0x06]);
+        pkt[14..22].copy_from_slice(&[0, 1, 0x08, 0, 6, 4, 0, oper]);
+        pkt[22..28].copy_from_slice(&MAC_SRC);
+        pkt[28..32].copy_from_slice(&sender_ip);
+        pkt[38..42].copy_from_slice(&target_ip);
+        pkt
+    };
+    let cache = NeighbourCache::new();
+    cache.insert(0x0a000005, [0; 6]);
+    port.inject_packets(0,
+                        &[arp(1, [10, 0, 0, 2], [10, 0, 0, 1]),
+                          arp(1, [10, 0, 0, 2], [10, 0, 0, 9]),
+                          packet(17, 1000, 10),
+                          arp(2, [10, 0, 0, 3], [10, 0, 0, 1]),
+                          arp(1, [10, 0, 0, 4], [10, 0, 0, 9]),
+                          arp(2, [10, 0, 0, 5], [10, 0, 0, 9])]);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .respond_arp(port.copy(), 0, vec![0x0a000001], cache.clone())
+        .send(port.copy(), 0)
+        .process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(sent.len(), 2);
+    let reply = &sent[0];
+    assert_eq!(&reply[0..6], &MAC_SRC);
+    assert_eq!(&reply[6..12], &our_mac);
+    assert_eq!(&reply[20..22], &[0, 2]);
+    assert_eq!(&reply[22..28], &our_mac);
+    assert_eq!(&reply[28..32], &[10, 0, 0, 1]);
+    assert_eq!(&reply[32..38], &MAC_SRC);
+    assert_eq!(&reply[38..42], &[10, 0, 0, 2]);
+    assert_eq!(sent[1], packet(17, 1000, 10));
+
+    // Both senders of packets for us were learned, and can be used to address packets. Senders of packets for other
+    // hosts are only learned if they were already known.
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.lookup(0x0a000003), Some(MAC_SRC));
+    assert_eq!(cache.lookup(0x0a000004), None);
+    assert_eq!(cache.lookup(0x0a000005), Some(MAC_SRC));
+    let mut mac = MacHeader::new();
+    assert!(cache.fill_dst(&mut mac, 0x0a000002));
+    assert_eq!(mac.dst, MAC_SRC);
+    assert!(!cache.fill_dst(&mut MacHeader::new(), 0x0a000004));
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn icmp_responses() {
+    use headers::checksum;
+
+    let port = test_port();
+    let our_mac = port.mac_address().addr;
+    let mut echo = packet(1, 0x0800, 10);
+    echo[36..38].copy_from_slice(&[0, 0]);
+    let csum = checksum(&echo[34..]);
+    echo[36] = (csum >> 8) as u8;
+    echo[37] = csum as u8;
+    let mut expired = packet(17, 1000, 100);
+    expired[22] = 1;
+    expired[33] = 9;
+    let forwarded = packet(17, 1000, 10);
+    port.inject_packets(0, &[echo.clone(), expired.clone(), forwarded.clone()]);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .parse::<IpHeader>()
+        .respond_icmp(port.copy(), 0, 0x0a0000fe, router_icmp(vec![0x0a000002], 1500))
+        .send(port.copy(), 0)
+        .process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(sent.len(), 3);
+
+    // Echo reply: addresses swapped, type changed, checksum still valid.
+    let reply = &sent[0];
+    assert_eq!(&reply[0..6], &MAC_SRC);
+    assert_eq!(&reply[6..12], &our_mac);
+    assert_eq!(&reply[26..30], &[10, 0, 0, 2]);
+    assert_eq!(&reply[30..34], &[10, 0, 0, 1]);
+    assert_eq!(reply[34], 0);
+    assert_eq!(checksum(&reply[34..]), 0);
+
+    // Time exceeded: new headers followed by the original IP header and 8 bytes of its payload.
+    let error = &sent[1];
+    assert_eq!(error.len(), 14 + 28 + 28);
+    assert_eq!(&error[0..6], &MAC_SRC);
+    assert_eq!(&error[6..12], &our_mac);
+    assert_eq!(&error[12..14], &[0x08, 0x00]);
+    assert_eq!(&error[14..18], &[0x45, 0, 0, 56]);
+    assert_eq!(error[23], 1);
+    assert_eq!(&error[26..30], &[10, 0, 0, 254]);
+    assert_eq!(&error[30..34], &[10, 0, 0, 1]);
+    assert_eq!(checksum(&error[14..34]), 0);
+    assert_eq!(&error[34..36], &[11, 0]);
+    assert_eq!(checksum(&error[34..]), 0);
+    assert_eq!(&error[42..], &expired[14..42]);
+
+    assert_eq!(sent[2], forwarded);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn icmpv6_responses() {
+    use headers::{fold_checksum, ipv6_pseudo_header_sum, ones_complement_sum};
+    use std::net::Ipv6Addr;
+
+    let addr = |last: u8| [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, last];
+    let ipv6 = |next_header: u8, hop_limit: u8, dst: u8, payload: &[u8]| {
+        let mut pkt = vec![0u8; 54];
+        pkt[0..6].copy_from_slice(&MAC_DST);
+        pkt[6..12].copy_from_slice(&MAC_SRC);
+        pkt[12..14].copy_from_slice(&[0x86, 0xdd]);
+        pkt[14] = 0x60;
+        pkt[18] = (payload.len() >> 8) as u8;
+        pkt[19] = payload.len() as u8;
+        pkt[20] = next_header;
+        pkt[21] = hop_limit;
+        pkt[22..38].copy_from_slice(&addr(1));
+        pkt[38..54].copy_from_slice(&addr(dst));
+        pkt.extend_from_slice(payload);
+        pkt
+    };
+    let icmpv6_checksum = |pkt: &[u8]| {
+        let (mut src, mut dst) = ([0u8; 16], [0u8; 16]);
+        src.copy_from_slice(&pkt[22..38]);
+        dst.copy_from_slice(&pkt[38..54]);
+        let pseudo = ipv6_pseudo_header_sum(&src, &dst, 58, (pkt.len() - 54) as u32);
+        fold_checksum(ones_complement_sum(&pkt[54..], pseudo))
+    };
+
+    let port = test_port();
+    let our_mac = port.mac_address().addr;
+    let mut echo = ipv6(58, 64, 2, &[128, 0, 0, 0, 0, 1, 0, 1, 1, 2, 3, 4]);
+    let csum = icmpv6_checksum(&echo);
+    echo[56] = (csum >> 8) as u8;
+    echo[57] = csum as u8;
+    let expired = ipv6(17, 1, 9, &[7; 100]);
+    let too_big = ipv6(17, 64, 9, &[7; 1500]);
+    let forwarded = ipv6(17, 64, 9, &[7; 10]);
+    port.inject_packets(0, &[echo.clone(), expired.clone(), too_big.clone(), forwarded.clone()]);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .parse::<Ipv6Header>()
+        .respond_icmpv6(port.copy(),
+                        0,
+                        Ipv6Addr::from(addr(0xfe)),
+                        router_icmpv6(vec![Ipv6Addr::from(addr(2))], 1500))
+        .send(port.copy(), 0)
+        .process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(sent.len(), 4);
+
+    // Echo reply: addresses swapped, type changed, checksum still valid.
+    let reply = &sent[0];
+    assert_eq!(&reply[0..6], &MAC_SRC);
+    assert_eq!(&reply[6..12], &our_mac);
+    assert_eq!(&reply[22..38], &addr(2));
+    assert_eq!(&reply[38..54], &addr(1));
+    assert_eq!(reply[54], 129);
+    assert_eq!(icmpv6_checksum(reply), 0);
+
+    // Time exceeded: new headers followed by the whole original packet.
+    let error = &sent[1];
+    assert_eq!(error.len(), 14 + 48 + 140);
+    assert_eq!(&error[0..6], &MAC_SRC);
+    assert_eq!(&error[6..12], &our_mac);
+    assert_eq!(&error[12..14], &[0x86, 0xdd]);
+    assert_eq!(&error[14..22], &[0x60, 0, 0, 0, 0, 148, 58, 64]);
+    assert_eq!(&error[22..38], &addr(0xfe));
+    assert_eq!(&error[38..54], &addr(1));
+    assert_eq!(&error[54..56], &[3, 0]);
+    assert_eq!(icmpv6_checksum(error), 0);
+    assert_eq!(&error[62..], &expired[14..]);
+
+    // Packet too big: the next hop MTU is reported, and the quote is cut to fit the minimum IPv6 MTU.
+    let error = &sent[2];
+    assert_eq!(error.len(), 14 + 1280);
+    assert_eq!(&error[54..56], &[2, 0]);
+    assert_eq!(&error[58..62], &[0, 0, 0x05, 0xdc]);
+    assert_eq!(icmpv6_checksum(error), 0);
+    assert_eq!(&error[62..], &too_big[14..14 + 1232]);
+
+    assert_eq!(sent[3], forwarded);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn vlan_push_pop() {
+    use super::iterator::BatchIterator;
+    use super::packet_batch::PacketBatch;
+    use std::ptr;
+
+    let port = test_port();
+    let plain = packet(17, 1000, 10);
+    let tag = |pkt: &[u8], tpid: u16, tci: u16| {
+        let mut tagged = pkt[0..12].to_vec();
+        tagged.extend_from_slice(&[(tpid >> 8) as u8, tpid as u8, (tci >> 8) as u8, tci as u8]);
+        tagged.extend_from_slice(&pkt[12..]);
+        tagged
+    };
+    let tagged = tag(&plain, ETHERTYPE_VLAN, 5);
+    // The second packet's tag was stripped on receive.
+    let batch = |pkts: &[&[u8]]| {
+        let mut batch = PacketBatch::new(pkts.len() as i32);
+        let len = pkts.iter().map(|pkt| pkt.len()).max().unwrap_or(0);
+        batch.allocate_batch_with_size(len as u16).expect("Could not allocate packets");
+        for (i, pkt) in pkts.iter().enumerate() {
+            batch.adjust_payload_size(i, pkt.len() as isize - len as isize);
+            unsafe {
+                let mbuf = batch.next_base_payload(i).expect("Packet missing").0.mbuf;
+                ptr::copy_nonoverlapping(pkt.as_ptr(), (*mbuf).data_address(0), pkt.len());
+                if i == 1 {
+                    (*mbuf).set_rx_vlan_tci(7);
+                }
+            }
+        }
+        batch
+    };
+
+    batch(&[&plain, &plain, &tagged])
+        .parse::<MacHeader>()
+        .push_vlan(0x2005)
+        .map(box |mac, _, _| {
+            let vlan = mac.vlan().expect("Packet is not tagged");
+            assert_eq!((vlan.vid(), vlan.pcp(), vlan.dei()), (5, 1, false));
+            assert_eq!(mac.inner_etype(), 0x0800);
+        })
+        .send(port.copy(), 0)
+        .process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(sent,
+               vec![tag(&plain, ETHERTYPE_VLAN, 0x2005),
+                    tag(&tag(&plain, ETHERTYPE_VLAN, 7), ETHERTYPE_VLAN, 0x2005),
+                    tag(&tagged, ETHERTYPE_VLAN, 0x2005)]);
+
+    batch(&[&plain, &plain, &tag(&tagged, ETHERTYPE_QINQ, 9)])
+        .parse::<MacHeader>()
+        .pop_vlan()
+        .send(port.copy(), 0)
+        .process();
+    let sent = port.take_sent_packets(0);
+    assert_eq!(sent, vec![plain.clone(), plain.clone(), tagged.clone()]);
+    assert_eq!(mbufs_in_use(), 0);
+}
+
+#[test]
+fn tunnel_encap_decap() {
+    use headers::checksum;
+
+    let port = test_port();
+    let inner: Vec<_> = (0..2).map(|i| packet(17, i, 10)).collect();
+    let mut mac = MacHeader::new();
+    mac.dst = MAC_DST;
+    mac.src = MAC_SRC;
+    let mut ip = IpHeader::new();
+    ip.set_src(0xc0a80001);
+    ip.set_dst(0xc0a80002);
+    ip.set_ttl(64);
+    let templates = vec![(TunnelTemplate::vxlan(&mac, &ip, 5000, 42), 17),
+                         (TunnelTemplate::gre(&mac, &ip, Some(42)), 47),
+                         (TunnelTemplate::geneve(&mac, &ip, 5000, 42), 17)];
+    for (template, proto) in templates {
+        let len = template.len();
+        let tunnel = template.tunnel();
+        port.inject_packets(0, &inner);
+        ReceiveBatch::new(port.copy(), 0)
+            .parse::<MacHeader>()
+            .encap(template)
+            .send(port.copy(), 0)
+            .process();
+        let sent = port.take_sent_packets(0);
+        assert_eq!(sent.len(), inner.len());
+        for (outer, pkt) in sent.iter().zip(inner.iter()) {
+            assert_eq!(outer.len(), len + pkt.len());
+            assert_eq!(&outer[len..], &pkt[..]);
+            assert_eq!(&outer[12..14], &[0x08, 0x00]);
+            assert_eq!(outer[23], proto);
+            let ip_len = outer.len() - 14;
+            assert_eq!(&outer[16..18], &[(ip_len >> 8) as u8, ip_len as u8]);
+            assert_eq!(checksum(&outer[14..34]), 0);
+            if proto == 17 {
+                assert_eq!(&outer[38..40], &[((ip_len - 20) >> 8) as u8, (ip_len - 20) as u8]);
+                // VXLAN and Geneve both put the VNI in the same place.
+                assert_eq!(&outer[46..49], &[0, 0, 42]);
+            } else {
+                assert_eq!(&outer[34..38], &[0x20, 0x00, 0x65, 0x58]);
+                assert_eq!(&outer[38..42], &[0, 0, 0, 42]);
+            }
+        }
+
+        // Decapsulation restores the inner packets, and leaves packets which are not tunneled alone.
+        port.inject_packets(0, &sent);
+        port.inject_packets(0, &inner[0..1]);
+        ReceiveBatch::new(port.copy(), 0)
+            .parse::<MacHeader>()
+            .decap(tunnel)
+            .send(port.copy(), 0)
+            .process();
+        let mut expected = inner.clone();
+        expected.push(inner[0].clone());
+        assert_eq!(port.take_sent_packets(0), expected);
+    }
+
+    // IP packets carried by GRE keep the outer MAC header.
+    let mut gre_ip = packet(47, 0, 0)[0..34].to_vec();
+    gre_ip[16..18].copy_from_slice(&[0, 20 + 4 + 38]);
+    gre_ip.extend_from_slice(&[0, 0, 0x08, 0x00]);
+    gre_ip.extend_from_slice(&inner[0][14..]);
+    port.inject_packets(0, &[gre_ip]);
+    ReceiveBatch::new(port.copy(), 0)
+        .parse::<MacHeader>()
+        .decap(Tunnel::Gre)
+        .send(port.copy(), 0)
+        .process();
+    assert_eq!(port.take_sent_packets(0), vec![inner[0].clone()]);
+    assert_eq!(mbufs_in_use(), 0);
+}
@@ -0,0 +1,114 @@
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::iterator::*;
+use std::any::Any;
+use utils::rdtsc;
+
+/// Record the current TSC value in every packet, see `Batch::timestamp`.
+pub struct TimestampBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    parent: V,
+}
+
+impl<V> TimestampBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V) -> TimestampBatch<V> {
+        TimestampBatch { parent: parent }
+    }
+}
+
+impl<V> Batch for TimestampBatch<V> where V: Batch + BatchIterator + Act {}
+
+impl<V> BatchIterator for TimestampBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
+
+/// Internal interface for packets.
+impl<V> Act for TimestampBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        // Packets in a batch were received together, reading the TSC once is both cheaper and just as accurate.
+        let now = rdtsc();
+        let mut idx = self.parent.start();
+        while let Some((PacketDescriptor { mbuf, .. }, _, next_idx)) = unsafe { self.parent.next_base_payload(idx) } {
+            unsafe { (*mbuf).set_timestamp(now) };
+            idx = next_idx;
+        }
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.parent.done();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
@@ -0,0 +1,384 @@
+use headers::{EndOffset, GeneveHeader, GreHeader, IpHeader, MacHeader, UdpHeader, VxlanHeader, ETHERTYPE_TEB,
+              GENEVE_PORT, GRE_PROTO, VXLAN_PORT};
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::HeaderOperations;
+use super::iterator::*;
+use super::packet_batch::cast_from_u8;
+use std::any::Any;
+use std::ptr;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const UDP_PROTO: u8 = 17;
+const MAC_HDR_SIZE: usize = 14;
+const IP_HDR_SIZE: usize = 20;
+const UDP_HDR_SIZE: usize = 8;
+/// IP flags set on fragments other than the last one.
+const MORE_FRAGMENTS: u8 = 0x1;
+
+/// Tunnel protocols understood by `HeaderOperations::encap` and `HeaderOperations::decap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tunnel {
+    /// VXLAN over UDP, on `VXLAN_PORT`.
+    Vxlan,
+    /// GRE, carrying Ethernet frames (or, when decapsulating, also IP packets).
+    Gre,
+    /// Geneve over UDP, on `GENEVE_PORT`, carrying Ethernet frames.
+    Geneve,
+}
+
+/// The outer headers `HeaderOperations::encap` prepends to packets: a MAC and an IPv4 header, followed by a UDP and
+/// VXLAN or Geneve header, or a GRE header. Length and checksum fields are filled in per packet; UDP checksums are
+/// left at zero, as the VXLAN (RFC 7348) and Geneve specifications recommend.
+#[derive(Clone, Debug)]
+pub struct TunnelTemplate {
+    tunnel: Tunnel,
+    bytes: Vec<u8>,
+}
+
+impl TunnelTemplate {
+    /// Encapsulate in VXLAN with network identifier `vni`, sent from `src_port` (to `VXLAN_PORT`).
+    pub fn vxlan(mac: &MacHeader, ip: &IpHeader, src_port: u16, vni: u32) -> TunnelTemplate {
+        let mut template = TunnelTemplate::new(Tunnel::Vxlan, mac, ip, UDP_PROTO, UDP_HDR_SIZE + VxlanHeader::size());
+        template.write_udp(src_port, VXLAN_PORT);
+        let vxlan =
+            cast_from_u8::<VxlanHeader>(template.bytes[MAC_HDR_SIZE + IP_HDR_SIZE + UDP_HDR_SIZE..].as_mut_ptr());
+        *vxlan = VxlanHeader::new();
+        vxlan.set_vni(vni);
+        template
+    }
+
+    /// Encapsulate in GRE, with `key` if given.
+    pub fn gre(mac: &MacHeader, ip: &IpHeader, key: Option<u32>) -> TunnelTemplate {
+        let gre_len = if key.is_some() { GreHeader::size() + 4 } else { GreHeader::size() };
+        let mut template = TunnelTemplate::new(Tunnel::Gre, mac, ip, GRE_PROTO, gre_len);
+        let gre = cast_from_u8::<GreHeader>(template.bytes[MAC_HDR_SIZE + IP_HDR_SIZE..].as_mut_ptr());
+        *gre = GreHeader::new();
+        gre.set_protocol(ETHERTYPE_TEB);
+        gre.set_key(key);
+        template
+    }
+
+    /// Encapsulate in Geneve (without options) with network identifier `vni`, sent from `src_port` (to
+    /// `GENEVE_PORT`).
+    pub fn geneve(mac: &MacHeader, ip: &IpHeader, src_port: u16, vni: u32) -> TunnelTemplate {
+        let mut template = TunnelTemplate::new(Tunnel::Geneve, mac, ip, UDP_PROTO, UDP_HDR_SIZE + GeneveHeader::size());
+        template.write_udp(src_port, GENEVE_PORT);
+        let geneve =
+            cast_from_u8::<GeneveHeader>(template.bytes[MAC_HDR_SIZE + IP_HDR_SIZE + UDP_HDR_SIZE..].as_mut_ptr());
+        *geneve = GeneveHeader::new();
+        geneve.set_protocol(ETHERTYPE_TEB);
+        geneve.set_vni(vni);
+        template
+    }
+
+    /// Outer MAC and IP headers, taken from `mac` (addresses only) and `ip` (without options), followed by `len` zeroed
+    /// bytes for the tunnel headers.
+    fn new(tunnel: Tunnel, mac: &MacHeader, ip: &IpHeader, protocol: u8, len: usize) -> TunnelTemplate {
+        let mut bytes = vec![0u8; MAC_HDR_SIZE + IP_HDR_SIZE + len];
+        {
+            let outer_mac = cast_from_u8::<MacHeader>(bytes.as_mut_ptr());
+            outer_mac.dst = mac.dst;
+            outer_mac.src = mac.src;
+            outer_mac.set_etype(ETHERTYPE_IPV4);
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(ip as *const IpHeader as *const u8,
+                                     bytes[MAC_HDR_SIZE..].as_mut_ptr(),
+                                     IP_HDR_SIZE);
+        }
+        {
+            let outer_ip = cast_from_u8::<IpHeader>(bytes[MAC_HDR_SIZE..].as_mut_ptr());
+            outer_ip.set_version(4);
+            outer_ip.set_ihl((IP_HDR_SIZE / 4) as u8);
+            outer_ip.set_protocol(protocol);
+        }
+        TunnelTemplate {
+            tunnel: tunnel,
+            bytes: bytes,
+        }
+    }
+
+    fn write_udp(&mut self, src_port: u16, dst_port: u16) {
+        let udp = cast_from_u8::<UdpHeader>(self.bytes[MAC_HDR_SIZE + IP_HDR_SIZE..].as_mut_ptr());
+        udp.set_src_port(src_port);
+        udp.set_dst_port(dst_port);
+    }
+
+    /// Number of bytes prepended to each packet.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn tunnel(&self) -> Tunnel {
+        self.tunnel
+    }
+}
+
+/// What a `TunnelBatch` does to each packet.
+#[derive(Clone, Debug)]
+pub enum TunnelOp {
+    Encap(TunnelTemplate),
+    Decap(Tunnel),
+}
+
+/// Adds or strips tunnel headers, see `HeaderOperations::encap` and `HeaderOperations::decap`.
+pub struct TunnelBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    parent: V,
+    op: TunnelOp,
+    capacity: usize,
+}
+
+impl<V> TunnelBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V, op: TunnelOp) -> TunnelBatch<V> {
+        let capacity = parent.capacity() as usize;
+        TunnelBatch {
+            parent: parent,
+            op: op,
+            capacity: capacity,
+        }
+    }
+}
+
+#[inline]
+fn first_segment<V: BatchIterator>(parent: &mut V, idx: usize) -> *mut MBuf {
+    match unsafe { parent.next_base_payload(idx) } {
+        Some((PacketDescriptor { mbuf, .. }, _, _)) => mbuf,
+        None => ptr::null_mut(),
+    }
+}
+
+/// Prepend the template to packet `idx`. Returns false if the packet does not have enough headroom, or would be too
+/// long.
+fn encap<V: BatchIterator + Act>(parent: &mut V, idx: usize, template: &TunnelTemplate) -> bool {
+    let len = template.len();
+    if parent.adjust_headroom(idx, len as isize).is_none() {
+        return false;
+    }
+    let mbuf = first_segment(parent, idx);
+    unsafe {
+        if mbuf.is_null() {
+            return false;
+        }
+        let ip_len = (*mbuf).pkt_len() - MAC_HDR_SIZE;
+        if ip_len > u16::max_value() as usize {
+            return false;
+        }
+        let data = (*mbuf).data_address(0);
+        ptr::copy_nonoverlapping(template.bytes.as_ptr(), data, len);
+        let ip = cast_from_u8::<IpHeader>(data.offset(MAC_HDR_SIZE as isize));
+        ip.set_length(ip_len as u16);
+        ip.compute_checksum();
+        if template.tunnel != Tunnel::Gre {
+            let udp = cast_from_u8::<UdpHeader>(data.offset((MAC_HDR_SIZE + IP_HDR_SIZE) as isize));
+            udp.set_length((ip_len - IP_HDR_SIZE) as u16);
+        }
+    }
+    true
+}
+
+/// Strip the outer headers of packet `idx` if it is encapsulated in `tunnel`. IP packets carried by GRE keep the outer
+/// MAC header (with the ethertype changed to match), so that all decapsulated packets start with one.
+fn decap<V: BatchIterator + Act>(parent: &mut V, idx: usize, tunnel: Tunnel) {
+    let mbuf = first_segment(parent, idx);
+    if mbuf.is_null() {
+        return;
+    }
+    let (strip, etype) = unsafe { outer_headers((*mbuf).data_address(0), (*mbuf).data_len(), tunnel) };
+    if strip == 0 {
+        return;
+    }
+    if let Some(etype) = etype {
+        unsafe {
+            let data = (*mbuf).data_address(0);
+            ptr::copy(data, data.offset(strip as isize), MAC_HDR_SIZE);
+            cast_from_u8::<MacHeader>(data.offset(strip as isize)).set_etype(etype);
+        }
+    }
+    parent.adjust_headroom(idx, -(strip as isize));
+}
+
+/// Work out how many bytes of outer headers to strip from a packet whose first segment holds `len` bytes at `data`,
+/// and the ethertype to give the MAC header left in front of the inner packet (if one is left in place). Returns 0 if
+/// the packet is not encapsulated in `tunnel`.
+unsafe fn outer_headers(data: *mut u8, len: usize, tunnel: Tunnel) -> (usize, Option<u16>) {
+    if len < MAC_HDR_SIZE + IP_HDR_SIZE || cast_from_u8::<MacHeader>(data).etype() != ETHERTYPE_IPV4 {
+        return (0, None);
+    }
+    let ip = cast_from_u8::<IpHeader>(data.offset(MAC_HDR_SIZE as isize));
+    let ip_end = MAC_HDR_SIZE + ip.ihl() as usize * 4;
+    // Fragments need to be reassembled first.
+    if ip.version() != 4 || ip.ihl() < 5 || ip.flags() & MORE_FRAGMENTS != 0 || ip.fragment_offset() != 0 {
+        return (0, None);
+    }
+    match (tunnel, ip.protocol()) {
+        (Tunnel::Vxlan, UDP_PROTO) |
+        (Tunnel::Geneve, UDP_PROTO) => {
+            if len < ip_end + UDP_HDR_SIZE + VxlanHeader::size() {
+                return (0, None);
+            }
+            let udp = cast_from_u8::<UdpHeader>(data.offset(ip_end as isize));
+            let tunnel_hdr = data.offset((ip_end + UDP_HDR_SIZE) as isize);
+            let end = match tunnel {
+                Tunnel::Vxlan if udp.dst_port() == VXLAN_PORT => {
+                    ip_end + UDP_HDR_SIZE + cast_from_u8::<VxlanHeader>(tunnel_hdr).offset()
+                }
+                Tunnel::Geneve if udp.dst_port() == GENEVE_PORT => {
+                    let geneve = cast_from_u8::<GeneveHeader>(tunnel_hdr);
+                    if geneve.version() != 0 || geneve.protocol() != ETHERTYPE_TEB {
+                        return (0, None);
+                    }
+                    ip_end + UDP_HDR_SIZE + geneve.offset()
+                }
+                _ => return (0, None),
+            };
+            if len < end + MAC_HDR_SIZE {
+                (0, None)
+            } else {
+                (end, None)
+            }
+        }
+        (Tunnel::Gre, GRE_PROTO) => {
+            if len < ip_end + GreHeader::size() {
+                return (0, None);
+            }
+            let gre = cast_from_u8::<GreHeader>(data.offset(ip_end as isize));
+            let end = ip_end + gre.offset();
+            if gre.version() != 0 {
+                return (0, None);
+            }
+            match gre.protocol() {
+                ETHERTYPE_TEB if len >= end + MAC_HDR_SIZE => (end, None),
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 if len >= end => (end - MAC_HDR_SIZE, Some(gre.protocol())),
+                _ => (0, None),
+            }
+        }
+        _ => (0, None),
+    }
+}
+
+impl<V> Batch for TunnelBatch<V> where V: Batch + BatchIterator + Act {}
+
+impl<V> HeaderOperations for TunnelBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    type Header = MacHeader;
+}
+
+impl<V> BatchIterator for TunnelBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
+
+/// Internal interface for packets.
+impl<V> Act for TunnelBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    /// Headers are added and removed using the packets' headroom. Packets which cannot be encapsulated are dropped,
+    /// packets which are not encapsulated (in the expected way) are left untouched by decapsulation.
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        let mut idxes = Vec::<usize>::with_capacity(self.capacity);
+        {
+            let mut idx = self.parent.start();
+            while let Some((_, _, next)) = unsafe { self.parent.next_base_payload(idx) } {
+                idxes.push(idx);
+                idx = next;
+            }
+        }
+        match self.op {
+            TunnelOp::Encap(ref template) => {
+                let mut dropped = Vec::<usize>::new();
+                for idx in idxes {
+                    if !encap(&mut self.parent, idx, template) {
+                        dropped.push(idx);
+                    }
+                }
+                if !dropped.is_empty() {
+                    self.parent.drop_packets(dropped).expect("Encapsulation was performed incorrectly");
+                }
+            }
+            TunnelOp::Decap(tunnel) => {
+                for idx in idxes {
+                    decap(&mut self.parent, idx, tunnel);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.parent.done();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
@@ -0,0 +1,230 @@
+use headers::{is_vlan_ethertype, MacHeader, ETHERTYPE_VLAN};
+use io::MBuf;
+use io::PmdPort;
+use io::Result;
+use super::act::Act;
+use super::Batch;
+use super::HeaderOperations;
+use super::iterator::*;
+use std::any::Any;
+use std::ptr;
+use std::slice;
+
+/// Bytes of the MAC header preceding the ethertype (or tag protocol identifier), i.e., the addresses.
+const MAC_ADDRS_SIZE: usize = 12;
+const TAG_SIZE: usize = 4;
+
+/// What a `VlanBatch` does to each packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VlanOp {
+    /// Push a tag with the given tag protocol identifier and TCI.
+    Push(u16, u16),
+    /// Pop the outer tag.
+    Pop,
+}
+
+/// Pushes or pops VLAN tags, see `HeaderOperations::push_vlan` and `HeaderOperations::pop_vlan`.
+pub struct VlanBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    parent: V,
+    op: VlanOp,
+    capacity: usize,
+}
+
+impl<V> VlanBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    pub fn new(parent: V, op: VlanOp) -> VlanBatch<V> {
+        let capacity = parent.capacity() as usize;
+        VlanBatch {
+            parent: parent,
+            op: op,
+            capacity: capacity,
+        }
+    }
+
+    #[inline]
+    fn first_segment(&mut self, idx: usize) -> *mut MBuf {
+        match unsafe { self.parent.next_base_payload(idx) } {
+            Some((PacketDescriptor { mbuf, .. }, _, _)) => mbuf,
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Insert a tag right after the MAC addresses of packet `idx`. Returns false if the packet does not have enough
+    /// headroom, or its first segment is too short to hold the addresses.
+    fn insert_tag(&mut self, idx: usize, tpid: u16, tci: u16) -> bool {
+        if self.parent.adjust_headroom(idx, TAG_SIZE as isize).is_none() {
+            return false;
+        }
+        let mbuf = self.first_segment(idx);
+        unsafe {
+            if mbuf.is_null() || (*mbuf).data_len() < MAC_ADDRS_SIZE + TAG_SIZE {
+                return false;
+            }
+            let data = (*mbuf).data_address(0);
+            ptr::copy(data.offset(TAG_SIZE as isize), data, MAC_ADDRS_SIZE);
+            let tag = slice::from_raw_parts_mut(data.offset(MAC_ADDRS_SIZE as isize), TAG_SIZE);
+            tag[0] = (tpid >> 8) as u8;
+            tag[1] = tpid as u8;
+            tag[2] = (tci >> 8) as u8;
+            tag[3] = tci as u8;
+        }
+        true
+    }
+
+    /// Remove the outer tag of packet `idx`, if it has one. A tag stripped by the NIC on receive is the outer tag, so
+    /// this only needs to forget about it.
+    fn remove_tag(&mut self, idx: usize) {
+        let mbuf = self.first_segment(idx);
+        if mbuf.is_null() {
+            return;
+        }
+        unsafe {
+            if (*mbuf).rx_vlan_tci().is_some() {
+                (*mbuf).clear_rx_vlan_tci();
+                return;
+            }
+            if (*mbuf).data_len() < MAC_ADDRS_SIZE + TAG_SIZE {
+                return;
+            }
+            let data = (*mbuf).data_address(0);
+            let tag = slice::from_raw_parts(data.offset(MAC_ADDRS_SIZE as isize), TAG_SIZE);
+            let tpid = ((tag[0] as u16) << 8) | tag[1] as u16;
+            if !is_vlan_ethertype(tpid) {
+                return;
+            }
+            ptr::copy(data, data.offset(TAG_SIZE as isize), MAC_ADDRS_SIZE);
+        }
+        self.parent.adjust_headroom(idx, -(TAG_SIZE as isize));
+    }
+}
+
+impl<V> Batch for VlanBatch<V> where V: Batch + BatchIterator + Act {}
+
+impl<V> HeaderOperations for VlanBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    type Header = MacHeader;
+}
+
+impl<V> BatchIterator for VlanBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    #[inline]
+    fn start(&mut self) -> usize {
+        self.parent.start()
+    }
+
+    #[inline]
+    unsafe fn next_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_base_payload(&mut self, idx: usize) -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_base_payload(idx)
+    }
+
+    #[inline]
+    unsafe fn next_payload_popped(&mut self,
+                                  idx: usize,
+                                  pop: i32)
+                                  -> Option<(PacketDescriptor, Option<&mut Any>, usize)> {
+        self.parent.next_payload_popped(idx, pop)
+    }
+}
+
+/// Internal interface for packets.
+impl<V> Act for VlanBatch<V>
+    where V: Batch + BatchIterator + Act
+{
+    /// Tags are pushed and popped by moving the MAC addresses within the headroom. Packets which cannot be tagged
+    /// (because they are too short, or there is no room to grow them in place) are dropped.
+    #[inline]
+    fn act(&mut self) {
+        self.parent.act();
+        let mut idxes = Vec::<usize>::with_capacity(self.capacity);
+        {
+            let mut idx = self.parent.start();
+            while let Some((_, _, next)) = unsafe { self.parent.next_base_payload(idx) } {
+                idxes.push(idx);
+                idx = next;
+            }
+        }
+        let mut dropped = Vec::<usize>::new();
+        for idx in idxes {
+            match self.op {
+                VlanOp::Push(tpid, tci) => {
+                    // A tag stripped on receive was the outer tag, put it back in the packet underneath the new one.
+                    let mbuf = self.first_segment(idx);
+                    let stripped = if mbuf.is_null() {
+                        None
+                    } else {
+                        unsafe { (*mbuf).rx_vlan_tci() }
+                    };
+                    let ok = match stripped {
+                        Some(stripped) => {
+                            unsafe { (*mbuf).clear_rx_vlan_tci() };
+                            self.insert_tag(idx, ETHERTYPE_VLAN, stripped)
+                        }
+                        None => true,
+                    };
+                    if !(ok && self.insert_tag(idx, tpid, tci)) {
+                        dropped.push(idx);
+                    }
+                }
+                VlanOp::Pop => self.remove_tag(idx),
+            }
+        }
+        if !dropped.is_empty() {
+            self.parent.drop_packets(dropped).expect("VLAN tagging was performed incorrectly");
+        }
+    }
+
+    #[inline]
+    fn done(&mut self) {
+        self.parent.done();
+    }
+
+    #[inline]
+    fn send_queue(&mut self, port: &mut PmdPort, queue: i32) -> Result<u32> {
+        self.parent.send_queue(port, queue)
+    }
+
+    #[inline]
+    fn capacity(&self) -> i32 {
+        self.parent.capacity()
+    }
+
+    #[inline]
+    fn last_received(&self) -> usize {
+        self.parent.last_received()
+    }
+
+    #[inline]
+    fn drop_packets(&mut self, idxes: Vec<usize>) -> Option<usize> {
+        self.parent.drop_packets(idxes)
+    }
+
+    #[inline]
+    fn remove_packets(&mut self, idxes: Vec<usize>) -> Option<Vec<*mut MBuf>> {
+        self.parent.remove_packets(idxes)
+    }
+
+    #[inline]
+    fn adjust_payload_size(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_payload_size(idx, size)
+    }
+
+    #[inline]
+    fn adjust_headroom(&mut self, idx: usize, size: isize) -> Option<isize> {
+        self.parent.adjust_headroom(idx, size)
+    }
+
+    #[inline]
+    fn prepend_headroom_segment(&mut self, idx: usize, size: usize) -> Option<isize> {
+        self.parent.prepend_headroom_segment(idx, size)
+    }
+}
@@ -0,0 +1,511 @@
+use fnv::FnvHasher;
+
+use headers::IpHeader;
+use std::collections::HashMap;
+use std::collections::hash_map::{Entry, Iter};
+use std::hash::BuildHasherDefault;
+use std::time::Duration;
+use time::precise_time_ns;
+
+use utils::Flow;
+
+type FnvHash = BuildHasherDefault<FnvHasher>;
+
+const TCP_PROTO: u8 = 6;
+const UDP_PROTO: u8 = 17;
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+/// Offset of the flags byte in the TCP header.
+const TCP_FLAGS_OFFSET: usize = 13;
+
+/// Number of slots in the timer wheel. Timeouts longer than `WHEEL_SLOTS` ticks take several turns of the wheel.
+const WHEEL_SLOTS: usize = 1 << 12;
+const DEFAULT_TICK_MS: u64 = 100;
+const DEFAULT_MAX_CONNECTIONS: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Packets have only been seen in the direction of the packet that created the connection.
+    New,
+    /// Packets have been seen in both directions.
+    Established,
+    /// A TCP FIN has been seen in at least one direction.
+    Closing,
+    /// A TCP RST has been seen. Further packets do not extend the connection's lifetime, until a SYN in the original
+    /// direction starts it over as `New`.
+    Closed,
+}
+
+/// Direction of a packet relative to the packet that created its connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Original,
+    Reply,
+}
+
+/// How long a connection may stay idle, depending on its protocol and state, before it is expired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    pub tcp_new: Duration,
+    pub tcp_established: Duration,
+    pub tcp_closing: Duration,
+    pub tcp_closed: Duration,
+    /// UDP and other protocols, before a reply has been seen.
+    pub udp_new: Duration,
+    /// UDP and other protocols, once a reply has been seen.
+    pub udp_established: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            tcp_new: Duration::from_secs(120),
+            tcp_established: Duration::from_secs(3600),
+            tcp_closing: Duration::from_secs(120),
+            tcp_closed: Duration::from_secs(10),
+            udp_new: Duration::from_secs(30),
+            udp_established: Duration::from_secs(180),
+        }
+    }
+}
+
+impl Timeouts {
+    fn timeout_ns(&self, proto: u8, state: ConnectionState) -> u64 {
+        let timeout = match (proto, state) {
+            (TCP_PROTO, ConnectionState::New) => self.tcp_new,
+            (TCP_PROTO, ConnectionState::Established) => self.tcp_established,
+            (TCP_PROTO, ConnectionState::Closing) => self.tcp_closing,
+            (TCP_PROTO, ConnectionState::Closed) => self.tcp_closed,
+            (_, ConnectionState::New) => self.udp_new,
+            (_, _) => self.udp_established,
+        };
+        duration_ns(timeout)
+    }
+}
+
+#[inline]
+fn duration_ns(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+/// A tracked connection, with user data `T` (e.g., NAT bindings or firewall verdicts).
+pub struct Connection<T> {
+    flow: Flow,
+    state: ConnectionState,
+    packets: [u64; 2],
+    expires: u64,
+    /// Tick at which this connection's timer fires.
+    timer: u64,
+    /// Distinguishes this connection from earlier ones with the same flow, whose timers might still be in the wheel.
+    generation: u64,
+    pub data: T,
+}
+
+impl<T> Connection<T> {
+    /// The flow in the direction of the packet that created the connection.
+    #[inline]
+    pub fn flow(&self) -> &Flow {
+        &self.flow
+    }
+
+    #[inline]
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Number of packets seen in `direction`.
+    #[inline]
+    pub fn packets(&self, direction: Direction) -> u64 {
+        self.packets[direction as usize]
+    }
+
+    /// Update state for a packet with `tcp_flags` (0 for protocols other than TCP) seen in `direction`.
+    #[inline]
+    fn update(&mut self, direction: Direction, tcp_flags: u8) {
+        self.packets[direction as usize] += 1;
+        let syn_only = tcp_flags & (TCP_SYN | TCP_FIN | TCP_RST) == TCP_SYN;
+        self.state = match self.state {
+            // A new SYN in the original direction after the connection was closed down or reset starts over.
+            ConnectionState::Closing |
+            ConnectionState::Closed if syn_only && direction == Direction::Original => {
+                self.packets = [1, 0];
+                ConnectionState::New
+            }
+            ConnectionState::Closed => ConnectionState::Closed,
+            _ if tcp_flags & TCP_RST != 0 => ConnectionState::Closed,
+            _ if tcp_flags & TCP_FIN != 0 => ConnectionState::Closing,
+            ConnectionState::New if direction == Direction::Reply => ConnectionState::Established,
+            state => state,
+        };
+    }
+}
+
+/// The key for both directions of a flow.
+#[inline]
+fn canonical(flow: &Flow) -> Flow {
+    if (flow.src_ip, flow.src_port) <= (flow.dst_ip, flow.dst_port) {
+        *flow
+    } else {
+        Flow {
+            src_ip: flow.dst_ip,
+            dst_ip: flow.src_ip,
+            src_port: flow.dst_port,
+            dst_port: flow.src_port,
+            proto: flow.proto,
+        }
+    }
+}
+
+/// Called with each connection that expires.
+pub type ExpiryFn<T> = Box<FnMut(Connection<T>)>;
+
+/// A connection tracking table for TCP, UDP (and, keyed by addresses alone, other IPv4 protocols). Both directions of
+/// a connection share an entry. Connections which have been idle for longer than the timeout for their state (see
+/// `Timeouts`) are expired using a timer wheel, which is advanced as packets are tracked (or explicitly with
+/// `expire`); an `ExpiryFn` can be set to release resources held by expired connections.
+///
+/// The table is meant to be owned by the closure of a `transform` (or `map`) on an `IpHeader`, e.g.,
+///
+/// ```ignore
+/// let mut table = ConnectionTable::<Binding>::new();
+/// batch.transform(box move |ip, payload, _| {
+///     if let Some((conn, direction)) = table.track(ip, payload) {
+///         ...
+///     }
+/// })
+/// ```
+///
+/// IP fragments other than the first are not recognized, they are tracked as if their payload started with an L4
+/// header.
+pub struct ConnectionTable<T: Default> {
+    connections: HashMap<Flow, Connection<T>, FnvHash>,
+    wheel: Vec<Vec<(Flow, u64)>>,
+    tick_ns: u64,
+    current_tick: u64,
+    timeouts: Timeouts,
+    max_connections: usize,
+    next_generation: u64,
+    on_expire: Option<ExpiryFn<T>>,
+    /// Connections that were not created because the table was full.
+    pub rejected: u64,
+}
+
+impl<T: Default> ConnectionTable<T> {
+    pub fn new() -> ConnectionTable<T> {
+        ConnectionTable::with_timeouts(Timeouts::default(), DEFAULT_MAX_CONNECTIONS)
+    }
+
+    /// A table holding at most `max_connections` connections, expiring them according to `timeouts`.
+    pub fn with_timeouts(timeouts: Timeouts, max_connections: usize) -> ConnectionTable<T> {
+        ConnectionTable::with_timeouts_and_tick(timeouts, max_connections, Duration::from_millis(DEFAULT_TICK_MS))
+    }
+
+    /// Like `with_timeouts`, with timers checked every `tick` (connections expire up to a tick late).
+    pub fn with_timeouts_and_tick(timeouts: Timeouts, max_connections: usize, tick: Duration) -> ConnectionTable<T> {
+        let tick_ns = duration_ns(tick);
+        assert!(tick_ns > 0, "Tick must be positive");
+        ConnectionTable {
+            connections: HashMap::with_capacity_and_hasher(max_connections.min(1 << 16), Default::default()),
+            wheel: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            tick_ns: tick_ns,
+            current_tick: 0,
+            timeouts: timeouts,
+            max_connections: max_connections,
+            next_generation: 0,
+            on_expire: None,
+            rejected: 0,
+        }
+    }
+
+    /// Call `on_expire` with every connection as it expires.
+    pub fn set_expiry_handler(&mut self, on_expire: ExpiryFn<T>) {
+        self.on_expire = Some(on_expire);
+    }
+
+    /// Track a packet given its IP header and payload, as passed to a `transform` on `IpHeader`. Returns the packet's
+    /// connection (creating it if need be) and the direction of the packet, or `None` if the packet is too short or the
+    /// table is full.
+    #[inline]
+    pub fn track(&mut self, ip: &IpHeader, payload: &[u8]) -> Option<(&mut Connection<T>, Direction)> {
+        self.track_at(ip, payload, precise_time_ns())
+    }
+
+    /// Like `track`, with the current time (in nanoseconds, as returned by `time::precise_time_ns`) given explicitly.
+    pub fn track_at(&mut self, ip: &IpHeader, payload: &[u8], now: u64) -> Option<(&mut Connection<T>, Direction)> {
+        let proto = ip.protocol();
+        let (ports, tcp_flags) = match proto {
+            TCP_PROTO if payload.len() > TCP_FLAGS_OFFSET => (true, payload[TCP_FLAGS_OFFSET]),
+            UDP_PROTO if payload.len() >= 4 => (true, 0),
+            TCP_PROTO | UDP_PROTO => return None,
+            _ => (false, 0),
+        };
+        let flow = Flow {
+            src_ip: ip.src(),
+            dst_ip: ip.dst(),
+            src_port: if ports { ((payload[0] as u16) << 8) | payload[1] as u16 } else { 0 },
+            dst_port: if ports { ((payload[2] as u16) << 8) | payload[3] as u16 } else { 0 },
+            proto: proto,
+        };
+        self.track_flow_at(&flow, tcp_flags, now)
+    }
+
+    /// Track a packet belonging to `flow` (with `tcp_flags`, 0 if not TCP) seen at `now`.
+    pub fn track_flow_at(&mut self, flow: &Flow, tcp_flags: u8, now: u64) -> Option<(&mut Connection<T>, Direction)> {
+        self.expire(now);
+        let key = canonical(flow);
+        let full = self.connections.len() >= self.max_connections;
+        let (conn, direction) = match self.connections.entry(key) {
+            Entry::Occupied(e) => {
+                let conn = e.into_mut();
+                let direction = if conn.flow == *flow {
+                    Direction::Original
+                } else {
+                    Direction::Reply
+                };
+                let closed = conn.state == ConnectionState::Closed;
+                conn.update(direction, tcp_flags);
+                // Stray packets after a reset do not keep a closed connection alive.
+                if closed && conn.state == ConnectionState::Closed {
+                    return Some((conn, direction));
+                }
+                (conn, direction)
+            }
+            Entry::Vacant(_) if full => {
+                self.rejected += 1;
+                return None;
+            }
+            Entry::Vacant(e) => {
+                self.next_generation += 1;
+                let mut conn = Connection {
+                    flow: *flow,
+                    state: ConnectionState::New,
+                    packets: [0, 0],
+                    expires: 0,
+                    timer: u64::max_value(),
+                    generation: self.next_generation,
+                    data: T::default(),
+                };
+                conn.update(Direction::Original, tcp_flags);
+                (e.insert(conn), Direction::Original)
+            }
+        };
+        conn.expires = now + self.timeouts.timeout_ns(flow.proto, conn.state);
+        // Timers are not moved back when a connection sees traffic, rather the connection is rescheduled when its
+        // timer fires early. A timer only needs to be added when the connection now expires sooner (e.g., because it
+        // is closing), the old one is ignored when it fires.
+        let tick = ConnectionTable::<T>::timer_tick(self.current_tick, self.tick_ns, conn.expires);
+        if tick < conn.timer {
+            conn.timer = tick;
+            self.wheel[(tick % WHEEL_SLOTS as u64) as usize].push((key, conn.generation));
+        }
+        Some((conn, direction))
+    }
+
+    /// Tick at which to fire a timer expiring at `expires`, clamped to one turn of the wheel from `current_tick`.
+    #[inline]
+    fn timer_tick(current_tick: u64, tick_ns: u64, expires: u64) -> u64 {
+        let tick = (expires + tick_ns - 1) / tick_ns;
+        if tick <= current_tick {
+            current_tick + 1
+        } else if tick >= current_tick + WHEEL_SLOTS as u64 {
+            current_tick + WHEEL_SLOTS as u64 - 1
+        } else {
+            tick
+        }
+    }
+
+    /// Expire connections which have been idle for too long as of `now`. Called by `track`, so only needs to be called
+    /// explicitly when no packets are being tracked.
+    pub fn expire(&mut self, now: u64) {
+        let target = now / self.tick_ns;
+        if target <= self.current_tick {
+            return;
+        }
+        if self.connections.is_empty() {
+            // Nothing to expire (timers left behind by removed connections are skipped when they fire anyway).
+            self.current_tick = target;
+            return;
+        }
+        // After a long idle period, a single turn of the wheel visits every timer.
+        let ticks = (target - self.current_tick).min(WHEEL_SLOTS as u64);
+        for tick in (target - ticks + 1)..(target + 1) {
+            self.current_tick = tick;
+            let slot = (tick % WHEEL_SLOTS as u64) as usize;
+            let timers: Vec<_> = self.wheel[slot].drain(..).collect();
+            for (key, generation) in timers {
+                let expired = match self.connections.get_mut(&key) {
+                    Some(ref mut conn) if conn.generation == generation && conn.timer <= tick &&
+                                          (conn.timer % WHEEL_SLOTS as u64) as usize == slot => {
+                        if conn.expires <= now {
+                            true
+                        } else {
+                            conn.timer = ConnectionTable::<T>::timer_tick(tick, self.tick_ns, conn.expires);
+                            self.wheel[(conn.timer % WHEEL_SLOTS as u64) as usize].push((key, generation));
+                            false
+                        }
+                    }
+                    // Removed, replaced by a newer connection or rescheduled to an earlier tick.
+                    _ => false,
+                };
+                if expired {
+                    let conn = self.connections.remove(&key).unwrap();
+                    if let Some(ref mut on_expire) = self.on_expire {
+                        on_expire(conn);
+                    }
+                }
+            }
+        }
+        self.current_tick = target;
+    }
+
+    /// Look up the connection `flow` (in either direction) belongs to, without counting a packet.
+    pub fn get_mut(&mut self, flow: &Flow) -> Option<(&mut Connection<T>, Direction)> {
+        let key = canonical(flow);
+        self.connections.get_mut(&key).map(|conn| {
+            let direction = if conn.flow == *flow {
+                Direction::Original
+            } else {
+                Direction::Reply
+            };
+            (conn, direction)
+        })
+    }
+
+    /// Remove the connection `flow` (in either direction) belongs to, without calling the expiry handler.
+    pub fn remove(&mut self, flow: &Flow) -> Option<Connection<T>> {
+        let key = canonical(flow);
+        self.connections.remove(&key)
+    }
+
+    /// Iterate over connections. Keys are the flow in a canonical direction, see `Connection::flow` for the original
+    /// direction.
+    pub fn iter(&self) -> Iter<Flow, Connection<T>> {
+        self.connections.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn ip(src: u32, dst: u32, proto: u8) -> IpHeader {
+        let mut ip = IpHeader::new();
+        ip.set_src(src);
+        ip.set_dst(dst);
+        ip.set_protocol(proto);
+        ip
+    }
+
+    fn tcp_flow(src_port: u16) -> Flow {
+        Flow {
+            src_ip: 1,
+            dst_ip: 2,
+            src_port: src_port,
+            dst_port: 80,
+            proto: TCP_PROTO,
+        }
+    }
+
+    fn reverse(flow: &Flow) -> Flow {
+        Flow {
+            src_ip: flow.dst_ip,
+            dst_ip: flow.src_ip,
+            src_port: flow.dst_port,
+            dst_port: flow.src_port,
+            proto: flow.proto,
+        }
+    }
+
+    #[test]
+    fn track_udp() {
+        let mut table = ConnectionTable::<u32>::new();
+        let request = ip(0x0a000001, 0x0a000002, UDP_PROTO);
+        let reply = ip(0x0a000002, 0x0a000001, UDP_PROTO);
+        let (out, back) = ([0x03, 0xe8, 0, 53], [0, 53, 0x03, 0xe8]);
+        let mut seen = Vec::new();
+        for &(ip, payload) in &[(&request, out), (&reply, back), (&request, out)] {
+            let (conn, direction) = table.track_at(ip, &payload, 0).expect("Table full");
+            conn.data += 1;
+            seen.push((conn.state(), direction, conn.data));
+        }
+        assert_eq!(seen,
+                   vec![(ConnectionState::New, Direction::Original, 1),
+                        (ConnectionState::Established, Direction::Reply, 2),
+                        (ConnectionState::Established, Direction::Original, 3)]);
+        assert_eq!(table.len(), 1);
+        // Too short to hold the ports.
+        assert!(table.track_at(&request, &[0x03, 0xe8, 0], 0).is_none());
+    }
+
+    #[test]
+    fn tcp_states_and_expiry() {
+        let mut timeouts = Timeouts::default();
+        timeouts.tcp_new = Duration::from_secs(5);
+        timeouts.tcp_established = Duration::from_secs(60);
+        let expired = Rc::new(RefCell::new(Vec::new()));
+        let expired_in = expired.clone();
+        let mut table = ConnectionTable::<()>::with_timeouts(timeouts, 2);
+        table.set_expiry_handler(box move |conn| expired_in.borrow_mut().push(*conn.flow()));
+        let start = table.track_flow_at(&tcp_flow(1), TCP_SYN, 0).map(|(c, _)| c.state());
+        assert_eq!(start, Some(ConnectionState::New));
+        table.track_flow_at(&reverse(&tcp_flow(1)), TCP_SYN | 0x10, SECOND);
+        table.track_flow_at(&tcp_flow(2), TCP_SYN, SECOND);
+        assert!(table.track_flow_at(&tcp_flow(3), TCP_SYN, SECOND).is_none());
+        assert_eq!(table.rejected, 1);
+        // The half open connection times out, the established one is kept alive by traffic.
+        for t in 2..10 {
+            table.track_flow_at(&tcp_flow(1), 0x10, t * SECOND);
+        }
+        assert_eq!(*expired.borrow(), vec![tcp_flow(2)]);
+        assert_eq!(table.get_mut(&reverse(&tcp_flow(1))).map(|(c, d)| (c.state(), d)),
+                   Some((ConnectionState::Established, Direction::Reply)));
+        table.track_flow_at(&reverse(&tcp_flow(1)), TCP_FIN, 10 * SECOND);
+        assert_eq!(table.get_mut(&tcp_flow(1)).map(|(c, _)| c.state()), Some(ConnectionState::Closing));
+        table.expire(10000 * SECOND);
+        assert_eq!(*expired.borrow(), vec![tcp_flow(2), tcp_flow(1)]);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn tcp_reset() {
+        let mut timeouts = Timeouts::default();
+        timeouts.tcp_closed = Duration::from_secs(10);
+        let mut table = ConnectionTable::<()>::with_timeouts(timeouts, 16);
+        let flow = tcp_flow(1);
+        table.track_flow_at(&flow, TCP_SYN, 0);
+        table.track_flow_at(&reverse(&flow), TCP_SYN | 0x10, 0);
+        table.track_flow_at(&reverse(&flow), TCP_RST, SECOND);
+        assert_eq!(table.get_mut(&flow).map(|(c, _)| c.state()), Some(ConnectionState::Closed));
+        // Neither stray packets, nor a SYN in the reply direction, reopen or refresh a reset connection.
+        let state = table.track_flow_at(&reverse(&flow), TCP_SYN, 2 * SECOND).map(|(c, _)| c.state());
+        assert_eq!(state, Some(ConnectionState::Closed));
+        for t in 3..11 {
+            let state = table.track_flow_at(&flow, 0x10, t * SECOND).map(|(c, _)| c.state());
+            assert_eq!(state, Some(ConnectionState::Closed));
+        }
+        table.expire(12 * SECOND);
+        assert_eq!(table.len(), 0);
+
+        // A new SYN reuses the flow once it has been reset.
+        table.track_flow_at(&flow, TCP_SYN, 20 * SECOND);
+        table.track_flow_at(&flow, TCP_RST, 21 * SECOND);
+        let (conn, direction) = table.track_flow_at(&flow, TCP_SYN, 22 * SECOND).unwrap();
+        assert_eq!((conn.state(), direction), (ConnectionState::New, Direction::Original));
+        assert_eq!((conn.packets(Direction::Original), conn.packets(Direction::Reply)), (1, 0));
+        let state = table.track_flow_at(&reverse(&flow), TCP_SYN | 0x10, 23 * SECOND).map(|(c, _)| c.state());
+        assert_eq!(state, Some(ConnectionState::Established));
+        // A SYN with RST set is still a reset.
+        table.track_flow_at(&flow, TCP_RST, 24 * SECOND);
+        let state = table.track_flow_at(&flow, TCP_SYN | TCP_RST, 25 * SECOND).map(|(c, _)| c.state());
+        assert_eq!(state, Some(ConnectionState::Closed));
+    }
+}
@@ -5,7 +5,9 @@ use std::hash::BuildHasherDefault;
 use std::collections::hash_map::Iter;
 use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
 use std::ops::AddAssign;
+use time::precise_time_ns;
 
+use super::eviction::{EvictionPolicy, EvictionSender, Evictor};
 use utils::Flow;
 
 type XxHasher = BuildHasherDefault<XxHash>;
@@ -15,10 +17,8 @@ const VEC_SIZE: usize = 1 << 24;
 /// guarantee ordering for things being merged. The merge function is implemented by implementing the
 /// (AddAssign)[https://doc.rust-lang.org/std/ops/trait.AddAssign.html] trait and overriding the `add_assign` method
 /// there. We assume that the stored quantity needs to only be accessed from the control plane, and cannot be accessed
-/// from the data plane.
-///
-/// #[FIXME]
-/// Garbage collection.
+/// from the data plane. Entries are kept until removed, unless an `EvictionPolicy` is set on the control plane side
+/// with `set_eviction`.
 #[derive(Clone)]
 pub struct CpMergeableStoreDataPath<T: AddAssign<T> + Default + Clone> {
     /// Contains the counts on the data path.
@@ -34,6 +34,7 @@ pub struct CpMergeableStoreControlPlane<T: AddAssign<T> + Default + Clone> {
     /// The actual values.
     flow_counters: HashMap<Flow, T, XxHasher>,
     channel: Receiver<Vec<(Flow, T)>>,
+    evictor: Evictor<T>,
 }
 
 impl<T: AddAssign<T> + Default + Clone> CpMergeableStoreDataPath<T> {
@@ -54,17 +55,55 @@ impl<T: AddAssign<T> + Default + Clone> CpMergeableStoreDataPath<T> {
 
 impl<T: AddAssign<T> + Default + Clone> CpMergeableStoreControlPlane<T> {
     fn update_internal(&mut self, v: Vec<(Flow, T)>) {
+        if self.evictor.enabled() {
+            let now = precise_time_ns();
+            for &(ref flow, _) in &v {
+                self.evictor.touch(flow, now);
+            }
+        }
         for (flow, c) in v {
             *(self.flow_counters.entry(flow).or_insert(Default::default())) += c;
         }
     }
 
-    /// Call periodically to drain the queue.
+    /// Call periodically to drain the queue. Also evicts entries according to the eviction policy.
     pub fn recv(&mut self) {
         match self.channel.try_recv() {
             Err(_) => (),
             Ok(v) => self.update_internal(v),
         }
+        if self.evictor.enabled() {
+            self.evict_at(precise_time_ns());
+        }
+    }
+
+    /// Evict entries according to `policy`, reporting evicted entries on `sender` (if any), e.g., to a flow monitor.
+    pub fn set_eviction(&mut self, policy: EvictionPolicy, sender: Option<EvictionSender<T>>) {
+        self.evictor.set_policy(policy, sender, self.flow_counters.keys(), precise_time_ns());
+    }
+
+    /// Evict entries according to the eviction policy, as of `now` (in nanoseconds, as returned by
+    /// `time::precise_time_ns`).
+    pub fn evict_at(&mut self, now: u64) {
+        let flow_counters = &mut self.flow_counters;
+        self.evictor.evict(now, |flow| flow_counters.remove(flow));
+    }
+
+    /// Start a new epoch: evict (and report) all entries.
+    pub fn reset_epoch(&mut self) {
+        self.evictor.reset(precise_time_ns());
+        let evicted = self.flow_counters.drain().collect();
+        self.evictor.report(evicted);
+    }
+
+    /// Number of entries evicted so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictor.evicted
+    }
+
+    /// Number of evicted entries that could not be reported because the eviction channel was full (or not set).
+    pub fn evictions_lost(&self) -> u64 {
+        self.evictor.lost
     }
 
     pub fn get(&self, flow: &Flow) -> T {
@@ -85,6 +124,7 @@ impl<T: AddAssign<T> + Default + Clone> CpMergeableStoreControlPlane<T> {
     /// Remove an entry from the table.
     #[inline]
     pub fn remove(&mut self, flow: &Flow) -> T {
+        self.evictor.forget(flow);
         self.flow_counters.remove(flow).unwrap_or(Default::default())
     }
 }
@@ -106,5 +146,6 @@ pub fn new_cp_mergeable_store<T: AddAssign<T> + Default + Clone>(delay: usize,
         // FIXME: Don't need this to be quite this big?
         flow_counters: HashMap::with_capacity_and_hasher(VEC_SIZE, Default::default()),
         channel: receiver,
+        evictor: Evictor::new(),
     })
 }
@@ -4,7 +4,9 @@ use std::collections::HashMap;
 use std::hash::BuildHasherDefault;
 use std::ops::AddAssign;
 use std::collections::hash_map::Iter;
+use time::precise_time_ns;
 
+use super::eviction::{EvictionPolicy, EvictionSender, Evictor};
 use utils::Flow;
 
 /// A generic store for associating some merge-able type with each flow. Note, the merge must be commutative, we do not
@@ -12,10 +14,7 @@ use utils::Flow;
 /// [AddAssign](https://doc.rust-lang.org/std/ops/trait.AddAssign.html) trait and overriding the `add_assign` method
 /// there. We assume that the quantity stored here does not need to be accessed by the control plane and can only be
 /// accessed from the data plane. The `cache_size` should be tuned depending on whether gets or puts are the most common
-/// operation in this table.
-///
-/// #[FIXME]
-/// Garbage collection.
+/// operation in this table. Entries are kept until removed, unless an `EvictionPolicy` is set with `set_eviction`.
 type FnvHash = BuildHasherDefault<FnvHasher>;
 const VEC_SIZE: usize = 1 << 24;
 #[derive(Clone)]
@@ -24,6 +23,7 @@ pub struct DpMergeableStore<T: AddAssign<T> + Default> {
     flow_counters: HashMap<Flow, T, FnvHash>,
     cache: Vec<(Flow, T)>,
     cache_size: usize,
+    evictor: Evictor<T>,
 }
 const CACHE_SIZE: usize = 1 << 14;
 impl<T: AddAssign<T> + Default> DpMergeableStore<T> {
@@ -32,6 +32,7 @@ impl<T: AddAssign<T> + Default> DpMergeableStore<T> {
             flow_counters: HashMap::with_capacity_and_hasher(size, Default::default()),
             cache: Vec::with_capacity(cache),
             cache_size: cache,
+            evictor: Evictor::new(),
         }
     }
 
@@ -40,7 +41,53 @@ impl<T: AddAssign<T> + Default> DpMergeableStore<T> {
     }
 
     fn merge_cache(&mut self) {
-        self.flow_counters.extend(self.cache.drain(0..));
+        if self.evictor.enabled() && !self.cache.is_empty() {
+            let now = precise_time_ns();
+            for &(ref flow, _) in &self.cache {
+                self.evictor.touch(flow, now);
+            }
+            self.flow_counters.extend(self.cache.drain(0..));
+            self.evict_at(now);
+        } else {
+            self.flow_counters.extend(self.cache.drain(0..));
+        }
+    }
+
+    /// Evict entries according to `policy`, reporting evicted entries on `sender` (if any). Eviction is checked
+    /// whenever updates are merged into the table, and can be triggered explicitly with `evict`.
+    pub fn set_eviction(&mut self, policy: EvictionPolicy, sender: Option<EvictionSender<T>>) {
+        self.merge_cache();
+        self.evictor.set_policy(policy, sender, self.flow_counters.keys(), precise_time_ns());
+    }
+
+    /// Evict entries according to the eviction policy.
+    pub fn evict(&mut self) {
+        self.merge_cache();
+        self.evict_at(precise_time_ns());
+    }
+
+    /// Like `evict`, with the current time (in nanoseconds, as returned by `time::precise_time_ns`) given explicitly.
+    pub fn evict_at(&mut self, now: u64) {
+        let flow_counters = &mut self.flow_counters;
+        self.evictor.evict(now, |flow| flow_counters.remove(flow));
+    }
+
+    /// Start a new epoch: evict (and report) all entries.
+    pub fn reset_epoch(&mut self) {
+        self.merge_cache();
+        self.evictor.reset(precise_time_ns());
+        let evicted = self.flow_counters.drain().collect();
+        self.evictor.report(evicted);
+    }
+
+    /// Number of entries evicted so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictor.evicted
+    }
+
+    /// Number of evicted entries that could not be reported because the eviction channel was full (or not set).
+    pub fn evictions_lost(&self) -> u64 {
+        self.evictor.lost
     }
 
     /// Change the value for the given `Flow`.
@@ -58,6 +105,7 @@ impl<T: AddAssign<T> + Default> DpMergeableStore<T> {
     #[inline]
     pub fn remove(&mut self, flow: &Flow) -> T {
         self.merge_cache();
+        self.evictor.forget(flow);
         self.flow_counters.remove(flow).unwrap_or(Default::default())
     }
 
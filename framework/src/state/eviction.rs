@@ -0,0 +1,221 @@
+use fnv::FnvHasher;
+
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::sync::mpsc::SyncSender;
+use std::time::Duration;
+
+use utils::Flow;
+
+type FnvHash = BuildHasherDefault<FnvHasher>;
+
+/// When a mergeable store evicts entries. Regardless of policy, all entries can be evicted at once by starting a new
+/// epoch (`reset_epoch`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Entries stay until they are removed. This is the default.
+    Never,
+    /// Evict entries that have not been updated for this long.
+    IdleTimeout(Duration),
+    /// Keep at most this many entries, evicting the least recently updated ones when there are more.
+    Lru(usize),
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> EvictionPolicy {
+        EvictionPolicy::Never
+    }
+}
+
+/// Where a store reports evicted entries, so that, e.g., a flow monitor on the control plane can export them before
+/// they are gone. Create with `std::sync::mpsc::sync_channel`. Evictions are batched, and are dropped (and counted in
+/// the store's `evictions_lost`) if the channel is full.
+pub type EvictionSender<T> = SyncSender<Vec<(Flow, T)>>;
+
+/// Tracks when entries were last updated, and picks entries to evict according to an `EvictionPolicy`. Shared by the
+/// mergeable stores.
+#[derive(Clone)]
+pub struct Evictor<T> {
+    policy: EvictionPolicy,
+    last_used: HashMap<Flow, u64, FnvHash>,
+    last_scan: u64,
+    sender: Option<EvictionSender<T>>,
+    /// Number of entries evicted.
+    pub evicted: u64,
+    /// Number of evicted entries that could not be reported because the channel was full.
+    pub lost: u64,
+}
+
+impl<T> Evictor<T> {
+    pub fn new() -> Evictor<T> {
+        Evictor {
+            policy: EvictionPolicy::Never,
+            last_used: HashMap::default(),
+            last_scan: 0,
+            sender: None,
+            evicted: 0,
+            lost: 0,
+        }
+    }
+
+    /// Whether updates need to be tracked, i.e., whether any policy other than `Never` is set.
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.policy != EvictionPolicy::Never
+    }
+
+    /// Change the policy. Entries already in the store are considered to have been updated at `now`.
+    pub fn set_policy<'a, I: Iterator<Item = &'a Flow>>(&mut self,
+                                                         policy: EvictionPolicy,
+                                                         sender: Option<EvictionSender<T>>,
+                                                         flows: I,
+                                                         now: u64) {
+        self.policy = policy;
+        self.sender = sender;
+        self.last_used.clear();
+        self.last_scan = now;
+        if policy != EvictionPolicy::Never {
+            self.last_used.extend(flows.map(|f| (*f, now)));
+        }
+    }
+
+    /// Record an update to `flow`.
+    #[inline]
+    pub fn touch(&mut self, flow: &Flow, now: u64) {
+        if self.policy != EvictionPolicy::Never {
+            self.last_used.insert(*flow, now);
+        }
+    }
+
+    /// Record the removal of `flow` (other than by eviction).
+    #[inline]
+    pub fn forget(&mut self, flow: &Flow) {
+        if self.policy != EvictionPolicy::Never {
+            self.last_used.remove(flow);
+        }
+    }
+
+    /// Pick entries to evict as of `now`, calling `remove` to take each out of the store, and report them.
+    pub fn evict<F: FnMut(&Flow) -> Option<T>>(&mut self, now: u64, mut remove: F) {
+        let victims: Vec<Flow> = match self.policy {
+            EvictionPolicy::Never => return,
+            EvictionPolicy::IdleTimeout(timeout) => {
+                let timeout = timeout.as_secs() * 1_000_000_000 + timeout.subsec_nanos() as u64;
+                // Scanning is linear in the number of entries, so only scan a few times per timeout.
+                if now < self.last_scan + timeout / 4 {
+                    return;
+                }
+                self.last_scan = now;
+                self.last_used
+                    .iter()
+                    .filter(|&(_, used)| *used + timeout <= now)
+                    .map(|(flow, _)| *flow)
+                    .collect()
+            }
+            EvictionPolicy::Lru(max) => {
+                if self.last_used.len() <= max {
+                    return;
+                }
+                // Evict down to 7/8 of the limit, so sorting is amortized over many updates.
+                let target = max - max / 8;
+                let mut by_age: Vec<_> = self.last_used.iter().map(|(flow, used)| (*used, *flow)).collect();
+                by_age.sort_by(|a, b| a.0.cmp(&b.0));
+                let excess = by_age.len() - target;
+                by_age.truncate(excess);
+                by_age.into_iter().map(|(_, flow)| flow).collect()
+            }
+        };
+        let mut evicted = Vec::with_capacity(victims.len());
+        for flow in victims {
+            self.last_used.remove(&flow);
+            if let Some(value) = remove(&flow) {
+                evicted.push((flow, value));
+            }
+        }
+        self.report(evicted);
+    }
+
+    /// Report entries evicted from the store (e.g., all of them, when starting a new epoch).
+    pub fn report(&mut self, evicted: Vec<(Flow, T)>) {
+        if evicted.is_empty() {
+            return;
+        }
+        let count = evicted.len() as u64;
+        self.evicted += count;
+        let reported = match self.sender {
+            Some(ref sender) => sender.try_send(evicted).is_ok(),
+            None => false,
+        };
+        if !reported {
+            self.lost += count;
+        }
+    }
+
+    /// Start a new epoch, the store is about to report all its entries.
+    pub fn reset(&mut self, now: u64) {
+        self.last_used.clear();
+        self.last_scan = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use state::{DpMergeableStore, EvictionPolicy, MergeableStoreCP};
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+    use std::time::Duration;
+    use time::precise_time_ns;
+    use utils::Flow;
+
+    fn flow(port: u16) -> Flow {
+        Flow {
+            src_ip: 0x0a000001,
+            dst_ip: 0x0a000002,
+            src_port: port,
+            dst_port: 53,
+            proto: 17,
+        }
+    }
+
+    #[test]
+    fn lru() {
+        // Once over the limit, the least recently updated entries go.
+        let (sender, receiver) = sync_channel(4);
+        let mut dp = DpMergeableStore::<u64>::with_cache_and_size(1, 16);
+        dp.set_eviction(EvictionPolicy::Lru(8), Some(sender));
+        for port in 0..9 {
+            dp.update(flow(port), 1);
+        }
+        assert_eq!(dp.len(), 7);
+        let mut evicted = receiver.try_recv().expect("Nothing reported");
+        evicted.sort_by_key(|&(f, _)| f.src_port);
+        assert_eq!(evicted, vec![(flow(0), 1), (flow(1), 1)]);
+
+        // Starting a new epoch reports everything.
+        dp.reset_epoch();
+        assert_eq!(dp.len(), 0);
+        assert_eq!(receiver.try_recv().expect("Nothing reported").len(), 7);
+        assert_eq!(dp.evictions(), 9);
+        assert_eq!(dp.evictions_lost(), 0);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        // Evicted entries reach the control plane store.
+        let mut cp = MergeableStoreCP::<u64>::new();
+        let mut dp = cp.dp_store_with_eviction(1, 16, EvictionPolicy::IdleTimeout(Duration::from_secs(1)));
+        dp.update(flow(1), 5);
+        let updated = precise_time_ns();
+        thread::sleep(Duration::from_millis(1));
+        dp.update(flow(2), 5);
+        cp.sync();
+        assert_eq!(cp.len(), 2);
+        dp.evict_at(updated + 500_000_000);
+        assert!(cp.evicted().is_empty());
+        dp.evict_at(updated + 1_000_000_000);
+        assert_eq!(cp.evicted(), vec![(flow(1), 5)]);
+        cp.sync();
+        assert_eq!(cp.len(), 1);
+        assert_eq!(dp.evictions(), 1);
+    }
+}
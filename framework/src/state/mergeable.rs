@@ -3,10 +3,13 @@ use fnv::FnvHasher;
 use std::collections::HashMap;
 use std::collections::hash_map::Iter;
 use std::hash::BuildHasherDefault;
+use std::mem;
 use std::ops::AddAssign;
-use std::sync::{Arc, RwLock, RwLockReadGuard};
-use std::cmp::max;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::cmp::min;
+use time::precise_time_ns;
 
+use super::eviction::{EvictionPolicy, Evictor};
 use utils::Flow;
 
 /// A generic store for associating some merge-able type with each flow. Note, the merge must be commutative, we do not
@@ -14,40 +17,88 @@ use utils::Flow;
 /// [AddAssign](https://doc.rust-lang.org/std/ops/trait.AddAssign.html) trait and overriding the `add_assign` method
 /// there. We assume that the quantity stored here does not need to be accessed by the control plane and can only be
 /// accessed from the data plane. The `cache_size` should be tuned depending on whether gets or puts are the most common
-/// operation in this table.
+/// operation in this table. Data path stores can evict entries (see `dp_store_with_eviction`), evicted entries are
+/// reported back to the control plane store and can be retrieved with `evicted`, e.g., so a flow monitor can export
+/// them.
 ///
-/// #[FIXME]
-/// Garbage collection.
-/// The current version does not work well with large flow tables. The problem is we need to record a set of differences
-/// rather than copying the entire hashmap. This of course comes with some consistency issues, so we need to fix this.
+/// Data path stores do not share their tables with the control plane. Instead each one accumulates the changes made
+/// since it last reported (a delta), and sends it to the control plane when merging its cache; `sync` then merges
+/// received deltas into the control plane's view using `AddAssign`. Values for a flow updated by several data path
+/// stores are hence summed. The control plane also keeps what each data path store has reported, so when a store
+/// removes (or evicts) a flow only that store's contribution is taken out of the control plane's view.
 type FnvHash = BuildHasherDefault<FnvHasher>;
 const VEC_SIZE: usize = 1 << 10;
 const CACHE_SIZE: usize = 1 << 10;
 const MAX_CACHE_SIZE: usize = 1 << 20;
 const CHAN_SIZE: usize = 128;
 
+/// Changes to a data path store since it last reported to the control plane. Removals happened before all updates.
+struct Delta<T> {
+    /// The data path store these changes were made to.
+    store: usize,
+    updates: HashMap<Flow, T, FnvHash>,
+    removed: Vec<Flow>,
+}
+
+impl<T: AddAssign<T> + Default> Delta<T> {
+    fn new(store: usize) -> Delta<T> {
+        Delta {
+            store: store,
+            updates: HashMap::default(),
+            removed: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.updates.is_empty() && self.removed.is_empty()
+    }
+
+    #[inline]
+    fn update(&mut self, flow: Flow, inc: T) {
+        *(self.updates.entry(flow).or_insert(Default::default())) += inc;
+    }
+
+    #[inline]
+    fn remove(&mut self, flow: &Flow) {
+        self.updates.remove(flow);
+        self.removed.push(*flow);
+    }
+}
+
 pub struct MergeableStoreCP<T: AddAssign<T> + Default + Clone> {
     flow_counters: HashMap<Flow, T, FnvHash>,
-    hashmaps: Vec<Arc<RwLock<HashMap<Flow, T, FnvHash>>>>,
+    /// What each data path store has reported, indexed by `Delta.store`.
+    reported: Vec<HashMap<Flow, T, FnvHash>>,
+    delta_sender: SyncSender<Delta<T>>,
+    delta_receiver: Receiver<Delta<T>>,
+    eviction_sender: SyncSender<Vec<(Flow, T)>>,
+    eviction_receiver: Receiver<Vec<(Flow, T)>>,
 }
 
 impl<T: AddAssign<T> + Default + Clone> MergeableStoreCP<T> {
     pub fn new() -> MergeableStoreCP<T> {
+        let (delta_sender, delta_receiver) = sync_channel(CHAN_SIZE);
+        let (eviction_sender, eviction_receiver) = sync_channel(CHAN_SIZE);
         MergeableStoreCP {
             flow_counters: HashMap::with_capacity_and_hasher(VEC_SIZE << 6, Default::default()),
-            hashmaps: Vec::with_capacity(CHAN_SIZE),
+            reported: Vec::new(),
+            delta_sender: delta_sender,
+            delta_receiver: delta_receiver,
+            eviction_sender: eviction_sender,
+            eviction_receiver: eviction_receiver,
         }
     }
 
     pub fn dp_store_with_cache_and_size(&mut self, cache: usize, size: usize) -> MergeableStoreDP<T> {
-        let hmap = Arc::new(RwLock::new(HashMap::with_capacity_and_hasher(size, Default::default())));
-        self.hashmaps.push(hmap.clone());
+        self.reported.push(HashMap::default());
         MergeableStoreDP {
-            flow_counters: hmap,
+            flow_counters: HashMap::with_capacity_and_hasher(size, Default::default()),
+            delta: Delta::new(self.reported.len() - 1),
+            channel: self.delta_sender.clone(),
             cache: Vec::with_capacity(cache),
             cache_size: cache,
             base_cache_size: cache,
-            len: 0,
+            evictor: Evictor::new(),
         }
     }
 
@@ -55,27 +106,53 @@ impl<T: AddAssign<T> + Default + Clone> MergeableStoreCP<T> {
         MergeableStoreCP::dp_store_with_cache_and_size(self, CACHE_SIZE, VEC_SIZE)
     }
 
-    fn to_vec(hash: &RwLockReadGuard<HashMap<Flow, T, FnvHash>>) -> Vec<(Flow, T)> {
-        let mut t = Vec::with_capacity(hash.len());
-        t.extend(hash.iter().map(|(f, v)| (f.clone(), v.clone())));
-        t
+    /// A data path store that evicts entries according to `policy`, reporting them to this store.
+    pub fn dp_store_with_eviction(&mut self, cache: usize, size: usize, policy: EvictionPolicy) -> MergeableStoreDP<T> {
+        let mut store = self.dp_store_with_cache_and_size(cache, size);
+        store.evictor.set_policy(policy, Some(self.eviction_sender.clone()), [].iter(), precise_time_ns());
+        store
     }
 
+    /// Entries evicted from data path stores since the last call. Evicted entries are no longer part of this store
+    /// after the next `sync`.
+    pub fn evicted(&mut self) -> Vec<(Flow, T)> {
+        let mut evicted = Vec::new();
+        while let Ok(mut v) = self.eviction_receiver.try_recv() {
+            evicted.extend(v.drain(0..));
+        }
+        evicted
+    }
+
+    /// Merge the changes data path stores have reported since the last call.
     pub fn sync(&mut self) {
-        let mut copies: Vec<Vec<_>> = Vec::with_capacity(self.hashmaps.len());
-        {
-            for hmap in self.hashmaps.iter() {
-                {
-                    match hmap.try_read() {
-                        Ok(g) => copies.push(MergeableStoreCP::to_vec(&g)),
-                        _ => (),
-                    }
+        while let Ok(delta) = self.delta_receiver.try_recv() {
+            for flow in &delta.removed {
+                if self.reported[delta.store].remove(flow).is_some() {
+                    self.recompute(flow);
                 }
             }
+            let reported = &mut self.reported[delta.store];
+            for (flow, inc) in delta.updates {
+                *(reported.entry(flow).or_insert(Default::default())) += inc.clone();
+                *(self.flow_counters.entry(flow).or_insert(Default::default())) += inc;
+            }
+        }
+    }
+
+    /// Recompute the value for `flow` from what data path stores have reported, after one of them removed it.
+    fn recompute(&mut self, flow: &Flow) {
+        let mut total: T = Default::default();
+        let mut found = false;
+        for reported in &self.reported {
+            if let Some(value) = reported.get(flow) {
+                total += value.clone();
+                found = true;
+            }
         }
-        self.flow_counters.clear();
-        for mut copy in copies {
-            self.flow_counters.extend(copy.drain(0..));
+        if found {
+            self.flow_counters.insert(*flow, total);
+        } else {
+            self.flow_counters.remove(flow);
         }
     }
 
@@ -95,25 +172,65 @@ impl<T: AddAssign<T> + Default + Clone> MergeableStoreCP<T> {
     }
 }
 
-#[derive(Clone)]
+/// A data path store, obtained from `MergeableStoreCP::dp_store`. Stores cannot be cloned (a clone would report the
+/// same changes again), get a separate store from the control plane for each pipeline or thread instead.
 pub struct MergeableStoreDP<T: AddAssign<T> + Default + Clone> {
     /// Contains the counts on the data path.
-    flow_counters: Arc<RwLock<HashMap<Flow, T, FnvHash>>>,
+    flow_counters: HashMap<Flow, T, FnvHash>,
+    /// Changes not yet sent to the control plane.
+    delta: Delta<T>,
+    channel: SyncSender<Delta<T>>,
     cache: Vec<(Flow, T)>,
     base_cache_size: usize,
     cache_size: usize,
-    len: usize,
+    evictor: Evictor<T>,
 }
 
 impl<T: AddAssign<T> + Default + Clone> MergeableStoreDP<T> {
     fn merge_cache(&mut self) {
-        match self.flow_counters.try_write() {
-            Ok(mut g) => {
-                g.extend(self.cache.drain(0..));
-                self.cache_size = self.base_cache_size;
-                self.len = g.len();
+        if self.evictor.enabled() && !self.cache.is_empty() {
+            let now = precise_time_ns();
+            for &(ref flow, _) in &self.cache {
+                self.evictor.touch(flow, now);
+            }
+            self.merge_updates();
+            self.evict_internal(now);
+        } else {
+            self.merge_updates();
+        }
+    }
+
+    fn merge_updates(&mut self) {
+        for (flow, inc) in self.cache.drain(0..) {
+            *(self.flow_counters.entry(flow).or_insert(Default::default())) += inc.clone();
+            self.delta.update(flow, inc);
+        }
+    }
+
+    fn evict_internal(&mut self, now: u64) {
+        let flow_counters = &mut self.flow_counters;
+        let delta = &mut self.delta;
+        self.evictor.evict(now, |flow| {
+            delta.remove(flow);
+            flow_counters.remove(flow)
+        });
+    }
+
+    /// Send the accumulated changes to the control plane. If the control plane is not keeping up, changes keep
+    /// accumulating (and updates are cached for longer) until they can be sent.
+    fn send_delta(&mut self) {
+        if self.delta.is_empty() {
+            return;
+        }
+        let store = self.delta.store;
+        let delta = mem::replace(&mut self.delta, Delta::new(store));
+        match self.channel.try_send(delta) {
+            Ok(_) => self.cache_size = self.base_cache_size,
+            Err(TrySendError::Full(delta)) |
+            Err(TrySendError::Disconnected(delta)) => {
+                self.delta = delta;
+                self.cache_size = min(self.cache_size * 2, MAX_CACHE_SIZE);
             }
-            _ => self.cache_size = max(self.cache_size * 2, MAX_CACHE_SIZE),
         }
     }
 
@@ -124,27 +241,128 @@ impl<T: AddAssign<T> + Default + Clone> MergeableStoreDP<T> {
             self.cache.push((flow, inc));
         }
         if self.cache.len() >= self.cache_size {
-            self.merge_cache();
+            self.flush();
         }
     }
 
+    /// Merge cached updates and report all changes to the control plane. This happens automatically as updates are
+    /// made, call this when updates might stop for a while so the control plane does not lag behind.
+    pub fn flush(&mut self) {
+        self.merge_cache();
+        self.send_delta();
+    }
+
     /// Remove an entry from the table.
     #[inline]
     pub fn remove(&mut self, flow: &Flow) -> T {
-        // self.merge_cache();
-        match self.flow_counters.write() {
-            Ok(mut g) => {
-                g.extend(self.cache.drain(0..));
-                self.cache_size = self.base_cache_size;
-                self.len = g.len();
-                g.remove(flow).unwrap_or(Default::default())
-            }
-            _ => panic!("Could not acquire write lock"),
+        self.merge_cache();
+        self.evictor.forget(flow);
+        self.delta.remove(flow);
+        self.flow_counters.remove(flow).unwrap_or(Default::default())
+    }
+
+    /// Evict entries according to the eviction policy. Eviction is also checked whenever updates are merged.
+    pub fn evict(&mut self) {
+        self.evict_at(precise_time_ns());
+    }
+
+    /// Like `evict`, with the current time (in nanoseconds, as returned by `time::precise_time_ns`) given explicitly.
+    pub fn evict_at(&mut self, now: u64) {
+        for &(ref flow, _) in &self.cache {
+            self.evictor.touch(flow, now);
         }
+        self.merge_updates();
+        self.evict_internal(now);
+        self.send_delta();
+    }
+
+    /// Start a new epoch: evict (and report) all entries.
+    pub fn reset_epoch(&mut self) {
+        self.merge_updates();
+        self.evictor.reset(precise_time_ns());
+        self.delta.updates.clear();
+        self.delta.removed.extend(self.flow_counters.keys());
+        let evicted = self.flow_counters.drain().collect();
+        self.evictor.report(evicted);
+        self.send_delta();
     }
 
-    /// Approximate length of the table. 
+    /// Number of entries evicted so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictor.evicted
+    }
+
+    /// Number of evicted entries that could not be reported because the control plane was not keeping up.
+    pub fn evictions_lost(&self) -> u64 {
+        self.evictor.lost
+    }
+
+    /// Length of the table.
     pub fn len(&mut self) -> usize {
-        self.len
+        self.merge_cache();
+        self.flow_counters.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_deltas() {
+        let flow = |port: u16| {
+            Flow {
+                src_ip: 0x0a000001,
+                dst_ip: 0x0a000002,
+                src_port: port,
+                dst_port: 53,
+                proto: 17,
+            }
+        };
+        let mut cp = MergeableStoreCP::<u64>::new();
+        let mut dp0 = cp.dp_store_with_cache_and_size(4, 16);
+        let mut dp1 = cp.dp_store_with_cache_and_size(4, 16);
+        for _ in 0..3 {
+            dp0.update(flow(1), 2);
+        }
+        dp0.update(flow(2), 1);
+        dp1.update(flow(1), 10);
+        dp1.flush();
+        cp.sync();
+        assert_eq!(cp.get(&flow(1)), 16);
+        assert_eq!(cp.get(&flow(2)), 1);
+
+        // Nothing new to report: the view stays as is.
+        cp.sync();
+        assert_eq!(cp.len(), 2);
+
+        // Only changes are sent, and removals are applied before later updates.
+        dp0.update(flow(2), 1);
+        assert_eq!(dp0.remove(&flow(2)), 2);
+        dp0.update(flow(2), 5);
+        dp0.update(flow(3), 1);
+        dp0.flush();
+        cp.sync();
+        assert_eq!(cp.get(&flow(1)), 16);
+        assert_eq!(cp.get(&flow(2)), 5);
+        assert_eq!(cp.get(&flow(3)), 1);
+        assert_eq!(dp0.len(), 3);
+
+        // Removing or evicting a flow on one store leaves the other stores' contributions.
+        dp1.update(flow(2), 7);
+        dp1.flush();
+        cp.sync();
+        assert_eq!(cp.get(&flow(2)), 12);
+        assert_eq!(dp0.remove(&flow(1)), 6);
+        assert_eq!(dp0.remove(&flow(2)), 5);
+        dp0.flush();
+        cp.sync();
+        assert_eq!(cp.get(&flow(1)), 10);
+        assert_eq!(cp.get(&flow(2)), 7);
+        dp1.reset_epoch();
+        cp.sync();
+        assert_eq!(cp.get(&flow(1)), 0);
+        assert_eq!(cp.get(&flow(2)), 0);
+        assert_eq!(cp.len(), 1);
     }
 }
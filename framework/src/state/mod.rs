@@ -1,6 +1,12 @@
+pub use self::conntrack::*;
 pub use self::cp_mergeable::*;
 pub use self::dp_mergeable::*;
+pub use self::eviction::{EvictionPolicy, EvictionSender};
 pub use self::mergeable::*;
+pub use self::neighbour::*;
+mod conntrack;
 mod dp_mergeable;
 mod cp_mergeable;
+mod eviction;
 mod mergeable;
+mod neighbour;
@@ -0,0 +1,128 @@
+use fnv::FnvHasher;
+
+use headers::MacHeader;
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use time::precise_time_ns;
+
+type FnvHash = BuildHasherDefault<FnvHasher>;
+
+#[derive(Clone, Copy)]
+struct Neighbour {
+    mac: [u8; 6],
+    learned: u64,
+}
+
+/// IPv4 to MAC address mappings, learned from ARP packets (see `HeaderOperations::respond_arp`). Clones share the same
+/// cache, so one can be handed to the batch learning addresses and others to the batches that need to fill in
+/// `MacHeader.dst`.
+#[derive(Clone)]
+pub struct NeighbourCache {
+    neighbours: Arc<RwLock<HashMap<u32, Neighbour, FnvHash>>>,
+    /// Entries older than this (in nanoseconds) are ignored, 0 if entries do not expire.
+    timeout: u64,
+}
+
+impl NeighbourCache {
+    /// A cache whose entries never expire.
+    pub fn new() -> NeighbourCache {
+        NeighbourCache {
+            neighbours: Arc::new(RwLock::new(HashMap::default())),
+            timeout: 0,
+        }
+    }
+
+    /// A cache whose entries are ignored once they have not been refreshed for `timeout`.
+    pub fn with_timeout(timeout: Duration) -> NeighbourCache {
+        NeighbourCache {
+            timeout: timeout.as_secs() * 1_000_000_000 + timeout.subsec_nanos() as u64,
+            ..NeighbourCache::new()
+        }
+    }
+
+    /// Record that `ip` is at `mac`.
+    pub fn insert(&self, ip: u32, mac: [u8; 6]) {
+        self.insert_at(ip, mac, precise_time_ns())
+    }
+
+    /// Like `insert`, with the current time (in nanoseconds, as returned by `time::precise_time_ns`) given explicitly.
+    pub fn insert_at(&self, ip: u32, mac: [u8; 6], now: u64) {
+        let mut neighbours = self.neighbours.write().expect("Could not acquire write lock");
+        neighbours.insert(ip,
+                          Neighbour {
+                              mac: mac,
+                              learned: now,
+                          });
+    }
+
+    /// Record that `ip` is at `mac` if `ip` is already in the cache, returns false (leaving the cache unchanged)
+    /// otherwise.
+    pub fn update(&self, ip: u32, mac: [u8; 6]) -> bool {
+        self.update_at(ip, mac, precise_time_ns())
+    }
+
+    /// Like `update`, with the current time given explicitly.
+    pub fn update_at(&self, ip: u32, mac: [u8; 6], now: u64) -> bool {
+        let mut neighbours = self.neighbours.write().expect("Could not acquire write lock");
+        match neighbours.get_mut(&ip) {
+            Some(n) => {
+                n.mac = mac;
+                n.learned = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The MAC address of `ip`, if known.
+    pub fn lookup(&self, ip: u32) -> Option<[u8; 6]> {
+        self.lookup_at(ip, precise_time_ns())
+    }
+
+    /// Like `lookup`, with the current time given explicitly.
+    pub fn lookup_at(&self, ip: u32, now: u64) -> Option<[u8; 6]> {
+        let neighbours = self.neighbours.read().expect("Could not acquire read lock");
+        match neighbours.get(&ip) {
+            Some(n) if self.timeout == 0 || n.learned + self.timeout > now => Some(n.mac),
+            _ => None,
+        }
+    }
+
+    /// Set `mac`'s destination to the address of `ip`. Returns false (leaving `mac` unchanged) if `ip` is unknown, in
+    /// which case the packet should usually be dropped.
+    pub fn fill_dst(&self, mac: &mut MacHeader, ip: u32) -> bool {
+        match self.lookup(ip) {
+            Some(addr) => {
+                mac.dst = addr;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&self, ip: u32) -> Option<[u8; 6]> {
+        let mut neighbours = self.neighbours.write().expect("Could not acquire write lock");
+        neighbours.remove(&ip).map(|n| n.mac)
+    }
+
+    /// Drop expired entries.
+    pub fn expire(&self) {
+        if self.timeout != 0 {
+            let now = precise_time_ns();
+            let mut neighbours = self.neighbours.write().expect("Could not acquire write lock");
+            let expired: Vec<u32> = neighbours.iter()
+                .filter(|&(_, n)| n.learned + self.timeout <= now)
+                .map(|(ip, _)| *ip)
+                .collect();
+            for ip in expired {
+                neighbours.remove(&ip);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.neighbours.read().expect("Could not acquire read lock").len()
+    }
+}
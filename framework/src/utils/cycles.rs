@@ -0,0 +1,28 @@
+#[cfg(any(test, feature = "test-backend"))]
+use io::test_backend::{current_cycles, cycles_per_second};
+
+#[cfg(not(any(test, feature = "test-backend")))]
+#[link(name = "zcsi")]
+extern "C" {
+    fn current_cycles() -> u64;
+    fn cycles_per_second() -> u64;
+}
+
+/// Read the time stamp counter (using DPDK's `rte_rdtsc`).
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { current_cycles() }
+}
+
+/// Frequency of the time stamp counter, as calibrated by DPDK. Only valid once the system has been initialized.
+#[inline]
+pub fn tsc_hz() -> u64 {
+    unsafe { cycles_per_second() }
+}
+
+/// Convert a number of cycles to nanoseconds, given the TSC frequency `hz` (which must not be 0).
+#[inline]
+pub fn cycles_to_ns(cycles: u64, hz: u64) -> u64 {
+    debug_assert!(hz > 0, "Converting cycles with an unknown TSC frequency");
+    ((cycles as f64 * 1e9) / hz as f64) as u64
+}
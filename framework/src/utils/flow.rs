@@ -1,6 +1,7 @@
 use fnv::FnvHasher;
 
 use byteorder::{BigEndian, ByteOrder};
+use headers::{is_ipv6_extension_header, Ipv6ExtensionHeaders};
 use std::hash::Hasher;
 use std::mem;
 use std::slice;
@@ -17,6 +18,17 @@ pub struct Flow {
     pub proto: u8,
 }
 
+/// Five-tuple for IPv6 packets. Addresses are kept as bytes in network order.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Ord, PartialOrd)]
+#[repr(C,packed)]
+pub struct Ipv6Flow {
+    pub src_ip: [u8; 16],
+    pub dst_ip: [u8; 16],
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+}
+
 const IHL_TO_BYTE_FACTOR: usize = 4; // IHL is in terms of number of 32-bit words.
 
 /// This assumes the function is given the Mac Payload
@@ -32,6 +44,41 @@ pub fn ipv4_extract_flow(bytes: &[u8]) -> Flow {
     }
 }
 
+const IPV6_HDR_SIZE: usize = 40;
+const TCP_PROTO: u8 = 6;
+const UDP_PROTO: u8 = 17;
+const SCTP_PROTO: u8 = 132;
+
+/// This assumes the function is given the Mac Payload. Extension headers are skipped to find the upper layer protocol;
+/// ports are only filled in for TCP, UDP and SCTP, and are left as 0 for fragments other than the first. Returns `None`
+/// if the packet is truncated or the extension header chain is malformed.
+#[inline]
+pub fn ipv6_extract_flow(bytes: &[u8]) -> Option<Ipv6Flow> {
+    if bytes.len() < IPV6_HDR_SIZE {
+        return None;
+    }
+    let mut flow = Ipv6Flow::default();
+    flow.src_ip.copy_from_slice(&bytes[8..24]);
+    flow.dst_ip.copy_from_slice(&bytes[24..40]);
+    let payload = &bytes[IPV6_HDR_SIZE..];
+    let mut headers = Ipv6ExtensionHeaders::new(bytes[6], payload);
+    let mut later_fragment = false;
+    while let Some(hdr) = headers.next() {
+        if hdr.fragment_offset().unwrap_or(0) != 0 {
+            later_fragment = true;
+        }
+    }
+    headers.upper_layer().map(|(proto, port_start)| {
+        flow.proto = proto;
+        if !later_fragment && !is_ipv6_extension_header(proto) && payload.len() >= port_start + 4 &&
+           (proto == TCP_PROTO || proto == UDP_PROTO || proto == SCTP_PROTO) {
+            flow.src_port = BigEndian::read_u16(&payload[port_start..(port_start + 2)]);
+            flow.dst_port = BigEndian::read_u16(&payload[(port_start + 2)..(port_start + 4)]);
+        }
+        flow
+    })
+}
+
 /// Given the MAC payload, generate a flow hash. The flow hash generated depends on the IV, so different IVs will
 /// produce different results (in cases when implementing Cuckoo hashing, etc.).
 #[inline]
@@ -47,6 +94,21 @@ pub fn flow_hash(flow: &Flow) -> usize {
     // farmhash::hash32(flow_as_u8(flow))
 }
 
+/// Given the MAC payload of an IPv6 packet, generate a flow hash. Packets whose flow cannot be extracted all hash to
+/// the same value.
+#[inline]
+pub fn ipv6_flow_hash(bytes: &[u8], _iv: u32) -> usize {
+    ipv6_extract_flow(bytes).map_or(0, |flow| {
+        let mut hasher = FnvHasher::default();
+        hasher.write(flow_as_u8(&flow));
+        hasher.finish() as usize
+    })
+}
+
+#[cfg(any(test, feature = "test-backend"))]
+use io::test_backend::crc_hash_native;
+
+#[cfg(not(any(test, feature = "test-backend")))]
 #[link(name = "zcsi")]
 extern "C" {
     fn crc_hash_native(to_hash: *const u8, size: u32, iv: u32) -> u32;
@@ -65,7 +127,91 @@ pub fn crc_hash<T: Sized>(to_hash: &T, iv: u32) -> u32 {
     }
 }
 
-fn flow_as_u8<'a>(flow: &'a Flow) -> &'a [u8] {
-    let size = mem::size_of::<Flow>();
-    unsafe { slice::from_raw_parts(((flow as *const Flow) as *const u8), size) }
+fn flow_as_u8<'a, T: Sized>(flow: &'a T) -> &'a [u8] {
+    let size = mem::size_of::<T>();
+    unsafe { slice::from_raw_parts(((flow as *const T) as *const u8), size) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headers::{IPV6_DESTINATION_OPTIONS, IPV6_FRAGMENT, IPV6_HOP_BY_HOP};
+
+    /// An IPv6 header with the given next header followed by `payload`.
+    fn packet(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x60, 0, 0, 0, 0, payload.len() as u8, next_header, 64];
+        bytes.extend((0..16).map(|i| i as u8));
+        bytes.extend((0..16).map(|i| 0x80 | i as u8));
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn fragment(next_header: u8, offset: u16) -> Vec<u8> {
+        vec![next_header, 0, (offset >> 5) as u8, (offset << 3) as u8 | 1, 0, 0, 0, 7]
+    }
+
+    const PORTS: [u8; 8] = [0x12, 0x34, 0x56, 0x78, 0, 8, 0, 0];
+
+    #[test]
+    fn ipv6_flow_without_extension_headers() {
+        let bytes = packet(UDP_PROTO, &PORTS);
+        let flow = ipv6_extract_flow(&bytes).unwrap();
+        assert_eq!(flow.src_ip[15], 15);
+        assert_eq!(flow.dst_ip[0], 0x80);
+        assert_eq!(flow.proto, UDP_PROTO);
+        assert_eq!((flow.src_port, flow.dst_port), (0x1234, 0x5678));
+    }
+
+    #[test]
+    fn ipv6_flow_through_extension_headers() {
+        let mut payload = vec![IPV6_DESTINATION_OPTIONS, 0, 0, 0, 0, 0, 0, 0];
+        payload.extend_from_slice(&[TCP_PROTO, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        payload.extend_from_slice(&PORTS);
+        let flow = ipv6_extract_flow(&packet(IPV6_HOP_BY_HOP, &payload)).unwrap();
+        assert_eq!(flow.proto, TCP_PROTO);
+        assert_eq!((flow.src_port, flow.dst_port), (0x1234, 0x5678));
+    }
+
+    #[test]
+    fn ipv6_flow_fragments() {
+        let mut payload = fragment(UDP_PROTO, 0);
+        payload.extend_from_slice(&PORTS);
+        let flow = ipv6_extract_flow(&packet(IPV6_FRAGMENT, &payload)).unwrap();
+        assert_eq!(flow.proto, UDP_PROTO);
+        assert_eq!((flow.src_port, flow.dst_port), (0x1234, 0x5678));
+
+        // Later fragments carry data from the middle of the datagram, which must not be read as ports.
+        let mut payload = fragment(UDP_PROTO, 185);
+        payload.extend_from_slice(&PORTS);
+        let flow = ipv6_extract_flow(&packet(IPV6_FRAGMENT, &payload)).unwrap();
+        assert_eq!(flow.proto, UDP_PROTO);
+        assert_eq!((flow.src_port, flow.dst_port), (0, 0));
+    }
+
+    #[test]
+    fn ipv6_flow_without_ports() {
+        // Too short to hold the ports.
+        let flow = ipv6_extract_flow(&packet(UDP_PROTO, &PORTS[..3])).unwrap();
+        assert_eq!(flow.proto, UDP_PROTO);
+        assert_eq!((flow.src_port, flow.dst_port), (0, 0));
+        // Not a protocol with ports.
+        let flow = ipv6_extract_flow(&packet(58, &PORTS)).unwrap();
+        assert_eq!(flow.proto, 58);
+        assert_eq!((flow.src_port, flow.dst_port), (0, 0));
+    }
+
+    #[test]
+    fn ipv6_flow_truncated() {
+        let bytes = packet(UDP_PROTO, &PORTS);
+        assert!(ipv6_extract_flow(&bytes[..IPV6_HDR_SIZE - 1]).is_none());
+        assert_eq!(ipv6_flow_hash(&bytes[..IPV6_HDR_SIZE - 1], 0), 0);
+        // Extension header cut short.
+        let payload = fragment(UDP_PROTO, 0);
+        assert!(ipv6_extract_flow(&packet(IPV6_FRAGMENT, &payload[..6])).is_none());
+        // Extension header claiming more bytes than the packet holds.
+        let payload = [UDP_PROTO, 4, 0, 0, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78];
+        assert!(ipv6_extract_flow(&packet(IPV6_HOP_BY_HOP, &payload)).is_none());
+        // Extension header announced but missing.
+        assert!(ipv6_extract_flow(&packet(IPV6_DESTINATION_OPTIONS, &[])).is_none());
+    }
 }
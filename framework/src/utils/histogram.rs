@@ -0,0 +1,99 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Each power of two is split into this many (linear) buckets, bounding the relative error to about 3%.
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// A log-linear histogram of latencies (in nanoseconds), e.g., as recorded by `Batch::measure_latency`. Clones share
+/// the same counts, so one clone can be recorded into by the data plane while another is read (e.g., to report
+/// percentiles) from a monitoring thread.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    buckets: Arc<Vec<AtomicUsize>>,
+}
+
+#[inline]
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        value as usize
+    } else {
+        let msb = 63 - value.leading_zeros();
+        let shift = msb - SUB_BUCKET_BITS;
+        let sub = (value >> shift) as usize & (SUB_BUCKETS - 1);
+        ((shift as usize + 1) << SUB_BUCKET_BITS) + sub
+    }
+}
+
+/// Smallest value that falls into bucket `idx`.
+#[inline]
+fn bucket_value(idx: usize) -> u64 {
+    if idx < SUB_BUCKETS {
+        idx as u64
+    } else {
+        let shift = (idx >> SUB_BUCKET_BITS) - 1;
+        ((SUB_BUCKETS + (idx & (SUB_BUCKETS - 1))) as u64) << shift
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram { buckets: Arc::new((0..BUCKETS).map(|_| AtomicUsize::new(0)).collect()) }
+    }
+
+    /// Record a latency of `ns` nanoseconds.
+    #[inline]
+    pub fn record(&self, ns: u64) {
+        self.buckets[bucket_index(ns)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of latencies recorded.
+    pub fn count(&self) -> usize {
+        self.buckets.iter().fold(0, |count, bucket| count + bucket.load(Ordering::Relaxed))
+    }
+
+    /// The latency (in nanoseconds, rounded down to the resolution of the histogram) below which `percentile` percent
+    /// of recorded latencies fall, e.g., `percentile(99.9)`. Returns 0 if nothing has been recorded.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let counts: Vec<_> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let total = counts.iter().fold(0, |total, count| total + count);
+        if total == 0 {
+            return 0;
+        }
+        let rank = ((percentile / 100.0) * total as f64).ceil().max(1.0) as usize;
+        let mut seen = 0;
+        for (idx, count) in counts.iter().enumerate() {
+            seen += *count;
+            if seen >= rank {
+                return bucket_value(idx);
+            }
+        }
+        bucket_value(BUCKETS - 1)
+    }
+
+    /// Forget everything recorded so far.
+    pub fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram::new()
+    }
+}
+
+impl fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "count: {} p50: {}ns p99: {}ns p999: {}ns",
+               self.count(),
+               self.percentile(50.0),
+               self.percentile(99.0),
+               self.percentile(99.9))
+    }
+}
@@ -1,2 +1,6 @@
+pub use self::cycles::*;
 pub use self::flow::*;
+pub use self::histogram::*;
+mod cycles;
 mod flow;
+mod histogram;
@@ -16,6 +16,7 @@ int init_secondary_mempool(const char* mempool_name);
 int find_secondary_mempool();
 struct rte_mbuf *mbuf_alloc();
 void mbuf_free(struct rte_mbuf* buf);
+void mbuf_refcnt_update(struct rte_mbuf* buf, int16_t value);
 int mbuf_alloc_bulk(mbuf_array_t array, uint16_t len, int cnt);
 int mbuf_free_bulk(mbuf_array_t array, int cnt);
 struct rte_mempool *get_pframe_pool(int coreid, int sid);
@@ -3,9 +3,19 @@
 int num_pmd_ports();
 int get_pmd_ports(struct rte_eth_dev_info* info, int len);
 void enumerate_pmd_ports();
+struct rss_conf;
 int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
-		int nrxd, int ntxd, int loopback, int tso, int csumoffload);
+		int nrxd, int ntxd, int loopback, int tso, int csumoffload,
+		const struct rss_conf *rss);
+int add_fdir_filter(int port, uint32_t src_ip, uint32_t dst_ip,
+		uint16_t src_port, uint16_t dst_port, uint8_t proto, int queue);
 int free_pmd_port(int port);
 int recv_pkts(int port, int qid, mbuf_array_t pkts, int len);
 int send_pkts(int port, int qid, mbuf_array_t pkts, int len);
+int init_pcap_port(const char *rx_pcap, const char *tx_pcap);
+struct port_stats;
+int get_port_stats(int port, struct port_stats* stats);
+struct port_xstat;
+int get_port_xstats(int port, struct port_xstat* xstats, int len);
+void reset_port_stats(int port);
 #endif
@@ -215,6 +215,16 @@ void mbuf_free(struct rte_mbuf* buf)
 	rte_pktmbuf_free(buf);
 }
 
+/* Update the reference count of every segment in a packet (rte_pktmbuf_free
+ * decrements each segment's count). */
+void mbuf_refcnt_update(struct rte_mbuf* buf, int16_t value)
+{
+	while (buf != NULL) {
+		rte_mbuf_refcnt_update(buf, value);
+		buf = buf->next;
+	}
+}
+
 /* Using AVX for now. Revisit this decision someday */
 /* mbuf_alloc_bulk: Bulk alloc packets.
  *	array: Array to allocate into.
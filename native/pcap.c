@@ -0,0 +1,49 @@
+#include <errno.h>
+#include <stdio.h>
+#include <string.h>
+
+#include <rte_config.h>
+#include <rte_ethdev.h>
+#include <rte_log.h>
+
+#include "mempool.h"
+
+/**
+ * This file provides a way to attach DPDK's pcap PMD at runtime, so that a
+ * pipeline can receive packets from a recorded trace and write whatever it
+ * sends into another trace without needing any NICs.
+ **/
+
+#define DEVARGS_LEN 1024
+
+/* Each attached vdev needs a unique name. */
+static int pcap_port_count = 0;
+
+/* Attach a pcap backed port, reading from rx_pcap and writing to tx_pcap.
+ * Returns the port number or a negative value on error. The port still needs
+ * to be configured (init_pmd_port) before use. */
+int init_pcap_port(const char *rx_pcap, const char *tx_pcap)
+{
+	char devargs[DEVARGS_LEN];
+	uint8_t port;
+	int ret;
+
+	if (rx_pcap == NULL || tx_pcap == NULL) {
+		return -EINVAL;
+	}
+
+	ret = snprintf(devargs, DEVARGS_LEN, "eth_pcap%d,rx_pcap=%s,tx_pcap=%s",
+			pcap_port_count, rx_pcap, tx_pcap);
+	if (ret < 0 || ret >= DEVARGS_LEN) {
+		return -EINVAL;
+	}
+
+	ret = rte_eth_dev_attach(devargs, &port);
+	if (ret != 0) {
+		RTE_LOG(WARNING, PMD, "Could not attach %s\n", devargs);
+		return ret;
+	}
+	pcap_port_count++;
+	RTE_LOG(INFO, PMD, "Attached %s as port %d\n", devargs, port);
+	return port;
+}
@@ -1,3 +1,4 @@
+#include <netinet/in.h>
 #include <rte_config.h>
 #include <rte_eal.h>
 #include <rte_ethdev.h>
@@ -92,8 +93,36 @@ void enumerate_pmd_ports()
 	}
 }
 
+/* Mirrors RssConf in framework/src/io/pmd.rs. */
+struct rss_conf {
+	uint64_t hash_functions;	/* ETH_RSS_* flags */
+	const uint8_t *key;		/* NULL for the driver's default key */
+	uint32_t key_len;
+	const uint16_t *reta;		/* NULL for the driver's default table */
+	uint32_t reta_len;
+	int flow_director;		/* Enable perfect match flow director */
+};
+
+/* Fill the NIC's redirection table by repeating reta. */
+static int update_reta(int port, const uint16_t *reta, uint32_t reta_len,
+		uint16_t reta_size)
+{
+	struct rte_eth_rss_reta_entry64 conf[reta_size / RTE_RETA_GROUP_SIZE + 1];
+	uint16_t i;
+
+	memset(conf, 0, sizeof(conf));
+	for (i = 0; i < reta_size; i++) {
+		struct rte_eth_rss_reta_entry64 *group =
+			&conf[i / RTE_RETA_GROUP_SIZE];
+		group->mask |= 1ULL << (i % RTE_RETA_GROUP_SIZE);
+		group->reta[i % RTE_RETA_GROUP_SIZE] = reta[i % reta_len];
+	}
+	return rte_eth_dev_rss_reta_update(port, conf, reta_size);
+}
+
 int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
-		int nrxd, int ntxd, int loopback, int tso, int csumoffload)
+		int nrxd, int ntxd, int loopback, int tso, int csumoffload,
+		const struct rss_conf *rss)
 {
 	struct rte_eth_dev_info dev_info = {};
 	struct rte_eth_conf eth_conf;
@@ -111,6 +140,22 @@ int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
 
 	eth_conf = default_eth_conf;
 	eth_conf.lpbk_mode = !(!loopback);
+	if (rss) {
+		eth_conf.rx_adv_conf.rss_conf.rss_hf = rss->hash_functions;
+		eth_conf.rx_adv_conf.rss_conf.rss_key = (uint8_t *)rss->key;
+		eth_conf.rx_adv_conf.rss_conf.rss_key_len = rss->key_len;
+		if (rss->flow_director) {
+			/* Match on the whole IPv4 five tuple. */
+			eth_conf.fdir_conf.mode = RTE_FDIR_MODE_PERFECT;
+			eth_conf.fdir_conf.pballoc = RTE_FDIR_PBALLOC_64K;
+			eth_conf.fdir_conf.status = RTE_FDIR_REPORT_STATUS;
+			eth_conf.fdir_conf.drop_queue = 127;
+			eth_conf.fdir_conf.mask.ipv4_mask.src_ip = 0xffffffff;
+			eth_conf.fdir_conf.mask.ipv4_mask.dst_ip = 0xffffffff;
+			eth_conf.fdir_conf.mask.src_port_mask = 0xffff;
+			eth_conf.fdir_conf.mask.dst_port_mask = 0xffff;
+		}
+	}
 
 	/* Use defaut rx/tx configuration as provided by PMD drivers,
 	 * with minor tweaks */
@@ -163,9 +208,62 @@ int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
 		printf("Failed to start \n");
 		return ret; /* Clean up things */
 	}
+
+	if (rss && rss->reta && rss->reta_len > 0) {
+		if (dev_info.reta_size == 0) {
+			printf("Port %d does not have a redirection table\n", port);
+			return -ENOTSUP;
+		}
+		ret = update_reta(port, rss->reta, rss->reta_len,
+				dev_info.reta_size);
+		if (ret != 0) {
+			printf("Failed to update redirection table\n");
+			return ret;
+		}
+	}
 	return 0;
 }
 
+/* Steer IPv4 packets matching the five tuple (addresses and ports in host
+ * order) to queue. Requires the port to be configured with flow director. */
+int add_fdir_filter(int port, uint32_t src_ip, uint32_t dst_ip,
+		uint16_t src_port, uint16_t dst_port, uint8_t proto, int queue)
+{
+	struct rte_eth_fdir_filter filter;
+	static uint32_t soft_id = 0;
+
+	memset(&filter, 0, sizeof(filter));
+	/* Filters may be added from several threads. */
+	filter.soft_id = __sync_fetch_and_add(&soft_id, 1);
+	switch (proto) {
+	case IPPROTO_TCP:
+		filter.input.flow_type = RTE_ETH_FLOW_NONFRAG_IPV4_TCP;
+		filter.input.flow.tcp4_flow.ip.src_ip = rte_cpu_to_be_32(src_ip);
+		filter.input.flow.tcp4_flow.ip.dst_ip = rte_cpu_to_be_32(dst_ip);
+		filter.input.flow.tcp4_flow.src_port = rte_cpu_to_be_16(src_port);
+		filter.input.flow.tcp4_flow.dst_port = rte_cpu_to_be_16(dst_port);
+		break;
+	case IPPROTO_UDP:
+		filter.input.flow_type = RTE_ETH_FLOW_NONFRAG_IPV4_UDP;
+		filter.input.flow.udp4_flow.ip.src_ip = rte_cpu_to_be_32(src_ip);
+		filter.input.flow.udp4_flow.ip.dst_ip = rte_cpu_to_be_32(dst_ip);
+		filter.input.flow.udp4_flow.src_port = rte_cpu_to_be_16(src_port);
+		filter.input.flow.udp4_flow.dst_port = rte_cpu_to_be_16(dst_port);
+		break;
+	default:
+		filter.input.flow_type = RTE_ETH_FLOW_NONFRAG_IPV4_OTHER;
+		filter.input.flow.ip4_flow.src_ip = rte_cpu_to_be_32(src_ip);
+		filter.input.flow.ip4_flow.dst_ip = rte_cpu_to_be_32(dst_ip);
+		filter.input.flow.ip4_flow.proto = proto;
+		break;
+	}
+	filter.action.rx_queue = queue;
+	filter.action.behavior = RTE_ETH_FDIR_ACCEPT;
+	filter.action.report_status = RTE_ETH_FDIR_REPORT_ID;
+	return rte_eth_dev_filter_ctrl(port, RTE_ETH_FILTER_FDIR,
+			RTE_ETH_FILTER_ADD, &filter);
+}
+
 void free_pmd_port(int port)
 {
 	rte_eth_dev_stop(port);
@@ -185,3 +283,61 @@ int send_pkts(int port, int qid, mbuf_array_t pkts, int len)
 	return rte_eth_tx_burst(port, (uint16_t) qid, (struct rte_mbuf **)pkts, 
 			(uint16_t)len);
 }
+
+/* Mirrors HardwareStats in framework/src/io/stats.rs. */
+struct port_stats {
+	uint64_t ipackets;
+	uint64_t opackets;
+	uint64_t ibytes;
+	uint64_t obytes;
+	uint64_t imissed;
+	uint64_t ierrors;
+	uint64_t oerrors;
+	uint64_t rx_nombuf;
+};
+
+int get_port_stats(int port, struct port_stats* stats)
+{
+	struct rte_eth_stats eth_stats;
+	memset(&eth_stats, 0, sizeof(eth_stats));
+	/* Older DPDK versions return void here. */
+	rte_eth_stats_get(port, &eth_stats);
+	stats->ipackets = eth_stats.ipackets;
+	stats->opackets = eth_stats.opackets;
+	stats->ibytes = eth_stats.ibytes;
+	stats->obytes = eth_stats.obytes;
+	stats->imissed = eth_stats.imissed;
+	stats->ierrors = eth_stats.ierrors;
+	stats->oerrors = eth_stats.oerrors;
+	stats->rx_nombuf = eth_stats.rx_nombuf;
+	return 0;
+}
+
+/* Mirrors PortXstat in framework/src/io/pmd.rs. */
+struct port_xstat {
+	char name[RTE_ETH_XSTATS_NAME_SIZE];
+	uint64_t value;
+};
+
+/* Copy up to len extended statistics into xstats. Returns the number of
+ * statistics available if xstats is NULL or len is too small. */
+int get_port_xstats(int port, struct port_xstat* xstats, int len)
+{
+	int count = rte_eth_xstats_get(port, NULL, 0);
+	if (count <= 0 || xstats == NULL || len < count) {
+		return count;
+	}
+	struct rte_eth_xstats eth_xstats[count];
+	count = rte_eth_xstats_get(port, eth_xstats, count);
+	for (int i = 0; i < count && i < len; i++) {
+		snprintf(xstats[i].name, sizeof(xstats[i].name), "%s", eth_xstats[i].name);
+		xstats[i].value = eth_xstats[i].value;
+	}
+	return count;
+}
+
+void reset_port_stats(int port)
+{
+	rte_eth_stats_reset(port);
+	rte_eth_xstats_reset(port);
+}
@@ -1,5 +1,4 @@
 #![feature(box_syntax)]
-#![feature(asm)]
 extern crate e2d2;
 extern crate fnv;
 extern crate time;
@@ -9,6 +8,7 @@ extern crate rand;
 use e2d2::io::*;
 use e2d2::headers::*;
 use e2d2::packet_batch::*;
+use e2d2::utils::{rdtsc, LatencyHistogram};
 use getopts::Options;
 use std::collections::HashMap;
 use std::env;
@@ -17,63 +17,35 @@ use std::thread;
 
 const CONVERSION_FACTOR: f64 = 1000000000.;
 
-#[inline]
-fn rdtscp_unsafe() -> u64 {
-    unsafe {
-        let low: u32;
-        let high: u32;
-        asm!("rdtscp"
-             : "={eax}" (low), "={edx}" (high)
-             :
-             : "{ecx}"
-             : "volatile");
-        ((high as u64) << 32) | (low as u64)
-    }
-}
-
-#[inline]
-fn rdtscp() -> u64 {
-    rdtscp_unsafe()
-}
-
-#[inline]
-fn lat() {
-    unsafe {
-        asm!("nop"
-             :
-             :
-             :
-             : "volatile");
-    }
-}
-
+/// Busy wait for `delay` cycles.
 #[inline]
 fn delay_loop(delay: u64) {
-    let mut d = 0;
-    while d < delay {
-        lat();
-        d += 1;
-    }
+    let start = rdtsc();
+    while rdtsc() - start < delay {}
 }
 
-fn delay<T: 'static + Batch>(parent: T, delay: u64) -> CompositionBatch {
-    parent.parse::<MacHeader>()
+/// Swap MAC addresses and wait for `delay` cycles on each packet, recording how long packets spend in the pipeline in
+/// `histogram`.
+fn delay<T: 'static + Batch>(parent: T, delay: u64, histogram: LatencyHistogram) -> CompositionBatch {
+    parent.timestamp()
+          .parse::<MacHeader>()
           .transform(box move |hdr, _, _| {
               let src = hdr.src.clone();
               hdr.src = hdr.dst;
               hdr.dst = src;
               delay_loop(delay);
           })
+          .measure_latency(histogram)
           .compose()
 }
 
-fn recv_thread(ports: Vec<PmdPort>, queue: i32, core: i32, delay_arg: u64) {
+fn recv_thread(ports: Vec<PmdPort>, queue: i32, core: i32, delay_arg: u64, histogram: LatencyHistogram) {
     init_thread(core, core);
     println!("Receiving started");
 
     let pipelines: Vec<_> = ports.iter()
                                  .map(|port| {
-                                     delay(ReceiveBatch::new(port.copy(), queue), delay_arg)
+                                     delay(ReceiveBatch::new(port.copy(), queue), delay_arg, histogram.clone())
                                          .send(port.copy(), queue)
                                          .compose()
                                  }).collect();
@@ -164,11 +136,14 @@ fn main() {
     }
     const _BATCH: usize = 1 << 10;
     const _CHANNEL_SIZE: usize = 256;
+    // Shared by all pipelines, so latencies are reported across all cores.
+    let histogram = LatencyHistogram::new();
     let _thread: Vec<_> = ports_by_core.iter()
                                        .map(|(core, ports)| {
                                            let c = core.clone();
                                            let p: Vec<_> = ports.iter().map(|p| p.copy()).collect();
-                                           std::thread::spawn(move || recv_thread(p, 0, c, delay_arg))
+                                           let h = histogram.clone();
+                                           std::thread::spawn(move || recv_thread(p, 0, c, delay_arg, h))
                                        })
                                        .collect();
     let mut pkts_so_far = (0, 0);
@@ -185,17 +160,14 @@ fn main() {
                                             .fold((0, 0), |(r, t), (rp, tp)| (r + rp, t + tp))
                                     })
                                     .fold((0, 0), |(r, t), (rp, tp)| (r + rp, t + tp));
-            let start_cycles = rdtscp();
-            delay_loop(100);
-            let end_cycles = rdtscp();
-            let delay = end_cycles - start_cycles;
-            println!("{:.2} OVERALL RX {:.2} TX {:.2} CYCLE_PER_DELAY {} {} {}",
+            println!("{:.2} OVERALL RX {:.2} TX {:.2} LATENCY p50 {}ns p99 {}ns p999 {}ns",
                      now - start,
                      (pkts.0 - pkts_so_far.0) as f64 / (now - start),
                      (pkts.1 - pkts_so_far.1) as f64 / (now - start),
-                     start_cycles,
-                     end_cycles,
-                     delay);
+                     histogram.percentile(50.0),
+                     histogram.percentile(99.0),
+                     histogram.percentile(99.9));
+            histogram.reset();
             start = now;
             pkts_so_far = pkts;
         }
@@ -38,13 +38,13 @@ fn monitor<T: 'static + Batch>(parent: T, mut monitoring_cache: MergeableStoreDP
           .compose()
 }
 
-fn recv_thread(ports: Vec<PmdPort>, queue: i32, core: i32, counter: MergeableStoreDP<isize>) {
+fn recv_thread(ports: Vec<PmdPort>, queue: i32, core: i32, counters: Vec<MergeableStoreDP<isize>>) {
     init_thread(core, core);
     println!("Receiving started");
 
     let pipelines: Vec<_> = ports.iter()
-                                 .map(|port| {
-                                     let ctr = counter.clone();
+                                 .zip(counters.into_iter())
+                                 .map(|(port, ctr)| {
                                      monitor(ReceiveBatch::new(port.copy(), queue), ctr)
                                          .send(port.copy(), queue)
                                          .compose()
@@ -125,12 +125,13 @@ fn main() {
     let _thread: Vec<_> = ports_by_core.iter()
                                        .map(|(core, ports)| {
                                            let c = core.clone();
-                                           let mon = consumer.dp_store();
+                                           let mon: Vec<_> = ports.iter().map(|_| consumer.dp_store()).collect();
                                            let p: Vec<_> = ports.iter().map(|p| p.copy()).collect();
                                            std::thread::spawn(move || recv_thread(p, 0, c, mon))
                                        })
                                        .collect();
-    let mut pkts_so_far = (0, 0);
+    let ports: Vec<_> = ports_by_core.values().flat_map(|pvec| pvec.iter()).collect();
+    let mut stats_so_far: Vec<_> = ports.iter().map(|p| p.port_stats()).collect();
     let mut start = time::precise_time_ns() as f64 / CONVERSION_FACTOR;
     let sleep_time = Duration::from_millis(500);
     loop {
@@ -138,20 +139,25 @@ fn main() {
         consumer.sync();
         let now = time::precise_time_ns() as f64 / CONVERSION_FACTOR;
         if now - start > 1.0 {
-            let pkts = ports_by_core.values()
-                                    .map(|pvec| {
-                                        pvec.iter()
-                                            .map(|p| p.stats(0))
-                                            .fold((0, 0), |(r, t), (rp, tp)| (r + rp, t + tp))
-                                    })
-                                    .fold((0, 0), |(r, t), (rp, tp)| (r + rp, t + tp));
-            println!("{:.2} OVERALL RX {:.2} TX {:.2} FLOWS {}",
+            let stats: Vec<_> = ports.iter().map(|p| p.port_stats()).collect();
+            let (rx, tx, dropped, missed) = stats.iter()
+                                                 .zip(stats_so_far.iter())
+                                                 .map(|(s, before)| s.delta(before))
+                                                 .fold((0, 0, 0, 0), |(r, t, d, m), delta| {
+                                                     (r + delta.rx_packets(),
+                                                      t + delta.tx_packets(),
+                                                      d + delta.tx_dropped(),
+                                                      m + delta.hardware.rx_missed)
+                                                 });
+            println!("{:.2} OVERALL RX {:.2} TX {:.2} TX DROPPED {:.2} RX MISSED {:.2} FLOWS {}",
                      now - start,
-                     (pkts.0 - pkts_so_far.0) as f64 / (now - start),
-                     (pkts.1 - pkts_so_far.1) as f64 / (now - start),
+                     rx as f64 / (now - start),
+                     tx as f64 / (now - start),
+                     dropped as f64 / (now - start),
+                     missed as f64 / (now - start),
                      consumer.len());
             start = now;
-            pkts_so_far = pkts;
+            stats_so_far = stats;
         }
     }
 }